@@ -0,0 +1,25 @@
+use kvs::{KvStore, KvStoreOptions, Result};
+use std::path::Path;
+
+// Builds a store in `dir` with `live_keys` keys and `garbage_ratio` of the log records being garbage.
+// Automatic compaction is disabled so the garbage stays around for a forced compaction.
+pub fn build_store(dir: &Path, live_keys: usize, garbage_ratio: f64) -> Result<KvStore> {
+  let options = KvStoreOptions {
+    compaction_threshold: u64::MAX,
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_options(dir, options)?;
+
+  for key_id in 0..live_keys {
+    store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+  }
+
+  // garbage / (live + garbage) = ratio
+  let garbage = (live_keys as f64 * garbage_ratio / (1.0 - garbage_ratio)).round() as usize;
+  for iter in 0..garbage {
+    let key_id = iter % live_keys;
+    store.set(format!("key{}", key_id), format!("value{}-{}", key_id, iter))?;
+  }
+
+  Ok(store)
+}
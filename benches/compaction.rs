@@ -0,0 +1,47 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use tempfile::TempDir;
+
+mod common;
+
+const LIVE_KEYS: &[usize] = &[1_000, 10_000];
+const GARBAGE_RATIOS: &[f64] = &[0.25, 0.5, 0.75];
+
+fn compaction(c: &mut Criterion) {
+  let mut group = c.benchmark_group("compaction");
+
+  for &live_keys in LIVE_KEYS {
+    for &ratio in GARBAGE_RATIOS {
+      // the setup is deterministic so one dry run tells how many bytes each compaction reclaims
+      let reclaimed = {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = common::build_store(temp_dir.path(), live_keys, ratio).unwrap();
+        store.compact().unwrap()
+      };
+      group.throughput(Throughput::Bytes(reclaimed));
+
+      group.bench_with_input(
+        BenchmarkId::new(format!("{}_keys", live_keys), ratio),
+        &ratio,
+        |b, &ratio| {
+          b.iter_batched(
+            || {
+              let temp_dir = TempDir::new().unwrap();
+              let store = common::build_store(temp_dir.path(), live_keys, ratio).unwrap();
+              (temp_dir, store)
+            },
+            |(temp_dir, mut store)| {
+              store.compact().unwrap();
+              (temp_dir, store)
+            },
+            BatchSize::PerIteration,
+          )
+        },
+      );
+    }
+  }
+
+  group.finish();
+}
+
+criterion_group!(benches, compaction);
+criterion_main!(benches);
@@ -0,0 +1,60 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use kvs::{KvStore, KvStoreOptions, SyncPolicy, WriteBatch};
+use tempfile::TempDir;
+
+const WRITES: usize = 100;
+
+fn group_commit(c: &mut Criterion) {
+  let mut group = c.benchmark_group("durable_writes");
+  group.throughput(Throughput::Elements(WRITES as u64));
+
+  let policies = &[
+    ("every_write", SyncPolicy::EveryWrite),
+    ("group_10", SyncPolicy::Group(10)),
+    ("group_100", SyncPolicy::Group(WRITES)),
+  ];
+  for &(name, sync_policy) in policies {
+    group.bench_function(BenchmarkId::new("set", name), |b| {
+      b.iter_batched(
+        || open_store(sync_policy),
+        |(temp_dir, mut store)| {
+          for key_id in 0..WRITES {
+            store.set(format!("key{}", key_id), format!("value{}", key_id)).unwrap();
+          }
+          (temp_dir, store)
+        },
+        BatchSize::PerIteration,
+      )
+    });
+  }
+
+  group.bench_function(BenchmarkId::new("batch", "every_write"), |b| {
+    b.iter_batched(
+      || open_store(SyncPolicy::EveryWrite),
+      |(temp_dir, mut store)| {
+        let mut batch = WriteBatch::new();
+        for key_id in 0..WRITES {
+          batch.set(format!("key{}", key_id), format!("value{}", key_id));
+        }
+        store.write(batch).unwrap();
+        (temp_dir, store)
+      },
+      BatchSize::PerIteration,
+    )
+  });
+
+  group.finish();
+}
+
+fn open_store(sync_policy: SyncPolicy) -> (TempDir, KvStore) {
+  let temp_dir = TempDir::new().unwrap();
+  let options = KvStoreOptions {
+    sync_policy,
+    ..KvStoreOptions::default()
+  };
+  let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+  (temp_dir, store)
+}
+
+criterion_group!(benches, group_commit);
+criterion_main!(benches);
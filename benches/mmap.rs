@@ -0,0 +1,40 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::{KvStore, KvStoreOptions};
+use tempfile::TempDir;
+
+const KEYS: usize = 10_000;
+
+fn random_get(c: &mut Criterion) {
+  let temp_dir = TempDir::new().unwrap();
+  {
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    for key_id in 0..KEYS {
+      store.set(format!("key{}", key_id), format!("value{}", key_id)).unwrap();
+    }
+  }
+
+  // 7919 is coprime with KEYS, so this visits every key once in a scattered order
+  let keys: Vec<String> = (0..KEYS).map(|i| format!("key{}", (i * 7919) % KEYS)).collect();
+
+  let mut group = c.benchmark_group("random_get");
+  for &mmap_reads in &[false, true] {
+    let options = KvStoreOptions {
+      mmap_reads,
+      ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+
+    let name = if mmap_reads { "mmap" } else { "file" };
+    group.bench_function(BenchmarkId::from_parameter(name), |b| {
+      b.iter(|| {
+        for key in &keys {
+          store.get(key.clone()).unwrap();
+        }
+      })
+    });
+  }
+  group.finish();
+}
+
+criterion_group!(benches, random_get);
+criterion_main!(benches);
@@ -0,0 +1,48 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use kvs::KvStore;
+use tempfile::TempDir;
+
+const KEYS: usize = 10_000;
+
+// back to back reads and writes, where per-operation allocations show
+fn sequential(c: &mut Criterion) {
+  let mut group = c.benchmark_group("sequential");
+  group.throughput(Throughput::Elements(KEYS as u64));
+
+  let keys: Vec<String> = (0..KEYS).map(|key_id| format!("key{}", key_id)).collect();
+
+  group.bench_function("set", |b| {
+    b.iter_batched(
+      || {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        (temp_dir, store)
+      },
+      |(temp_dir, mut store)| {
+        for key in &keys {
+          store.set(key.clone(), "value".repeat(20)).unwrap();
+        }
+        (temp_dir, store)
+      },
+      BatchSize::PerIteration,
+    )
+  });
+
+  let temp_dir = TempDir::new().unwrap();
+  let mut store = KvStore::open(temp_dir.path()).unwrap();
+  for key in &keys {
+    store.set(key.clone(), "value".repeat(20)).unwrap();
+  }
+  group.bench_function("get", |b| {
+    b.iter(|| {
+      for key in &keys {
+        store.get(key.clone()).unwrap();
+      }
+    })
+  });
+
+  group.finish();
+}
+
+criterion_group!(benches, sequential);
+criterion_main!(benches);
@@ -119,8 +119,8 @@ fn server_loop(stream: TcpStream) -> Result<()> {
     println!("recv PING");
 
     let pong_reply = b"+PONG";
-    reader.get_mut().write(pong_reply)?;
-    reader.get_mut().write(b"\r\n")?;
+    reader.get_mut().write_all(pong_reply)?;
+    reader.get_mut().write_all(b"\r\n")?;
 
     println!("sent PONG");
   }
@@ -0,0 +1,16 @@
+// Feed arbitrary bytes to the RESP readers, which must only ever fail with an error, never panic
+//
+// Run with `cargo fuzz run resp` from the repository root, starting from the seed corpus in `fuzz/corpus/resp`.
+
+#![no_main]
+use kvs::resp;
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+  let mut reader = Cursor::new(data);
+  while let Ok(Some(_)) = resp::read_command(&mut reader) {}
+
+  let mut reader = Cursor::new(data);
+  while resp::read_value(&mut reader).is_ok() {}
+});
@@ -0,0 +1,74 @@
+//! An async interface to KvStore, for use from async tasks without blocking the reactor
+
+use crate::{FileStorage, KvStore, KvStoreError, LogStorage, Result};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+/// A key-value store engine with async operations
+pub trait AsyncKvsEngine: Clone + Send + Sync + 'static {
+  /// Get the value associated with the given key
+  fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> + Send;
+
+  /// Set the value associated with the given key
+  fn set(&self, key: String, value: String) -> impl Future<Output = Result<()>> + Send;
+
+  /// Remove the given key and its associated value
+  fn remove(&self, key: String) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// AsyncKvStore runs the operations of a shared KvStore on Tokio's blocking thread pool
+///
+/// Clones share the same store, and operations on it take turns.
+pub struct AsyncKvStore<S: LogStorage = FileStorage> {
+  store: Arc<Mutex<KvStore<S>>>,
+}
+
+impl<S: LogStorage> Clone for AsyncKvStore<S> {
+  fn clone(&self) -> Self {
+    Self {
+      store: self.store.clone(),
+    }
+  }
+}
+
+impl<S: LogStorage + Send + 'static> AsyncKvStore<S> {
+  /// Wraps the given store
+  pub fn new(store: KvStore<S>) -> Self {
+    Self {
+      store: Arc::new(Mutex::new(store)),
+    }
+  }
+
+  // run `f` on the store from a blocking thread
+  fn run<T, F>(&self, f: F) -> impl Future<Output = Result<T>> + Send
+  where
+    T: Send + 'static,
+    F: FnOnce(&mut KvStore<S>) -> Result<T> + Send + 'static,
+  {
+    let store = self.store.clone();
+    async move {
+      tokio::task::spawn_blocking(move || {
+        let mut store = store
+          .lock()
+          .map_err(|_| KvStoreError::TaskError("store is unavailable after a panic".to_owned()))?;
+        f(&mut store)
+      })
+      .await
+      .map_err(|e| KvStoreError::TaskError(e.to_string()))?
+    }
+  }
+}
+
+impl<S: LogStorage + Send + 'static> AsyncKvsEngine for AsyncKvStore<S> {
+  fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> + Send {
+    self.run(move |store| store.get(key))
+  }
+
+  fn set(&self, key: String, value: String) -> impl Future<Output = Result<()>> + Send {
+    self.run(move |store| store.set(key, value))
+  }
+
+  fn remove(&self, key: String) -> impl Future<Output = Result<()>> + Send {
+    self.run(move |store| store.remove(key))
+  }
+}
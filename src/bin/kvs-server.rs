@@ -0,0 +1,62 @@
+use anyhow::{Context, Result};
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::time::Duration;
+use structopt::StructOpt;
+
+use kvs::server::{parse_addr, run_server, ServerConfig};
+
+/// Serve the store in the current directory over RESP, until interrupted or terminated
+#[derive(Debug, StructOpt)]
+#[structopt(
+  name = "kvs-server",
+  author = env!("CARGO_PKG_AUTHORS"),
+)]
+struct Opt {
+  /// Address to listen on as host:port, e.g. 0.0.0.0:4000 for every interface or [::1]:4000 for IPv6 loopback
+  #[structopt(long, default_value = "127.0.0.1:4000", parse(try_from_str = parse_addr))]
+  addr: SocketAddr,
+  /// Also listen on a Unix domain socket at this path
+  #[structopt(long, parse(from_os_str))]
+  socket: Option<PathBuf>,
+  /// Accept FLUSHALL, which removes every key
+  #[structopt(long)]
+  allow_flush: bool,
+  /// Seconds a connection may stay silent before it's dropped, 0 to wait forever
+  #[structopt(long, default_value = "10")]
+  read_timeout: u64,
+  /// Seconds a connection may take to accept a reply before it's dropped, 0 to wait forever
+  #[structopt(long, default_value = "10")]
+  write_timeout: u64,
+  /// Connections served at once, further ones are rejected
+  #[structopt(long, default_value = "1024")]
+  max_connections: usize,
+  /// Seconds between background compaction checks, 0 to compact inline on writes instead
+  #[structopt(long, default_value = "0")]
+  compaction_interval: u64,
+}
+
+// 0 seconds means no timeout, or no interval
+fn timeout(secs: u64) -> Option<Duration> {
+  Some(Duration::from_secs(secs)).filter(|timeout| !timeout.is_zero())
+}
+
+fn main() -> Result<()> {
+  let opt = Opt::from_args();
+  let listener = TcpListener::bind(opt.addr).with_context(|| format!("Cannot bind {}", opt.addr))?;
+  let mut config = ServerConfig::from_listener(listener)
+    .with_allow_flush(opt.allow_flush)
+    .with_read_timeout(timeout(opt.read_timeout))
+    .with_write_timeout(timeout(opt.write_timeout))
+    .with_max_connections(opt.max_connections)
+    .with_compaction_interval(timeout(opt.compaction_interval));
+  if let Some(path) = opt.socket {
+    config = config.with_socket(path);
+  }
+
+  // SIGINT and SIGTERM stop the server once the requests being answered are done, and flush the store
+  let shutdown = config.shutdown_handle();
+  ctrlc::set_handler(move || shutdown.shutdown()).context("Cannot handle shutdown signals")?;
+
+  Ok(run_server(config)?)
+}
@@ -1,4 +1,10 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
+use serde::Serialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
 use kvs::*;
@@ -8,40 +14,366 @@ use kvs::*;
   author = env!("CARGO_PKG_AUTHORS"),
   about = env!("CARGO_PKG_DESCRIPTION"),
 )]
+struct Opt {
+  /// How results and errors are printed, `json` prints `{"result": ...}` or `{"error": "..."}` on stdout
+  #[structopt(long, default_value = "text", possible_values = &["text", "json"])]
+  format: String,
+  #[structopt(subcommand)]
+  cmd: Kv,
+}
+
+#[derive(Debug, StructOpt)]
 enum Kv {
-  Get { key: String },
-  Set { key: String, value: String },
-  Rm { key: String },
+  Get {
+    key: String,
+  },
+  Set {
+    key: String,
+    #[structopt(required_unless = "stdin", conflicts_with = "stdin")]
+    value: Option<String>,
+    /// Read the value from standard input instead
+    #[structopt(long)]
+    stdin: bool,
+  },
+  Rm {
+    #[structopt(required_unless_one = &["prefix", "all"], conflicts_with_all = &["prefix", "all"])]
+    key: Option<String>,
+    /// Remove every key starting with this prefix instead
+    #[structopt(long)]
+    prefix: Option<String>,
+    /// Allow removing every key, either alone or with an empty prefix
+    #[structopt(long)]
+    all: bool,
+  },
+  /// Show statistics of the store
+  Stats {
+    /// Print as JSON
+    #[structopt(long)]
+    json: bool,
+  },
+  /// Compact the log, reclaiming space taken by overwritten and removed keys
+  Compact {
+    /// Report how many keys were compacted so far on stderr
+    #[structopt(long)]
+    progress: bool,
+  },
+  /// Print all key-value pairs as tab separated lines, with backslash, tab and newlines escaped
+  Scan {
+    /// Only print keys starting with this prefix
+    #[structopt(long, default_value = "")]
+    prefix: String,
+  },
+  /// Truncate a corrupted log after the last record that can be read
+  Repair,
+  /// Show the version of kvs, and the format version of the store in this directory if there is one
+  Version,
+  /// Load key-value pairs from a file, either a JSON object or `key<sep>value` lines
+  Import {
+    file: PathBuf,
+    /// `json` for a JSON object of string values, `kv` for one pair per line
+    #[structopt(long, default_value = "json", possible_values = &["json", "kv"])]
+    format: String,
+    /// Separator between key and value with `--format kv`, escape it with a backslash inside keys and values
+    #[structopt(long, default_value = "\t")]
+    sep: String,
+  },
 }
 
-fn main() -> Result<()> {
-  match Kv::from_args() {
+// Escape a key or value so it fits on one tab separated line
+fn escape(s: &str) -> String {
+  let mut escaped = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '\\' => escaped.push_str("\\\\"),
+      '\n' => escaped.push_str("\\n"),
+      '\r' => escaped.push_str("\\r"),
+      '\t' => escaped.push_str("\\t"),
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+// Parse a `key<sep>value` line, undoing the escapes of `escape` along with `\<sep>`
+fn parse_kv_line(line: &str, sep: char) -> Result<(String, String)> {
+  let mut key = None;
+  let mut current = String::new();
+  let mut chars = line.chars();
+  while let Some(c) = chars.next() {
+    match c {
+      '\\' => match chars.next() {
+        Some('\\') => current.push('\\'),
+        Some('n') => current.push('\n'),
+        Some('r') => current.push('\r'),
+        Some('t') => current.push('\t'),
+        Some(c) if c == sep => current.push(c),
+        Some(c) => bail!("unknown escape \\{}", c),
+        None => bail!("line ends with a lone backslash"),
+      },
+      c if c == sep && key.is_none() => key = Some(std::mem::take(&mut current)),
+      c => current.push(c),
+    }
+  }
+
+  match key {
+    Some(key) => Ok((key, current)),
+    None => bail!("no separator {:?}", sep),
+  }
+}
+
+// Parse the separator given to `--sep`, a single character or `\t` for a tab
+fn parse_sep(sep: &str) -> Result<char> {
+  if sep == "\\t" {
+    return Ok('\t');
+  }
+
+  let mut chars = sep.chars();
+  match (chars.next(), chars.next()) {
+    // these would clash with escapes or line breaks
+    (Some(c), None) if !matches!(c, '\\' | 'n' | 'r' | 't' | '\n' | '\r') => Ok(c),
+    _ => bail!("invalid separator {:?}, expected a single character", sep),
+  }
+}
+
+#[derive(Serialize)]
+struct StatsReport {
+  #[serde(flatten)]
+  stats: KvStoreStats,
+  compaction_threshold: u64,
+}
+
+#[derive(Serialize)]
+struct VersionReport {
+  version: &'static str,
+  engine: &'static str,
+  format_version: Option<u32>,
+}
+
+// What a subcommand ended up with, `main` decides what gets printed and the exit code
+enum Outcome {
+  Done,
+  Value(String),
+  Text(String),
+  Json(JsonValue),
+  KeyNotFound,
+  RmKeyNotFound,
+}
+
+// one-shot commands skip compacting on open, `kvs compact` is there to reclaim space explicitly
+fn open_store() -> Result<KvStore> {
+  let options = KvStoreOptions {
+    compact_on_open: false,
+    ..KvStoreOptions::default()
+  };
+  Ok(KvStore::open_with_options(".", options)?)
+}
+
+fn run(cmd: Kv, json_output: bool) -> Result<Outcome> {
+  match cmd {
     Kv::Get { key } => {
-      let mut store = KvStore::open(".")?;
+      let mut store = open_store()?;
       match store.get(key)? {
-        Some(vv) => println!("{}", vv),
-        None => println!("Key not found"),
+        Some(vv) => Ok(Outcome::Value(vv)),
+        None => Ok(Outcome::KeyNotFound),
       }
-      Ok(())
     }
-    Kv::Set { key, value } => {
-      let mut store = KvStore::open(".")?;
-      store.set(key, value)?;
-      Ok(())
-    }
-    Kv::Rm { key } => {
-      let mut store = KvStore::open(".")?;
-      let handled_not_found = match store.remove(key) {
-        Err(KvStoreError::RmKeyNotFoundError) => {
-          println!("Key not found");
-          std::process::exit(1);
+    Kv::Set { key, value, stdin } => {
+      let value = match value {
+        Some(value) if !stdin => value,
+        _ => {
+          let mut value = String::new();
+          io::stdin().read_to_string(&mut value)?;
+          value
         }
-        other => other,
       };
 
-      handled_not_found?;
+      let mut store = open_store()?;
+      store.set(key, value)?;
+      Ok(Outcome::Done)
+    }
+    Kv::Rm { key: Some(key), .. } => {
+      let mut store = open_store()?;
+      match store.remove(key) {
+        Ok(()) => Ok(Outcome::Done),
+        Err(KvStoreError::RmKeyNotFoundError) => Ok(Outcome::RmKeyNotFound),
+        Err(e) => Err(e.into()),
+      }
+    }
+    Kv::Rm { key: None, prefix, all } => {
+      let prefix = prefix.unwrap_or_default();
+      if prefix.is_empty() && !all {
+        bail!("refusing to remove every key without --all");
+      }
 
-      Ok(())
+      let mut store = open_store()?;
+      let removed = store.remove_prefix(&prefix)?;
+      Ok(Outcome::Text(format!("removed {} keys", removed)))
+    }
+    Kv::Stats { json } => {
+      let mut store = open_store()?;
+      let report = StatsReport {
+        stats: store.stats()?,
+        compaction_threshold: store.options().compaction_threshold,
+      };
+
+      if json || json_output {
+        Ok(Outcome::Json(serde_json::to_value(&report)?))
+      } else {
+        Ok(Outcome::Text(format!(
+          "live keys: {}\ngarbage: {}\nlog size: {} bytes\ncompaction threshold: {}",
+          report.stats.live_keys, report.stats.garbage, report.stats.log_bytes, report.compaction_threshold
+        )))
+      }
+    }
+    Kv::Compact { progress } => {
+      let mut store = open_store()?;
+      if store.stats()?.garbage == 0 {
+        return Ok(Outcome::Text("nothing to reclaim".to_owned()));
+      }
+
+      let reclaimed = if progress {
+        let reclaimed = store.compact_with_progress(|processed, total| {
+          eprint!("\rcompacted {}/{} keys", processed, total);
+        })?;
+        eprintln!();
+        reclaimed
+      } else {
+        store.compact()?
+      };
+      Ok(Outcome::Text(format!("reclaimed {} bytes", reclaimed)))
+    }
+    Kv::Scan { prefix } => {
+      let mut store = open_store()?;
+      let lines: Vec<String> = store
+        .scan_prefix(&prefix)?
+        .iter()
+        .map(|(key, value)| format!("{}\t{}", escape(key), escape(value)))
+        .collect();
+
+      if lines.is_empty() {
+        Ok(Outcome::Done)
+      } else {
+        Ok(Outcome::Text(lines.join("\n")))
+      }
+    }
+    Kv::Repair => {
+      let report = KvStore::repair(".")?;
+      Ok(Outcome::Text(format!(
+        "salvaged {} records, discarded {} bytes",
+        report.salvaged_records, report.discarded_bytes
+      )))
+    }
+    Kv::Version => {
+      // looking for the log rather than opening the store, which would create one
+      let report = VersionReport {
+        version: env!("CARGO_PKG_VERSION"),
+        engine: "kvs",
+        format_version: if Path::new("kvs.log").is_file() {
+          let mut header = Vec::with_capacity(format::HEADER_LEN);
+          fs::File::open("kvs.log")?
+            .take(format::HEADER_LEN as u64)
+            .read_to_end(&mut header)?;
+          Some(format::detect_version(&header)?.0)
+        } else {
+          None
+        },
+      };
+
+      if json_output {
+        Ok(Outcome::Json(serde_json::to_value(&report)?))
+      } else {
+        let format_version = match report.format_version {
+          Some(version) => version.to_string(),
+          None => "no store in this directory".to_owned(),
+        };
+        Ok(Outcome::Text(format!(
+          "kvs {}\nengine: {}\nformat version: {}",
+          report.version, report.engine, format_version
+        )))
+      }
+    }
+    Kv::Import { file, format, sep } => {
+      let contents = fs::read_to_string(&file)?;
+      let entries: Vec<(String, String)> = if format == "kv" {
+        let sep = parse_sep(&sep)?;
+        contents
+          .lines()
+          .enumerate()
+          .filter(|(_, line)| !line.is_empty())
+          .map(|(i, line)| parse_kv_line(line, sep).map_err(|e| e.context(format!("line {}", i + 1))))
+          .collect::<Result<_>>()?
+      } else {
+        serde_json::from_str::<BTreeMap<String, String>>(&contents)?
+          .into_iter()
+          .collect()
+      };
+
+      let imported = entries.len();
+      let mut store = open_store()?;
+      store.bulk_load(entries.into_iter())?;
+      Ok(Outcome::Text(format!("imported {} keys", imported)))
+    }
+  }
+}
+
+// Print the outcome as text, returning the exit code
+fn print_text(outcome: Outcome) -> i32 {
+  match outcome {
+    Outcome::Done => 0,
+    Outcome::Value(vv) => {
+      println!("{}", vv);
+      0
+    }
+    Outcome::Text(text) => {
+      println!("{}", text);
+      0
+    }
+    Outcome::Json(value) => {
+      println!("{}", value);
+      0
+    }
+    Outcome::KeyNotFound => {
+      println!("Key not found");
+      0
+    }
+    Outcome::RmKeyNotFound => {
+      println!("Key not found");
+      1
     }
   }
 }
+
+// Print the outcome or error as a JSON object, returning the exit code
+fn print_json(outcome: Result<Outcome>) -> i32 {
+  let (output, exit_code) = match outcome {
+    Ok(Outcome::Done) | Ok(Outcome::KeyNotFound) => (json!({ "result": null }), 0),
+    Ok(Outcome::Value(text)) | Ok(Outcome::Text(text)) => (json!({ "result": text }), 0),
+    Ok(Outcome::Json(value)) => (json!({ "result": value }), 0),
+    Ok(Outcome::RmKeyNotFound) => (
+      json!({ "error": "Key not found", "code": KvStoreError::KeyNotFoundError.code() }),
+      1,
+    ),
+    Err(e) => {
+      // the code of the store error behind it, errors of the CLI itself have none
+      let code = e
+        .chain()
+        .find_map(|e| e.downcast_ref::<KvStoreError>())
+        .map(KvStoreError::code);
+      (json!({ "error": format!("{:#}", e), "code": code }), 1)
+    }
+  };
+  println!("{}", output);
+  exit_code
+}
+
+fn main() -> Result<()> {
+  let opt = Opt::from_args();
+  let outcome = run(opt.cmd, opt.format == "json");
+  let exit_code = if opt.format == "json" {
+    print_json(outcome)
+  } else {
+    print_text(outcome?)
+  };
+
+  std::process::exit(exit_code)
+}
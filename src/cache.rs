@@ -0,0 +1,92 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// A least recently used cache of values, bounded by the bytes of its keys and values
+pub(crate) struct ValueCache {
+  capacity: usize,
+  used: usize,
+  // key -> (value, tick of its last use)
+  entries: HashMap<String, (String, u64)>,
+  // tick of last use -> key, oldest first
+  order: BTreeMap<u64, String>,
+  tick: u64,
+}
+
+impl ValueCache {
+  /// Creates a cache holding up to `capacity` bytes, a capacity of 0 caches nothing
+  pub(crate) fn new(capacity: usize) -> Self {
+    Self {
+      capacity,
+      used: 0,
+      entries: HashMap::new(),
+      order: BTreeMap::new(),
+      tick: 0,
+    }
+  }
+
+  /// Get the cached value of the key, marking it as the most recently used
+  pub(crate) fn get(&mut self, key: &str) -> Option<&str> {
+    let tick = self.next_tick();
+    let (value, last_used) = self.entries.get_mut(key)?;
+    let key = self.order.remove(last_used).expect("cache order out of sync");
+    self.order.insert(tick, key);
+    *last_used = tick;
+
+    Some(value)
+  }
+
+  /// Cache the value of the key, evicting the least recently used entries to make room
+  ///
+  /// Entries bigger than the whole cache are not cached.
+  pub(crate) fn insert(&mut self, key: String, value: String) {
+    self.remove(&key);
+    let size = key.len() + value.len();
+    if size > self.capacity {
+      return;
+    }
+
+    while self.used + size > self.capacity {
+      let (_, oldest) = self.order.pop_first().expect("cache order out of sync");
+      self.forget(&oldest);
+    }
+
+    let tick = self.next_tick();
+    self.used += size;
+    self.order.insert(tick, key.clone());
+    self.entries.insert(key, (value, tick));
+  }
+
+  /// Drop the cached value of the key, if any
+  pub(crate) fn remove(&mut self, key: &str) {
+    if let Some(tick) = self.forget(key) {
+      self.order.remove(&tick);
+    }
+  }
+
+  /// Change how many bytes the cache holds, evicting the least recently used entries down to the new capacity
+  pub(crate) fn set_capacity(&mut self, capacity: usize) {
+    self.capacity = capacity;
+    while self.used > self.capacity {
+      let (_, oldest) = self.order.pop_first().expect("cache order out of sync");
+      self.forget(&oldest);
+    }
+  }
+
+  /// Drop every cached value
+  pub(crate) fn clear(&mut self) {
+    self.entries.clear();
+    self.order.clear();
+    self.used = 0;
+  }
+
+  // drop the entry but not its place in the order, returns the tick of its last use
+  fn forget(&mut self, key: &str) -> Option<u64> {
+    let (value, tick) = self.entries.remove(key)?;
+    self.used -= key.len() + value.len();
+    Some(tick)
+  }
+
+  fn next_tick(&mut self) -> u64 {
+    self.tick += 1;
+    self.tick
+  }
+}
@@ -0,0 +1,282 @@
+//! A client for KvsServer, and a pool to share connections between threads
+
+use crate::resp::{self, RespCommand, RespValue};
+use crate::{KvStoreError, Result};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufReader};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// KvsClient sends commands to a KvsServer over a single connection
+///
+/// A request failing on the connection drops it, and the next request connects again. Requests that are safe
+/// to repeat are retried on a new connection, as configured by `ClientOptions`.
+pub struct KvsClient {
+  addr: SocketAddr,
+  options: ClientOptions,
+  // None once a request failed halfway, after which replies can't be matched to requests anymore
+  conn: Option<BufReader<TcpStream>>,
+}
+
+/// Options for a KvsClient
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+  /// How many times a failed request is retried before giving up with the last error
+  pub max_retries: u32,
+  /// Wait before the first retry, doubled for every further one
+  pub initial_backoff: Duration,
+  /// The longest wait between retries
+  pub max_backoff: Duration,
+  /// Retry removes as well, which aren't by default as a remove that did reach the server fails when repeated
+  pub retry_remove: bool,
+}
+
+impl Default for ClientOptions {
+  fn default() -> Self {
+    Self {
+      max_retries: 3,
+      initial_backoff: Duration::from_millis(50),
+      max_backoff: Duration::from_secs(1),
+      retry_remove: false,
+    }
+  }
+}
+
+impl KvsClient {
+  /// Connect to the server at the given address
+  ///
+  /// An address taken from the command line or a config file is best parsed with `server::parse_addr` first,
+  /// which says what's wrong with a malformed one.
+  pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+    Self::connect_with_options(addr, ClientOptions::default())
+  }
+
+  /// Connect to the server at the given address with the given options
+  pub fn connect_with_options(addr: impl ToSocketAddrs, options: ClientOptions) -> Result<Self> {
+    let addr = resolve(addr)?;
+    let stream = TcpStream::connect(addr)?;
+    Ok(Self {
+      addr,
+      options,
+      conn: Some(BufReader::new(stream)),
+    })
+  }
+
+  /// Get the value associated with the given key
+  pub fn get(&mut self, key: String) -> Result<Option<String>> {
+    match self.request(RespCommand::new(vec!["GET".to_owned(), key]), true)? {
+      RespValue::Bulk(None) => Ok(None),
+      RespValue::Bulk(Some(bytes)) => String::from_utf8(bytes)
+        .map(Some)
+        .map_err(|_| KvStoreError::ProtocolError("value is not valid UTF-8".to_owned())),
+      reply => Err(unexpected(reply)),
+    }
+  }
+
+  /// Set the value associated with the given key
+  pub fn set(&mut self, key: String, value: String) -> Result<()> {
+    match self.request(RespCommand::new(vec!["SET".to_owned(), key, value]), true)? {
+      RespValue::SimpleString(ref ok) if ok == "OK" => Ok(()),
+      reply => Err(unexpected(reply)),
+    }
+  }
+
+  /// Remove the given key and its associated value
+  pub fn remove(&mut self, key: String) -> Result<()> {
+    let retry = self.options.retry_remove;
+    match self.request(RespCommand::new(vec!["DEL".to_owned(), key]), retry)? {
+      RespValue::Integer(1) => Ok(()),
+      RespValue::Integer(0) => Err(KvStoreError::RmKeyNotFoundError),
+      reply => Err(unexpected(reply)),
+    }
+  }
+
+  // send a command and read its reply, retrying on connection failures if `retry` is set
+  //
+  // Error replies become errors, and are never retried.
+  fn request(&mut self, cmd: RespCommand, retry: bool) -> Result<RespValue> {
+    let mut attempt = 0;
+    loop {
+      match self.try_request(&cmd) {
+        Ok(RespValue::Error(message)) => return Err(KvStoreError::ServerError(message)),
+        Ok(reply) => return Ok(reply),
+        Err(e) if !retry || attempt >= self.options.max_retries => return Err(e.into()),
+        Err(e) => {
+          log::warn!("Request to {} failed, retrying: {}", self.addr, e);
+          thread::sleep(self.backoff(attempt));
+          attempt += 1;
+        }
+      }
+    }
+  }
+
+  fn try_request(&mut self, cmd: &RespCommand) -> io::Result<RespValue> {
+    let mut conn = match self.conn.take() {
+      Some(conn) => conn,
+      None => BufReader::new(TcpStream::connect(self.addr)?),
+    };
+    resp::write_command(cmd, conn.get_mut())?;
+    let reply = resp::read_value(&mut conn)?;
+    // only a connection that got through the whole request is kept
+    self.conn = Some(conn);
+
+    Ok(reply)
+  }
+
+  // exponential backoff with jitter: wait between half and all of the doubled delay
+  fn backoff(&self, attempt: u32) -> Duration {
+    let delay = self
+      .options
+      .initial_backoff
+      .checked_mul(1 << attempt.min(16))
+      .map_or(self.options.max_backoff, |delay| delay.min(self.options.max_backoff));
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(attempt);
+    let jitter = (hasher.finish() % 1000) as u32;
+    delay / 2 + delay / 2 * jitter / 1000
+  }
+
+  // whether the connection can still be used, without sending anything
+  fn is_alive(&self) -> bool {
+    let reader = match &self.conn {
+      Some(reader) if reader.buffer().is_empty() => reader,
+      _ => return false,
+    };
+
+    // a closed connection reads as EOF right away, an open one has nothing to read
+    let stream = reader.get_ref();
+    if stream.set_nonblocking(true).is_err() {
+      return false;
+    }
+    let idle = matches!(stream.peek(&mut [0]), Err(e) if e.kind() == io::ErrorKind::WouldBlock);
+    stream.set_nonblocking(false).is_ok() && idle
+  }
+}
+
+fn resolve(addr: impl ToSocketAddrs) -> Result<SocketAddr> {
+  addr
+    .to_socket_addrs()?
+    .next()
+    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to").into())
+}
+
+fn unexpected(reply: RespValue) -> KvStoreError {
+  KvStoreError::ProtocolError(format!("unexpected reply {:?}", reply))
+}
+
+/// ClientPool keeps up to `size` connections to a KvsServer, handing them out to one thread at a time
+///
+/// Connections are opened as needed and go back to the pool once the `PooledClient` is dropped.
+/// Connections the server closed in the meantime are discarded and replaced when handed out.
+pub struct ClientPool {
+  addr: SocketAddr,
+  size: usize,
+  options: ClientOptions,
+  state: Mutex<PoolState>,
+  returned: Condvar,
+}
+
+struct PoolState {
+  idle: Vec<KvsClient>,
+  // connections open, idle or handed out
+  open: usize,
+}
+
+impl ClientPool {
+  /// Creates a pool of up to `size` connections to the server at the given address
+  ///
+  /// Like `KvsClient::connect`, parse addresses given as text with `server::parse_addr`.
+  pub fn new(addr: impl ToSocketAddrs, size: usize) -> Result<Self> {
+    Self::with_options(addr, size, ClientOptions::default())
+  }
+
+  /// Creates a pool of up to `size` connections to the server at the given address, with the given client options
+  pub fn with_options(addr: impl ToSocketAddrs, size: usize, options: ClientOptions) -> Result<Self> {
+    Ok(Self {
+      addr: resolve(addr)?,
+      size: size.max(1),
+      options,
+      state: Mutex::new(PoolState {
+        idle: Vec::new(),
+        open: 0,
+      }),
+      returned: Condvar::new(),
+    })
+  }
+
+  /// Take a connection out of the pool, waiting for one to be returned if all of them are in use
+  pub fn get(&self) -> Result<PooledClient<'_>> {
+    let mut state = self.state.lock().expect("client pool poisoned");
+    loop {
+      while let Some(client) = state.idle.pop() {
+        if client.is_alive() {
+          return Ok(PooledClient {
+            pool: self,
+            client: Some(client),
+          });
+        }
+        state.open -= 1;
+      }
+
+      if state.open < self.size {
+        state.open += 1;
+        break;
+      }
+      state = self.returned.wait(state).expect("client pool poisoned");
+    }
+    drop(state);
+
+    // connect without holding the lock, giving the slot back if that fails
+    match KvsClient::connect_with_options(self.addr, self.options.clone()) {
+      Ok(client) => Ok(PooledClient {
+        pool: self,
+        client: Some(client),
+      }),
+      Err(e) => {
+        self.release(None);
+        Err(e)
+      }
+    }
+  }
+
+  // put a connection back, or just free its slot
+  fn release(&self, client: Option<KvsClient>) {
+    let mut state = self.state.lock().expect("client pool poisoned");
+    match client {
+      Some(client) if client.conn.is_some() => state.idle.push(client),
+      _ => state.open -= 1,
+    }
+    self.returned.notify_one();
+  }
+}
+
+/// A connection taken out of a ClientPool, returned to it when dropped
+pub struct PooledClient<'a> {
+  pool: &'a ClientPool,
+  client: Option<KvsClient>,
+}
+
+impl Deref for PooledClient<'_> {
+  type Target = KvsClient;
+
+  fn deref(&self) -> &KvsClient {
+    self.client.as_ref().expect("pooled client already returned")
+  }
+}
+
+impl DerefMut for PooledClient<'_> {
+  fn deref_mut(&mut self) -> &mut KvsClient {
+    self.client.as_mut().expect("pooled client already returned")
+  }
+}
+
+impl Drop for PooledClient<'_> {
+  fn drop(&mut self) {
+    self.pool.release(self.client.take());
+  }
+}
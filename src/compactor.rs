@@ -0,0 +1,69 @@
+//! Compacting a shared KvStore from a background thread, off the request path
+
+use crate::{KvStore, LogStorage};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// BackgroundCompactor periodically compacts a shared store once its garbage reaches the compaction threshold
+///
+/// Compaction runs through `KvStore::compact_shared`, which only takes the store's lock briefly, to start and to
+/// swap the new log in, so requests go on while the log is copied. To keep compaction off the request path
+/// entirely, open the store with `KvStoreOptions::compact_on_write` turned off, writes then leave the garbage to
+/// this thread. Dropping the compactor stops the thread and waits for it.
+pub struct BackgroundCompactor {
+  stop: Option<Sender<()>>,
+  handle: Option<JoinHandle<()>>,
+}
+
+impl BackgroundCompactor {
+  /// Start checking the store every `interval`
+  pub fn start<S: LogStorage + Send + 'static>(store: Arc<Mutex<KvStore<S>>>, interval: Duration) -> Self {
+    let (stop, stopped) = mpsc::channel();
+    let handle = thread::spawn(move || loop {
+      match stopped.recv_timeout(interval) {
+        Err(RecvTimeoutError::Timeout) => {}
+        // stopped, or the compactor is gone
+        _ => return,
+      }
+
+      let garbage = match store.lock() {
+        // not `stats`, which replays the log to count the live keys with `IndexMode::None`
+        Ok(store) => (store.garbage, store.options().compaction_threshold),
+        Err(_) => {
+          log::error!("Background compaction stopped, the store is unavailable after a panic");
+          return;
+        }
+      };
+      let (garbage, threshold) = garbage;
+      if garbage >= threshold {
+        if let Err(e) = KvStore::compact_shared(&store) {
+          log::error!("Background compaction failed: {}", e);
+        }
+      }
+    });
+
+    Self {
+      stop: Some(stop),
+      handle: Some(handle),
+    }
+  }
+
+  /// Stop the thread and wait for it, letting a compaction in progress finish
+  pub fn shutdown(self) {
+    drop(self)
+  }
+}
+
+impl Drop for BackgroundCompactor {
+  fn drop(&mut self) {
+    // hanging up wakes the thread as well
+    self.stop.take();
+    if let Some(handle) = self.handle.take() {
+      if handle.join().is_err() {
+        log::error!("Background compaction thread panicked");
+      }
+    }
+  }
+}
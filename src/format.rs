@@ -0,0 +1,147 @@
+//! The log's on-disk format, for tools that read or write logs without going through a KvStore
+//!
+//! A log is a sequence of records with nothing in between: no length prefixes, no checksums. Logs of
+//! version 2, the version stores write, start with a header naming their version, `HEADER_MAGIC` followed by
+//! the version as a big-endian `u32`, and then go on with records. Logs of version 1 have no header, and no
+//! stamped records either, so stores that only know version 1 reject a log of version 2 rather than fail on its
+//! first stamp. Stores read both, see `detect_version` and `record_fits`. A record is a `KvCommand` serialized with
+//! MessagePack the way rmp-serde 0.14 encodes enums by default, tagged with the variant's index rather than
+//! its name:
+//!
+//! | Index | Variant         | Fields                                                        |
+//! |-------|-----------------|---------------------------------------------------------------|
+//! | 0     | `Set`           | key, value                                                    |
+//! | 1     | `Rm`            | key                                                           |
+//! | 2     | `SetEx`         | key, value, expiry deadline in ms since the unix epoch        |
+//! | 3     | `SetCompressed` | key, LZ4 block with its size prepended (binary), expiry or nil |
+//! | 4     | `Timestamped`   | ms since the unix epoch, the stamped record                   |
+//!
+//! Keys and values are MessagePack strings. In logs of version 2, sets, plain or compressed, are written stamped
+//! with when they were made, removes never are, and a stamp never wraps another stamp. A store appending to a log
+//! of version 1 leaves its sets unstamped, so the log stays readable as version 1 until compacting rewrites it in
+//! version 2. Variants are only ever added at the end, so existing indices keep their meaning.
+//!
+//! Logs are portable byte for byte: a log written on one machine opens on any other, whatever its endianness
+//! or pointer width. MessagePack writes integers big-endian in the smallest form that holds their value, and
+//! offsets, deadlines and stamps are `u64` everywhere, nothing is written the way the machine lays it out in
+//! memory. So logs record no platform, and opening one never checks it. Anything platform-dependent would
+//! take a new version of the format, which stores that can't read it reject with `UnsupportedFormatError`.
+//! Index hints are MessagePack as well, and just as portable.
+//!
+//! Reading records one after another from a log written by a store:
+//!
+//! ```
+//! use kvs::{format, KvCommand, KvStore};
+//!
+//! let dir = tempfile::TempDir::new()?;
+//! let mut store = KvStore::open(dir.path())?;
+//! store.set("key1".to_owned(), "value1".to_owned())?;
+//! store.remove("key1".to_owned())?;
+//! drop(store);
+//!
+//! let log = std::fs::read(dir.path().join("kvs.log"))?;
+//! let (version, start) = format::detect_version(&log)?;
+//! assert_eq!(version, format::FORMAT_VERSION);
+//! let log = &log[start as usize..];
+//! let (record, len) = format::decode_record(log)?;
+//! let (cmd, modified) = format::into_command(record)?;
+//! assert_eq!(cmd, KvCommand::Set("key1".to_owned(), "value1".to_owned()));
+//! assert!(modified.is_some());
+//!
+//! let (record, _) = format::decode_record(&log[len..])?;
+//! assert_eq!(record, KvCommand::Rm("key1".to_owned()));
+//! # Ok::<(), kvs::KvStoreError>(())
+//! ```
+
+use crate::{decompress, unstamp, KvCommand, KvStoreError, Result};
+use rmp_serde::decode::Deserializer;
+use rmp_serde::encode;
+use serde::Deserialize;
+use std::convert::TryInto;
+use std::io::Cursor;
+
+/// Version of the format stores write
+///
+/// It's written in the header of every log a store starts, new, cleared, compacted or backed up. It only changes
+/// if records stop being readable the way described here, adding variants at the end doesn't.
+pub const FORMAT_VERSION: u32 = 2;
+/// Latest version of the format stores can read
+pub const MAX_FORMAT_VERSION: u32 = 2;
+/// What the header of a log of version 2 or later starts with
+///
+/// A version 1 log never starts with these bytes, the first byte of a record being a MessagePack array marker.
+pub const HEADER_MAGIC: [u8; 4] = *b"KVS\0";
+/// Length in bytes of the header of a log of version 2 or later
+pub const HEADER_LEN: usize = 8;
+
+/// Get the version of the log starting with `bytes`, along with the offset its first record starts at
+///
+/// `bytes` holds the first `HEADER_LEN` bytes of the log, or all of it if the log is shorter. Logs without
+/// a header are of version 1. Fails with `UnsupportedFormatError` for a version newer than `MAX_FORMAT_VERSION`,
+/// or a header cut short.
+pub fn detect_version(bytes: &[u8]) -> Result<(u32, u64)> {
+  if !bytes.starts_with(&HEADER_MAGIC) {
+    return Ok((1, 0));
+  }
+
+  let version = match bytes.get(HEADER_MAGIC.len()..HEADER_LEN) {
+    Some(version) => u32::from_be_bytes(version.try_into().expect("4 bytes were sliced")),
+    None => {
+      return Err(KvStoreError::UnsupportedFormatError(
+        "the header is cut short".to_owned(),
+      ))
+    }
+  };
+  if !(2..=MAX_FORMAT_VERSION).contains(&version) {
+    return Err(KvStoreError::UnsupportedFormatError(format!("version {}", version)));
+  }
+
+  Ok((version, HEADER_LEN as u64))
+}
+
+/// Encode the header a log of the given version starts with onto the end of `buf`, nothing for version 1
+pub fn encode_header(version: u32, buf: &mut Vec<u8>) {
+  if version >= 2 {
+    buf.extend_from_slice(&HEADER_MAGIC);
+    buf.extend_from_slice(&version.to_be_bytes());
+  }
+}
+
+/// Decode the record at the start of `bytes`, returns it along with its length in bytes
+///
+/// The record comes exactly as written, see `into_command` to get the command it records.
+pub fn decode_record(bytes: &[u8]) -> Result<(KvCommand, usize)> {
+  let mut reader = Cursor::new(bytes);
+  let record = KvCommand::deserialize(&mut Deserializer::new(&mut reader))?;
+
+  Ok((record, reader.position() as usize))
+}
+
+/// Whether a decoded record is one a log of the given version can hold
+///
+/// Logs of version 1 predate stamps, so a stamped record in one is as broken as a record that doesn't decode,
+/// stores stop replaying there. In no version does a stamp wrap another stamp.
+pub fn record_fits(record: &KvCommand, version: u32) -> bool {
+  match record {
+    KvCommand::Timestamped(_, cmd) => version >= 2 && !matches!(**cmd, KvCommand::Timestamped(..)),
+    _ => true,
+  }
+}
+
+/// Encode a record onto the end of `buf`
+///
+/// Appending records encoded this way to a log is safe while no store has it open. Stamp sets with
+/// `KvCommand::Timestamped` for their modification time to show up in `KvStore::get_meta`.
+pub fn encode_record(record: &KvCommand, buf: &mut Vec<u8>) -> Result<()> {
+  encode::write(buf, record)?;
+  Ok(())
+}
+
+/// Get the command a record stands for, as a plain `Set`, `SetEx` or `Rm` with its value decompressed,
+/// along with when it was made if the record is stamped
+///
+/// Decompressing needs the `compression` feature, records with compressed values fail without it.
+pub fn into_command(record: KvCommand) -> Result<(KvCommand, Option<u64>)> {
+  let (cmd, modified) = unstamp(record);
+  Ok((decompress(cmd)?, modified))
+}
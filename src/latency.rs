@@ -0,0 +1,72 @@
+//! Latency percentiles of a KvStore's operations, recorded in HDR histograms
+
+use hdrhistogram::Histogram;
+use std::time::{Duration, Instant};
+
+/// Latency percentiles of a KvStore's operations, as returned by `KvStore::latency_snapshot`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyReport {
+  /// Latency of `get`
+  pub get: OperationLatency,
+  /// Latency of `set`
+  pub set: OperationLatency,
+}
+
+/// Latency percentiles of one kind of operation
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct OperationLatency {
+  /// Number of operations recorded
+  pub count: u64,
+  /// Median latency
+  pub p50: Duration,
+  /// 99th percentile latency
+  pub p99: Duration,
+}
+
+/// The operations latency is recorded for
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Operation {
+  Get,
+  Set,
+}
+
+/// Histograms of operation latencies in nanoseconds
+pub(crate) struct LatencyRecorder {
+  get: Histogram<u64>,
+  set: Histogram<u64>,
+}
+
+impl LatencyRecorder {
+  pub(crate) fn new() -> Self {
+    // auto-resizing, so no latency is ever out of range
+    let histogram = || Histogram::new(3).expect("3 significant figures are supported");
+    Self {
+      get: histogram(),
+      set: histogram(),
+    }
+  }
+
+  /// Record an operation that started at `started` and just finished
+  pub(crate) fn record(&mut self, operation: Operation, started: Instant) {
+    let histogram = match operation {
+      Operation::Get => &mut self.get,
+      Operation::Set => &mut self.set,
+    };
+    histogram.saturating_record(started.elapsed().as_nanos() as u64);
+  }
+
+  pub(crate) fn report(&self) -> LatencyReport {
+    LatencyReport {
+      get: percentiles(&self.get),
+      set: percentiles(&self.set),
+    }
+  }
+}
+
+fn percentiles(histogram: &Histogram<u64>) -> OperationLatency {
+  OperationLatency {
+    count: histogram.len(),
+    p50: Duration::from_nanos(histogram.value_at_quantile(0.5)),
+    p99: Duration::from_nanos(histogram.value_at_quantile(0.99)),
+  }
+}
@@ -4,15 +4,48 @@
 
 use rmp_serde::decode::{Deserializer, ReadReader};
 use rmp_serde::encode;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs::{self, File, OpenOptions};
-use std::io::{BufReader, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
+#[cfg(feature = "async")]
+pub mod async_engine;
+mod cache;
+pub mod client;
+pub mod compactor;
+pub mod format;
+mod latency;
+pub mod resp;
+pub mod server;
+pub mod sharded;
+mod snapshot;
+mod storage;
+#[cfg(feature = "bench-utils")]
+pub mod workload;
+
+use cache::ValueCache;
+use latency::{LatencyRecorder, Operation};
+pub use latency::{LatencyReport, OperationLatency};
+pub use snapshot::Snapshot;
+use snapshot::Source;
+use storage::{hint_path, StorageReader};
+pub use storage::{FileStorage, LogStorage, MemoryStorage};
+
 /// Error kinds enum for KvStore operations
+///
+/// More kinds may be added over time, so matches need a wildcard arm.
 #[derive(Debug, Error)]
+#[non_exhaustive]
 #[allow(missing_docs)] // descriptions are provided through macro
 pub enum KvStoreError {
   #[error("I/O operation failed")]
@@ -29,6 +62,98 @@ pub enum KvStoreError {
   GetError,
   #[error("Error during compaction")]
   CompactionError,
+  #[error("Invalid namespace: {0}")]
+  InvalidNamespaceError(String),
+  #[error("Invalid log file name: {0}")]
+  InvalidLogFileNameError(String),
+  #[error("Value of {0} bytes exceeds the maximum value size")]
+  ValueTooLarge(usize),
+  #[error("Invalid key: {0}")]
+  InvalidKey(String),
+  #[error("Dumping the store failed: {0}")]
+  DumpError(String),
+  #[error("Key not found")]
+  KeyNotFoundError,
+  #[error("Converting typed value failed")]
+  TypedValueError(#[from] serde_json::Error),
+  #[error("Decompressing value failed: {0}")]
+  CompressionError(String),
+  #[error("Batch precondition failed: {0}")]
+  PreconditionFailedError(String),
+  #[error("Running a store task failed: {0}")]
+  TaskError(String),
+  #[error("Server replied with an error: {0}")]
+  ServerError(String),
+  #[error("Unexpected reply from the server: {0}")]
+  ProtocolError(String),
+  #[error("Key exists in both stores: {0}")]
+  MergeConflictError(String),
+  #[error("Invalid glob pattern: {0}")]
+  InvalidPatternError(String),
+  #[error("Option can't be changed on an open store: {0}")]
+  ImmutableOptionError(String),
+  #[error("Invalid number of shards: {0}")]
+  ShardCountError(String),
+  #[error("Invalid address: {0}")]
+  InvalidAddressError(String),
+  #[error("Log is full, it may not grow past {0} bytes")]
+  LogFull(u64),
+  #[error("Unsupported log format: {0}")]
+  UnsupportedFormatError(String),
+}
+
+impl KvStoreError {
+  /// Render the error as a RESP error reply, `-ERR <description>\r\n`
+  ///
+  /// Line breaks within the description are replaced with spaces, as RESP errors must fit on one line.
+  pub fn to_resp_error(&self) -> String {
+    let description = self.to_string().replace(['\r', '\n'], " ");
+    format!("-ERR {}\r\n", description)
+  }
+
+  /// A stable identifier of the kind of error, e.g. `"KEY_NOT_FOUND"`, for mapping errors to codes of other
+  /// protocols
+  ///
+  /// Unlike the description, codes never change once released. Kinds that come down to the same thing for
+  /// a caller share a code, e.g. both key not found errors.
+  pub fn code(&self) -> &'static str {
+    match self {
+      KvStoreError::IoError(_) => "IO",
+      KvStoreError::ReplayError(_) => "CORRUPT_LOG",
+      KvStoreError::EncodeError(_) => "ENCODE",
+      KvStoreError::DecodeError(_) => "DECODE",
+      KvStoreError::RmKeyNotFoundError | KvStoreError::KeyNotFoundError => "KEY_NOT_FOUND",
+      KvStoreError::GetError => "GET",
+      KvStoreError::CompactionError => "COMPACTION",
+      KvStoreError::InvalidNamespaceError(_) => "INVALID_NAMESPACE",
+      KvStoreError::InvalidLogFileNameError(_) => "INVALID_LOG_FILE_NAME",
+      KvStoreError::ValueTooLarge(_) => "VALUE_TOO_LARGE",
+      KvStoreError::InvalidKey(_) => "INVALID_KEY",
+      KvStoreError::DumpError(_) => "DUMP",
+      KvStoreError::TypedValueError(_) => "TYPED_VALUE",
+      KvStoreError::CompressionError(_) => "COMPRESSION",
+      KvStoreError::PreconditionFailedError(_) => "PRECONDITION_FAILED",
+      KvStoreError::TaskError(_) => "TASK",
+      KvStoreError::ServerError(_) => "SERVER",
+      KvStoreError::ProtocolError(_) => "PROTOCOL",
+      KvStoreError::MergeConflictError(_) => "MERGE_CONFLICT",
+      KvStoreError::InvalidPatternError(_) => "INVALID_PATTERN",
+      KvStoreError::ImmutableOptionError(_) => "IMMUTABLE_OPTION",
+      KvStoreError::ShardCountError(_) => "SHARD_COUNT",
+      KvStoreError::InvalidAddressError(_) => "INVALID_ADDRESS",
+      KvStoreError::LogFull(_) => "LOG_FULL",
+      KvStoreError::UnsupportedFormatError(_) => "UNSUPPORTED_FORMAT",
+    }
+  }
+}
+
+impl From<KvStoreError> for std::io::Error {
+  fn from(e: KvStoreError) -> Self {
+    match e {
+      KvStoreError::IoError(e) => e,
+      e => std::io::Error::other(e),
+    }
+  }
 }
 
 /// Result wrapper for KvStore operations
@@ -36,188 +161,2882 @@ pub type Result<T> = std::result::Result<T, KvStoreError>;
 
 // the in-memory index type (key -> log pointer)
 type Index = HashMap<String, u64>;
+// expiry deadlines of keys set with a TTL (key -> unix time in milliseconds)
+type Expiry = HashMap<String, u64>;
 // the log type
-type Log = Deserializer<ReadReader<BufReader<File>>>;
+type Log<S> = Deserializer<ReadReader<BufReader<StorageReader<S>>>>;
 
 /// KvStore is an in-memory key-value store
-pub struct KvStore {
+///
+/// The command log is kept in a `LogStorage`, which is a file on disk by default.
+///
+/// A KvStore is `Send` whenever its storage is, as `FileStorage` and `MemoryStorage` are, so it can be
+/// handed to another thread. Every operation, reads included, needs `&mut self` as it moves the log reader,
+/// so threads share a store behind a lock, e.g. `Arc<Mutex<KvStore>>` as `KvsServer` and `AsyncKvStore` do.
+pub struct KvStore<S: LogStorage = FileStorage> {
   index: Index,
-  log_dir: PathBuf,
-  log: Log,
-  garbage: u32,
+  expiry: Expiry,
+  log: Log<S>,
+  garbage: u64,
+  options: KvStoreOptions,
+  // number of records replayed when opening
+  replayed: usize,
+  // whether the log changed since the hint was last written
+  hint_stale: bool,
+  // number of writes appended since the log was last synced
+  unsynced: usize,
+  // prefixes watched through `subscribe`, with where to send their events
+  subscribers: Vec<(String, Sender<KeyEvent>)>,
+  // recently read values, see `KvStoreOptions::value_cache_bytes`
+  cache: ValueCache,
+  // None unless `KvStoreOptions::track_latency` is on
+  latency: Option<LatencyRecorder>,
+  // reused to encode records into, see `take_encode_buf`
+  encode_buf: Vec<u8>,
+  // reused to read values into, see `with_value`
+  decode_buf: Vec<u8>,
+  // start and end of the last set appended, if its key had no live value before, see `remove`
+  fresh_tail: Option<(u64, u64)>,
+  // whether a record cut short at the end of the log was looked for, see `check_tail`
+  tail_checked: bool,
+  // no record in the log is larger, which bounds what compacting can free, see `make_room`. Unknown until the
+  // whole log was replayed or compacted.
+  largest_record: u64,
+  // number of times the log was changed other than by appending, see `compact_shared`
+  rewrites: u64,
+  // where the first record starts, past the header of a log of version 2 or later
+  log_start: u64,
+  // version of the log's format, records are only stamped in logs of version 2 or later
+  log_version: u32,
+  // shared with every snapshot taken, see `clear`
+  snapshots: Arc<()>,
 }
 
 // Trigger compaction when garbages exceeding this value
-const COMPACTION_THRESHOLD: u32 = 100;
+const COMPACTION_THRESHOLD: u64 = 100;
+// Name of the log file unless configured otherwise
+const LOG_FILE_NAME: &str = "kvs.log";
+// Capacity of the buffer the log is read through unless configured otherwise, same as BufReader's default
+const READ_BUFFER_BYTES: usize = 8 * 1024;
+// The encode and decode buffers are only kept for reuse up to this size, so one huge value doesn't pin its memory
+const ENCODE_BUF_BYTES: usize = 64 * 1024;
+// Compaction reports its progress once every this many keys
+const PROGRESS_INTERVAL_KEYS: usize = 1024;
+// Replaying reports its progress once every this many bytes of log
+const PROGRESS_INTERVAL_BYTES: u64 = 1 << 20;
+// Bulk loads append the log in chunks of about this size
+const BULK_CHUNK_BYTES: usize = 1 << 20;
 
-type Key = String;
-type Value = String;
-#[derive(Debug, Serialize, Deserialize)]
-enum KvCommand {
-  Set(Key, Value),
-  Rm(Key),
+/// Options for opening a KvStore
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+  /// Compaction is triggered once the number of garbage records reaches this value
+  pub compaction_threshold: u64,
+  /// Memory-map the log file and decode reads straight from the mapping
+  pub mmap_reads: bool,
+  /// Keep the store in `<dir>/<namespace>/` so several stores can share a directory
+  ///
+  /// Namespaces may only contain ASCII letters, digits, `-` and `_`.
+  pub namespace: Option<String>,
+  /// Name of the log file within the store's directory
+  ///
+  /// Compaction's scratch logs and the index hint are named after the whole of it, e.g. `data.log.hint`, so
+  /// stores with different log file names can share a directory.
+  pub log_file_name: String,
+  /// Reject values longer than this many bytes, unbounded if `None`
+  pub max_value_bytes: Option<usize>,
+  /// Reject keys longer than this many bytes, unbounded if `None`
+  ///
+  /// Empty keys are always rejected.
+  pub max_key_bytes: Option<usize>,
+  /// Compact on open if the log already reached the compaction threshold
+  ///
+  /// Turning this off keeps short-lived opens fast, but leaves the garbage in the log until a write crosses
+  /// the threshold again or `compact` is called.
+  pub compact_on_open: bool,
+  /// When writes are synced to durable storage
+  pub sync_policy: SyncPolicy,
+  /// Write the compacted log into this directory instead of next to the log, e.g. when the log's disk is full
+  pub compaction_dir: Option<PathBuf>,
+  /// Sync the log's directory once compaction renamed the new log into place, on by default
+  ///
+  /// Without it, a crash soon after compacting may bring back the old log. Directories are only synced on
+  /// Unix, see `FileStorage::with_dir_sync`. Changing it through `KvStore::set_options` applies from the next
+  /// compaction on, as nothing about the files depends on it.
+  pub sync_dir: bool,
+  /// How values are compressed when written
+  ///
+  /// Every record says whether its value is compressed, so logs written with different settings open just fine.
+  pub compression: Compression,
+  /// What replaying the log does about records that don't decode
+  pub recovery_mode: RecoveryMode,
+  /// Compact the log automatically once it has enough garbage
+  ///
+  /// Turning this off keeps every command ever recorded, see `KvStore::replay_history`.
+  /// The log then only shrinks when `compact` is called explicitly.
+  pub compaction: bool,
+  /// Compact once a write brings the garbage to the compaction threshold, on by default
+  ///
+  /// Turning this off leaves the garbage to `compact`, `compact_on_open` or a `BackgroundCompactor`, keeping
+  /// compaction off the write path. Unlike turning `compaction` off, the log still doesn't keep its history:
+  /// removes still cut off sets they undo, and a full log is still compacted to make room.
+  pub compact_on_write: bool,
+  /// Write the compacted log in key order instead of the index's arbitrary order
+  ///
+  /// Keys next to each other then sit next to each other in the log, so prefix and range scans read it mostly
+  /// sequentially. Sorting costs compaction `O(n log n)` over the live keys.
+  pub sorted_compaction: bool,
+  /// Keep recently read values in memory, up to this many bytes of keys and values, no caching if `None`
+  ///
+  /// Reads of cached keys skip the log, and writing a key drops its cached value.
+  pub value_cache_bytes: Option<usize>,
+  /// Record the latency of every `get` and `set`, see `KvStore::latency_snapshot`
+  pub track_latency: bool,
+  /// Drop what the log reader buffered before every read, on by default
+  ///
+  /// The store's own writes are visible either way: they bypass the read buffer, and since the log is only
+  /// appended to, bytes already buffered never go stale because of them. What the buffer can miss is the log
+  /// being changed in place by someone else, e.g. another process rewriting it. Turning this off lets a read
+  /// reuse the bytes buffered by an earlier one when its record starts within them, which saves a read from
+  /// storage for records close to each other, at the cost of possibly seeing such outside changes late.
+  /// Reads served from a memory map (`mmap_reads`) are never buffered, so this doesn't apply to them.
+  pub read_your_writes: bool,
+  /// Where every command written to the log is mirrored to, nowhere by default
+  pub audit_sink: Arc<dyn AuditSink>,
+  /// Capacity of the buffer the log is read through
+  ///
+  /// A buffer bigger than the typical value saves reads when replaying or reading large values.
+  pub read_buffer_bytes: usize,
+  /// What to report replaying the log to, on open and `reload_index`, nothing by default
+  ///
+  /// Opening a store whose index hint is current skips replaying, and reports nothing.
+  pub replay_progress: Option<Arc<dyn ReplayProgress>>,
+  /// Fail writes that would grow the log past this many bytes with `LogFull`, unbounded if `None`
+  ///
+  /// A write that doesn't fit compacts the log first if compaction is on and there's enough garbage to reclaim,
+  /// with compaction off the log only shrinks again when `compact` is called. Removes always go through, even
+  /// past the maximum, as their tombstones are what lets compaction make room again.
+  pub max_log_bytes: Option<u64>,
+  /// Whether the store keeps an index of every key in memory, see `IndexMode`
+  pub index: IndexMode,
+  /// Create the store's directory on open if it doesn't exist, along with its missing parents, on by default
+  ///
+  /// With this off, opening a directory that doesn't exist fails. A namespace's subdirectory is created either way.
+  pub create_dir: bool,
 }
 
-impl KvStore {
-  /// Creates a new key-value store
-  pub fn open(directory: impl Into<PathBuf>) -> Result<Self> {
-    let log_dir = directory.into();
-    let log_path = log_dir.clone().join("kvs.log");
+/// What replaying a log does about records that don't decode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecoveryMode {
+  /// Stop at the first bad record, keeping only what came before it
+  StopAtBadRecord,
+  /// Warn about bad records and go on from the next offset a record decodes at
+  ///
+  /// This is best effort: MessagePack records carry no marker to find their start by, so resyncing tries every
+  /// offset after the bad record, and a few broken bytes may happen to decode as a bogus record.
+  /// Only use this when losing the records after a corruption is worse.
+  SkipBadRecords,
+}
 
-    let log_file = OpenOptions::new().write(true).read(true).create(true).open(&log_path)?;
+/// Whether a KvStore indexes the log's keys in memory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexMode {
+  /// Replay the log into an index of every key on open, so reads go straight to their record
+  InMemory,
+  /// Open without replaying the log, and find keys by scanning it instead
+  ///
+  /// Only keys written since the store was opened are indexed. Reading any other key (`get`, `with_value`,
+  /// `read_value_to`, `get_meta`, `contains_key`) or removing it scans the whole log for its latest record,
+  /// which is `O(n)` in the size of the log per read, but opening costs nothing. This suits tiny stores and
+  /// one-shot uses.
+  ///
+  /// Operations that go through every key, e.g. `len`, `stats`, the scans, `remove_prefix`, `remove_range`,
+  /// `snapshot`, `export_resp`, `backup`, `rename`, `copy` and compaction, replay the whole log first and forget
+  /// the index again after, so each of them is `O(n)` as well. So does `clear` while keys are subscribed to. Garbage is only counted for keys written since the last replay, so compaction
+  /// triggers late. The first write replays the log too, dropping a record cut short by a crash that the write
+  /// would otherwise land behind. Only compaction writes an index hint.
+  None,
+}
 
-    let reader = BufReader::new(log_file);
-    let mut log = Deserializer::new(reader);
+/// How `KvStore::merge_from` resolves keys that exist in both stores
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+  /// Keep the value already in this store
+  KeepSelf,
+  /// Take the value from the other store
+  Overwrite,
+  /// Fail the whole merge with `MergeConflictError`
+  Error,
+}
 
-    let mut index = HashMap::new();
-    let mut garbage = 0;
-    log.get_mut().seek(SeekFrom::Start(0))?;
+/// How a KvStore compresses values in the log
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+  /// Store values as they are
+  None,
+  /// Compress values with LZ4, keeping them as they are whenever that doesn't save space
+  #[cfg(feature = "compression")]
+  Lz4,
+}
 
-    loop {
-      let pos = log.get_mut().seek(SeekFrom::Current(0))?;
-      if let Ok(cmd) = KvCommand::deserialize(&mut log) {
-        match cmd {
-          KvCommand::Set(key, _value) => {
-            if index.insert(key, pos).is_some() {
-              // key is replaced
-              garbage += 1;
-            }
-          }
-          KvCommand::Rm(key) => {
-            index.remove(&key);
-            // rm is always garbage
-            garbage += 1;
-          }
-        }
-      } else {
-        // TODO check for EoF and error out otherwise
-        break;
-      }
+/// When a KvStore syncs its writes to durable storage
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+  /// Only on `flush`, `close` and drop
+  OnFlush,
+  /// After every write, so a write is durable once it returns
+  EveryWrite,
+  /// Group commit: once every `n` writes, so one sync covers the whole group
+  ///
+  /// A crash can lose up to the last `n - 1` writes. `Group(0)` and `Group(1)` sync after every write,
+  /// just like `EveryWrite`.
+  Group(usize),
+}
+
+impl Default for KvStoreOptions {
+  fn default() -> Self {
+    Self {
+      compaction_threshold: COMPACTION_THRESHOLD,
+      mmap_reads: false,
+      namespace: None,
+      log_file_name: LOG_FILE_NAME.to_owned(),
+      max_value_bytes: None,
+      max_key_bytes: None,
+      compact_on_open: true,
+      sync_policy: SyncPolicy::OnFlush,
+      compaction_dir: None,
+      sync_dir: true,
+      compression: Compression::None,
+      value_cache_bytes: None,
+      compaction: true,
+      compact_on_write: true,
+      sorted_compaction: false,
+      recovery_mode: RecoveryMode::StopAtBadRecord,
+      track_latency: false,
+      read_buffer_bytes: READ_BUFFER_BYTES,
+      audit_sink: Arc::new(NoAudit),
+      replay_progress: None,
+      max_log_bytes: None,
+      read_your_writes: true,
+      index: IndexMode::InMemory,
+      create_dir: true,
     }
+  }
+}
 
-    let mut kvs = Self {
-      index,
-      log_dir,
-      log,
-      garbage,
-    };
-    kvs.maybe_compact_logs()?;
+/// Statistics of a KvStore
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KvStoreStats {
+  /// Number of live keys
+  pub live_keys: usize,
+  /// Number of garbage records (overwritten sets and removes) in the log
+  pub garbage: u64,
+  /// Size of the log in bytes
+  pub log_bytes: u64,
+  /// Number of log records replayed when opening, 0 if the index was loaded from a hint
+  pub replayed_records: usize,
+}
 
-    Ok(kvs)
+/// AuditSink observes every command a KvStore writes to its log, e.g. to mirror writes to an external system
+///
+/// Commands are passed as plain `Set`, `SetEx` or `Rm`, however they're encoded, along with the offset
+/// of their record in the log. The sink is called after the append succeeded and, if the sync policy calls
+/// for it, after the log was synced. Compaction rewrites records without any new commands, so it isn't observed.
+/// A remove that cuts a set off the end of the log instead of appending (see `KvStore::remove`) is still passed
+/// as an `Rm`, with the offset the set was at.
+///
+/// Keys also go away without any command: all of them when the store is cleared, and each key set with a TTL
+/// once it's swept as expired. Those are observed through `on_clear` and `on_expire`, which do nothing unless
+/// implemented.
+pub trait AuditSink: Send + Sync {
+  /// Called once for every command appended to the log
+  fn on_command(&self, cmd: &KvCommand, offset: u64);
+
+  /// Called once the store was cleared and the emptied log flushed, see `KvStore::clear`
+  fn on_clear(&self) {}
+
+  /// Called for every key dropped from the index as its TTL passed, when expired keys are swept
+  ///
+  /// Expired keys are swept before compacting and by `KvStore::purge_expired`, a key can expire well before.
+  fn on_expire(&self, _key: &str) {}
+}
+
+impl fmt::Debug for dyn AuditSink {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("AuditSink")
   }
+}
 
-  /// Get the value associated with the given key in the key-value store
-  pub fn get(&mut self, key: String) -> Result<Option<String>> {
+// the default sink, observing nothing
+struct NoAudit;
+
+impl AuditSink for NoAudit {
+  fn on_command(&self, _cmd: &KvCommand, _offset: u64) {}
+}
+
+/// ReplayProgress is told how far replaying a log got, e.g. to show a loading bar while opening a big store
+///
+/// It's called with `(replayed_bytes, total_bytes)` about once every MiB of log, and a last time with both
+/// equal once replaying is done, including when it stopped early at a bad record. Closures taking the two
+/// counts implement it.
+pub trait ReplayProgress: Send + Sync {
+  /// Called as replaying goes
+  fn on_progress(&self, replayed_bytes: u64, total_bytes: u64);
+}
+
+impl<F: Fn(u64, u64) + Send + Sync> ReplayProgress for F {
+  fn on_progress(&self, replayed_bytes: u64, total_bytes: u64) {
+    self(replayed_bytes, total_bytes)
+  }
+}
+
+impl fmt::Debug for dyn ReplayProgress {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("ReplayProgress")
+  }
+}
+
+/// Metadata of a key, as returned by `KvStore::get_meta`
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyMeta {
+  /// When the key was last set, `None` if it was set in a log of version 1, whose records carry no timestamps
+  pub modified: Option<SystemTime>,
+  /// Size of the value in bytes
+  pub value_bytes: usize,
+  /// When the key expires, `None` if it was set without a TTL
+  pub expires: Option<SystemTime>,
+}
+
+/// A change to a key, as sent to subscribers of `KvStore::subscribe`
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyEvent {
+  /// The key was set to a new value
+  Set {
+    /// The key that changed
+    key: String,
+  },
+  /// The key was removed
+  Removed {
+    /// The key that changed
+    key: String,
+  },
+}
+
+/// Document formats `KvStore::dump` can serialize the store to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DumpFormat {
+  /// A JSON object
+  Json,
+  /// A RON map
+  Ron,
+}
+
+/// What `KvStore::verify` found in a log
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyReport {
+  /// Number of records that decoded
+  pub records: usize,
+  /// Number of live keys the records add up to
+  pub live_keys: usize,
+  /// Number of garbage records (overwritten sets and removes)
+  pub garbage: u64,
+  /// Offset of the first byte that doesn't decode as a record, if the log doesn't end cleanly
+  pub corrupted_at: Option<u64>,
+  /// Number of live keys whose record turned out to hold a different key
+  pub inconsistent_keys: usize,
+}
+
+impl VerifyReport {
+  /// Whether the log passed every check
+  pub fn is_ok(&self) -> bool {
+    self.corrupted_at.is_none() && self.inconsistent_keys == 0
+  }
+}
+
+/// What `KvStore::repair` salvaged from a log
+#[derive(Debug, Clone, PartialEq)]
+pub struct RepairReport {
+  /// Number of records kept, everything before the first corrupted one
+  pub salvaged_records: usize,
+  /// Number of bytes cut off from the corrupted record onwards
+  ///
+  /// Records past a corruption can't be told apart reliably, so they're only counted in bytes.
+  pub discarded_bytes: u64,
+}
+
+/// A group of sets and removes written to the log in a single append by `KvStore::write`
+///
+/// Operations apply in the order they were added. Under `SyncPolicy::EveryWrite` the whole batch costs one sync.
+///
+/// A batch can also carry preconditions, which are all checked against the state before the batch.
+/// If any of them doesn't hold, nothing is written.
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+  cmds: Vec<KvCommand>,
+  preconditions: Vec<Precondition>,
+}
+
+// what must hold for a batch to be applied
+#[derive(Debug)]
+enum Precondition {
+  Exists(Key),
+  Absent(Key),
+  Equals(Key, Value),
+}
+
+impl WriteBatch {
+  /// Creates an empty batch
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Add setting the value associated with the given key
+  pub fn set(&mut self, key: String, value: String) -> &mut Self {
+    self.cmds.push(KvCommand::Set(key, value));
     self
-      .index
-      .get(&key)
-      .map(|v| *v) //
-      .map(|log_pointer| {
-        self.log.get_mut().seek(SeekFrom::Start(log_pointer))?;
-
-        if let Ok(KvCommand::Set(key_in_log, value)) = KvCommand::deserialize(&mut self.log) {
-          if key_in_log == key {
-            Ok(value)
-          } else {
-            Err(KvStoreError::GetError)
-          }
-        } else {
-          Err(KvStoreError::GetError)
-        }
-      })
-      .transpose()
   }
 
-  /// Set the value associated with the given key in the key-value store
-  pub fn set(&mut self, key: String, value: String) -> Result<()> {
-    // write log
-    let cmd = KvCommand::Set(key.clone(), value);
-    let log_pointer = self.write_log(cmd)?;
+  /// Add removing the given key, the whole batch fails if the key doesn't exist by then
+  pub fn remove(&mut self, key: String) -> &mut Self {
+    self.cmds.push(KvCommand::Rm(key));
+    self
+  }
 
-    // update in-memory index
-    if self.index.insert(key, log_pointer).is_some() {
-      self.garbage += 1;
-      self.maybe_compact_logs()?;
+  /// Only apply the batch if the given key exists
+  pub fn require_exists(&mut self, key: String) -> &mut Self {
+    self.preconditions.push(Precondition::Exists(key));
+    self
+  }
+
+  /// Only apply the batch if the given key doesn't exist
+  pub fn require_absent(&mut self, key: String) -> &mut Self {
+    self.preconditions.push(Precondition::Absent(key));
+    self
+  }
+
+  /// Only apply the batch if the given key holds the given value
+  pub fn require_value(&mut self, key: String, value: String) -> &mut Self {
+    self.preconditions.push(Precondition::Equals(key, value));
+    self
+  }
+
+  /// Add setting the key to `new`, and only apply the batch if the key holds `expected`, or doesn't exist if `None`
+  pub fn compare_and_swap(&mut self, key: String, expected: Option<String>, new: String) -> &mut Self {
+    match expected {
+      Some(expected) => self.require_value(key.clone(), expected),
+      None => self.require_absent(key.clone()),
+    };
+    self.set(key, new)
+  }
+
+  /// Number of operations in the batch, not counting preconditions
+  pub fn len(&self) -> usize {
+    self.cmds.len()
+  }
+
+  /// Whether the batch has no operations
+  pub fn is_empty(&self) -> bool {
+    self.cmds.is_empty()
+  }
+}
+
+/// A key as stored in the log
+pub type Key = String;
+/// A value as stored in the log
+pub type Value = String;
+
+/// A command recorded in the log
+///
+/// Commands handed out by the store, e.g. by `replay_history`, are always `Set`, `SetEx` or `Rm`.
+/// The other variants only show up in the log itself, see the `format` module for how records are encoded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum KvCommand {
+  /// Set a key to a value
+  Set(Key, Value),
+  /// Remove a key
+  Rm(Key),
+  /// Set a key to a value that expires at a deadline, in milliseconds since the unix epoch
+  SetEx(Key, Value, u64),
+  /// Set a key to an LZ4 compressed value, with the expiry deadline if it has one
+  SetCompressed(Key, #[serde(with = "serde_bytes")] Vec<u8>, Option<u64>),
+  /// A set along with when it was written, in milliseconds since the unix epoch
+  ///
+  /// Every set is written like this in logs of version 2, see `format`, older logs without them still replay.
+  Timestamped(u64, Box<KvCommand>),
+}
+
+// encode a command as a record, compressing its value if the compression setting calls for it,
+// and stamping sets with when they were modified if given
+//
+// The command is handed back, as stamping moves it into the record.
+fn encode_command(
+  cmd: KvCommand,
+  modified: Option<u64>,
+  compression: Compression,
+  buf: &mut Vec<u8>,
+) -> Result<KvCommand> {
+  match compress(&cmd, compression) {
+    Some(compressed) => {
+      encode_record(compressed, modified, buf)?;
+      Ok(cmd)
     }
+    None => encode_record(cmd, modified, buf),
+  }
+}
 
-    Ok(())
+fn encode_record(record: KvCommand, modified: Option<u64>, buf: &mut Vec<u8>) -> Result<KvCommand> {
+  let modified = match modified {
+    Some(modified) if !matches!(record, KvCommand::Rm(_)) => modified,
+    _ => {
+      encode::write(buf, &record)?;
+      return Ok(record);
+    }
+  };
+
+  let stamped = KvCommand::Timestamped(modified, Box::new(record));
+  encode::write(buf, &stamped)?;
+  let KvCommand::Timestamped(_, record) = stamped else {
+    unreachable!("record was just stamped")
+  };
+  Ok(*record)
+}
+
+// encode the set a live key points at as the record compaction keeps of it
+fn encode_live_record(
+  key: &str,
+  cmd: KvCommand,
+  modified: Option<u64>,
+  compression: Compression,
+  buf: &mut Vec<u8>,
+) -> Result<()> {
+  let cmd = match cmd {
+    KvCommand::Set(_, value) => KvCommand::Set(key.to_owned(), value),
+    KvCommand::SetEx(_, value, expires_at) => KvCommand::SetEx(key.to_owned(), value, expires_at),
+    _ => return Err(KvStoreError::GetError),
+  };
+  encode_command(cmd, modified, compression, buf)?;
+
+  Ok(())
+}
+
+// split off the timestamp of a stamped record
+fn unstamp(record: KvCommand) -> (KvCommand, Option<u64>) {
+  match record {
+    KvCommand::Timestamped(modified, cmd) => (*cmd, Some(modified)),
+    cmd => (cmd, None),
   }
+}
 
-  /// Remove the given key and its associated value from the key-value store
-  pub fn remove(&mut self, key: String) -> Result<()> {
-    // check exist
-    if !self.index.contains_key(&key) {
-      return Err(KvStoreError::RmKeyNotFoundError);
+// the compressed form of a set, if compression is on and actually makes the value smaller
+fn compress(cmd: &KvCommand, compression: Compression) -> Option<KvCommand> {
+  match (compression, cmd) {
+    (Compression::None, _) => None,
+    #[cfg(feature = "compression")]
+    (Compression::Lz4, KvCommand::Set(key, value)) => compress_set(key, value, None),
+    #[cfg(feature = "compression")]
+    (Compression::Lz4, KvCommand::SetEx(key, value, expires_at)) => compress_set(key, value, Some(*expires_at)),
+    #[cfg(feature = "compression")]
+    (Compression::Lz4, _) => None,
+  }
+}
+
+#[cfg(feature = "compression")]
+fn compress_set(key: &str, value: &str, expires_at: Option<u64>) -> Option<KvCommand> {
+  let bytes = lz4_flex::compress_prepend_size(value.as_bytes());
+  if bytes.len() >= value.len() {
+    return None;
+  }
+  Some(KvCommand::SetCompressed(key.to_owned(), bytes, expires_at))
+}
+
+// turn a compressed set back into the plain set it was written from
+fn decompress(cmd: KvCommand) -> Result<KvCommand> {
+  match cmd {
+    KvCommand::SetCompressed(key, bytes, expires_at) => {
+      let value = decompress_value(&bytes)?;
+      match expires_at {
+        Some(expires_at) => Ok(KvCommand::SetEx(key, value, expires_at)),
+        None => Ok(KvCommand::Set(key, value)),
+      }
     }
+    cmd => Ok(cmd),
+  }
+}
 
-    // write log
-    let cmd = KvCommand::Rm(key.clone());
-    self.write_log(cmd)?;
+// read the value of the key from its set at the given log pointer of the log
+pub(crate) fn read_value_at<R: Read + Seek>(
+  log: &mut Deserializer<ReadReader<R>>,
+  key: &str,
+  log_pointer: u64,
+) -> Result<String> {
+  log.get_mut().seek(SeekFrom::Start(log_pointer))?;
+  let (cmd, _) = unstamp(KvCommand::deserialize(&mut *log)?);
+  match decompress(cmd)? {
+    KvCommand::Set(key_in_log, value) | KvCommand::SetEx(key_in_log, value, _) if key_in_log == key => Ok(value),
+    _ => Err(KvStoreError::GetError),
+  }
+}
 
-    // update in-memory index
-    self.index.remove(&key);
-    self.garbage += 1;
-    self.maybe_compact_logs()?;
+#[cfg(feature = "compression")]
+fn decompress_value(bytes: &[u8]) -> Result<String> {
+  let bytes = lz4_flex::decompress_size_prepended(bytes).map_err(|e| KvStoreError::CompressionError(e.to_string()))?;
+  String::from_utf8(bytes).map_err(|e| KvStoreError::CompressionError(e.to_string()))
+}
 
-    Ok(())
+#[cfg(not(feature = "compression"))]
+fn decompress_value(_bytes: &[u8]) -> Result<String> {
+  Err(KvStoreError::CompressionError(
+    "the log has compressed values, but kvs was built without the compression feature".to_owned(),
+  ))
+}
+
+// the in-memory index saved alongside the log, valid as long as the log still has the same length
+#[derive(Serialize, Deserialize)]
+struct Hint<I, E> {
+  log_len: u64,
+  garbage: u64,
+  index: I,
+  expiry: E,
+}
+
+// milliseconds since the unix epoch, which expiry deadlines are kept in
+fn now_millis() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+// find the log's version and where its records start, past its header if it has one, and seek there
+fn records_start<R: Read + Seek>(reader: &mut R) -> Result<(u32, u64)> {
+  reader.seek(SeekFrom::Start(0))?;
+  let mut header = Vec::with_capacity(format::HEADER_LEN);
+  reader
+    .by_ref()
+    .take(format::HEADER_LEN as u64)
+    .read_to_end(&mut header)?;
+  let (version, start) = format::detect_version(&header)?;
+  reader.seek(SeekFrom::Start(start))?;
+
+  Ok((version, start))
+}
+
+// the header every log a store starts is written with, of the version stores write
+fn log_header() -> Vec<u8> {
+  let mut header = Vec::with_capacity(format::HEADER_LEN);
+  format::encode_header(format::FORMAT_VERSION, &mut header);
+  header
+}
+
+// what `KvStore::compact_shared` copies from without holding the lock
+struct CompactionSnapshot {
+  // live keys when compaction started
+  index: Index,
+  // length of the log then
+  len: u64,
+  garbage: u64,
+  rewrites: u64,
+}
+
+// what replaying a log rebuilt
+struct Replayed {
+  index: Index,
+  expiry: Expiry,
+  garbage: u64,
+  records: usize,
+  // end of the last record that decoded
+  valid_len: u64,
+  // size of the largest record that decoded
+  largest_record: u64,
+}
+
+// bytes of the records encoded one after another into `len` bytes at the given offsets that count against
+// `max_log_bytes`, all but tombstones, and the size of the largest one
+fn measure_records(records: &[(KvCommand, u64)], len: u64) -> (u64, u64) {
+  let mut counted = 0;
+  let mut largest = 0;
+  for (i, (cmd, offset)) in records.iter().enumerate() {
+    let size = records.get(i + 1).map_or(len, |(_, next)| *next) - offset;
+    if !matches!(cmd, KvCommand::Rm(_)) {
+      counted += size;
+    }
+    largest = largest.max(size);
+  }
+
+  (counted, largest)
+}
+
+// decode the record at the current position of a log of the given version, without its stamp, `None` if it's
+// broken, see `format::record_fits`
+fn decode_record<R: Read>(log: &mut Deserializer<ReadReader<R>>, version: u32) -> Option<KvCommand> {
+  let record = KvCommand::deserialize(&mut *log).ok()?;
+  if !format::record_fits(&record, version) {
+    return None;
   }
 
-  fn write_log(&mut self, cmd: KvCommand) -> Result<u64> {
-    // Go to file tail
-    let pos = self.log.get_mut().seek(SeekFrom::End(0))?;
+  Some(unstamp(record).0)
+}
 
-    // Write command
-    let bytes = encode::to_vec(&cmd)?;
-    self.log.get_mut().get_mut().write_all(&bytes)?;
+// read a record's MessagePack framing up to the value of the plain set of `key` it should be, stamped or not,
+// returning the value's length with the reader left at its first byte
+//
+// Only the framing is read, so a value can be copied out of the log without decoding it. A compressed set, whose
+// value has to be decompressed whole anyway, gives `None`. A record that isn't a set of `key` fails with
+// `InvalidData`, one the log ends within with `UnexpectedEof`.
+fn set_value_len<R: Read>(reader: &mut R, key: &str) -> io::Result<Option<u64>> {
+  // variant indices of the records, see `format`
+  const SET: u64 = 0;
+  const SET_EX: u64 = 2;
+  const SET_COMPRESSED: u64 = 3;
+  const TIMESTAMPED: u64 = 4;
 
-    Ok(pos)
+  let mut variant = read_variant(reader)?;
+  if variant == TIMESTAMPED {
+    // the stamp, then the stamped record
+    read_array_len(reader)?;
+    read_uint(reader)?;
+    variant = read_variant(reader)?;
+  }
+  match variant {
+    SET | SET_EX => {}
+    SET_COMPRESSED => return Ok(None),
+    _ => return Err(invalid_record("not a set")),
   }
 
-  fn maybe_compact_logs(&mut self) -> Result<()> {
-    if self.garbage < COMPACTION_THRESHOLD {
-      return Ok(());
+  read_array_len(reader)?;
+  if read_bytes_len(reader)? != key.len() as u64 {
+    return Err(invalid_record("a set of another key"));
+  }
+  let mut key_in_log = vec![0; key.len()];
+  reader.read_exact(&mut key_in_log)?;
+  if key_in_log != key.as_bytes() {
+    return Err(invalid_record("a set of another key"));
+  }
+
+  read_bytes_len(reader).map(Some)
+}
+
+fn invalid_record(what: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+// the variant index of the enum at the reader's position, which MessagePack has as an array of it and its fields
+fn read_variant<R: Read>(reader: &mut R) -> io::Result<u64> {
+  if read_array_len(reader)? != 2 {
+    return Err(invalid_record("not a record"));
+  }
+  read_uint(reader)
+}
+
+fn read_array_len<R: Read>(reader: &mut R) -> io::Result<u64> {
+  match read_marker(reader)? {
+    marker @ 0x90..=0x9f => Ok(u64::from(marker & 0x0f)),
+    0xdc => read_be(reader, 2),
+    0xdd => read_be(reader, 4),
+    _ => Err(invalid_record("expected an array")),
+  }
+}
+
+fn read_uint<R: Read>(reader: &mut R) -> io::Result<u64> {
+  match read_marker(reader)? {
+    marker @ 0x00..=0x7f => Ok(u64::from(marker)),
+    0xcc => read_be(reader, 1),
+    0xcd => read_be(reader, 2),
+    0xce => read_be(reader, 4),
+    0xcf => read_be(reader, 8),
+    _ => Err(invalid_record("expected an unsigned integer")),
+  }
+}
+
+// length of the string, or binary, at the reader's position
+fn read_bytes_len<R: Read>(reader: &mut R) -> io::Result<u64> {
+  match read_marker(reader)? {
+    marker @ 0xa0..=0xbf => Ok(u64::from(marker & 0x1f)),
+    0xd9 | 0xc4 => read_be(reader, 1),
+    0xda | 0xc5 => read_be(reader, 2),
+    0xdb | 0xc6 => read_be(reader, 4),
+    _ => Err(invalid_record("expected a string")),
+  }
+}
+
+fn read_marker<R: Read>(reader: &mut R) -> io::Result<u8> {
+  let mut marker = [0];
+  reader.read_exact(&mut marker)?;
+  Ok(marker[0])
+}
+
+// a big-endian unsigned integer of `len` bytes
+fn read_be<R: Read>(reader: &mut R, len: usize) -> io::Result<u64> {
+  let mut bytes = [0; 8];
+  reader.read_exact(&mut bytes[8 - len..])?;
+  Ok(u64::from_be_bytes(bytes))
+}
+
+// whether decoding failed because the log ended within the record
+fn cut_short(e: &rmp_serde::decode::Error) -> bool {
+  match e {
+    rmp_serde::decode::Error::InvalidMarkerRead(e) | rmp_serde::decode::Error::InvalidDataRead(e) => {
+      e.kind() == io::ErrorKind::UnexpectedEof
     }
+    _ => false,
+  }
+}
 
-    // write a new log with only Set commands
-    let clog_path = self.log_dir.clone().join("kvs-comp.log");
+// find the next offset after `from` that a record of a log of the given version decodes at, if any
+fn resync<R: Read + Seek>(
+  log: &mut Deserializer<ReadReader<R>>,
+  version: u32,
+  from: u64,
+  len: u64,
+) -> Result<Option<u64>> {
+  for pos in from..len {
+    log.get_mut().seek(SeekFrom::Start(pos))?;
+    if decode_record(log, version).is_some() {
+      log.get_mut().seek(SeekFrom::Start(pos))?;
+      return Ok(Some(pos));
+    }
+  }
 
-    // use a block to close new file (?)
-    let new_index = {
-      let mut clog_file = OpenOptions::new().write(true).create(true).open(&clog_path)?;
+  Ok(None)
+}
 
-      let mut new_pos = 0;
-      let mut index = self.index.clone();
-      for (key, log_pointer) in index.iter_mut() {
-        self.log.get_mut().seek(SeekFrom::Start(*log_pointer))?;
+// decode records of a log of the given version from the current position until one fails to, which is normally
+// the end of the log
+//
+// With `RecoveryMode::SkipBadRecords`, replay goes on from the next offset a record decodes at instead.
+fn replay_log<R: Read + Seek>(
+  log: &mut Deserializer<ReadReader<R>>,
+  version: u32,
+  mode: RecoveryMode,
+  progress: Option<&dyn ReplayProgress>,
+) -> Result<Replayed> {
+  let mut index = HashMap::new();
+  let mut expiry = HashMap::new();
+  let mut garbage = 0;
+  let mut records = 0;
+  let mut largest_record = 0;
+  // start of the record decoded last, it's sized once the next one starts
+  let mut last_start = None;
 
-        if let Ok(KvCommand::Set(_, value)) = KvCommand::deserialize(&mut self.log) {
-          *log_pointer = new_pos;
+  let start = log.get_mut().stream_position()?;
+  let len = log.get_mut().seek(SeekFrom::End(0))?;
+  log.get_mut().seek(SeekFrom::Start(start))?;
+  let mut next_report = start;
 
-          let cmd = KvCommand::Set(key.to_owned(), value);
-          let bytes = encode::to_vec(&cmd)?;
-          clog_file.write_all(&bytes)?;
-          new_pos += bytes.len() as u64;
-        } else {
-          return Err(KvStoreError::CompactionError);
+  let valid_len = loop {
+    let pos = log.get_mut().stream_position()?;
+    if let Some(last_start) = last_start.take() {
+      largest_record = largest_record.max(pos - last_start);
+    }
+    if let Some(progress) = progress.filter(|_| pos >= next_report) {
+      progress.on_progress(pos, len);
+      next_report = pos + PROGRESS_INTERVAL_BYTES;
+    }
+    if let Some(cmd) = decode_record(log, version) {
+      records += 1;
+      last_start = Some(pos);
+      match cmd {
+        KvCommand::Set(key, _value) => {
+          expiry.remove(&key);
+          if index.insert(key, pos).is_some() {
+            // key is replaced
+            garbage += 1;
+          }
+        }
+        KvCommand::SetEx(key, _, expires_at) | KvCommand::SetCompressed(key, _, Some(expires_at)) => {
+          if index.insert(key.clone(), pos).is_some() {
+            // key is replaced
+            garbage += 1;
+          }
+          expiry.insert(key, expires_at);
+        }
+        KvCommand::SetCompressed(key, _, None) => {
+          expiry.remove(&key);
+          if index.insert(key, pos).is_some() {
+            // key is replaced
+            garbage += 1;
+          }
+        }
+        KvCommand::Rm(key) => {
+          index.remove(&key);
+          expiry.remove(&key);
+          // rm is always garbage
+          garbage += 1;
         }
+        KvCommand::Timestamped(..) => unreachable!("stamped records are filtered out above"),
+      }
+    } else if pos < len && mode == RecoveryMode::SkipBadRecords {
+      match resync(log, version, pos + 1, len)? {
+        Some(next) => log::warn!("Skipped {} bytes of bad records at offset {}", next - pos, pos),
+        None => break pos,
+      }
+    } else {
+      break pos;
+    }
+  };
+  if let Some(progress) = progress {
+    progress.on_progress(len, len);
+  }
+
+  Ok(Replayed {
+    index,
+    expiry,
+    garbage,
+    records,
+    valid_len,
+    largest_record,
+  })
+}
+
+// path of the log the options name in `directory`, within the namespace's subdirectory for a namespaced store
+fn log_path(mut log_dir: PathBuf, options: &KvStoreOptions) -> Result<PathBuf> {
+  if let Some(namespace) = &options.namespace {
+    check_namespace(namespace)?;
+    log_dir.push(namespace);
+  }
+
+  // a plain file name, nothing that would lead out of the directory
+  let name = Path::new(&options.log_file_name);
+  if name.file_name() != Some(name.as_os_str()) {
+    return Err(KvStoreError::InvalidLogFileNameError(options.log_file_name.to_owned()));
+  }
+
+  Ok(log_dir.join(&options.log_file_name))
+}
+
+// fail with `InvalidNamespaceError` unless the namespace is a plain directory name, see `KvStoreOptions::namespace`
+pub(crate) fn check_namespace(namespace: &str) -> Result<()> {
+  let valid = !namespace.is_empty()
+    && namespace
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+  if !valid {
+    return Err(KvStoreError::InvalidNamespaceError(namespace.to_owned()));
+  }
+
+  Ok(())
+}
+
+impl KvStore {
+  /// Creates a new key-value store
+  pub fn open(directory: impl Into<PathBuf>) -> Result<Self> {
+    Self::open_with_options(directory, KvStoreOptions::default())
+  }
+
+  /// Creates a new key-value store with the given options
+  pub fn open_with_options(directory: impl Into<PathBuf>, options: KvStoreOptions) -> Result<Self> {
+    let directory = directory.into();
+    if options.create_dir {
+      fs::create_dir_all(&directory)?;
+    }
+    let log_path = log_path(directory, &options)?;
+    if options.namespace.is_some() {
+      match fs::create_dir(log_path.parent().expect("the log is in the namespace's directory")) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e.into()),
+        _ => {}
       }
-      clog_file.sync_all()?;
+    }
 
-      index
+    let mut storage = if options.mmap_reads {
+      FileStorage::open_mmap(log_path)?
+    } else {
+      FileStorage::open(log_path)?
     };
+    if let Some(dir) = &options.compaction_dir {
+      storage = storage.with_scratch_dir(dir);
+    }
+    storage = storage.with_dir_sync(options.sync_dir);
 
-    // move (rename) the log and reopen it
-    let log_path = self.log_dir.clone().join("kvs.log");
-    fs::rename(clog_path, &log_path)?;
-    let log_file = OpenOptions::new().write(true).read(true).open(&log_path)?;
-    let reader = BufReader::new(log_file);
-    let new_log = Deserializer::new(reader);
+    Self::open_with_storage(storage, options)
+  }
 
-    // reset struct fields
-    self.index = new_index;
-    self.log = new_log;
-    self.garbage = 0;
+  /// Directory the log lives in, the namespace's subdirectory for namespaced stores
+  pub fn log_dir(&self) -> &Path {
+    self.file_storage().path().parent().unwrap_or_else(|| Path::new("."))
+  }
 
-    Ok(())
+  /// Path of the log file
+  pub fn log_path(&self) -> PathBuf {
+    self.file_storage().path().to_owned()
+  }
+
+  fn file_storage(&self) -> &FileStorage {
+    &self.log.get_ref().get_ref().storage
+  }
+
+  /// Number of times replacing the log synced its directory since the store was opened, see
+  /// `KvStoreOptions::sync_dir`
+  ///
+  /// Only there for tests to check the directory is synced, nothing else can observe it.
+  #[cfg(feature = "test-utils")]
+  #[doc(hidden)]
+  pub fn dir_syncs(&self) -> u64 {
+    self.file_storage().dir_syncs()
+  }
+
+  // a reader of the log of its own, so values can be read with only `&self` to the store, see `locate_indexed`
+  pub(crate) fn shared_reader(&self) -> Result<Log<FileStorage>> {
+    let reader = StorageReader::new(self.file_storage().reader()?);
+    Ok(Deserializer::new(BufReader::with_capacity(
+      self.options.read_buffer_bytes.max(1),
+      reader,
+    )))
+  }
+
+  // number of times the log was changed other than by appending, a shared reader opened before may be reading
+  // a log that was replaced since
+  pub(crate) fn rewrites(&self) -> u64 {
+    self.rewrites
+  }
+
+  /// Check the log in the given directory without changing anything, not even creating a missing log
+  ///
+  /// Every record must decode, and every live key must point at a set of that same key.
+  pub fn verify(directory: impl Into<PathBuf>) -> Result<VerifyReport> {
+    Self::verify_with_options(directory, &KvStoreOptions::default())
+  }
+
+  /// Check the log in the given directory like `verify`, finding it by the options' `namespace` and
+  /// `log_file_name` the way `open_with_options` does, no other option matters
+  pub fn verify_with_options(directory: impl Into<PathBuf>, options: &KvStoreOptions) -> Result<VerifyReport> {
+    let file = File::open(log_path(directory.into(), options)?)?;
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let (version, _) = records_start(&mut reader)?;
+    let mut log = Deserializer::new(reader);
+    let replayed = replay_log(&mut log, version, RecoveryMode::StopAtBadRecord, None)?;
+
+    let mut inconsistent_keys = 0;
+    for (key, log_pointer) in &replayed.index {
+      log.get_mut().seek(SeekFrom::Start(*log_pointer))?;
+      match KvCommand::deserialize(&mut log).map(|record| unstamp(record).0) {
+        Ok(KvCommand::Set(key_in_log, _))
+        | Ok(KvCommand::SetEx(key_in_log, _, _))
+        | Ok(KvCommand::SetCompressed(key_in_log, _, _))
+          if key_in_log == *key => {}
+        _ => inconsistent_keys += 1,
+      }
+    }
+
+    let now = now_millis();
+    let expired = replayed
+      .expiry
+      .values()
+      .filter(|expires_at| **expires_at <= now)
+      .count();
+    Ok(VerifyReport {
+      records: replayed.records,
+      live_keys: replayed.index.len() - expired,
+      garbage: replayed.garbage,
+      corrupted_at: Some(replayed.valid_len).filter(|valid_len| *valid_len < len),
+      inconsistent_keys,
+    })
+  }
+
+  /// Salvage a corrupted log in the given directory, keeping the longest prefix of records that decode
+  ///
+  /// The log is truncated right after the last good record, so the store opens with the surviving keys
+  /// and new writes are no longer appended behind the corruption.
+  pub fn repair(directory: impl Into<PathBuf>) -> Result<RepairReport> {
+    Self::repair_with_options(directory, &KvStoreOptions::default())
+  }
+
+  /// Salvage a corrupted log in the given directory like `repair`, finding it by the options' `namespace` and
+  /// `log_file_name` the way `open_with_options` does, no other option matters
+  pub fn repair_with_options(directory: impl Into<PathBuf>, options: &KvStoreOptions) -> Result<RepairReport> {
+    let storage = FileStorage::open(log_path(directory.into(), options)?)?;
+    let old_len = storage.len()?;
+    let mut reader = BufReader::new(StorageReader::new(storage));
+    let (version, mut valid_len) = records_start(&mut reader)?;
+    let mut log = Deserializer::new(reader);
+
+    let mut salvaged_records = 0;
+    while decode_record(&mut log, version).is_some() {
+      salvaged_records += 1;
+      valid_len = log.get_mut().stream_position()?;
+    }
+
+    let storage = &mut log.get_mut().get_mut().storage;
+    if valid_len < old_len {
+      storage.truncate(valid_len)?;
+      storage.sync()?;
+    }
+
+    Ok(RepairReport {
+      salvaged_records,
+      discarded_bytes: old_len - valid_len,
+    })
+  }
+}
+
+impl KvStore<MemoryStorage> {
+  /// Creates a new key-value store backed by an in-memory buffer, nothing is written to disk
+  pub fn open_in_memory() -> Result<Self> {
+    Self::open_with_storage(MemoryStorage::default(), KvStoreOptions::default())
+  }
+}
+
+impl<S: LogStorage> KvStore<S> {
+  /// Creates a key-value store on top of the given log storage, replaying any existing log
+  ///
+  /// If the storage has a valid index hint for the log, it's loaded instead of replaying. A record the log ends
+  /// in the middle of, as a crash while appending leaves it, is truncated away when replaying.
+  pub fn open_with_storage(storage: S, options: KvStoreOptions) -> Result<Self> {
+    let reader = BufReader::with_capacity(options.read_buffer_bytes.max(1), StorageReader::new(storage));
+    let log = Deserializer::new(reader);
+    let cache = ValueCache::new(options.value_cache_bytes.unwrap_or(0));
+    // replaying on open finds a cut short record, the hint only exists for a log that ends cleanly
+    let tail_checked = options.index == IndexMode::InMemory;
+    let latency = if options.track_latency {
+      Some(LatencyRecorder::new())
+    } else {
+      None
+    };
+
+    let mut kvs = Self {
+      index: HashMap::new(),
+      expiry: HashMap::new(),
+      log,
+      garbage: 0,
+      options,
+      replayed: 0,
+      hint_stale: true,
+      unsynced: 0,
+      subscribers: Vec::new(),
+      cache,
+      latency,
+      encode_buf: Vec::new(),
+      decode_buf: Vec::new(),
+      fresh_tail: None,
+      tail_checked,
+      largest_record: u64::MAX,
+      rewrites: 0,
+      log_start: 0,
+      log_version: format::FORMAT_VERSION,
+      snapshots: Arc::new(()),
+    };
+    if kvs.storage().len()? == 0 {
+      // a new log, or one emptied behind the store's back, starts out in the version stores write
+      kvs.storage().append(&log_header())?;
+    }
+    let (log_version, log_start) = records_start(kvs.log.get_mut())?;
+    kvs.log_version = log_version;
+    kvs.log_start = log_start;
+    if kvs.options.index == IndexMode::None {
+      return Ok(kvs);
+    }
+    if !kvs.load_hint()? {
+      let valid_len = kvs.replay()?;
+      kvs.drop_torn_tail(valid_len)?;
+    }
+    if kvs.options.compact_on_open {
+      kvs.compact_if_due()?;
+    }
+
+    Ok(kvs)
+  }
+
+  // rebuild the index by replaying the whole log, returns where the last record that decoded ends
+  fn replay(&mut self) -> Result<u64> {
+    self.log.get_mut().seek(SeekFrom::Start(self.log_start))?;
+    let progress = self.options.replay_progress.clone();
+    let replayed = replay_log(
+      &mut self.log,
+      self.log_version,
+      self.options.recovery_mode,
+      progress.as_deref(),
+    )?;
+
+    self.index = replayed.index;
+    self.expiry = replayed.expiry;
+    self.garbage = replayed.garbage;
+    self.replayed = replayed.records;
+    self.largest_record = replayed.largest_record;
+
+    Ok(replayed.valid_len)
+  }
+
+  // cut off a record left half written at the end of the log by a crash mid-append
+  //
+  // Left in place, it would hide every record appended after it from the next replay. Only a record the log ends
+  // within, with nothing readable after it, is cut off: corruption anywhere else is left for `repair`.
+  fn drop_torn_tail(&mut self, valid_len: u64) -> Result<()> {
+    let len = self.storage().len()?;
+    if valid_len >= len {
+      return Ok(());
+    }
+
+    self.log.get_mut().seek(SeekFrom::Start(valid_len))?;
+    let torn = match KvCommand::deserialize(&mut self.log) {
+      Err(e) => cut_short(&e) && resync(&mut self.log, self.log_version, valid_len + 1, len)?.is_none(),
+      Ok(_) => false,
+    };
+    if torn {
+      log::warn!(
+        "Dropping a record cut short at the end of the log, {} bytes at offset {}",
+        len - valid_len,
+        valid_len
+      );
+      self.storage().truncate(valid_len)?;
+      self.rewrites += 1;
+      // forget what was buffered of the dropped bytes
+      self.log.get_mut().seek(SeekFrom::Start(valid_len))?;
+    }
+
+    Ok(())
+  }
+
+  // with `IndexMode::None`, replay the log so the index holds every live key, for an operation going through all
+  // of them
+  //
+  // `forget_full_index` drops it again once the operation is done, one that fails just leaves it, as it's
+  // complete and kept up to date like any index.
+  fn full_index(&mut self) -> Result<()> {
+    if self.options.index == IndexMode::InMemory {
+      return Ok(());
+    }
+
+    let valid_len = self.replay()?;
+    self.drop_torn_tail(valid_len)?;
+    self.tail_checked = true;
+
+    Ok(())
+  }
+
+  // with `IndexMode::None`, forget the index `full_index` built, keys are found by scanning the log again
+  fn forget_full_index(&mut self) {
+    if self.options.index == IndexMode::None {
+      self.index.clear();
+      self.expiry.clear();
+    }
+  }
+
+  // with `IndexMode::None` nothing looks at the end of the log on open, so before the first append drop a record
+  // cut short by a crash there, the append would otherwise land behind it and never be read back
+  fn check_tail(&mut self) -> Result<()> {
+    if self.tail_checked {
+      return Ok(());
+    }
+
+    self.full_index()?;
+    self.forget_full_index();
+
+    Ok(())
+  }
+
+  /// Rebuild the index by replaying the log from scratch, without reopening it
+  ///
+  /// This picks up changes made to the log behind the store's back, e.g. by hand or by `repair`.
+  /// The log has to be changed in place though, a log replaced by a new file isn't seen.
+  pub fn reload_index(&mut self) -> Result<()> {
+    self.storage().reload()?;
+    let (log_version, log_start) = records_start(self.log.get_mut())?;
+    self.log_version = log_version;
+    self.log_start = log_start;
+    self.replay()?;
+    self.cache.clear();
+    self.hint_stale = true;
+    self.fresh_tail = None;
+    self.rewrites += 1;
+
+    Ok(())
+  }
+
+  // load the index from the storage's hint, returns false if there's no usable one
+  fn load_hint(&mut self) -> Result<bool> {
+    let bytes = match self.storage().load_hint()? {
+      Some(bytes) => bytes,
+      None => return Ok(false),
+    };
+
+    // a broken hint just means replaying the log
+    let hint: Hint<Index, Expiry> = match rmp_serde::from_read_ref(&bytes) {
+      Ok(hint) => hint,
+      Err(_) => return Ok(false),
+    };
+    if hint.log_len != self.storage().len()? {
+      return Ok(false);
+    }
+
+    self.index = hint.index;
+    self.expiry = hint.expiry;
+    self.garbage = hint.garbage;
+    self.hint_stale = false;
+
+    Ok(true)
+  }
+
+  fn write_hint(&mut self) -> Result<()> {
+    let hint = Hint {
+      log_len: self.storage().len()?,
+      garbage: self.garbage,
+      index: &self.index,
+      expiry: &self.expiry,
+    };
+    let bytes = encode::to_vec(&hint)?;
+    self.storage().store_hint(&bytes)?;
+    self.hint_stale = false;
+
+    Ok(())
+  }
+
+  /// Flush the log to durable storage, and save an index hint so the next open can skip replaying the log
+  pub fn flush(&mut self) -> Result<()> {
+    self.storage().sync()?;
+    self.unsynced = 0;
+    // an index missing keys would hide them from the next open
+    if self.options.index == IndexMode::None {
+      return Ok(());
+    }
+    self.write_hint()
+  }
+
+  /// Flush and close the key-value store
+  ///
+  /// Dropping the store flushes it as well, but can only log a failure, so prefer this to find out about errors.
+  pub fn close(mut self) -> Result<()> {
+    self.flush()
+  }
+
+  /// Get the value associated with the given key in the key-value store
+  ///
+  /// Keys whose TTL has passed are treated as missing, even before they're purged.
+  pub fn get(&mut self, key: String) -> Result<Option<String>> {
+    let started = self.start_timer();
+    let value = self.lookup(key);
+    self.record_latency(Operation::Get, started);
+
+    value
+  }
+
+  fn lookup(&mut self, key: String) -> Result<Option<String>> {
+    let log_pointer = match self.locate(&key)? {
+      Some(log_pointer) => log_pointer,
+      None => return Ok(None),
+    };
+    if let Some(value) = self.cache.get(&key) {
+      return Ok(Some(value.to_owned()));
+    }
+
+    let value = self.read_value(&key, log_pointer)?;
+    self.cache.insert(key, value.clone());
+
+    Ok(Some(value))
+  }
+
+  /// Pass the value associated with the given key to `f`, returning what it returns
+  ///
+  /// Unlike `get`, this doesn't allocate for the value: a cached one is lent straight from the cache, and one
+  /// stored uncompressed is read from the log into a buffer the store keeps reusing, without being cached.
+  /// A compressed value is decompressed into a value of its own.
+  pub fn with_value<R>(&mut self, key: &str, f: impl FnOnce(Option<&str>) -> R) -> Result<R> {
+    let log_pointer = match self.locate(key)? {
+      Some(log_pointer) => log_pointer,
+      None => return Ok(f(None)),
+    };
+    if let Some(value) = self.cache.get(key) {
+      return Ok(f(Some(value)));
+    }
+
+    let reader = self.log.get_mut();
+    reader.seek(SeekFrom::Start(log_pointer))?;
+    let len = match set_value_len(reader, key) {
+      Ok(Some(len)) => len,
+      Ok(None) => {
+        let value = self.read_value(key, log_pointer)?;
+        return Ok(f(Some(&value)));
+      }
+      Err(e) => return Err(self.framing_error(e, key, log_pointer)?),
+    };
+    let mut buf = mem::take(&mut self.decode_buf);
+    buf.clear();
+    if (self.log.get_mut().take(len).read_to_end(&mut buf)? as u64) < len {
+      return Err(self.cut_short_at(key, log_pointer)?);
+    }
+    let result = match std::str::from_utf8(&buf) {
+      Ok(value) => f(Some(value)),
+      Err(_) => return Err(KvStoreError::GetError),
+    };
+    if buf.capacity() <= ENCODE_BUF_BYTES {
+      self.decode_buf = buf;
+    }
+
+    Ok(result)
+  }
+
+  /// Write the value associated with the given key to `w`, returning whether the key exists
+  ///
+  /// Meant for values too large to hand around as a `String`: a value stored uncompressed is copied from the log
+  /// to `w` as it's read, without ever being held whole, and isn't cached so it doesn't crowd out the cache.
+  /// A compressed value is decompressed whole first. If reading from the log fails partway, `w` is left with
+  /// part of the value.
+  pub fn read_value_to(&mut self, key: &str, w: &mut impl Write) -> Result<bool> {
+    let log_pointer = match self.locate(key)? {
+      Some(log_pointer) => log_pointer,
+      None => return Ok(false),
+    };
+    if let Some(value) = self.cache.get(key) {
+      w.write_all(value.as_bytes())?;
+      return Ok(true);
+    }
+
+    let reader = self.log.get_mut();
+    reader.seek(SeekFrom::Start(log_pointer))?;
+    let len = match set_value_len(reader, key) {
+      Ok(Some(len)) => len,
+      Ok(None) => {
+        let value = self.read_value(key, log_pointer)?;
+        w.write_all(value.as_bytes())?;
+        return Ok(true);
+      }
+      Err(e) => return Err(self.framing_error(e, key, log_pointer)?),
+    };
+    if io::copy(&mut self.log.get_mut().take(len), w)? < len {
+      return Err(self.cut_short_at(key, log_pointer)?);
+    }
+
+    Ok(true)
+  }
+
+  // where the record of the key's live value is, if the index alone can tell, see `locate`
+  //
+  // Only needs `&self`, so a store shared behind a `RwLock` can be read under its read lock, with the value read
+  // through a reader of the caller's (see `shared_reader`). None with `IndexMode::None` for a key the index
+  // doesn't hold.
+  pub(crate) fn locate_indexed(&self, key: &str) -> Option<Option<u64>> {
+    match self.index.get(key) {
+      Some(log_pointer) => Some(Some(*log_pointer).filter(|_| !self.is_expired(key, now_millis()))),
+      None if self.options.index == IndexMode::InMemory => Some(None),
+      None => None,
+    }
+  }
+
+  // where the record of the key's live value is, if it has one
+  //
+  // With `IndexMode::None`, keys the index doesn't hold are looked for by scanning the log.
+  fn locate(&mut self, key: &str) -> Result<Option<u64>> {
+    let now = now_millis();
+    if let Some(log_pointer) = self.index.get(key) {
+      return Ok(Some(*log_pointer).filter(|_| !self.is_expired(key, now)));
+    }
+    if self.options.index == IndexMode::InMemory {
+      return Ok(None);
+    }
+
+    // the index holds every key written since open, so the log's latest record of the key is from before that
+    self.log.get_mut().seek(SeekFrom::Start(self.log_start))?;
+    let mut log_pointer = None;
+    loop {
+      let pos = self.log.get_mut().stream_position()?;
+      match decode_record(&mut self.log, self.log_version) {
+        Some(KvCommand::Set(key_in_log, _)) | Some(KvCommand::SetCompressed(key_in_log, _, None))
+          if key_in_log == key =>
+        {
+          log_pointer = Some(pos);
+        }
+        Some(KvCommand::SetEx(key_in_log, _, expires_at))
+        | Some(KvCommand::SetCompressed(key_in_log, _, Some(expires_at)))
+          if key_in_log == key =>
+        {
+          log_pointer = Some(pos).filter(|_| expires_at > now);
+        }
+        Some(KvCommand::Rm(key_in_log)) if key_in_log == key => log_pointer = None,
+        Some(_) => {}
+        None => break,
+      }
+    }
+
+    Ok(log_pointer)
+  }
+
+  // read the value of the key from the given log pointer, checking the record is the key's
+  //
+  // A record cut short by the end of the log, e.g. as the log was truncated behind the store's back,
+  // fails with a `ReplayError` saying so rather than the generic `GetError`.
+  fn read_value(&mut self, key: &str, log_pointer: u64) -> Result<String> {
+    match self.read_command(log_pointer) {
+      Ok(KvCommand::Set(key_in_log, value)) | Ok(KvCommand::SetEx(key_in_log, value, _)) if key_in_log == key => {
+        Ok(value)
+      }
+      Err(KvStoreError::DecodeError(e)) if cut_short(&e) => Err(self.cut_short_at(key, log_pointer)?),
+      _ => Err(KvStoreError::GetError),
+    }
+  }
+
+  // the error for reading the framing of the record of the key at the given log pointer failing, see
+  // `set_value_len`
+  fn framing_error(&mut self, e: io::Error, key: &str, log_pointer: u64) -> Result<KvStoreError> {
+    match e.kind() {
+      io::ErrorKind::UnexpectedEof => self.cut_short_at(key, log_pointer),
+      io::ErrorKind::InvalidData => Ok(KvStoreError::GetError),
+      _ => Ok(e.into()),
+    }
+  }
+
+  // the error for the record of the key at the given log pointer being cut short by the end of the log
+  fn cut_short_at(&mut self, key: &str, log_pointer: u64) -> Result<KvStoreError> {
+    Ok(KvStoreError::ReplayError(format!(
+      "record of key {:?} at offset {} is cut short by the end of the log at {} bytes",
+      key,
+      log_pointer,
+      self.storage().len()?
+    )))
+  }
+
+  /// Walk every command in the log from the start, including overwritten sets and removes
+  ///
+  /// Compaction drops history, so this is most useful with `KvStoreOptions::compaction` turned off.
+  /// Values come decompressed, and a record that doesn't decode ends the walk with an error.
+  pub fn replay_history(&mut self) -> impl Iterator<Item = Result<KvCommand>> + '_ {
+    History {
+      log: &mut self.log,
+      version: self.log_version,
+      start: self.log_start,
+      started: false,
+      done: false,
+    }
+  }
+
+  /// Decode every record in the log along with its offset, live or not, exactly as it was written
+  ///
+  /// This is meant for debugging the log format, so sets come stamped and possibly compressed.
+  /// The log must end cleanly, a record that doesn't decode fails the whole dump.
+  pub fn dump_log(&mut self) -> Result<Vec<(u64, KvCommand)>> {
+    let len = self.storage().len()?;
+    self.log.get_mut().seek(SeekFrom::Start(self.log_start))?;
+
+    let mut records = Vec::new();
+    loop {
+      let pos = self.log.get_mut().stream_position()?;
+      if pos >= len {
+        break;
+      }
+
+      match KvCommand::deserialize(&mut self.log) {
+        Ok(record) => records.push((pos, record)),
+        Err(e) => {
+          return Err(KvStoreError::ReplayError(format!(
+            "record at offset {} doesn't decode: {}",
+            pos, e
+          )))
+        }
+      }
+    }
+
+    Ok(records)
+  }
+
+  /// Get when the given key was last set, the size of its value and when it expires
+  pub fn get_meta(&mut self, key: String) -> Result<Option<KeyMeta>> {
+    let log_pointer = match self.locate(&key)? {
+      Some(log_pointer) => log_pointer,
+      None => return Ok(None),
+    };
+
+    let (value, expires_at, modified) = match self.read_record(log_pointer) {
+      Ok((KvCommand::Set(key_in_log, value), modified)) if key_in_log == key => (value, None, modified),
+      Ok((KvCommand::SetEx(key_in_log, value, expires_at), modified)) if key_in_log == key => {
+        (value, Some(expires_at), modified)
+      }
+      _ => return Err(KvStoreError::GetError),
+    };
+
+    Ok(Some(KeyMeta {
+      modified: modified.map(|modified| UNIX_EPOCH + Duration::from_millis(modified)),
+      value_bytes: value.len(),
+      expires: expires_at.map(|expires_at| UNIX_EPOCH + Duration::from_millis(expires_at)),
+    }))
+  }
+
+  /// Whether the given key exists in the key-value store, without reading its value
+  pub fn contains_key(&mut self, key: &str) -> Result<bool> {
+    Ok(self.locate(key)?.is_some())
+  }
+
+  /// Get the value associated with the given key, or the given default if there's none
+  ///
+  /// The default is not inserted into the store.
+  pub fn get_or<D: Into<String>>(&mut self, key: String, default: D) -> Result<String> {
+    Ok(self.get(key)?.unwrap_or_else(|| default.into()))
+  }
+
+  /// Get the value associated with the given key, or set it to the value computed by `f` if there's none
+  pub fn get_or_insert_with<F: FnOnce() -> String>(&mut self, key: String, f: F) -> Result<String> {
+    if let Some(value) = self.get(key.clone())? {
+      return Ok(value);
+    }
+
+    let value = f();
+    self.set(key, value.clone())?;
+    Ok(value)
+  }
+
+  /// Get the value associated with the given key, deserialized from the JSON `set_typed` stored it as
+  pub fn get_typed<T: DeserializeOwned>(&mut self, key: String) -> Result<Option<T>> {
+    match self.get(key)? {
+      Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+      None => Ok(None),
+    }
+  }
+
+  /// Get all live key-value pairs whose key starts with the given prefix, sorted by key
+  pub fn scan_prefix(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+    self.full_index()?;
+    let now = now_millis();
+    let mut entries: Vec<(String, u64)> = self
+      .index
+      .iter()
+      .filter(|(key, _)| key.starts_with(prefix) && !self.is_expired(key, now))
+      .map(|(key, log_pointer)| (key.to_owned(), *log_pointer))
+      .collect();
+    entries.sort();
+    self.forget_full_index();
+
+    entries
+      .into_iter()
+      .map(|(key, log_pointer)| match self.read_command(log_pointer) {
+        Ok(KvCommand::Set(_, value)) | Ok(KvCommand::SetEx(_, value, _)) => Ok((key, value)),
+        _ => Err(KvStoreError::GetError),
+      })
+      .collect()
+  }
+
+  /// Take a snapshot of the live keys and their values, which later writes to the store don't change
+  ///
+  /// Taking one copies the live keys out of the index, values are only read as they're asked for, through a
+  /// reader of the log of the snapshot's own (see `LogStorage::open_reader`). Storages without readers have
+  /// every value read right away instead. With `IndexMode::None`, this replays the log to find the live keys.
+  pub fn snapshot(&mut self) -> Result<Snapshot<S>> {
+    self.full_index()?;
+    let now = now_millis();
+    let mut entries: Vec<(String, u64)> = self
+      .index
+      .iter()
+      .filter(|(key, _)| !self.is_expired(key, now))
+      .map(|(key, log_pointer)| (key.to_owned(), *log_pointer))
+      .collect();
+    entries.sort();
+    self.forget_full_index();
+    // a remove mustn't cut off a record the snapshot may read
+    self.fresh_tail = None;
+
+    let source = match self.storage().open_reader()? {
+      Some(reader) => {
+        let reader = BufReader::with_capacity(self.options.read_buffer_bytes.max(1), StorageReader::new(reader));
+        Source::Log(Deserializer::new(reader))
+      }
+      None => Source::Values(
+        entries
+          .iter()
+          .map(|(key, log_pointer)| self.read_value(key, *log_pointer))
+          .collect::<Result<_>>()?,
+      ),
+    };
+
+    Ok(Snapshot::new(entries, source, self.snapshots.clone()))
+  }
+
+  /// Get all live keys matching the given glob pattern, sorted
+  ///
+  /// Patterns are matched like Redis' `KEYS`: `*` matches any run of characters, `?` any single one,
+  /// and `[abc]` or `[a-c]` one of a set, with `[!abc]` negating it. Keys are matched as a whole,
+  /// and a `/` is just another character.
+  pub fn scan_glob(&mut self, pattern: &str) -> Result<Vec<String>> {
+    let pattern = glob::Pattern::new(pattern).map_err(|e| KvStoreError::InvalidPatternError(e.to_string()))?;
+    self.full_index()?;
+    let now = now_millis();
+    let mut keys: Vec<String> = self
+      .index
+      .keys()
+      .filter(|key| pattern.matches(key) && !self.is_expired(key, now))
+      .cloned()
+      .collect();
+    keys.sort();
+    self.forget_full_index();
+
+    Ok(keys)
+  }
+
+  /// Serialize every live key-value pair into a single document, with keys sorted
+  ///
+  /// The whole document is built in memory, so this is meant for debugging small stores.
+  pub fn dump(&mut self, format: DumpFormat) -> Result<String> {
+    let entries: BTreeMap<String, String> = self.scan_prefix("")?.into_iter().collect();
+
+    match format {
+      DumpFormat::Json => serde_json::to_string_pretty(&entries).map_err(|e| KvStoreError::DumpError(e.to_string())),
+      DumpFormat::Ron => ron::ser::to_string_pretty(&entries, ron::ser::PrettyConfig::default())
+        .map_err(|e| KvStoreError::DumpError(e.to_string())),
+    }
+  }
+
+  /// Write every live key as a RESP `SET key value` command, sorted by key, e.g. for `redis-cli --pipe`
+  ///
+  /// Keys with a TTL get `PXAT` and their expiry deadline, which needs Redis 6.2 or later to load. Values
+  /// are read and written one at a time, so the export is never held in memory as a whole.
+  pub fn export_resp(&mut self, w: &mut impl Write) -> Result<()> {
+    self.full_index()?;
+    let now = now_millis();
+    let mut live: Vec<(String, u64)> = self
+      .index
+      .iter()
+      .filter(|(key, _)| !self.is_expired(key, now))
+      .map(|(key, log_pointer)| (key.to_owned(), *log_pointer))
+      .collect();
+    live.sort_unstable();
+
+    for (key, log_pointer) in live {
+      let value = match self.read_command(log_pointer)? {
+        KvCommand::Set(_, value) | KvCommand::SetEx(_, value, _) => value,
+        _ => return Err(KvStoreError::GetError),
+      };
+      let mut args = vec!["SET".to_owned(), key.clone(), value];
+      if let Some(expires_at) = self.expiry.get(&key) {
+        args.push("PXAT".to_owned());
+        args.push(expires_at.to_string());
+      }
+      resp::write_command(&resp::RespCommand::new(args), w)?;
+    }
+    self.forget_full_index();
+
+    Ok(())
+  }
+
+  /// Set the value associated with the given key in the key-value store
+  pub fn set(&mut self, key: String, value: String) -> Result<()> {
+    let started = self.start_timer();
+    let set = self.set_value(key, value);
+    self.record_latency(Operation::Set, started);
+
+    set
+  }
+
+  fn set_value(&mut self, key: String, value: String) -> Result<()> {
+    self.check_entry(&key, &value)?;
+
+    // write log
+    let cmd = KvCommand::Set(key.clone(), value);
+    let (log_pointer, end) = self.write_log(cmd)?;
+
+    // update in-memory index
+    self.expiry.remove(&key);
+    self.cache.remove(&key);
+    self.notify(&key, |key| KeyEvent::Set { key });
+    if self.index.insert(key, log_pointer).is_some() {
+      self.fresh_tail = None;
+      self.garbage = self.garbage.saturating_add(1);
+      self.maybe_compact_logs()?;
+    } else {
+      self.fresh_tail = Some((log_pointer, end));
+    }
+
+    Ok(())
+  }
+
+  /// Set the value associated with the given key to any serializable value
+  ///
+  /// Values are kept as JSON text, so they can also be read back with `get`.
+  pub fn set_typed<T: Serialize>(&mut self, key: String, value: &T) -> Result<()> {
+    let value = serde_json::to_string(value)?;
+    self.set(key, value)
+  }
+
+  /// Set the value associated with the given key, which expires once `ttl` has passed
+  ///
+  /// Expired keys read as missing right away, and their records are reclaimed by the next sweep,
+  /// see `purge_expired`.
+  pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+    self.check_entry(&key, &value)?;
+    let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+
+    // write log
+    let cmd = KvCommand::SetEx(key.clone(), value, expires_at);
+    let (log_pointer, end) = self.write_log(cmd)?;
+
+    // update in-memory index
+    self.expiry.insert(key.clone(), expires_at);
+    self.cache.remove(&key);
+    self.notify(&key, |key| KeyEvent::Set { key });
+    if self.index.insert(key, log_pointer).is_some() {
+      self.fresh_tail = None;
+      self.garbage = self.garbage.saturating_add(1);
+      self.maybe_compact_logs()?;
+    } else {
+      self.fresh_tail = Some((log_pointer, end));
+    }
+
+    Ok(())
+  }
+
+  /// Set or clear the TTL of an existing key, returns whether the key exists
+  ///
+  /// The key keeps its value, whose record is written to the log again along with the new expiry so the change
+  /// survives reopening the store. The record keeps the time the key was last set, and isn't checked against the
+  /// size limits, which only apply to new values. A `ttl` of `None` clears the key's expiry, so it lives until
+  /// removed, and writes nothing for a key without one.
+  pub fn expire(&mut self, key: &str, ttl: Option<Duration>) -> Result<bool> {
+    let log_pointer = match self.locate(key)? {
+      Some(log_pointer) => log_pointer,
+      None => return Ok(false),
+    };
+    let expires_at = ttl.map(|ttl| now_millis().saturating_add(ttl.as_millis() as u64));
+    if self.expiry.get(key).copied() == expires_at {
+      return Ok(true);
+    }
+    let (value, modified) = match self.read_record(log_pointer) {
+      Ok((KvCommand::Set(key_in_log, value), modified)) | Ok((KvCommand::SetEx(key_in_log, value, _), modified))
+        if key_in_log == key =>
+      {
+        (value, modified)
+      }
+      Err(KvStoreError::DecodeError(e)) if cut_short(&e) => return Err(self.cut_short_at(key, log_pointer)?),
+      _ => return Err(KvStoreError::GetError),
+    };
+
+    let cmd = match expires_at {
+      Some(expires_at) => KvCommand::SetEx(key.to_owned(), value, expires_at),
+      None => KvCommand::Set(key.to_owned(), value),
+    };
+    let (log_pointer, _) = self.write_stamped(cmd, modified)?;
+
+    // the value is the same, so a cached copy stays good
+    match expires_at {
+      Some(expires_at) => self.expiry.insert(key.to_owned(), expires_at),
+      None => self.expiry.remove(key),
+    };
+    self.notify(key, |key| KeyEvent::Set { key });
+    self.index.insert(key.to_owned(), log_pointer);
+    self.fresh_tail = None;
+    self.garbage = self.garbage.saturating_add(1);
+    self.maybe_compact_logs()?;
+
+    Ok(true)
+  }
+
+  /// Set many key-value pairs at once
+  ///
+  /// Records are appended to the log in large chunks and compaction is checked only once at the end,
+  /// which is a lot faster than calling `set` in a loop. Later entries win over earlier ones with the same key.
+  /// If an entry is rejected, chunks appended before it are kept.
+  pub fn bulk_load<I: Iterator<Item = (String, String)>>(&mut self, entries: I) -> Result<()> {
+    let mut buf = Vec::new();
+    let mut pending = Vec::new();
+    let now = now_millis();
+
+    for (key, value) in entries {
+      self.check_entry(&key, &value)?;
+      let offset = buf.len() as u64;
+      let cmd = encode_command(
+        KvCommand::Set(key, value),
+        Some(now),
+        self.options.compression,
+        &mut buf,
+      )?;
+      pending.push((cmd, offset));
+
+      if buf.len() >= BULK_CHUNK_BYTES {
+        self.append_chunk(&mut buf, &mut pending)?;
+      }
+    }
+    self.append_chunk(&mut buf, &mut pending)?;
+
+    self.maybe_compact_logs()
+  }
+
+  // reject empty keys, and keys or values over the configured size limits
+  fn check_entry(&self, key: &str, value: &str) -> Result<()> {
+    if key.is_empty() {
+      return Err(KvStoreError::InvalidKey("key is empty".to_owned()));
+    }
+    if let Some(max) = self.options.max_key_bytes {
+      if key.len() > max {
+        return Err(KvStoreError::InvalidKey(format!(
+          "key of {} bytes exceeds the maximum key size",
+          key.len()
+        )));
+      }
+    }
+
+    match self.options.max_value_bytes {
+      Some(max) if value.len() > max => Err(KvStoreError::ValueTooLarge(value.len())),
+      _ => Ok(()),
+    }
+  }
+
+  // append a chunk of encoded Set commands, and index them by their offsets within the chunk
+  fn append_chunk(&mut self, buf: &mut Vec<u8>, pending: &mut Vec<(KvCommand, u64)>) -> Result<()> {
+    if buf.is_empty() {
+      return Ok(());
+    }
+
+    let (counted, largest) = measure_records(pending, buf.len() as u64);
+    self.check_tail()?;
+    self.make_room(counted)?;
+    let base = self.storage().append(buf)?;
+    self.largest_record = self.largest_record.max(largest);
+    self.hint_stale = true;
+    self.commit()?;
+    buf.clear();
+
+    for (cmd, offset) in pending.drain(..) {
+      self.options.audit_sink.on_command(&cmd, base + offset);
+      let key = match cmd {
+        KvCommand::Set(key, _) => key,
+        _ => unreachable!("bulk loads only hold sets"),
+      };
+      self.expiry.remove(&key);
+      self.cache.remove(&key);
+      self.notify(&key, |key| KeyEvent::Set { key });
+      if self.index.insert(key, base + offset).is_some() {
+        self.garbage = self.garbage.saturating_add(1);
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Remove the given key and its associated value from the key-value store
+  ///
+  /// Removing a key that was new as of the last record in the log cuts that record off instead of appending
+  /// a tombstone, so setting and removing keys in turn doesn't grow the log. With `compaction` turned off,
+  /// keeping the log's whole history, a tombstone is always appended.
+  pub fn remove(&mut self, key: String) -> Result<()> {
+    // check exist
+    let log_pointer = match self.locate(&key)? {
+      Some(log_pointer) => log_pointer,
+      None => return Err(KvStoreError::RmKeyNotFoundError),
+    };
+
+    // nothing was appended since, and the log had no live value of the key before: cutting the set off leaves
+    // the log as it was before the key was set. Without an index, whether it had one isn't known.
+    let undo_set = match self.fresh_tail.take() {
+      Some((start, end))
+        if start == log_pointer && self.options.compaction && self.options.index == IndexMode::InMemory =>
+      {
+        end == self.storage().len()?
+      }
+      _ => false,
+    };
+    if undo_set {
+      self.storage().truncate(log_pointer)?;
+      self.rewrites += 1;
+      // forget what was buffered of the record, another one will be appended in its place
+      self.log.get_mut().seek(SeekFrom::Start(log_pointer))?;
+      self.hint_stale = true;
+      self.commit()?;
+      self
+        .options
+        .audit_sink
+        .on_command(&KvCommand::Rm(key.clone()), log_pointer);
+    } else {
+      // write log
+      let cmd = KvCommand::Rm(key.clone());
+      self.write_log(cmd)?;
+      self.garbage = self.garbage.saturating_add(1);
+    }
+
+    // update in-memory index
+    self.index.remove(&key);
+    self.expiry.remove(&key);
+    self.cache.remove(&key);
+    self.notify(&key, |key| KeyEvent::Removed { key });
+    self.maybe_compact_logs()?;
+
+    Ok(())
+  }
+
+  /// Remove all keys starting with the given prefix, returns how many were removed
+  ///
+  /// All the removals are appended to the log in a single write, and an empty prefix removes every key.
+  pub fn remove_prefix(&mut self, prefix: &str) -> Result<usize> {
+    self.full_index()?;
+    let now = now_millis();
+    let cmds: Vec<KvCommand> = self
+      .index
+      .keys()
+      .filter(|key| key.starts_with(prefix) && !self.is_expired(key, now))
+      .map(|key| KvCommand::Rm(key.to_owned()))
+      .collect();
+
+    let removed = self.remove_all(cmds);
+    self.forget_full_index();
+    removed
+  }
+
+  /// Remove all keys within the given range, returns how many were removed
+  ///
+  /// All the removals are appended to the log in a single write. The index isn't ordered,
+  /// so finding the keys in the range takes a pass over all of them.
+  pub fn remove_range<R: RangeBounds<String>>(&mut self, range: R) -> Result<usize> {
+    self.full_index()?;
+    let now = now_millis();
+    let cmds: Vec<KvCommand> = self
+      .index
+      .keys()
+      .filter(|key| range.contains(*key) && !self.is_expired(key, now))
+      .map(|key| KvCommand::Rm(key.to_owned()))
+      .collect();
+
+    let removed = self.remove_all(cmds);
+    self.forget_full_index();
+    removed
+  }
+
+  // append the removes in a single write and drop their keys, returns how many there were
+  fn remove_all(&mut self, cmds: Vec<KvCommand>) -> Result<usize> {
+    if cmds.is_empty() {
+      return Ok(0);
+    }
+
+    // write log
+    let removed = self.write_batch(cmds)?;
+
+    // update in-memory index
+    for (cmd, _) in &removed {
+      if let KvCommand::Rm(key) = cmd {
+        self.index.remove(key);
+        self.expiry.remove(key);
+        self.cache.remove(key);
+        self.notify(key, |key| KeyEvent::Removed { key });
+      }
+    }
+    self.garbage = self.garbage.saturating_add(removed.len() as u64);
+    self.maybe_compact_logs()?;
+
+    Ok(removed.len())
+  }
+
+  /// Apply every operation of the batch, or none of them if any is invalid or a precondition doesn't hold
+  ///
+  /// The batch is checked against the current state first, then written to the log in a single append.
+  pub fn write(&mut self, batch: WriteBatch) -> Result<()> {
+    for precondition in &batch.preconditions {
+      self.check_precondition(precondition)?;
+    }
+    if batch.is_empty() {
+      return Ok(());
+    }
+
+    // check the batch, tracking which keys it removes along the way
+    let mut live: HashMap<&str, bool> = HashMap::new();
+    for cmd in &batch.cmds {
+      match cmd {
+        KvCommand::Set(key, value) | KvCommand::SetEx(key, value, _) => {
+          self.check_entry(key, value)?;
+          live.insert(key, true);
+        }
+        KvCommand::SetCompressed(..) | KvCommand::Timestamped(..) => unreachable!("batches only hold plain commands"),
+        KvCommand::Rm(key) => {
+          let exists = match live.get(key.as_str()) {
+            Some(exists) => *exists,
+            None => self.locate(key)?.is_some(),
+          };
+          if !exists {
+            return Err(KvStoreError::RmKeyNotFoundError);
+          }
+          live.insert(key, false);
+        }
+      }
+    }
+
+    // write log
+    let written = self.write_batch(batch.cmds)?;
+
+    // update in-memory index
+    for (cmd, log_pointer) in written {
+      match cmd {
+        KvCommand::Set(key, _) => {
+          self.expiry.remove(&key);
+          self.cache.remove(&key);
+          self.notify(&key, |key| KeyEvent::Set { key });
+          if self.index.insert(key, log_pointer).is_some() {
+            self.garbage = self.garbage.saturating_add(1);
+          }
+        }
+        KvCommand::SetEx(key, _, expires_at) => {
+          self.expiry.insert(key.clone(), expires_at);
+          self.cache.remove(&key);
+          self.notify(&key, |key| KeyEvent::Set { key });
+          if self.index.insert(key, log_pointer).is_some() {
+            self.garbage = self.garbage.saturating_add(1);
+          }
+        }
+        KvCommand::SetCompressed(..) | KvCommand::Timestamped(..) => unreachable!("batches only hold plain commands"),
+        KvCommand::Rm(key) => {
+          self.index.remove(&key);
+          self.expiry.remove(&key);
+          self.cache.remove(&key);
+          self.notify(&key, |key| KeyEvent::Removed { key });
+          self.garbage = self.garbage.saturating_add(1);
+        }
+      }
+    }
+    self.maybe_compact_logs()
+  }
+
+  fn check_precondition(&mut self, precondition: &Precondition) -> Result<()> {
+    let failure = match precondition {
+      Precondition::Exists(key) if !self.contains_key(key)? => format!("key {:?} doesn't exist", key),
+      Precondition::Absent(key) if self.contains_key(key)? => format!("key {:?} exists", key),
+      Precondition::Equals(key, value) if self.get(key.to_owned())?.as_ref() != Some(value) => {
+        format!("key {:?} doesn't hold the expected value", key)
+      }
+      _ => return Ok(()),
+    };
+
+    Err(KvStoreError::PreconditionFailedError(failure))
+  }
+
+  /// Remove every key, emptying the log
+  ///
+  /// Unlike removing keys one by one this writes no records, the log is truncated and then flushed. While
+  /// snapshots of the store are alive, the log is replaced with an empty one instead, the snapshots keeping the old.
+  pub fn clear(&mut self) -> Result<()> {
+    // subscribers hear about every key removed, not only the ones indexed
+    if !self.subscribers.is_empty() {
+      self.full_index()?;
+    }
+    if Arc::strong_count(&self.snapshots) > 1 {
+      // snapshots still read the old log, so it's replaced with an empty one rather than cut down in place
+      let mut scratch = self.storage().open_scratch()?;
+      scratch.append(&log_header())?;
+      self.storage().replace(scratch)?;
+    } else {
+      self.storage().truncate(0)?;
+      self.storage().append(&log_header())?;
+    }
+    // seeking discards anything buffered from the old log
+    self.log.get_mut().seek(SeekFrom::Start(0))?;
+
+    let keys: Vec<String> = self.index.drain().map(|(key, _)| key).collect();
+    for key in &keys {
+      self.notify(key, |key| KeyEvent::Removed { key });
+    }
+    self.expiry.clear();
+    self.cache.clear();
+    self.garbage = 0;
+    self.largest_record = 0;
+    self.hint_stale = true;
+    self.fresh_tail = None;
+    self.rewrites += 1;
+    self.log_start = format::HEADER_LEN as u64;
+    self.log_version = format::FORMAT_VERSION;
+
+    self.flush()?;
+    self.options.audit_sink.on_clear();
+
+    Ok(())
+  }
+
+  /// Move the value of `from` to `to`, overwriting `to` if it exists
+  ///
+  /// Both changes are written as a single batch, and the key keeps its TTL if it had one.
+  pub fn rename(&mut self, from: String, to: String) -> Result<()> {
+    // for the TTL of `from`
+    self.full_index()?;
+    let renamed = self.rename_indexed(from, to);
+    self.forget_full_index();
+    renamed
+  }
+
+  // `rename` once the index holds every key
+  fn rename_indexed(&mut self, from: String, to: String) -> Result<()> {
+    let value = match self.get(from.clone())? {
+      Some(value) => value,
+      None => return Err(KvStoreError::RmKeyNotFoundError),
+    };
+    if from == to {
+      return Ok(());
+    }
+
+    let mut batch = WriteBatch::new();
+    match self.expiry.get(&from) {
+      Some(expires_at) => batch.cmds.push(KvCommand::SetEx(to, value, *expires_at)),
+      None => batch.cmds.push(KvCommand::Set(to, value)),
+    }
+    batch.remove(from);
+    self.write(batch)
+  }
+
+  /// Copy the value of `from` to `to`, returns whether the copy happened
+  ///
+  /// An existing `to` is only overwritten if `overwrite` is set, and copying a key onto itself does nothing.
+  /// The copy keeps the TTL of `from` if it had one.
+  pub fn copy(&mut self, from: String, to: String, overwrite: bool) -> Result<bool> {
+    // for the TTL of `from`
+    self.full_index()?;
+    let copied = self.copy_indexed(from, to, overwrite);
+    self.forget_full_index();
+    copied
+  }
+
+  // `copy` once the index holds every key
+  fn copy_indexed(&mut self, from: String, to: String, overwrite: bool) -> Result<bool> {
+    let value = match self.get(from.clone())? {
+      Some(value) => value,
+      None => return Err(KvStoreError::KeyNotFoundError),
+    };
+    if from == to {
+      return Ok(false);
+    }
+    if !overwrite && self.index.contains_key(&to) && !self.is_expired(&to, now_millis()) {
+      return Ok(false);
+    }
+
+    match self.expiry.get(&from) {
+      Some(expires_at) => {
+        let ttl = Duration::from_millis(expires_at.saturating_sub(now_millis()));
+        self.set_with_ttl(to, value, ttl)?;
+      }
+      None => self.set(to, value)?,
+    }
+
+    Ok(true)
+  }
+
+  /// Copy every live key of the store in `other_dir` into this one, returns how many keys were written
+  ///
+  /// Keys in both stores are resolved by `on_conflict`, and keys set with a TTL keep their expiry.
+  /// Everything is written as a single batch, so a merge failing on a conflict leaves this store untouched.
+  /// The other store must exist under this store's log file name. Its log is only read, the way `verify` reads
+  /// one, so nothing in `other_dir` changes, not even a torn tail being cut off.
+  pub fn merge_from(&mut self, other_dir: &Path, on_conflict: ConflictPolicy) -> Result<usize> {
+    let other_log = other_dir.join(&self.options.log_file_name);
+    if !other_log.is_file() {
+      let message = format!("no store to merge from in {}", other_dir.display());
+      return Err(io::Error::new(io::ErrorKind::NotFound, message).into());
+    }
+    let mut reader = BufReader::new(File::open(other_log)?);
+    let (version, _) = records_start(&mut reader)?;
+    let mut log = Deserializer::new(reader);
+    let Replayed { index, expiry, .. } = replay_log(&mut log, version, RecoveryMode::StopAtBadRecord, None)?;
+    let now = now_millis();
+    let mut live: Vec<(String, u64)> = index
+      .into_iter()
+      .filter(|(key, _)| !matches!(expiry.get(key), Some(expires_at) if *expires_at <= now))
+      .collect();
+    live.sort();
+
+    let mut batch = WriteBatch::new();
+    for (key, log_pointer) in live {
+      if self.contains_key(&key)? {
+        match on_conflict {
+          ConflictPolicy::KeepSelf => continue,
+          ConflictPolicy::Overwrite => {}
+          ConflictPolicy::Error => return Err(KvStoreError::MergeConflictError(key)),
+        }
+      }
+      let value = read_value_at(&mut log, &key, log_pointer)?;
+      match expiry.get(&key) {
+        Some(expires_at) => batch.cmds.push(KvCommand::SetEx(key, value, *expires_at)),
+        None => batch.cmds.push(KvCommand::Set(key, value)),
+      }
+    }
+
+    let merged = batch.len();
+    self.write(batch)?;
+    Ok(merged)
+  }
+
+  /// Drop every key whose TTL has passed, returns how many were dropped
+  ///
+  /// Compaction checks sweep expired keys as well, this forces a sweep in between. The records of dropped keys
+  /// count as garbage and are reclaimed by the next compaction.
+  pub fn purge_expired(&mut self) -> Result<usize> {
+    let purged = self.sweep_expired();
+    self.maybe_compact_logs()?;
+
+    Ok(purged)
+  }
+
+  // drop expired keys from the index, their records become garbage
+  fn sweep_expired(&mut self) -> usize {
+    let now = now_millis();
+    let expired: Vec<String> = self
+      .expiry
+      .iter()
+      .filter(|(_, expires_at)| **expires_at <= now)
+      .map(|(key, _)| key.to_owned())
+      .collect();
+
+    for key in &expired {
+      self.expiry.remove(key);
+      self.index.remove(key);
+      self.cache.remove(key);
+      self.options.audit_sink.on_expire(key);
+    }
+    if !expired.is_empty() {
+      self.garbage = self.garbage.saturating_add(expired.len() as u64);
+      self.hint_stale = true;
+    }
+
+    expired.len()
+  }
+
+  // whether the key was set with a TTL that has passed by `now`
+  fn is_expired(&self, key: &str, now: u64) -> bool {
+    matches!(self.expiry.get(key), Some(expires_at) if *expires_at <= now)
+  }
+
+  /// Watch changes to keys starting with the given prefix
+  ///
+  /// Every set and remove of a matching key sends an event to the returned channel, in the order they're applied.
+  /// Events are only kept in memory, and a subscription ends once its receiver is dropped.
+  pub fn subscribe(&mut self, prefix: String) -> Receiver<KeyEvent> {
+    let (sender, receiver) = mpsc::channel();
+    self.subscribers.push((prefix, sender));
+    receiver
+  }
+
+  // send an event for the key to subscribers of matching prefixes, forgetting the ones that hung up
+  fn notify(&mut self, key: &str, event: fn(String) -> KeyEvent) {
+    self
+      .subscribers
+      .retain(|(prefix, sender)| !key.starts_with(prefix.as_str()) || sender.send(event(key.to_owned())).is_ok());
+  }
+
+  /// Number of live keys in the key-value store
+  pub fn len(&mut self) -> Result<usize> {
+    self.full_index()?;
+    let now = now_millis();
+    let len = self.index.keys().filter(|key| !self.is_expired(key, now)).count();
+    self.forget_full_index();
+
+    Ok(len)
+  }
+
+  /// Whether the key-value store has no live keys
+  pub fn is_empty(&mut self) -> Result<bool> {
+    Ok(self.len()? == 0)
+  }
+
+  /// Get statistics of the key-value store
+  pub fn stats(&mut self) -> Result<KvStoreStats> {
+    Ok(KvStoreStats {
+      live_keys: self.len()?,
+      garbage: self.garbage,
+      log_bytes: self.log.get_ref().get_ref().storage.len()?,
+      replayed_records: self.replayed,
+    })
+  }
+
+  /// Latency percentiles of `get` and `set` since the store was opened
+  ///
+  /// Latency is only recorded with `KvStoreOptions::track_latency` on, otherwise every count is 0.
+  pub fn latency_snapshot(&self) -> LatencyReport {
+    match &self.latency {
+      Some(latency) => latency.report(),
+      None => LatencyReport {
+        get: OperationLatency::default(),
+        set: OperationLatency::default(),
+      },
+    }
+  }
+
+  /// Options the key-value store was opened with, or last set with `set_options`
+  pub fn options(&self) -> &KvStoreOptions {
+    &self.options
+  }
+
+  /// Change the options of the open store, e.g. a long-running server's compaction threshold or sync policy
+  ///
+  /// Policies apply from the next operation on, shrinking the value cache evicts right away, and writes not
+  /// synced yet are synced when the sync policy changes. Options fixing how the store's files are laid out
+  /// and read can't change: `mmap_reads`, `namespace`, `log_file_name`, `compaction_dir`, `index` and
+  /// `read_buffer_bytes` must stay as they are, or this fails with `ImmutableOptionError` naming the first that doesn't.
+  /// `compact_on_open` and `recovery_mode` only matter when opening, so changing them has no effect until then.
+  pub fn set_options(&mut self, options: KvStoreOptions) -> Result<()> {
+    let current = &self.options;
+    let immutable = [
+      ("mmap_reads", options.mmap_reads == current.mmap_reads),
+      ("namespace", options.namespace == current.namespace),
+      ("log_file_name", options.log_file_name == current.log_file_name),
+      ("compaction_dir", options.compaction_dir == current.compaction_dir),
+      ("index", options.index == current.index),
+      (
+        "read_buffer_bytes",
+        options.read_buffer_bytes == current.read_buffer_bytes,
+      ),
+    ];
+    if let Some((name, _)) = immutable.iter().find(|(_, same)| !same) {
+      return Err(KvStoreError::ImmutableOptionError((*name).to_owned()));
+    }
+
+    if options.sync_policy != current.sync_policy && self.unsynced > 0 {
+      self.storage().sync()?;
+      self.unsynced = 0;
+    }
+    self.cache.set_capacity(options.value_cache_bytes.unwrap_or(0));
+    self.storage().set_dir_sync(options.sync_dir);
+    match (&self.latency, options.track_latency) {
+      (None, true) => self.latency = Some(LatencyRecorder::new()),
+      (Some(_), false) => self.latency = None,
+      _ => {}
+    }
+    self.options = options;
+
+    Ok(())
+  }
+
+  // when an operation started, if its latency is recorded
+  fn start_timer(&self) -> Option<Instant> {
+    self.latency.as_ref().map(|_| Instant::now())
+  }
+
+  fn record_latency(&mut self, operation: Operation, started: Option<Instant>) {
+    if let (Some(latency), Some(started)) = (&mut self.latency, started) {
+      latency.record(operation, started);
+    }
+  }
+
+  fn storage(&mut self) -> &mut S {
+    &mut self.log.get_mut().get_mut().storage
+  }
+
+  // decode the command at the given log pointer, with its value decompressed
+  fn read_command(&mut self, log_pointer: u64) -> Result<KvCommand> {
+    Ok(self.read_record(log_pointer)?.0)
+  }
+
+  // decode the command at the given log pointer, along with when it was modified if the record says
+  fn read_record(&mut self, log_pointer: u64) -> Result<(KvCommand, Option<u64>)> {
+    let record = match self.storage().slice_at(log_pointer)? {
+      Some(bytes) => rmp_serde::from_read_ref(bytes)?,
+      None => {
+        let reader = self.log.get_mut();
+        if self.options.read_your_writes {
+          reader.seek(SeekFrom::Start(log_pointer))?;
+        } else {
+          // keeps the buffer if the record starts within it
+          let pos = reader.stream_position()?;
+          reader.seek_relative(log_pointer as i64 - pos as i64)?;
+        }
+        KvCommand::deserialize(&mut self.log)?
+      }
+    };
+
+    let (cmd, modified) = unstamp(record);
+    Ok((decompress(cmd)?, modified))
+  }
+
+  // fail with LogFull unless `len` more bytes fit within `max_log_bytes`, compacting first if that can make room
+  //
+  // Tombstones are left out of `len`, so removes always fit: they're what lets a compaction make room again.
+  fn make_room(&mut self, len: u64) -> Result<()> {
+    let max = match self.options.max_log_bytes {
+      Some(max) => max,
+      None => return Ok(()),
+    };
+    if len == 0 || self.storage().len()?.saturating_add(len) <= max {
+      return Ok(());
+    }
+
+    // a compaction that can't free enough isn't worth its while, each garbage count frees at most two records:
+    // a remove counts once for its tombstone and the record it removes
+    let needed = self.storage().len()?.saturating_add(len) - max;
+    let freeable = self.garbage.saturating_mul(self.largest_record.saturating_mul(2));
+    if self.options.compaction && freeable >= needed {
+      self.compact()?;
+      if self.storage().len()?.saturating_add(len) <= max {
+        return Ok(());
+      }
+    }
+    Err(KvStoreError::LogFull(max))
+  }
+
+  // append a command, returns where its record starts and ends
+  fn write_log(&mut self, cmd: KvCommand) -> Result<(u64, u64)> {
+    self.write_stamped(cmd, self.stamp(now_millis()))
+  }
+
+  // append a command stamped as modified at the given time rather than now, see `write_log`
+  fn write_stamped(&mut self, cmd: KvCommand, modified: Option<u64>) -> Result<(u64, u64)> {
+    let mut bytes = self.take_encode_buf();
+    let cmd = encode_command(cmd, modified, self.options.compression, &mut bytes)?;
+    let len = bytes.len() as u64;
+    self.check_tail()?;
+    self.make_room(if matches!(cmd, KvCommand::Rm(_)) { 0 } else { len })?;
+    let pos = self.storage().append(&bytes)?;
+    let end = pos + len;
+    self.largest_record = self.largest_record.max(len);
+    self.keep_encode_buf(bytes);
+    self.hint_stale = true;
+    self.commit()?;
+    self.options.audit_sink.on_command(&cmd, pos);
+
+    Ok((pos, end))
+  }
+
+  // append several commands in a single write, returns each command with its log pointer
+  fn write_batch(&mut self, cmds: Vec<KvCommand>) -> Result<Vec<(KvCommand, u64)>> {
+    let mut buf = self.take_encode_buf();
+    let mut written = Vec::with_capacity(cmds.len());
+    let now = self.stamp(now_millis());
+    for cmd in cmds {
+      let offset = buf.len() as u64;
+      written.push((encode_command(cmd, now, self.options.compression, &mut buf)?, offset));
+    }
+
+    let (counted, largest) = measure_records(&written, buf.len() as u64);
+    self.check_tail()?;
+    self.make_room(counted)?;
+    let base = self.storage().append(&buf)?;
+    self.largest_record = self.largest_record.max(largest);
+    self.keep_encode_buf(buf);
+    self.hint_stale = true;
+    self.commit()?;
+
+    let written: Vec<(KvCommand, u64)> = written.into_iter().map(|(cmd, offset)| (cmd, base + offset)).collect();
+    for (cmd, offset) in &written {
+      self.options.audit_sink.on_command(cmd, *offset);
+    }
+    Ok(written)
+  }
+
+  // when a record appended now is stamped as modified, never in a log of version 1, which older stores that can't
+  // read stamps still open as their own. Such a log takes stamps once compacted into the version stores write.
+  fn stamp(&self, now: u64) -> Option<u64> {
+    Some(now).filter(|_| self.log_version >= 2)
+  }
+
+  // an empty buffer to encode records into, reusing the last one's allocation
+  //
+  // Hand it back with `keep_encode_buf` once done, a buffer dropped on an error is just allocated anew next time.
+  fn take_encode_buf(&mut self) -> Vec<u8> {
+    let mut buf = mem::take(&mut self.encode_buf);
+    buf.clear();
+    buf
+  }
+
+  fn keep_encode_buf(&mut self, buf: Vec<u8>) {
+    if buf.capacity() <= ENCODE_BUF_BYTES {
+      self.encode_buf = buf;
+    }
+  }
+
+  // count an appended write, and sync the log if the sync policy says it's due
+  fn commit(&mut self) -> Result<()> {
+    self.unsynced += 1;
+    let due = match self.options.sync_policy {
+      SyncPolicy::OnFlush => false,
+      SyncPolicy::EveryWrite => true,
+      SyncPolicy::Group(n) => self.unsynced >= n,
+    };
+
+    if due {
+      self.storage().sync()?;
+      self.unsynced = 0;
+    }
+
+    Ok(())
+  }
+
+  fn maybe_compact_logs(&mut self) -> Result<()> {
+    if !self.options.compact_on_write {
+      self.sweep_expired();
+      return Ok(());
+    }
+    self.compact_if_due()
+  }
+
+  // compact if the garbage reached the compaction threshold, unless compaction is off
+  fn compact_if_due(&mut self) -> Result<()> {
+    self.sweep_expired();
+    if !self.options.compaction || self.garbage < self.options.compaction_threshold {
+      return Ok(());
+    }
+
+    self.compact()?;
+
+    Ok(())
+  }
+
+  /// Compact the log regardless of the garbage count, returns the number of bytes reclaimed
+  pub fn compact(&mut self) -> Result<u64> {
+    self.compact_with_progress(|_, _| {})
+  }
+
+  /// Compact the log like `compact`, calling `progress` with `(processed_keys, total_keys)` as it goes
+  ///
+  /// The callback runs once every 1024 keys rather than for every key, and once more when all keys are
+  /// processed, before the new log is swapped in.
+  pub fn compact_with_progress(&mut self, mut progress: impl FnMut(usize, usize)) -> Result<u64> {
+    self.full_index()?;
+    self.sweep_expired();
+    let old_len = self.storage().len()?;
+
+    // write a new log with only Set commands
+    let mut scratch = self.storage().open_scratch()?;
+    let header = log_header();
+    scratch.append(&header)?;
+
+    let mut new_len = header.len() as u64;
+    let mut largest_record = 0;
+    let mut new_index = self.index.clone();
+    let mut bytes = self.take_encode_buf();
+    let total = new_index.len();
+    let mut live: Vec<(&String, &mut u64)> = new_index.iter_mut().collect();
+    if self.options.sorted_compaction {
+      live.sort_unstable_by_key(|(key, _)| *key);
+    }
+    for (processed, (key, log_pointer)) in live.into_iter().enumerate() {
+      if processed > 0 && processed % PROGRESS_INTERVAL_KEYS == 0 {
+        progress(processed, total);
+      }
+      bytes.clear();
+      self
+        .encode_live_record(key, *log_pointer, &mut bytes)
+        .map_err(|_| KvStoreError::CompactionError)?;
+      *log_pointer = scratch.append(&bytes)?;
+      new_len += bytes.len() as u64;
+      largest_record = largest_record.max(bytes.len() as u64);
+    }
+    progress(total, total);
+    scratch.sync()?;
+    self.keep_encode_buf(bytes);
+
+    // swap in the new log, seeking discards anything buffered from the old one
+    self.storage().replace(scratch)?;
+    self.log.get_mut().seek(SeekFrom::Start(0))?;
+
+    // reset struct fields, the scratch log was synced before the swap
+    self.index = new_index;
+    self.garbage = 0;
+    self.largest_record = largest_record;
+    self.unsynced = 0;
+    self.fresh_tail = None;
+    self.rewrites += 1;
+    // the new log is of the version stores write
+    self.log_start = format::HEADER_LEN as u64;
+    self.log_version = format::FORMAT_VERSION;
+    self.write_hint()?;
+    self.forget_full_index();
+
+    Ok(old_len.saturating_sub(new_len))
+  }
+
+  /// Compact a store shared behind a lock like `compact`, holding the lock only to start and to finish
+  ///
+  /// The live records are copied out of the old log through a reader of its own (see `LogStorage::open_reader`)
+  /// without the lock, so reads and writes go on against the old log meanwhile, at the offsets they always had.
+  /// Once they're copied, the lock is taken again to copy what was appended in the meantime and swap the new
+  /// log in. If the log was changed other than by appending in between, e.g. cleared, if the storage has no
+  /// reader to offer, or if the log is of an older version than the one written now, the store is compacted under
+  /// the lock instead.
+  pub fn compact_shared(store: &Mutex<Self>) -> Result<u64> {
+    let lock = || {
+      store
+        .lock()
+        .map_err(|_| KvStoreError::TaskError("the store is unavailable after a panic".to_owned()))
+    };
+
+    let (snapshot, reader, mut scratch, options) = {
+      let mut store = lock()?;
+      // building the index to copy from takes a replay, which may as well compact
+      if store.options.index == IndexMode::None {
+        return store.compact();
+      }
+      // what's appended meanwhile is copied as is, which would leave records of the old version in the new log
+      if store.log_version < format::FORMAT_VERSION {
+        return store.compact();
+      }
+      store.sweep_expired();
+      let reader = match store.storage().open_reader()? {
+        Some(reader) => reader,
+        None => return store.compact(),
+      };
+      let mut scratch = store.storage().open_scratch()?;
+      scratch.append(&log_header())?;
+      let snapshot = CompactionSnapshot {
+        index: store.index.clone(),
+        len: store.storage().len()?,
+        garbage: store.garbage,
+        rewrites: store.rewrites,
+      };
+      (snapshot, reader, scratch, store.options.clone())
+    };
+
+    // copy the live records as of the snapshot, pointing the snapshot's index at the copies
+    let reader = BufReader::with_capacity(options.read_buffer_bytes.max(1), StorageReader::new(reader));
+    let mut log = Deserializer::new(reader);
+    let mut copied = snapshot.index;
+    let mut live: Vec<(&String, &mut u64)> = copied.iter_mut().collect();
+    if options.sorted_compaction {
+      live.sort_unstable_by_key(|(key, _)| *key);
+    }
+    let mut bytes = Vec::new();
+    let mut largest_record = 0;
+    for (key, log_pointer) in live {
+      bytes.clear();
+      log.get_mut().seek(SeekFrom::Start(*log_pointer))?;
+      let (cmd, modified) = unstamp(KvCommand::deserialize(&mut log)?);
+      encode_live_record(key, decompress(cmd)?, modified, options.compression, &mut bytes)
+        .map_err(|_| KvStoreError::CompactionError)?;
+      *log_pointer = scratch.append(&bytes)?;
+      largest_record = largest_record.max(bytes.len() as u64);
+    }
+    drop(log);
+
+    let mut store = lock()?;
+    if store.rewrites != snapshot.rewrites {
+      return store.compact();
+    }
+
+    // what was appended meanwhile goes after the copies as is, so its offsets only shift
+    let old_len = store.storage().len()?;
+    let mut tail = vec![0; (old_len - snapshot.len) as usize];
+    store.log.get_mut().seek(SeekFrom::Start(snapshot.len))?;
+    store.log.get_mut().read_exact(&mut tail)?;
+    let tail_start = scratch.append(&tail)?;
+    let mut new_index = HashMap::with_capacity(store.index.len());
+    for (key, log_pointer) in &store.index {
+      let new_pointer = if *log_pointer >= snapshot.len {
+        log_pointer - snapshot.len + tail_start
+      } else {
+        // untouched since the snapshot
+        *copied.get(key).ok_or(KvStoreError::CompactionError)?
+      };
+      new_index.insert(key.to_owned(), new_pointer);
+    }
+    scratch.sync()?;
+
+    store.storage().replace(scratch)?;
+    store.log.get_mut().seek(SeekFrom::Start(0))?;
+
+    // only the garbage made since the snapshot made it into the new log
+    store.index = new_index;
+    store.garbage = store.garbage.saturating_sub(snapshot.garbage);
+    // what was appended meanwhile is bounded by the old bound already
+    store.largest_record = store.largest_record.max(largest_record);
+    store.unsynced = 0;
+    store.fresh_tail = None;
+    store.rewrites += 1;
+    store.log_start = format::HEADER_LEN as u64;
+    store.log_version = format::FORMAT_VERSION;
+    store.write_hint()?;
+
+    Ok(old_len.saturating_sub(tail_start + tail.len() as u64))
+  }
+
+  /// Write a consistent copy of the store into `dest_dir`, which `KvStore::open(dest_dir)` opens as is
+  ///
+  /// The copy holds only the live keys, like a compacted log, under the store's log file name.
+  /// It's written to a scratch file first and renamed into place, so an existing backup in `dest_dir`
+  /// is replaced in one go. Holding the store exclusively for the whole copy makes it a point-in-time view,
+  /// so writers sharing the store through a lock wait for the backup to finish.
+  ///
+  /// `dest_dir` can't be the store's own directory, however it's spelled, as the copy would replace the log.
+  pub fn backup(&mut self, dest_dir: impl Into<PathBuf>) -> Result<()> {
+    let dest_dir = dest_dir.into();
+    fs::create_dir_all(&dest_dir)?;
+    if let Some(log_dir) = self.storage().file_path().and_then(Path::parent) {
+      let log_dir = if log_dir.as_os_str().is_empty() {
+        Path::new(".")
+      } else {
+        log_dir
+      };
+      if fs::canonicalize(log_dir)? == fs::canonicalize(&dest_dir)? {
+        let message = format!("can't back up the store into its own directory {}", dest_dir.display());
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+      }
+    }
+    let scratch_path = dest_dir.join(format!("{}.backup", self.options.log_file_name));
+    let mut scratch = BufWriter::new(File::create(&scratch_path)?);
+    scratch.write_all(&log_header())?;
+
+    self.full_index()?;
+    let now = now_millis();
+    let live: Vec<(String, u64)> = self
+      .index
+      .iter()
+      .filter(|(key, _)| !self.is_expired(key, now))
+      .map(|(key, log_pointer)| (key.to_owned(), *log_pointer))
+      .collect();
+    let mut bytes = self.take_encode_buf();
+    for (key, log_pointer) in live {
+      bytes.clear();
+      self.encode_live_record(&key, log_pointer, &mut bytes)?;
+      scratch.write_all(&bytes)?;
+    }
+    self.keep_encode_buf(bytes);
+    self.forget_full_index();
+    scratch.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+    // a hint left by a store opened on an older backup doesn't describe this one
+    let log_path = dest_dir.join(&self.options.log_file_name);
+    match fs::remove_file(hint_path(&log_path)) {
+      Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+      _ => {}
+    }
+    fs::rename(&scratch_path, &log_path)?;
+
+    Ok(())
+  }
+
+  // encode the live record of the key at the given log pointer anew, keeping when the key was modified
+  fn encode_live_record(&mut self, key: &str, log_pointer: u64, buf: &mut Vec<u8>) -> Result<()> {
+    let (cmd, modified) = self.read_record(log_pointer)?;
+    encode_live_record(key, cmd, modified, self.options.compression, buf)
+  }
+}
+
+// walks the log for `KvStore::replay_history`
+struct History<'a, S: LogStorage> {
+  log: &'a mut Log<S>,
+  // of the log's format, which decides what records are well-formed
+  version: u32,
+  // where the first record starts
+  start: u64,
+  started: bool,
+  // set at the end of the log, or after a record that doesn't decode as nothing past it can be trusted
+  done: bool,
+}
+
+impl<S: LogStorage> History<'_, S> {
+  fn next_command(&mut self) -> Result<Option<KvCommand>> {
+    let reader = self.log.get_mut();
+    if !self.started {
+      reader.seek(SeekFrom::Start(self.start))?;
+      self.started = true;
+    }
+    if reader.stream_position()? >= reader.get_ref().storage.len()? {
+      return Ok(None);
+    }
+
+    let pos = reader.stream_position()?;
+    let record = KvCommand::deserialize(&mut *self.log)?;
+    if !format::record_fits(&record, self.version) {
+      return Err(KvStoreError::ReplayError(format!(
+        "record at offset {} can't be in a log of version {}",
+        pos, self.version
+      )));
+    }
+    Ok(Some(decompress(unstamp(record).0)?))
+  }
+}
+
+impl<S: LogStorage> Iterator for History<'_, S> {
+  type Item = Result<KvCommand>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.done {
+      return None;
+    }
+
+    let cmd = self.next_command().transpose();
+    self.done = !matches!(cmd, Some(Ok(_)));
+    cmd
+  }
+}
+
+impl<S: LogStorage> Drop for KvStore<S> {
+  fn drop(&mut self) {
+    if !self.hint_stale {
+      return;
+    }
+
+    if let Err(e) = self.flush() {
+      log::error!("Flushing KvStore on drop failed: {}", e);
+    }
   }
 }
@@ -0,0 +1,216 @@
+//! A minimal codec for RESP, the Redis serialization protocol
+//!
+//! Commands are arrays of bulk strings, replies can be any RESP value. Every frame ends with CR LF.
+
+use std::io::{self, BufRead, Read, Write};
+
+// the longest bulk string (and array) accepted, same as Redis
+const MAX_BULK_BYTES: usize = 512 * 1024 * 1024;
+// the longest line accepted, CR LF included, so a peer can't make us buffer a line that never ends
+const MAX_LINE_BYTES: usize = 64 * 1024;
+// arrays nested deeper than this are rejected rather than recursed into until the stack runs out
+const MAX_DEPTH: usize = 64;
+
+/// A command sent by a client, the command name followed by its arguments
+#[derive(Debug, Clone, PartialEq)]
+pub struct RespCommand {
+  args: Vec<String>,
+}
+
+impl RespCommand {
+  /// Creates a command from the command name and its arguments
+  pub fn new<I, A>(args: I) -> Self
+  where
+    I: IntoIterator<Item = A>,
+    A: Into<String>,
+  {
+    Self {
+      args: args.into_iter().map(Into::into).collect(),
+    }
+  }
+
+  /// The command name in upper case, as command names are case-insensitive
+  pub fn name(&self) -> String {
+    self
+      .args
+      .first()
+      .map(|name| name.to_ascii_uppercase())
+      .unwrap_or_default()
+  }
+
+  /// The arguments following the command name
+  pub fn args(&self) -> &[String] {
+    self.args.get(1..).unwrap_or(&[])
+  }
+}
+
+/// A RESP value, what replies are made of
+#[derive(Debug, Clone, PartialEq)]
+pub enum RespValue {
+  /// `+<string>`, can't contain line breaks
+  SimpleString(String),
+  /// `-<message>`, can't contain line breaks
+  Error(String),
+  /// `:<integer>`
+  Integer(i64),
+  /// `$<length>` followed by the bytes, `None` is the null bulk string
+  Bulk(Option<Vec<u8>>),
+  /// `*<length>` followed by the elements
+  Array(Vec<RespValue>),
+}
+
+/// Read a command, returns `None` if the stream ended before a command started
+pub fn read_command(reader: &mut impl BufRead) -> io::Result<Option<RespCommand>> {
+  let mut line = String::new();
+  if read_line(reader, &mut line)? == 0 {
+    return Ok(None);
+  }
+
+  let len = parse_length(&line, '*')?;
+  let mut args = Vec::with_capacity(len.min(16));
+  for _ in 0..len {
+    line.clear();
+    read_line(reader, &mut line)?;
+    let len = parse_length(&line, '$')?;
+    let buf = read_bulk(reader, len)?;
+    let arg = String::from_utf8(buf).map_err(|_| invalid_data("bulk string is not valid UTF-8".to_owned()))?;
+    args.push(arg);
+  }
+
+  Ok(Some(RespCommand { args }))
+}
+
+/// Read a value of any shape, such as a reply mixing types in nested arrays
+///
+/// Arrays may nest up to 64 levels deep.
+pub fn read_value(reader: &mut impl BufRead) -> io::Result<RespValue> {
+  read_nested_value(reader, 0)
+}
+
+fn read_nested_value(reader: &mut impl BufRead, depth: usize) -> io::Result<RespValue> {
+  let mut line = String::new();
+  if read_line(reader, &mut line)? == 0 {
+    return Err(io::Error::new(
+      io::ErrorKind::UnexpectedEof,
+      "stream ended before a reply",
+    ));
+  }
+
+  let content = line
+    .strip_suffix("\r\n")
+    .ok_or_else(|| invalid_data(format!("reply line {:?} has invalid ending", line)))?;
+  match content.chars().next() {
+    Some('+') => Ok(RespValue::SimpleString(content[1..].to_owned())),
+    Some('-') => Ok(RespValue::Error(content[1..].to_owned())),
+    Some(':') => content[1..]
+      .parse()
+      .map(RespValue::Integer)
+      .map_err(|_| invalid_data(format!("invalid integer reply {:?}", line))),
+    Some('$') if content == "$-1" => Ok(RespValue::Bulk(None)),
+    Some('$') => {
+      let len = parse_length(&line, '$')?;
+      read_bulk(reader, len).map(|buf| RespValue::Bulk(Some(buf)))
+    }
+    Some('*') if depth >= MAX_DEPTH => Err(invalid_data(format!("arrays nested over {} deep", MAX_DEPTH))),
+    Some('*') => {
+      let len = parse_length(&line, '*')?;
+      // the length is only a claim, don't allocate for it up front
+      let mut values = Vec::with_capacity(len.min(16));
+      for _ in 0..len {
+        values.push(read_nested_value(reader, depth + 1)?);
+      }
+      Ok(RespValue::Array(values))
+    }
+    _ => Err(invalid_data(format!("unknown reply type in {:?}", line))),
+  }
+}
+
+// read a bulk string of `len` bytes and its CR LF, growing the buffer as bytes arrive rather than trusting `len`
+fn read_bulk(reader: &mut impl BufRead, len: usize) -> io::Result<Vec<u8>> {
+  let mut buf = Vec::with_capacity(len.min(64 * 1024) + 2);
+  reader.take(len as u64 + 2).read_to_end(&mut buf)?;
+  if buf.len() < len + 2 {
+    return Err(io::Error::new(
+      io::ErrorKind::UnexpectedEof,
+      "stream ended within a bulk string",
+    ));
+  }
+  if !buf.ends_with(b"\r\n") {
+    return Err(invalid_data("bulk string has invalid ending".to_owned()));
+  }
+  buf.truncate(len);
+
+  Ok(buf)
+}
+
+// read a line up to its LF, failing once it's longer than `MAX_LINE_BYTES`
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> io::Result<usize> {
+  let read = Read::take(&mut *reader, MAX_LINE_BYTES as u64 + 1).read_line(line)?;
+  if read > MAX_LINE_BYTES {
+    return Err(invalid_data(format!("line longer than {} bytes", MAX_LINE_BYTES)));
+  }
+
+  Ok(read)
+}
+
+/// Write a command as an array of bulk strings
+pub fn write_command(cmd: &RespCommand, writer: &mut impl Write) -> io::Result<()> {
+  let mut buf = format!("*{}\r\n", cmd.args.len()).into_bytes();
+  for arg in &cmd.args {
+    encode_bulk(arg.as_bytes(), &mut buf);
+  }
+
+  writer.write_all(&buf)
+}
+
+/// Write a value of any shape, nested arrays and nulls included
+///
+/// Simple strings and errors can't hold line breaks, they're written with spaces in their place.
+pub fn write_value(value: &RespValue, writer: &mut impl Write) -> io::Result<()> {
+  let mut buf = Vec::new();
+  encode_value(value, &mut buf);
+  writer.write_all(&buf)
+}
+
+fn encode_value(value: &RespValue, buf: &mut Vec<u8>) {
+  match value {
+    RespValue::SimpleString(s) => encode_line(b'+', s, buf),
+    RespValue::Error(message) => encode_line(b'-', message, buf),
+    RespValue::Integer(n) => buf.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+    RespValue::Bulk(None) => buf.extend_from_slice(b"$-1\r\n"),
+    RespValue::Bulk(Some(bytes)) => encode_bulk(bytes, buf),
+    RespValue::Array(values) => {
+      buf.extend_from_slice(format!("*{}\r\n", values.len()).as_bytes());
+      for value in values {
+        encode_value(value, buf);
+      }
+    }
+  }
+}
+
+// simple strings and errors end at the first CR LF, so line breaks within them become spaces
+fn encode_line(marker: u8, s: &str, buf: &mut Vec<u8>) {
+  buf.push(marker);
+  buf.extend_from_slice(s.replace(['\r', '\n'], " ").as_bytes());
+  buf.extend_from_slice(b"\r\n");
+}
+
+fn encode_bulk(bytes: &[u8], buf: &mut Vec<u8>) {
+  buf.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+  buf.extend_from_slice(bytes);
+  buf.extend_from_slice(b"\r\n");
+}
+
+// parse a `<marker><length>\r\n` line
+fn parse_length(line: &str, marker: char) -> io::Result<usize> {
+  line
+    .strip_suffix("\r\n")
+    .and_then(|line| line.strip_prefix(marker))
+    .and_then(|len| len.parse::<usize>().ok())
+    .filter(|len| *len <= MAX_BULK_BYTES)
+    .ok_or_else(|| invalid_data(format!("expected a `{}` length line, got {:?}", marker, line)))
+}
+
+fn invalid_data(message: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message)
+}
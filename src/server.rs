@@ -0,0 +1,1196 @@
+//! A server exposing a KvStore over TCP, speaking RESP so Redis clients can talk to it
+//!
+//! The first byte a client sends picks the protocol for the connection: `RESP_PROTOCOL`, which is just the start
+//! of a RESP command so Redis clients need no handshake, or `SERDE_PROTOCOL` for the native protocol of
+//! MessagePack encoded `Request`s and `Response`s. Requests go in frames of their own, see `encode_request`.
+
+use crate::compactor::BackgroundCompactor;
+use crate::resp::{self, RespCommand, RespValue};
+use crate::{FileStorage, KvStore, KvStoreError, KvStoreOptions, LogStorage, Result, WriteBatch};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+#[cfg(unix)]
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// Read and write timeouts unless configured otherwise
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+// Connections served at once unless configured otherwise
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+/// Protocol byte of RESP, the `*` every RESP command starts with, which is read as part of the first command
+pub const RESP_PROTOCOL: u8 = b'*';
+/// Protocol byte of the native protocol, sent on its own before the first request
+pub const SERDE_PROTOCOL: u8 = b'S';
+
+/// A request of the native protocol, sent MessagePack encoded in a frame, see `encode_request`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Request {
+  /// Get the value of a key
+  Get(String),
+  /// Set a key to a value
+  Set(String, String),
+  /// Remove a key
+  Remove(String),
+  /// Check the server is up, answered without touching the store
+  Ping,
+}
+
+/// A reply of the native protocol, sent MessagePack encoded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Response {
+  /// The value of a key, `None` if it doesn't exist
+  Value(Option<String>),
+  /// The write succeeded
+  Ok,
+  /// The request failed, with a description of the error
+  Err(String),
+  /// The answer to a `Ping`
+  Pong,
+}
+
+// the longest frame of a native request accepted, room for a set of a key and value as long as RESP accepts
+const MAX_REQUEST_BYTES: usize = 1 << 30;
+
+/// Encode a request of the native protocol onto the end of `buf`, framed the way the server reads it
+///
+/// A frame is the length of the MessagePack encoded request as a big-endian `u32`, followed by the request.
+/// Frames over 1 GiB are rejected, and so is one that ends before its length says.
+pub fn encode_request(request: &Request, buf: &mut Vec<u8>) -> Result<()> {
+  let start = buf.len();
+  buf.extend_from_slice(&[0; 4]);
+  rmp_serde::encode::write(buf, request)?;
+  let len = (buf.len() - start - 4) as u32;
+  buf[start..start + 4].copy_from_slice(&len.to_be_bytes());
+
+  Ok(())
+}
+
+// every command the server knows, to tell a wrong number of arguments from an unknown command
+const COMMANDS: &[&str] = &[
+  "GET",
+  "SET",
+  "DEL",
+  "EXISTS",
+  "DBSIZE",
+  "KEYS",
+  "FLUSHALL",
+  "MULTI",
+  "EXEC",
+  "DISCARD",
+  "PUBLISH",
+  "SUBSCRIBE",
+  "PING",
+  "EXPIRE",
+  "PERSIST",
+  "TTL",
+];
+// the commands a transaction can queue
+const QUEUEABLE: &[&str] = &["SET", "DEL", "EXISTS"];
+
+/// KvsServer answers RESP commands from a KvStore
+///
+/// Supported commands are `GET key`, `SET key value`, `DEL key [key ...]`, `EXISTS key [key ...]`, `DBSIZE`,
+/// `KEYS pattern` and, if allowed, `FLUSHALL`. Command names are case-insensitive. `PING` answers `PONG`, or
+/// echoes its argument if given one, without touching the store, so it's a cheap check that the server is up.
+///
+/// `EXPIRE key seconds` gives a key a TTL, removing it right away for seconds of 0 or less, and `PERSIST key`
+/// takes its TTL away, both answering 1 if they changed the key and 0 otherwise. `TTL key` answers the seconds
+/// left until the key expires, -1 for a key without a TTL and -2 for a missing one, like Redis does.
+///
+/// `MULTI` starts a transaction, which queues `SET`, `DEL` and `EXISTS` until `EXEC` applies them at once as a
+/// `WriteBatch`, or `DISCARD` drops them. Within a transaction `EXISTS key` is a guard: `EXEC` fails without
+/// applying anything if the key doesn't exist by then, as does a `DEL` of a missing key. A command that can't
+/// be queued is rejected right away and makes `EXEC` discard the whole transaction.
+///
+/// `SUBSCRIBE channel [channel ...]` turns the connection into a subscriber, which gets every message sent with
+/// `PUBLISH channel message` to its channels from then on, as `message` arrays like Redis sends. A subscriber
+/// can only `SUBSCRIBE` to further channels, `UNSUBSCRIBE [channel ...]` from some or all of them, going back
+/// to serving commands once it has none left, and `PING`. Channels live in the server's memory, messages
+/// published to a channel without subscribers are dropped. A subscriber too slow to keep up with 1024 pending
+/// messages is disconnected.
+///
+/// Every connection is served on its own thread, commands from all of them take turns on the store.
+///
+/// A `ShutdownHandle` stops the server gracefully: no more connections are accepted, those being served
+/// finish the request at hand and are closed, and the store is flushed before `serve` returns.
+pub struct KvsServer<S: LogStorage = FileStorage> {
+  store: Arc<Mutex<KvStore<S>>>,
+  options: Arc<ServerOptions>,
+  // number of connections being served
+  connections: Arc<AtomicUsize>,
+  channels: Arc<Channels>,
+  shutdown: Arc<ShutdownState>,
+  // never read, kept only so dropping the server drops the compactor, which stops its thread
+  _compactor: Option<BackgroundCompactor>,
+}
+
+/// Options for running a KvsServer
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+  /// Accept `FLUSHALL`, which removes every key
+  pub allow_flush: bool,
+  /// Drop connections that send nothing for this long, including clients stalling in the middle of a command
+  pub read_timeout: Option<Duration>,
+  /// Drop connections that take longer than this to accept a reply
+  pub write_timeout: Option<Duration>,
+  /// Serve at most this many connections at once, further ones are rejected with an error reply
+  pub max_connections: usize,
+  /// Check for garbage this often and compact from a background thread, see `BackgroundCompactor`
+  pub compaction_interval: Option<Duration>,
+}
+
+impl Default for ServerOptions {
+  fn default() -> Self {
+    Self {
+      allow_flush: false,
+      read_timeout: Some(DEFAULT_TIMEOUT),
+      write_timeout: Some(DEFAULT_TIMEOUT),
+      max_connections: DEFAULT_MAX_CONNECTIONS,
+      compaction_interval: None,
+    }
+  }
+}
+
+/// Parse an address to listen on or connect to, given as `host:port`
+///
+/// The host is an IPv4 address such as `0.0.0.0`, an IPv6 address in brackets such as `[::1]`, or a name looked
+/// up to the first address it resolves to, such as `localhost`. Fails with `InvalidAddressError` saying what's
+/// wrong with the address.
+pub fn parse_addr(addr: &str) -> Result<SocketAddr> {
+  if let Ok(addr) = addr.parse() {
+    return Ok(addr);
+  }
+
+  let invalid = |reason: &str| KvStoreError::InvalidAddressError(format!("'{}': {}", addr, reason));
+  let (host, port) = addr
+    .rsplit_once(':')
+    .ok_or_else(|| invalid("expected host:port, e.g. 127.0.0.1:4000"))?;
+  let port: u16 = port
+    .parse()
+    .map_err(|_| invalid("the port must be a number from 0 to 65535"))?;
+  if host.is_empty() {
+    return Err(invalid("missing host, e.g. 127.0.0.1:4000"));
+  }
+  if host.contains(':') || host.starts_with('[') {
+    return Err(invalid("IPv6 addresses go in brackets, e.g. [::1]:4000"));
+  }
+
+  (host, port)
+    .to_socket_addrs()
+    .ok()
+    .and_then(|mut addrs| addrs.next())
+    .ok_or_else(|| invalid("the host doesn't resolve to an address"))
+}
+
+impl<S: LogStorage + Send + 'static> KvsServer<S> {
+  /// Creates a server over the given store
+  pub fn new(store: KvStore<S>) -> Self {
+    Self::with_options(store, ServerOptions::default())
+  }
+
+  /// Creates a server over the given store with the given options
+  pub fn with_options(store: KvStore<S>, options: ServerOptions) -> Self {
+    let store = Arc::new(Mutex::new(store));
+    let compactor = options
+      .compaction_interval
+      .map(|interval| BackgroundCompactor::start(store.clone(), interval));
+
+    Self {
+      store,
+      options: Arc::new(options),
+      connections: Arc::new(AtomicUsize::new(0)),
+      channels: Arc::new(Channels::default()),
+      shutdown: Arc::new(ShutdownState::default()),
+      _compactor: compactor,
+    }
+  }
+
+  /// Get a handle for shutting the server down from another thread, e.g. a signal handler
+  pub fn shutdown_handle(&self) -> ShutdownHandle {
+    ShutdownHandle(self.shutdown.clone())
+  }
+
+  /// Serve connections accepted by the listener until accepting fails or the server is shut down
+  ///
+  /// Each connection is served until the client closes it. A connection that fails
+  /// (e.g. sends a malformed command) is logged and dropped without stopping the server.
+  ///
+  /// Once shut down, this returns after every connection is closed and the store is flushed.
+  pub fn serve(&self, listener: TcpListener) -> Result<()> {
+    let mut addr = listener.local_addr()?;
+    // a listener on every interface is reachable through the loopback one
+    if addr.ip().is_unspecified() {
+      addr.set_ip(match addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+      });
+    }
+    let _waker = self.shutdown.register(move || {
+      TcpStream::connect(addr).ok();
+    });
+    self.serve_incoming(listener.incoming())
+  }
+
+  /// Serve connections accepted by the Unix domain socket listener until accepting fails, same as `serve`
+  ///
+  /// Both can run at once from different threads, sharing the store and the connection limit.
+  #[cfg(unix)]
+  pub fn serve_unix(&self, listener: UnixListener) -> Result<()> {
+    let path = listener.local_addr()?.as_pathname().map(|path| path.to_owned());
+    let _waker = self.shutdown.register(move || {
+      if let Some(path) = &path {
+        UnixStream::connect(path).ok();
+      }
+    });
+    self.serve_incoming(listener.incoming())
+  }
+
+  fn serve_incoming<C: Connection>(&self, mut incoming: impl Iterator<Item = io::Result<C>>) -> Result<()> {
+    // checked before accepting too, in case shutting down came before the listener's stopper was registered
+    while !self.shutdown.stopping() {
+      let mut stream = match incoming.next() {
+        Some(stream) => stream?,
+        None => break,
+      };
+      // the connection waking the loop up, or one that came too late
+      if self.shutdown.stopping() {
+        break;
+      }
+      let permit = match ConnectionPermit::acquire(&self.connections, self.options.max_connections) {
+        Some(permit) => permit,
+        None => {
+          log::warn!("Rejected connection over the limit of {}", self.options.max_connections);
+          let reply = RespValue::Error("ERR max number of clients reached".to_owned());
+          stream.set_timeouts(self.options.read_timeout, self.options.write_timeout)?;
+          resp::write_value(&reply, &mut stream).ok();
+          continue;
+        }
+      };
+
+      let store = self.store.clone();
+      let options = self.options.clone();
+      let channels = self.channels.clone();
+      // closing the reading half lets the request at hand finish, and ends the connection after it
+      let closer = stream.try_clone()?;
+      let stopper = self.shutdown.register(move || {
+        closer.shutdown(Shutdown::Read).ok();
+      });
+      thread::spawn(move || {
+        let result = if stopper.shutdown.stopping() {
+          Ok(())
+        } else {
+          handle(stream, &store, &options, &channels)
+        };
+        match result {
+          Ok(()) => {}
+          // timeouts surface as WouldBlock on Unix and TimedOut on Windows
+          Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            log::warn!("Dropped connection that timed out: {}", e);
+          }
+          Err(e) => log::error!("Handling connection failed: {}", e),
+        }
+        drop(stopper);
+        drop(permit);
+      });
+    }
+
+    if self.shutdown.stopping() {
+      // accepting stopped, wait for the connections still being served
+      while self.connections.load(Ordering::SeqCst) > 0 {
+        thread::sleep(Duration::from_millis(10));
+      }
+      let mut store = self
+        .store
+        .lock()
+        .map_err(|_| KvStoreError::TaskError("the store is unavailable after a panic".to_owned()))?;
+      store.flush()?;
+    }
+
+    Ok(())
+  }
+}
+
+/// ServerConfig says where and how `run_server` serves a store, set up one setting at a time
+///
+/// Everything but the address to listen on starts out as the default, the store being opened in the current
+/// directory with `KvStoreOptions::default()` and connections served with `ServerOptions::default()`:
+///
+/// ```no_run
+/// use kvs::server::{run_server, ServerConfig};
+/// use std::time::Duration;
+///
+/// let config = ServerConfig::new("127.0.0.1:4000".parse()?)
+///   .with_dir("/var/lib/kvs")
+///   .with_read_timeout(Some(Duration::from_secs(30)))
+///   .with_max_connections(64);
+/// run_server(config)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct ServerConfig {
+  listen: Listen,
+  dir: PathBuf,
+  store_options: KvStoreOptions,
+  options: ServerOptions,
+  socket: Option<PathBuf>,
+  shutdown: Arc<ShutdownState>,
+}
+
+enum Listen {
+  Addr(SocketAddr),
+  Listener(TcpListener),
+}
+
+impl ServerConfig {
+  /// Configures a server listening on the given address
+  pub fn new(addr: SocketAddr) -> Self {
+    Self::listening(Listen::Addr(addr))
+  }
+
+  /// Configures a server accepting connections from a listener already bound, e.g. to an ephemeral port
+  pub fn from_listener(listener: TcpListener) -> Self {
+    Self::listening(Listen::Listener(listener))
+  }
+
+  fn listening(listen: Listen) -> Self {
+    Self {
+      listen,
+      dir: PathBuf::from("."),
+      store_options: KvStoreOptions::default(),
+      options: ServerOptions::default(),
+      socket: None,
+      shutdown: Arc::new(ShutdownState::default()),
+    }
+  }
+
+  /// Directory to open the store in
+  pub fn with_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+    self.dir = dir.into();
+    self
+  }
+
+  /// Options to open the store with
+  ///
+  /// With a compaction interval set, writes leave compaction to the background compactor whatever
+  /// `compaction` says.
+  pub fn with_store_options(mut self, store_options: KvStoreOptions) -> Self {
+    self.store_options = store_options;
+    self
+  }
+
+  /// Options to serve connections with, replacing the ones set one at a time so far
+  pub fn with_options(mut self, options: ServerOptions) -> Self {
+    self.options = options;
+    self
+  }
+
+  /// See `ServerOptions::allow_flush`
+  pub fn with_allow_flush(mut self, allow_flush: bool) -> Self {
+    self.options.allow_flush = allow_flush;
+    self
+  }
+
+  /// See `ServerOptions::read_timeout`
+  pub fn with_read_timeout(mut self, read_timeout: Option<Duration>) -> Self {
+    self.options.read_timeout = read_timeout;
+    self
+  }
+
+  /// See `ServerOptions::write_timeout`
+  pub fn with_write_timeout(mut self, write_timeout: Option<Duration>) -> Self {
+    self.options.write_timeout = write_timeout;
+    self
+  }
+
+  /// See `ServerOptions::max_connections`
+  pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+    self.options.max_connections = max_connections;
+    self
+  }
+
+  /// See `ServerOptions::compaction_interval`
+  pub fn with_compaction_interval(mut self, compaction_interval: Option<Duration>) -> Self {
+    self.options.compaction_interval = compaction_interval;
+    self
+  }
+
+  /// Also serve connections on a Unix domain socket bound at `path`, which only Unix platforms support
+  ///
+  /// A socket left at `path` by a server that's gone, with nothing answering on it, is replaced. The socket is
+  /// unlinked again once the server stops.
+  pub fn with_socket(mut self, path: impl Into<PathBuf>) -> Self {
+    self.socket = Some(path.into());
+    self
+  }
+
+  /// Get a handle for shutting down the server once it runs, see `KvsServer::shutdown_handle`
+  ///
+  /// Shutting down before `run_server` is called makes it return as soon as the store is open.
+  pub fn shutdown_handle(&self) -> ShutdownHandle {
+    ShutdownHandle(self.shutdown.clone())
+  }
+}
+
+/// Run a server as configured, until it's shut down through the config's `shutdown_handle` or accepting fails
+///
+/// Binds the address unless the config came with a listener, opens the store, and serves TCP connections,
+/// along with Unix domain socket ones if a socket is configured. Once shut down, this returns after every
+/// connection is closed and the store is flushed, like `KvsServer::serve`.
+pub fn run_server(config: ServerConfig) -> Result<()> {
+  let listener = match config.listen {
+    Listen::Addr(addr) => TcpListener::bind(addr)?,
+    Listen::Listener(listener) => listener,
+  };
+  let store_options = KvStoreOptions {
+    // with a background compactor, writes leave compaction to it
+    compact_on_write: config.store_options.compact_on_write && config.options.compaction_interval.is_none(),
+    ..config.store_options
+  };
+  let store = KvStore::open_with_options(config.dir, store_options)?;
+
+  let mut server = KvsServer::with_options(store, config.options);
+  server.shutdown = config.shutdown;
+  match config.socket {
+    Some(path) => serve_with_socket(&server, listener, path),
+    None => server.serve(listener),
+  }
+}
+
+#[cfg(unix)]
+fn serve_with_socket(server: &KvsServer, listener: TcpListener, path: PathBuf) -> Result<()> {
+  let unix_listener = bind_socket(&path)?;
+  let served = thread::scope(|scope| {
+    let unix = scope.spawn(|| server.serve_unix(unix_listener));
+    let served = server.serve(listener);
+    // the Unix socket listener would otherwise go on accepting, and never be joined
+    if served.is_err() {
+      server.shutdown_handle().shutdown();
+    }
+    let unix = unix
+      .join()
+      .map_err(|_| KvStoreError::TaskError("the Unix socket listener panicked".to_owned()))?;
+    served.and(unix)
+  });
+
+  // the socket file outlives its listener, and would keep the next server from binding the path
+  match fs::remove_file(&path) {
+    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+      log::warn!("Unlinking the Unix socket {} failed: {}", path.display(), e)
+    }
+    _ => {}
+  }
+  served
+}
+
+// bind a Unix domain socket at `path`, replacing a stale one that nothing answers on anymore
+//
+// Only a socket is replaced, anything else at `path` fails binding as usual.
+#[cfg(unix)]
+fn bind_socket(path: &Path) -> Result<UnixListener> {
+  match UnixListener::bind(path) {
+    Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+      let is_socket = fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket());
+      if !is_socket || UnixStream::connect(path).is_ok() {
+        return Err(e.into());
+      }
+      log::warn!("Replacing the stale Unix socket {}", path.display());
+      fs::remove_file(path)?;
+      Ok(UnixListener::bind(path)?)
+    }
+    listener => Ok(listener?),
+  }
+}
+
+#[cfg(not(unix))]
+fn serve_with_socket(_server: &KvsServer, _listener: TcpListener, _path: PathBuf) -> Result<()> {
+  Err(KvStoreError::IoError(io::Error::new(
+    io::ErrorKind::Unsupported,
+    "serving a Unix domain socket needs a platform supporting them",
+  )))
+}
+
+/// ShutdownHandle stops the KvsServer it was got from, see `KvsServer::shutdown_handle`
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<ShutdownState>);
+
+impl ShutdownHandle {
+  /// Stop accepting connections and close the ones being served once their request at hand is answered
+  ///
+  /// This returns right away, `serve` returns once the server has stopped. Does nothing if already shut down.
+  pub fn shutdown(&self) {
+    self.0.stop();
+  }
+}
+
+// what shutting down has to wake up: listeners blocked accepting and connections blocked reading
+#[derive(Default)]
+struct ShutdownState {
+  stopping: AtomicBool,
+  stoppers: Mutex<HashMap<usize, Box<dyn Fn() + Send>>>,
+  next_id: AtomicUsize,
+}
+
+impl ShutdownState {
+  fn stopping(&self) -> bool {
+    self.stopping.load(Ordering::SeqCst)
+  }
+
+  fn stop(&self) {
+    if self.stopping.swap(true, Ordering::SeqCst) {
+      return;
+    }
+    let stoppers = self.stoppers.lock().unwrap_or_else(PoisonError::into_inner);
+    for stop in stoppers.values() {
+      stop();
+    }
+  }
+
+  // have `stop` called on shutting down, until the returned registration is dropped
+  //
+  // Stoppers registered after shutting down aren't called, callers check `stopping` after registering.
+  fn register(self: &Arc<Self>, stop: impl Fn() + Send + 'static) -> Stopper {
+    let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+    let mut stoppers = self.stoppers.lock().unwrap_or_else(PoisonError::into_inner);
+    stoppers.insert(id, Box::new(stop));
+    Stopper {
+      shutdown: self.clone(),
+      id,
+    }
+  }
+}
+
+// a registered stopper, unregistered when dropped
+struct Stopper {
+  shutdown: Arc<ShutdownState>,
+  id: usize,
+}
+
+impl Drop for Stopper {
+  fn drop(&mut self) {
+    let mut stoppers = self.shutdown.stoppers.lock().unwrap_or_else(PoisonError::into_inner);
+    stoppers.remove(&self.id);
+  }
+}
+
+// a stream the server can serve commands over
+trait Connection: Read + Write + Send + Sized + 'static {
+  fn set_timeouts(&self, read: Option<Duration>, write: Option<Duration>) -> io::Result<()>;
+
+  fn try_clone(&self) -> io::Result<Self>;
+
+  // close one or both directions, which wakes up a clone blocked reading
+  fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+  fn set_timeouts(&self, read: Option<Duration>, write: Option<Duration>) -> io::Result<()> {
+    self.set_read_timeout(read)?;
+    self.set_write_timeout(write)
+  }
+
+  fn try_clone(&self) -> io::Result<Self> {
+    TcpStream::try_clone(self)
+  }
+
+  fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+    TcpStream::shutdown(self, how)
+  }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+  fn set_timeouts(&self, read: Option<Duration>, write: Option<Duration>) -> io::Result<()> {
+    self.set_read_timeout(read)?;
+    self.set_write_timeout(write)
+  }
+
+  fn try_clone(&self) -> io::Result<Self> {
+    UnixStream::try_clone(self)
+  }
+
+  fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+    UnixStream::shutdown(self, how)
+  }
+}
+
+// a slot among the connections served at once, given back when dropped
+struct ConnectionPermit(Arc<AtomicUsize>);
+
+impl ConnectionPermit {
+  fn acquire(connections: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+    connections
+      .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+        if n < max {
+          Some(n + 1)
+        } else {
+          None
+        }
+      })
+      .ok()
+      .map(|_| Self(connections.clone()))
+  }
+}
+
+impl Drop for ConnectionPermit {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+// answer requests in the protocol the client picked until it disconnects
+fn handle<S: LogStorage>(
+  stream: impl Connection,
+  store: &Mutex<KvStore<S>>,
+  options: &ServerOptions,
+  channels: &Channels,
+) -> io::Result<()> {
+  stream.set_timeouts(options.read_timeout, options.write_timeout)?;
+  let mut reader = BufReader::new(stream);
+  let protocol = match reader.fill_buf()?.first() {
+    Some(protocol) => *protocol,
+    None => return Ok(()),
+  };
+
+  match protocol {
+    RESP_PROTOCOL => handle_resp(reader, store, options, channels),
+    SERDE_PROTOCOL => {
+      reader.consume(1);
+      handle_serde(reader, store)
+    }
+    protocol => {
+      log::warn!("Rejected connection with unknown protocol byte {:#04x}", protocol);
+      let reply = RespValue::Error(format!("ERR unknown protocol {:#04x}", protocol));
+      resp::write_value(&reply, reader.get_mut())
+    }
+  }
+}
+
+fn handle_resp<S: LogStorage>(
+  mut reader: BufReader<impl Connection>,
+  store: &Mutex<KvStore<S>>,
+  options: &ServerOptions,
+  channels: &Channels,
+) -> io::Result<()> {
+  // the transaction started by MULTI, if any
+  let mut transaction: Option<Transaction> = None;
+  // a client closing the connection between commands is a clean disconnect
+  while let Some(cmd) = resp::read_command(&mut reader)? {
+    let reply = match (cmd.name().as_str(), cmd.args(), transaction.as_mut()) {
+      ("MULTI", [], Some(_)) => RespValue::Error("ERR MULTI calls can not be nested".to_owned()),
+      ("MULTI", [], None) => {
+        transaction = Some(Transaction::default());
+        RespValue::SimpleString("OK".to_owned())
+      }
+      ("EXEC", [], None) => RespValue::Error("ERR EXEC without MULTI".to_owned()),
+      ("DISCARD", [], None) => RespValue::Error("ERR DISCARD without MULTI".to_owned()),
+      ("DISCARD", [], Some(_)) => {
+        transaction = None;
+        RespValue::SimpleString("OK".to_owned())
+      }
+      ("EXEC", [], Some(_)) => match transaction.take() {
+        Some(transaction) => with_store(store, |store| transaction.exec(store)),
+        None => unreachable!("matched an open transaction"),
+      },
+      (_, _, Some(transaction)) => transaction.queue(&cmd),
+      ("SUBSCRIBE", names, None) if !names.is_empty() => match serve_subscriber(reader, options, channels, names)? {
+        // unsubscribed from every channel, back to serving commands
+        Some(resumed) => {
+          reader = resumed;
+          continue;
+        }
+        None => return Ok(()),
+      },
+      ("PING", [], None) => RespValue::SimpleString("PONG".to_owned()),
+      ("PING", [message], None) => RespValue::Bulk(Some(message.as_bytes().to_vec())),
+      ("PUBLISH", [channel, message], None) => RespValue::Integer(channels.publish(channel, message) as i64),
+      (_, _, None) => with_store(store, |store| dispatch(store, options, &cmd)),
+    };
+    resp::write_value(&reply, reader.get_mut())?;
+  }
+
+  Ok(())
+}
+
+// what a subscribed connection's thread is sent
+enum Delivery {
+  // a message published to one of its channels
+  Message(String, String),
+  // a command the client sent
+  Command(RespCommand),
+  // the client disconnected, or its connection failed
+  Closed,
+}
+
+// messages a subscriber may have pending before it's disconnected, like Redis' client-output-buffer-limit
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+// a subscribed connection, as the channels it subscribed to know it
+#[derive(Clone)]
+struct Subscriber {
+  id: usize,
+  sender: SyncSender<Delivery>,
+  // set once a message didn't fit in its buffer, it's then disconnected
+  overflowed: Arc<AtomicBool>,
+}
+
+// the subscribers of every channel, fed by PUBLISH
+#[derive(Default)]
+struct Channels {
+  channels: Mutex<HashMap<String, Vec<Subscriber>>>,
+  next_id: AtomicUsize,
+}
+
+impl Channels {
+  fn subscriber(&self, sender: SyncSender<Delivery>) -> Subscriber {
+    Subscriber {
+      id: self.next_id.fetch_add(1, Ordering::Relaxed),
+      sender,
+      overflowed: Arc::new(AtomicBool::new(false)),
+    }
+  }
+
+  fn subscribe(&self, channel: &str, subscriber: &Subscriber) {
+    // a panic elsewhere while holding the lock can't leave the map half changed
+    let mut channels = self.channels.lock().unwrap_or_else(PoisonError::into_inner);
+    channels.entry(channel.to_owned()).or_default().push(subscriber.clone());
+  }
+
+  fn unsubscribe(&self, channel: &str, subscriber: &Subscriber) {
+    let mut channels = self.channels.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(subscribers) = channels.get_mut(channel) {
+      subscribers.retain(|other| other.id != subscriber.id);
+      if subscribers.is_empty() {
+        channels.remove(channel);
+      }
+    }
+  }
+
+  // unsubscribe from every channel, once the subscriber is done
+  fn forget(&self, subscriber: &Subscriber) {
+    let mut channels = self.channels.lock().unwrap_or_else(PoisonError::into_inner);
+    channels.retain(|_, subscribers| {
+      subscribers.retain(|other| other.id != subscriber.id);
+      !subscribers.is_empty()
+    });
+  }
+
+  // send the message to the channel's subscribers, returns how many got it
+  fn publish(&self, channel: &str, message: &str) -> usize {
+    let mut channels = self.channels.lock().unwrap_or_else(PoisonError::into_inner);
+    let subscribers = match channels.get_mut(channel) {
+      Some(subscribers) => subscribers,
+      None => return 0,
+    };
+
+    // subscribers that went away are only noticed here, and so are those too slow to keep up
+    subscribers.retain(|subscriber| {
+      match subscriber
+        .sender
+        .try_send(Delivery::Message(channel.to_owned(), message.to_owned()))
+      {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+          subscriber.overflowed.store(true, Ordering::SeqCst);
+          false
+        }
+        Err(TrySendError::Disconnected(_)) => false,
+      }
+    });
+    let delivered = subscribers.len();
+    if delivered == 0 {
+      channels.remove(channel);
+    }
+
+    delivered
+  }
+}
+
+// deliver the messages of the subscribed channels until the client disconnects or unsubscribes from all of
+// them, in which case the reader is handed back to serve commands again
+//
+// A thread of its own reads the client's commands off a clone of the connection, so messages go out while
+// waiting for them. Both end up on the same channel, so replies and messages are written from one place.
+fn serve_subscriber<C: Connection>(
+  reader: BufReader<C>,
+  options: &ServerOptions,
+  channels: &Channels,
+  names: &[String],
+) -> io::Result<Option<BufReader<C>>> {
+  let mut stream = reader.get_ref().try_clone()?;
+  // subscribers may wait for messages as long as they like
+  stream.set_timeouts(None, options.write_timeout)?;
+
+  let (sender, deliveries) = mpsc::sync_channel(SUBSCRIBER_BUFFER);
+  let subscriber = channels.subscriber(sender.clone());
+  // after an UNSUBSCRIBE, whether the client is still subscribed and the thread should keep reading
+  let (resume, resumed) = mpsc::channel();
+  let reading = thread::spawn(move || {
+    let mut reader = reader;
+    loop {
+      let delivery = match resp::read_command(&mut reader) {
+        Ok(Some(cmd)) => Delivery::Command(cmd),
+        _ => Delivery::Closed,
+      };
+      let unsubscribing = matches!(&delivery, Delivery::Command(cmd) if cmd.name() == "UNSUBSCRIBE");
+      let closed = matches!(delivery, Delivery::Closed);
+      if sender.send(delivery).is_err() || closed {
+        return None;
+      }
+      if unsubscribing {
+        match resumed.recv() {
+          Ok(true) => {}
+          Ok(false) => return Some(reader),
+          Err(_) => return None,
+        }
+      }
+    }
+  });
+
+  let result = deliver(&mut stream, &deliveries, &resume, &subscriber, channels, names);
+  channels.forget(&subscriber);
+  match result {
+    Ok(true) => {
+      let reader = reading.join().unwrap_or(None);
+      if let Some(reader) = &reader {
+        reader
+          .get_ref()
+          .set_timeouts(options.read_timeout, options.write_timeout)?;
+      }
+      Ok(reader)
+    }
+    result => {
+      // stops the reading thread in case delivering failed first
+      stream.shutdown(Shutdown::Both).ok();
+      result.map(|_| None)
+    }
+  }
+}
+
+// returns whether the client left subscribed mode, rather than disconnecting
+fn deliver(
+  stream: &mut impl Write,
+  deliveries: &Receiver<Delivery>,
+  resume: &Sender<bool>,
+  subscriber: &Subscriber,
+  channels: &Channels,
+  names: &[String],
+) -> io::Result<bool> {
+  let mut subscribed = HashSet::new();
+  subscribe(stream, &mut subscribed, subscriber, channels, names)?;
+  for delivery in deliveries {
+    if subscriber.overflowed.load(Ordering::SeqCst) {
+      log::warn!(
+        "Disconnecting a subscriber with over {} messages pending",
+        SUBSCRIBER_BUFFER
+      );
+      break;
+    }
+
+    let reply = match delivery {
+      Delivery::Message(channel, message) => RespValue::Array(vec![
+        RespValue::Bulk(Some(b"message".to_vec())),
+        RespValue::Bulk(Some(channel.into_bytes())),
+        RespValue::Bulk(Some(message.into_bytes())),
+      ]),
+      Delivery::Command(cmd) => match (cmd.name().as_str(), cmd.args()) {
+        ("SUBSCRIBE", names) if !names.is_empty() => {
+          subscribe(stream, &mut subscribed, subscriber, channels, names)?;
+          continue;
+        }
+        ("UNSUBSCRIBE", names) => {
+          unsubscribe(stream, &mut subscribed, subscriber, channels, names)?;
+          // the reading thread waits to hear whether it's still reading for a subscriber
+          let left = subscribed.is_empty();
+          resume.send(!left).ok();
+          if left {
+            return Ok(true);
+          }
+          continue;
+        }
+        // subscribers answer PING with an array, as Redis does
+        ("PING", []) => RespValue::Array(vec![
+          RespValue::Bulk(Some(b"pong".to_vec())),
+          RespValue::Bulk(Some(Vec::new())),
+        ]),
+        ("PING", [message]) => RespValue::Array(vec![
+          RespValue::Bulk(Some(b"pong".to_vec())),
+          RespValue::Bulk(Some(message.as_bytes().to_vec())),
+        ]),
+        (name, _) => RespValue::Error(format!(
+          "ERR Can't execute '{}': only SUBSCRIBE / UNSUBSCRIBE / PING are allowed in this context",
+          name.to_lowercase()
+        )),
+      },
+      Delivery::Closed => break,
+    };
+    resp::write_value(&reply, stream)?;
+  }
+
+  Ok(false)
+}
+
+// subscribe to the channels not subscribed to yet, confirming each one like Redis does
+fn subscribe(
+  stream: &mut impl Write,
+  subscribed: &mut HashSet<String>,
+  subscriber: &Subscriber,
+  channels: &Channels,
+  names: &[String],
+) -> io::Result<()> {
+  for name in names {
+    if subscribed.insert(name.to_owned()) {
+      channels.subscribe(name, subscriber);
+    }
+    let reply = RespValue::Array(vec![
+      RespValue::Bulk(Some(b"subscribe".to_vec())),
+      RespValue::Bulk(Some(name.as_bytes().to_vec())),
+      RespValue::Integer(subscribed.len() as i64),
+    ]);
+    resp::write_value(&reply, stream)?;
+  }
+
+  Ok(())
+}
+
+// unsubscribe from the given channels, or all of them if none are given, confirming each one like Redis does
+fn unsubscribe(
+  stream: &mut impl Write,
+  subscribed: &mut HashSet<String>,
+  subscriber: &Subscriber,
+  channels: &Channels,
+  names: &[String],
+) -> io::Result<()> {
+  let mut names = names.to_vec();
+  if names.is_empty() {
+    names = subscribed.iter().cloned().collect();
+    names.sort();
+  }
+
+  for name in names {
+    if subscribed.remove(&name) {
+      channels.unsubscribe(&name, subscriber);
+    }
+    let reply = RespValue::Array(vec![
+      RespValue::Bulk(Some(b"unsubscribe".to_vec())),
+      RespValue::Bulk(Some(name.into_bytes())),
+      RespValue::Integer(subscribed.len() as i64),
+    ]);
+    resp::write_value(&reply, stream)?;
+  }
+
+  Ok(())
+}
+
+// run `f` on the locked store
+fn with_store<S: LogStorage>(store: &Mutex<KvStore<S>>, f: impl FnOnce(&mut KvStore<S>) -> RespValue) -> RespValue {
+  match store.lock() {
+    Ok(mut store) => f(&mut store),
+    Err(_) => RespValue::Error("ERR store is unavailable after a panic".to_owned()),
+  }
+}
+
+// commands queued between MULTI and EXEC
+#[derive(Default)]
+struct Transaction {
+  batch: WriteBatch,
+  // what EXEC replies for each queued command once the batch is applied
+  replies: Vec<RespValue>,
+  // set once a command couldn't be queued, EXEC then discards the transaction
+  failed: bool,
+}
+
+impl Transaction {
+  fn queue(&mut self, cmd: &RespCommand) -> RespValue {
+    let name = cmd.name();
+    let reply = match (name.as_str(), cmd.args()) {
+      ("SET", [key, value]) => {
+        self.batch.set(key.to_owned(), value.to_owned());
+        RespValue::SimpleString("OK".to_owned())
+      }
+      ("DEL", keys) if !keys.is_empty() => {
+        for key in keys {
+          self.batch.remove(key.to_owned());
+        }
+        RespValue::Integer(keys.len() as i64)
+      }
+      ("EXISTS", [key]) => {
+        self.batch.require_exists(key.to_owned());
+        RespValue::Integer(1)
+      }
+      (name, _) => {
+        self.failed = true;
+        return RespValue::Error(if QUEUEABLE.contains(&name) {
+          format!("ERR wrong number of arguments for '{}' command", name.to_lowercase())
+        } else if COMMANDS.contains(&name) {
+          format!("ERR '{}' can't be used in a transaction", name.to_lowercase())
+        } else {
+          format!("ERR unknown command '{}'", name.to_lowercase())
+        });
+      }
+    };
+
+    self.replies.push(reply);
+    RespValue::SimpleString("QUEUED".to_owned())
+  }
+
+  fn exec<S: LogStorage>(self, store: &mut KvStore<S>) -> RespValue {
+    if self.failed {
+      return RespValue::Error("EXECABORT Transaction discarded because of previous errors".to_owned());
+    }
+
+    match store.write(self.batch) {
+      Ok(()) => RespValue::Array(self.replies),
+      Err(e) => RespValue::Error(format!("ERR {}", e)),
+    }
+  }
+}
+
+fn handle_serde<S: LogStorage>(mut reader: BufReader<impl Connection>, store: &Mutex<KvStore<S>>) -> io::Result<()> {
+  // a client closing the connection between requests is a clean disconnect
+  while !reader.fill_buf()?.is_empty() {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_REQUEST_BYTES {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("request of {} bytes is over the limit", len),
+      ));
+    }
+    // the length is only a claim, the frame grows as bytes arrive
+    let mut frame = Vec::with_capacity(len.min(64 * 1024));
+    Read::take(&mut reader, len as u64).read_to_end(&mut frame)?;
+    if frame.len() < len {
+      return Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "stream ended within a request",
+      ));
+    }
+    // decoding from the frame can't claim more bytes than it holds
+    let request: Request =
+      rmp_serde::from_read_ref(&frame).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if request == Request::Ping {
+      rmp_serde::encode::write(reader.get_mut(), &Response::Pong).map_err(io::Error::other)?;
+      continue;
+    }
+    let response = match store.lock() {
+      Ok(mut store) => match request {
+        Request::Get(key) => store.get(key).map(Response::Value),
+        Request::Set(key, value) => store.set(key, value).map(|()| Response::Ok),
+        Request::Remove(key) => store.remove(key).map(|()| Response::Ok),
+        Request::Ping => unreachable!("answered without the store"),
+      }
+      .unwrap_or_else(|e| Response::Err(e.to_string())),
+      Err(_) => Response::Err("store is unavailable after a panic".to_owned()),
+    };
+    rmp_serde::encode::write(reader.get_mut(), &response).map_err(io::Error::other)?;
+  }
+
+  Ok(())
+}
+
+fn dispatch<S: LogStorage>(store: &mut KvStore<S>, options: &ServerOptions, cmd: &RespCommand) -> RespValue {
+  let name = cmd.name();
+  let reply = match (name.as_str(), cmd.args()) {
+    ("GET", [key]) => store
+      .get(key.to_owned())
+      .map(|value| RespValue::Bulk(value.map(String::into_bytes))),
+    ("SET", [key, value]) => store
+      .set(key.to_owned(), value.to_owned())
+      .map(|()| RespValue::SimpleString("OK".to_owned())),
+    ("DEL", keys) if !keys.is_empty() => remove_keys(store, keys).map(RespValue::Integer),
+    ("EXISTS", keys) if !keys.is_empty() => {
+      // like Redis, a key given twice counts twice
+      keys
+        .iter()
+        .try_fold(0, |existing, key| Ok(existing + store.contains_key(key)? as i64))
+        .map(RespValue::Integer)
+    }
+    ("DBSIZE", []) => store.len().map(|len| RespValue::Integer(len as i64)),
+    ("KEYS", [pattern]) => store.scan_glob(pattern).map(|keys| {
+      RespValue::Array(
+        keys
+          .into_iter()
+          .map(|key| RespValue::Bulk(Some(key.into_bytes())))
+          .collect(),
+      )
+    }),
+    ("FLUSHALL", []) if !options.allow_flush => {
+      return RespValue::Error("ERR FLUSHALL is disabled, start the server with --allow-flush".to_owned())
+    }
+    ("FLUSHALL", []) => store.clear().map(|()| RespValue::SimpleString("OK".to_owned())),
+    ("EXPIRE", [key, seconds]) => match seconds.parse() {
+      Ok(seconds) => expire(store, key, seconds).map(RespValue::Integer),
+      Err(_) => return RespValue::Error("ERR value is not an integer or out of range".to_owned()),
+    },
+    ("PERSIST", [key]) => persist(store, key).map(RespValue::Integer),
+    ("TTL", [key]) => ttl(store, key).map(RespValue::Integer),
+    (name, _) if COMMANDS.contains(&name) => {
+      return RespValue::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name.to_lowercase()
+      ))
+    }
+    _ => return RespValue::Error(format!("ERR unknown command '{}'", name.to_lowercase())),
+  };
+
+  reply.unwrap_or_else(|e| RespValue::Error(format!("ERR {}", e)))
+}
+
+// give the key a TTL of `seconds`, returns 1 if the key exists and 0 otherwise
+fn expire<S: LogStorage>(store: &mut KvStore<S>, key: &str, seconds: i64) -> Result<i64> {
+  if seconds > 0 {
+    let expired = store.expire(key, Some(Duration::from_secs(seconds as u64)))?;
+    return Ok(expired as i64);
+  }
+
+  remove_keys(store, &[key.to_owned()])
+}
+
+// take the key's TTL away, returns 1 if it had one and 0 otherwise
+fn persist<S: LogStorage>(store: &mut KvStore<S>, key: &str) -> Result<i64> {
+  match store.get_meta(key.to_owned())? {
+    Some(meta) if meta.expires.is_some() => Ok(store.expire(key, None)? as i64),
+    _ => Ok(0),
+  }
+}
+
+// seconds left until the key expires, rounded, -1 without a TTL and -2 for a missing key
+fn ttl<S: LogStorage>(store: &mut KvStore<S>, key: &str) -> Result<i64> {
+  let left = match store.get_meta(key.to_owned())? {
+    Some(meta) => match meta.expires {
+      Some(expires) => expires.duration_since(SystemTime::now()).unwrap_or_default(),
+      None => return Ok(-1),
+    },
+    None => return Ok(-2),
+  };
+
+  Ok(((left.as_millis() + 500) / 1000) as i64)
+}
+
+// remove the keys that exist, returns how many there were
+fn remove_keys<S: LogStorage>(store: &mut KvStore<S>, keys: &[String]) -> Result<i64> {
+  let mut removed = 0;
+  for key in keys {
+    match store.remove(key.to_owned()) {
+      Ok(()) => removed += 1,
+      Err(KvStoreError::RmKeyNotFoundError) => {}
+      Err(e) => return Err(e),
+    }
+  }
+
+  Ok(removed)
+}
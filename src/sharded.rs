@@ -0,0 +1,187 @@
+//! A store split into shards by key hash, so writers to different shards don't wait on each other
+
+use crate::{check_namespace, read_value_at, FileStorage, KvStore, KvStoreError, KvStoreOptions, Log, Result};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Mutex, PoisonError, RwLock, RwLockWriteGuard};
+
+/// ShardedKvStore routes every key to one of several KvStores, each behind its own `RwLock`
+///
+/// A single KvStore shared through a lock serializes every operation. Splitting the keys over `n` shards lets
+/// operations on keys of different shards run at the same time, which `&self` methods make easy to share across
+/// threads. Gets only take their shard's read lock, reading the value through a log reader of their own, so
+/// they don't wait on each other either, only on writes to the same shard.
+///
+/// Each shard is a KvStore of its own, kept in the `shard-<i>` namespace of the directory, with its own log,
+/// compaction and index. Only sharding one store's index wouldn't let writers go ahead together: they'd still
+/// append to that store's single log, which takes the store to itself. The price is that keys are routed by an
+/// FNV-1a hash, which is stable across builds, so a directory must always be opened with the number of shards it
+/// was created with.
+pub struct ShardedKvStore {
+  shards: Vec<Shard>,
+}
+
+// one shard's store, along with the log readers its gets have opened
+struct Shard {
+  store: RwLock<KvStore>,
+  // readers for gets to take turns with, along with the store's `rewrites` when each was opened
+  readers: Mutex<Vec<(u64, Log<FileStorage>)>>,
+}
+
+impl ShardedKvStore {
+  /// Opens a store of `shards` shards with the default options
+  pub fn open(directory: impl Into<PathBuf>, shards: usize) -> Result<Self> {
+    Self::open_with_options(directory, shards, KvStoreOptions::default())
+  }
+
+  /// Opens a store of `shards` shards, each with the given options
+  ///
+  /// With a namespace set in the options, the shards go into the namespace's subdirectory. Fails with
+  /// `ShardCountError` if there are no shards, or if the directory already holds a different number of them,
+  /// and with `InvalidNamespaceError` for a namespace `KvStore::open_with_options` would refuse.
+  pub fn open_with_options(directory: impl Into<PathBuf>, shards: usize, options: KvStoreOptions) -> Result<Self> {
+    let mut dir = directory.into();
+    if options.create_dir {
+      fs::create_dir_all(&dir)?;
+    }
+    if let Some(namespace) = options.namespace.clone() {
+      check_namespace(&namespace)?;
+      dir.push(namespace);
+      match fs::create_dir(&dir) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e.into()),
+        _ => {}
+      }
+    }
+    if shards == 0 {
+      return Err(KvStoreError::ShardCountError(
+        "a store needs at least one shard".to_owned(),
+      ));
+    }
+
+    let mut existing = 0;
+    for entry in fs::read_dir(&dir)? {
+      let entry = entry?;
+      if entry.file_type()?.is_dir() && entry.file_name().to_string_lossy().starts_with("shard-") {
+        existing += 1;
+      }
+    }
+    if existing != 0 && existing != shards {
+      return Err(KvStoreError::ShardCountError(format!(
+        "opened with {} shards, the directory has {}",
+        shards, existing
+      )));
+    }
+
+    let shards = (0..shards)
+      .map(|shard| {
+        let options = KvStoreOptions {
+          namespace: Some(format!("shard-{}", shard)),
+          ..options.clone()
+        };
+        KvStore::open_with_options(&dir, options).map(|store| Shard {
+          store: RwLock::new(store),
+          readers: Mutex::new(Vec::new()),
+        })
+      })
+      .collect::<Result<_>>()?;
+
+    Ok(Self { shards })
+  }
+
+  /// Number of shards
+  pub fn shard_count(&self) -> usize {
+    self.shards.len()
+  }
+
+  /// Index of the shard the key is kept in
+  pub fn shard_of(&self, key: &str) -> usize {
+    (fnv1a(key.as_bytes()) % self.shards.len() as u64) as usize
+  }
+
+  /// Get the value associated with the given key
+  ///
+  /// Reads under the shard's read lock, unless the shard's index can't tell on its own where the value is, with
+  /// `IndexMode::None`, and the log has to be scanned under its write lock. Values read this way aren't cached.
+  pub fn get(&self, key: String) -> Result<Option<String>> {
+    let index = self.shard_of(&key);
+    let shard = self.shard(index)?;
+    {
+      let store = shard.store.read().map_err(|_| unavailable(index))?;
+      match store.locate_indexed(&key) {
+        Some(Some(log_pointer)) => return shard.read_value(&store, &key, log_pointer).map(Some),
+        Some(None) => return Ok(None),
+        None => {}
+      }
+    }
+    self.lock(index)?.get(key)
+  }
+
+  /// Set the value associated with the given key
+  pub fn set(&self, key: String, value: String) -> Result<()> {
+    self.lock(self.shard_of(&key))?.set(key, value)
+  }
+
+  /// Remove the given key and its associated value
+  pub fn remove(&self, key: String) -> Result<()> {
+    self.lock(self.shard_of(&key))?.remove(key)
+  }
+
+  /// Run `f` on one shard's store, holding that shard's write lock meanwhile
+  ///
+  /// This gives access to the rest of KvStore's API, e.g. compacting shards one at a time. Fails with
+  /// `ShardCountError` if there's no such shard.
+  pub fn with_shard<R>(&self, shard: usize, f: impl FnOnce(&mut KvStore) -> R) -> Result<R> {
+    let mut store = self.lock(shard)?;
+    Ok(f(&mut store))
+  }
+
+  fn lock(&self, shard: usize) -> Result<RwLockWriteGuard<'_, KvStore>> {
+    self.shard(shard)?.store.write().map_err(|_| unavailable(shard))
+  }
+
+  fn shard(&self, shard: usize) -> Result<&Shard> {
+    self
+      .shards
+      .get(shard)
+      .ok_or_else(|| KvStoreError::ShardCountError(format!("no shard {}, the store has {}", shard, self.shards.len())))
+  }
+}
+
+impl Shard {
+  // read the value at the given log pointer through a reader of the shard's, opening one if all are in use
+  //
+  // Readers opened before the log was last rewritten, e.g. compacted, may be reading the old log and are dropped.
+  fn read_value(&self, store: &KvStore, key: &str, log_pointer: u64) -> Result<String> {
+    let rewrites = store.rewrites();
+    let reader = {
+      let mut readers = self.readers.lock().unwrap_or_else(PoisonError::into_inner);
+      readers.retain(|(opened_at, _)| *opened_at == rewrites);
+      readers.pop()
+    };
+    let mut reader = match reader {
+      Some((_, reader)) => reader,
+      None => store.shared_reader()?,
+    };
+    let value = read_value_at(&mut reader, key, log_pointer)?;
+    self
+      .readers
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .push((rewrites, reader));
+
+    Ok(value)
+  }
+}
+
+// the error for a shard whose lock was poisoned
+fn unavailable(shard: usize) -> KvStoreError {
+  KvStoreError::TaskError(format!("shard {} is unavailable after a panic", shard))
+}
+
+// 64-bit FNV-1a, simple and the same on every platform and release
+fn fnv1a(bytes: &[u8]) -> u64 {
+  bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+    (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+  })
+}
@@ -0,0 +1,97 @@
+use crate::{read_value_at, FileStorage, Log, LogStorage, Result};
+use std::sync::Arc;
+
+/// A consistent view of a KvStore's live keys and values as of when it was taken, see `KvStore::snapshot`
+///
+/// The snapshot holds the keys that were live at that moment along with where their values are in the log,
+/// and reads them through a log reader of its own. Writes to the store after the snapshot was taken only
+/// append to the log, compaction writes a new log without touching the old one, and clearing the store
+/// leaves the old log to the snapshot, so nothing written since shows through. It needs no access to the store
+/// to be read, e.g. a store shared behind a lock can be written to while a snapshot of it is iterated.
+///
+/// Keys set with a TTL are in the snapshot if they were live when it was taken, even once they expire.
+pub struct Snapshot<S: LogStorage = FileStorage> {
+  // live keys in order, with their log pointers
+  entries: Vec<(String, u64)>,
+  source: Source<S>,
+  // counted by the store, see `KvStore::clear`
+  _pin: Arc<()>,
+}
+
+// where a snapshot reads values from
+pub(crate) enum Source<S: LogStorage> {
+  // a reader of the log of its own
+  Log(Log<S>),
+  // the values read when the snapshot was taken, in the order of its keys, for storages without readers
+  Values(Vec<String>),
+}
+
+impl<S: LogStorage> Snapshot<S> {
+  pub(crate) fn new(entries: Vec<(String, u64)>, source: Source<S>, pin: Arc<()>) -> Self {
+    Self {
+      entries,
+      source,
+      _pin: pin,
+    }
+  }
+
+  /// Number of live keys in the snapshot
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  /// Whether the snapshot has no live keys
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  /// Live keys in the snapshot, sorted
+  pub fn keys(&self) -> impl Iterator<Item = &str> + '_ {
+    self.entries.iter().map(|(key, _)| key.as_str())
+  }
+
+  /// Get the value the given key had when the snapshot was taken
+  pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+    let entry = match self.entries.binary_search_by(|(entry, _)| entry.as_str().cmp(key)) {
+      Ok(entry) => entry,
+      Err(_) => return Ok(None),
+    };
+
+    let (key, log_pointer) = &self.entries[entry];
+    self.source.read(entry, key, *log_pointer).map(Some)
+  }
+
+  /// Walk every live key-value pair of the snapshot, sorted by key
+  pub fn iter(&mut self) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+    let source = &mut self.source;
+    self
+      .entries
+      .iter()
+      .enumerate()
+      .map(move |(entry, (key, log_pointer))| Ok((key.clone(), source.read(entry, key, *log_pointer)?)))
+  }
+
+  /// Get all live key-value pairs of the snapshot whose key starts with the given prefix, sorted by key
+  pub fn scan_prefix(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+    let start = self.entries.partition_point(|(key, _)| key.as_str() < prefix);
+    let source = &mut self.source;
+    self.entries[start..]
+      .iter()
+      .enumerate()
+      .take_while(|(_, (key, _))| key.starts_with(prefix))
+      .map(|(entry, (key, log_pointer))| Ok((key.clone(), source.read(start + entry, key, *log_pointer)?)))
+      .collect()
+  }
+}
+
+impl<S: LogStorage> Source<S> {
+  // read the value of the snapshot's `entry`th key
+  fn read(&mut self, entry: usize, key: &str, log_pointer: u64) -> Result<String> {
+    let log = match self {
+      Source::Log(log) => log,
+      Source::Values(values) => return Ok(values[entry].clone()),
+    };
+
+    read_value_at(log, key, log_pointer)
+  }
+}
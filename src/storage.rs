@@ -0,0 +1,507 @@
+//! Storage backends for the command log
+
+use memmap2::Mmap;
+use std::cmp;
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// LogStorage is where a KvStore keeps its command log
+///
+/// The log is only ever appended to, compaction writes a fresh scratch log and then replaces the old one with it.
+pub trait LogStorage {
+  /// Read bytes starting at `offset` into `buf`, returns the number of bytes read (0 at the end of the log)
+  fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+  /// Append bytes to the end of the log, returns the offset they were written at
+  fn append(&mut self, bytes: &[u8]) -> io::Result<u64>;
+
+  /// Truncate (or extend with zeros) the log to `len` bytes
+  fn truncate(&mut self, len: u64) -> io::Result<()>;
+
+  /// Make appended bytes durable
+  fn sync(&mut self) -> io::Result<()>;
+
+  /// Length of the log in bytes
+  fn len(&self) -> io::Result<u64>;
+
+  /// Whether the log is empty
+  fn is_empty(&self) -> io::Result<bool> {
+    Ok(self.len()? == 0)
+  }
+
+  /// Bytes of the log from `offset` onwards, if the storage can hand them out without copying
+  ///
+  /// Reads decode straight from the returned slice instead of going through `read_at`.
+  fn slice_at(&mut self, _offset: u64) -> io::Result<Option<&[u8]>> {
+    Ok(None)
+  }
+
+  /// Load the index hint saved by `store_hint`, if there's one at least as new as the log
+  fn load_hint(&mut self) -> io::Result<Option<Vec<u8>>> {
+    Ok(None)
+  }
+
+  /// Save an index hint for the log, storages that can't keep one just drop it
+  fn store_hint(&mut self, _hint: &[u8]) -> io::Result<()> {
+    Ok(())
+  }
+
+  /// Forget anything kept about the log's contents, as someone else may have changed it
+  fn reload(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+
+  /// Path of the file the log is kept in, for storages that keep it in one
+  fn file_path(&self) -> Option<&Path> {
+    None
+  }
+
+  /// Whether replacing the log syncs the directory it's kept in, see `KvStoreOptions::sync_dir`
+  ///
+  /// Storages that keep the log in no directory ignore it.
+  fn set_dir_sync(&mut self, _sync_dir: bool) {}
+
+  /// Open a second handle on the log for reading it from another thread, if the storage can
+  ///
+  /// The handle must read at least the bytes the log has now, whatever is appended through this one or
+  /// until the log is replaced. `KvStore::compact_shared` reads the old log through it without holding
+  /// the store's lock, storages that return `None` are compacted under the lock instead.
+  fn open_reader(&mut self) -> io::Result<Option<Self>>
+  where
+    Self: Sized,
+  {
+    Ok(None)
+  }
+
+  /// Open an empty scratch log for compaction to write into
+  ///
+  /// Scratch logs open at the same time must be apart, a compaction in the background may still be writing
+  /// one while the store is cleared or compacted under its lock.
+  fn open_scratch(&mut self) -> io::Result<Self>
+  where
+    Self: Sized;
+
+  /// Replace the log with a scratch log previously opened by `open_scratch`
+  fn replace(&mut self, scratch: Self) -> io::Result<()>
+  where
+    Self: Sized;
+}
+
+/// FileStorage keeps the log in a file on disk
+pub struct FileStorage {
+  path: PathBuf,
+  file: File,
+  // None when reads go through the file, Some(None) until the first read maps it
+  map: Option<Option<Mmap>>,
+  // where scratch logs go, next to the log if None
+  scratch_dir: Option<PathBuf>,
+  // whether replacing the log syncs its directory
+  sync_dir: bool,
+  // times replacing the log synced its directory
+  dir_syncs: u64,
+  // numbers of the scratch logs opened that haven't replaced the log yet, see `scratch_name`
+  scratches: BTreeSet<usize>,
+  // this scratch log's number, if it is one
+  scratch: Option<usize>,
+}
+
+impl FileStorage {
+  /// Open (or create) the log file at the given path
+  pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+    let path = path.into();
+    let file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(&path)?;
+
+    Ok(Self {
+      path,
+      file,
+      map: None,
+      scratch_dir: None,
+      sync_dir: true,
+      dir_syncs: 0,
+      scratches: BTreeSet::new(),
+      scratch: None,
+    })
+  }
+
+  /// Open (or create) the log file at the given path, serving reads from a memory map of it
+  ///
+  /// The file is remapped whenever a read lands past the mapped length, writes still go through the file.
+  /// The log must not be truncated by anyone else while it's open, or reads will fault.
+  pub fn open_mmap(path: impl Into<PathBuf>) -> io::Result<Self> {
+    let mut storage = Self::open(path)?;
+    storage.map = Some(None);
+
+    Ok(storage)
+  }
+
+  /// Write compaction scratch logs into the given directory instead of next to the log
+  ///
+  /// The directory may be on another filesystem, the compacted log is then copied next to the log
+  /// before being renamed over it, so the swap stays atomic either way.
+  pub fn with_scratch_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+    self.scratch_dir = Some(dir.into());
+    self
+  }
+
+  /// Whether replacing the log with a compacted one syncs the log's directory afterwards, on by default
+  ///
+  /// A rename is only durable once the directory it happened in is synced, so without this a crash right
+  /// after compaction can bring back the old log. Only Unix lets directories be synced, elsewhere this does
+  /// nothing: on Windows a rename is as durable as the filesystem makes it.
+  pub fn with_dir_sync(mut self, sync_dir: bool) -> Self {
+    self.sync_dir = sync_dir;
+    self
+  }
+
+  /// Path of the log file
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  // kvs.log -> kvs.log.comp for the first scratch log, kvs.log.comp-1 for one opened while that's in use, ...
+  fn scratch_name(&self, scratch: usize) -> OsString {
+    if scratch == 0 {
+      suffixed_name(&self.path, ".comp")
+    } else {
+      suffixed_name(&self.path, &format!(".comp-{}", scratch))
+    }
+  }
+
+  fn hint_path(&self) -> PathBuf {
+    hint_path(&self.path)
+  }
+
+  // number of times replacing the log synced its directory since the storage was opened, see `with_dir_sync`
+  #[cfg(feature = "test-utils")]
+  pub(crate) fn dir_syncs(&self) -> u64 {
+    self.dir_syncs
+  }
+
+  // a reader of the log like `open_reader`'s, which only needs `&self`
+  pub(crate) fn reader(&self) -> io::Result<Self> {
+    // a handle of its own, a cloned one would share the position appends seek
+    Ok(Self {
+      path: self.path.clone(),
+      file: File::open(&self.path)?,
+      map: None,
+      scratch_dir: None,
+      sync_dir: true,
+      dir_syncs: 0,
+      scratches: BTreeSet::new(),
+      scratch: None,
+    })
+  }
+
+  // drop the current mapping, the next read maps the file again
+  fn unmap(&mut self) {
+    if let Some(map) = self.map.as_mut() {
+      *map = None;
+    }
+  }
+}
+
+impl Drop for FileStorage {
+  // a scratch log dropped without replacing the log, e.g. by a compaction that gave up, isn't left behind
+  fn drop(&mut self) {
+    if self.scratch.is_some() {
+      fs::remove_file(&self.path).ok();
+    }
+  }
+}
+
+// the file name of the log with `suffix` appended, for the files kept along with it
+//
+// The whole file name is kept, extension and all, so logs whose names only differ in their extension don't share
+// those files.
+fn suffixed_name(log_path: &Path, suffix: &str) -> OsString {
+  let mut name = log_path.file_name().unwrap_or_default().to_owned();
+  name.push(suffix);
+  name
+}
+
+// kvs.log -> kvs.log.hint
+pub(crate) fn hint_path(log_path: &Path) -> PathBuf {
+  log_path.with_file_name(suffixed_name(log_path, ".hint"))
+}
+
+impl LogStorage for FileStorage {
+  fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    self.file.seek(SeekFrom::Start(offset))?;
+    self.file.read(buf)
+  }
+
+  fn append(&mut self, bytes: &[u8]) -> io::Result<u64> {
+    let pos = self.file.seek(SeekFrom::End(0))?;
+    self.file.write_all(bytes)?;
+    Ok(pos)
+  }
+
+  fn truncate(&mut self, len: u64) -> io::Result<()> {
+    self.unmap();
+    self.file.set_len(len)
+  }
+
+  fn sync(&mut self) -> io::Result<()> {
+    self.file.sync_all()
+  }
+
+  fn len(&self) -> io::Result<u64> {
+    Ok(self.file.metadata()?.len())
+  }
+
+  fn slice_at(&mut self, offset: u64) -> io::Result<Option<&[u8]>> {
+    let len = match &self.map {
+      None => return Ok(None),
+      Some(map) => map.as_ref().map_or(0, |map| map.len() as u64),
+    };
+
+    // records are appended, so anything past the mapping was written after it was made
+    if offset >= len {
+      if self.len()? == 0 {
+        return Ok(None);
+      }
+      // Safety: the file is only ever appended to through this handle while mapped,
+      // truncate and replace unmap it first.
+      self.map = Some(Some(unsafe { Mmap::map(&self.file)? }));
+    }
+
+    match &self.map {
+      Some(Some(map)) => {
+        let start = cmp::min(offset, map.len() as u64) as usize;
+        Ok(Some(&map[start..]))
+      }
+      _ => Ok(None),
+    }
+  }
+
+  fn reload(&mut self) -> io::Result<()> {
+    self.unmap();
+    Ok(())
+  }
+
+  fn load_hint(&mut self) -> io::Result<Option<Vec<u8>>> {
+    let hint_path = self.hint_path();
+    let hint_modified = match fs::metadata(&hint_path) {
+      Ok(metadata) => metadata.modified(),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e),
+    };
+
+    // a hint older than the log can't describe it, nor can one we can't date
+    match (hint_modified, self.file.metadata()?.modified()) {
+      (Ok(hint_modified), Ok(log_modified)) if hint_modified >= log_modified => {}
+      _ => return Ok(None),
+    }
+
+    fs::read(hint_path).map(Some)
+  }
+
+  fn store_hint(&mut self, hint: &[u8]) -> io::Result<()> {
+    // write then rename, so a crash never leaves a half written hint
+    let hint_path = self.hint_path();
+    let tmp_path = self.path.with_file_name(suffixed_name(&self.path, ".hint.tmp"));
+    let mut tmp_file = File::create(&tmp_path)?;
+    tmp_file.write_all(hint)?;
+    tmp_file.sync_all()?;
+    drop(tmp_file);
+
+    fs::rename(tmp_path, hint_path)
+  }
+
+  fn file_path(&self) -> Option<&Path> {
+    Some(&self.path)
+  }
+
+  fn set_dir_sync(&mut self, sync_dir: bool) {
+    self.sync_dir = sync_dir;
+  }
+
+  fn open_reader(&mut self) -> io::Result<Option<Self>> {
+    self.reader().map(Some)
+  }
+
+  fn open_scratch(&mut self) -> io::Result<Self> {
+    // the lowest number not in use, one left behind by a crash is truncated like any other
+    let scratch = (0..)
+      .find(|scratch| !self.scratches.contains(scratch))
+      .unwrap_or_default();
+    let path = match &self.scratch_dir {
+      Some(dir) => dir.join(self.scratch_name(scratch)),
+      None => self.path.with_file_name(self.scratch_name(scratch)),
+    };
+
+    let file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(true)
+      .open(&path)?;
+    // a scratch log dropped rather than replacing the log keeps its number taken, while its file is removed
+    self.scratches.insert(scratch);
+
+    Ok(Self {
+      path,
+      file,
+      map: None,
+      scratch_dir: None,
+      sync_dir: true,
+      dir_syncs: 0,
+      scratches: BTreeSet::new(),
+      scratch: Some(scratch),
+    })
+  }
+
+  fn replace(&mut self, mut scratch: Self) -> io::Result<()> {
+    // taken first, so dropping the scratch log leaves its file be
+    if let Some(number) = scratch.scratch.take() {
+      self.scratches.remove(&number);
+    }
+    let path = scratch.path.clone();
+    drop(scratch);
+    self.unmap();
+
+    // move (rename) the scratch log and reopen it
+    if self.scratch_dir.is_none() {
+      fs::rename(path, &self.path)?;
+    } else {
+      match fs::rename(&path, &self.path) {
+        // renames can't cross filesystems, copy next to the log first and rename that instead
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+          let staged_path = self.path.with_file_name(path.file_name().unwrap_or_default());
+          fs::copy(&path, &staged_path)?;
+          File::open(&staged_path)?.sync_all()?;
+          fs::rename(staged_path, &self.path)?;
+          fs::remove_file(path)?;
+        }
+        result => result?,
+      }
+    }
+    if self.sync_dir {
+      sync_dir(self.path.parent().unwrap_or_else(|| Path::new("")))?;
+      self.dir_syncs += 1;
+    }
+    self.file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+
+    Ok(())
+  }
+}
+
+// make the renames within a directory durable
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+  // a relative log path in the current directory has an empty parent
+  let dir = if dir.as_os_str().is_empty() {
+    Path::new(".")
+  } else {
+    dir
+  };
+  File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+  Ok(())
+}
+
+/// MemoryStorage keeps the log in an in-memory buffer, nothing is written to disk
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+  buf: Vec<u8>,
+}
+
+impl LogStorage for MemoryStorage {
+  fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    let start = cmp::min(offset, self.buf.len() as u64) as usize;
+    let n = cmp::min(buf.len(), self.buf.len() - start);
+    buf[..n].copy_from_slice(&self.buf[start..start + n]);
+    Ok(n)
+  }
+
+  fn append(&mut self, bytes: &[u8]) -> io::Result<u64> {
+    let pos = self.buf.len() as u64;
+    self.buf.extend_from_slice(bytes);
+    Ok(pos)
+  }
+
+  fn truncate(&mut self, len: u64) -> io::Result<()> {
+    self.buf.resize(len as usize, 0);
+    Ok(())
+  }
+
+  fn sync(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+
+  fn len(&self) -> io::Result<u64> {
+    Ok(self.buf.len() as u64)
+  }
+
+  fn open_reader(&mut self) -> io::Result<Option<Self>> {
+    // a copy of the log as it is, which is all a reader needs
+    Ok(Some(Self { buf: self.buf.clone() }))
+  }
+
+  fn open_scratch(&mut self) -> io::Result<Self> {
+    Ok(Self::default())
+  }
+
+  fn replace(&mut self, scratch: Self) -> io::Result<()> {
+    *self = scratch;
+    Ok(())
+  }
+}
+
+// Read + Seek adapter over a LogStorage, so the log can be decoded as a stream
+pub(crate) struct StorageReader<S> {
+  pub(crate) storage: S,
+  pos: u64,
+}
+
+impl<S> StorageReader<S> {
+  pub(crate) fn new(storage: S) -> Self {
+    Self { storage, pos: 0 }
+  }
+}
+
+impl<S: LogStorage> Read for StorageReader<S> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let n = self.storage.read_at(self.pos, buf)?;
+    self.pos += n as u64;
+    Ok(n)
+  }
+}
+
+impl<S: LogStorage> Seek for StorageReader<S> {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    let (base, offset) = match pos {
+      SeekFrom::Start(offset) => (offset, 0),
+      SeekFrom::End(offset) => (self.storage.len()?, offset),
+      SeekFrom::Current(offset) => (self.pos, offset),
+    };
+
+    let new_pos = if offset >= 0 {
+      base.checked_add(offset as u64)
+    } else {
+      base.checked_sub(offset.unsigned_abs())
+    };
+
+    match new_pos {
+      Some(new_pos) => {
+        self.pos = new_pos;
+        Ok(new_pos)
+      }
+      None => Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "invalid seek to a negative or overflowing position",
+      )),
+    }
+  }
+}
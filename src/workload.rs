@@ -0,0 +1,113 @@
+//! Reproducible workloads for benchmarks: seeded key and value generation
+
+/// How keys of a workload are picked out of its key space
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyDistribution {
+  /// Every key is equally likely
+  Uniform,
+  /// The key of rank `k` (from 1) is picked with probability proportional to `1 / k^s`, `s` being the exponent,
+  /// so a few hot keys get most of the picks
+  Zipfian(f64),
+}
+
+/// Options for a Workload
+#[derive(Debug, Clone)]
+pub struct WorkloadOptions {
+  /// Number of distinct keys, named `key0` to `key{keys - 1}`
+  pub keys: u64,
+  /// How keys are picked, with `Zipfian` `key0` is the hottest
+  pub distribution: KeyDistribution,
+  /// Length of generated values in bytes
+  pub value_size: usize,
+}
+
+impl Default for WorkloadOptions {
+  fn default() -> Self {
+    Self {
+      keys: 1000,
+      distribution: KeyDistribution::Uniform,
+      value_size: 64,
+    }
+  }
+}
+
+/// Workload generates keys and values from a seed, the same seed always giving the same sequence
+pub struct Workload {
+  options: WorkloadOptions,
+  state: u64,
+  // cumulative weights of the key ranks for a zipfian distribution, empty for a uniform one
+  cumulative: Vec<f64>,
+}
+
+impl Workload {
+  /// Creates a workload with the default options
+  pub fn new(seed: u64) -> Self {
+    Self::with_options(seed, WorkloadOptions::default())
+  }
+
+  /// Creates a workload with the given options
+  pub fn with_options(seed: u64, options: WorkloadOptions) -> Self {
+    let cumulative = match options.distribution {
+      KeyDistribution::Uniform => Vec::new(),
+      KeyDistribution::Zipfian(exponent) => (1..=options.keys.max(1))
+        .scan(0.0, |total, rank| {
+          *total += 1.0 / (rank as f64).powf(exponent);
+          Some(*total)
+        })
+        .collect(),
+    };
+
+    Self {
+      options,
+      state: seed,
+      cumulative,
+    }
+  }
+
+  /// Pick the index of the next key, below `keys`
+  pub fn next_key_index(&mut self) -> u64 {
+    let keys = self.options.keys.max(1);
+    match self.cumulative.last().copied() {
+      None => self.next_u64() % keys,
+      Some(total) => {
+        let target = self.next_f64() * total;
+        let rank = self.cumulative.partition_point(|&weight| weight <= target);
+        (rank as u64).min(keys - 1)
+      }
+    }
+  }
+
+  /// Pick the next key
+  pub fn next_key(&mut self) -> String {
+    format!("key{}", self.next_key_index())
+  }
+
+  /// Generate the next value, `value_size` lowercase letters
+  pub fn next_value(&mut self) -> String {
+    let mut value = String::with_capacity(self.options.value_size);
+    while value.len() < self.options.value_size {
+      let mut bits = self.next_u64();
+      // 12 letters out of each number, 5 bits apiece
+      for _ in 0..12.min(self.options.value_size - value.len()) {
+        value.push((b'a' + (bits & 31) as u8 % 26) as char);
+        bits >>= 5;
+      }
+    }
+
+    value
+  }
+
+  // splitmix64, small and good enough for picking keys
+  fn next_u64(&mut self) -> u64 {
+    self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = self.state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+  }
+
+  // uniform in [0, 1)
+  fn next_f64(&mut self) -> f64 {
+    (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+  }
+}
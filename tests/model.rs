@@ -0,0 +1,72 @@
+// Property tests checking KvStore against a HashMap as the model of what it should hold
+
+use kvs::{KvStore, KvStoreError, KvStoreOptions};
+use proptest::prelude::*;
+use std::collections::HashMap;
+use tempfile::TempDir;
+
+#[derive(Debug, Clone)]
+enum Op {
+  Set(String, String),
+  Get(String),
+  Remove(String),
+  Reopen,
+}
+
+fn op() -> impl Strategy<Value = Op> {
+  // few keys, so operations keep hitting keys set or removed before
+  let key = "[a-d]{1,2}";
+  prop_oneof![
+    (key, "[a-z]{0,8}").prop_map(|(key, value)| Op::Set(key, value)),
+    key.prop_map(Op::Get),
+    key.prop_map(Op::Remove),
+    Just(Op::Reopen),
+  ]
+}
+
+// a low threshold, so long sequences compact along the way
+fn options() -> KvStoreOptions {
+  KvStoreOptions {
+    compaction_threshold: 8,
+    ..KvStoreOptions::default()
+  }
+}
+
+proptest! {
+  // Any sequence of sets, gets, removes and reopens should leave the store holding what the model holds,
+  // after every step as well as once reopened at the end.
+  #[test]
+  fn store_matches_model(ops in prop::collection::vec(op(), 1..100)) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), options()).unwrap();
+    let mut model = HashMap::new();
+
+    for op in ops {
+      match op {
+        Op::Set(key, value) => {
+          store.set(key.clone(), value.clone()).unwrap();
+          model.insert(key, value);
+        }
+        Op::Get(key) => prop_assert_eq!(store.get(key.clone()).unwrap(), model.get(&key).cloned()),
+        Op::Remove(key) => match (store.remove(key.clone()), model.remove(&key)) {
+          (Ok(()), Some(_)) | (Err(KvStoreError::RmKeyNotFoundError), None) => {}
+          (removed, expected) => prop_assert!(false, "removing {:?} gave {:?}, model had {:?}", key, removed, expected),
+        },
+        Op::Reopen => {
+          drop(store);
+          store = KvStore::open_with_options(temp_dir.path(), options()).unwrap();
+        }
+      }
+
+      let contents: HashMap<String, String> = store.scan_prefix("").unwrap().into_iter().collect();
+      prop_assert_eq!(&contents, &model);
+    }
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    prop_assert_eq!(store.len().unwrap(), model.len());
+    for (key, value) in &model {
+      prop_assert_eq!(store.get(key.clone()).unwrap(), Some(value.clone()));
+    }
+  }
+}
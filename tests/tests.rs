@@ -1,11 +1,30 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::client::{ClientOptions, ClientPool, KvsClient};
+use kvs::compactor::BackgroundCompactor;
+use kvs::resp::{self, RespCommand, RespValue};
+use kvs::server::{self, KvsServer, ServerConfig, ServerOptions};
+use kvs::sharded::ShardedKvStore;
+use kvs::{
+  AuditSink, ConflictPolicy, DumpFormat, FileStorage, IndexMode, KeyEvent, KvCommand, KvStore, KvStoreError,
+  KvStoreOptions, LogStorage, MemoryStorage, RecoveryMode, Result, SyncPolicy, WriteBatch,
+};
+use predicates::boolean::PredicateBooleanExt;
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::cell::{Cell, RefCell};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
 use std::process::Command;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+#[path = "../benches/common/mod.rs"]
+mod common;
+
 // `kvs` with no args should exit with a non-zero code.
 #[test]
 fn cli_no_args() {
@@ -117,6 +136,216 @@ fn cli_rm_stored() -> Result<()> {
   Ok(())
 }
 
+// Exit codes: get of a missing key succeeds, rm of a missing key fails, set and rm succeed.
+#[test]
+fn cli_exit_codes() {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["get", "key1"])
+    .current_dir(&temp_dir)
+    .assert()
+    .code(0);
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["rm", "key1"])
+    .current_dir(&temp_dir)
+    .assert()
+    .code(1);
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["set", "key1", "value1"])
+    .current_dir(&temp_dir)
+    .assert()
+    .code(0);
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["rm", "key1"])
+    .current_dir(&temp_dir)
+    .assert()
+    .code(0);
+}
+
+// `kvs stats` should report the live key count, also as JSON.
+#[test]
+fn cli_stats() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.set("key2".to_owned(), "value2".to_owned())?;
+  store.set("key1".to_owned(), "value3".to_owned())?;
+  drop(store);
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["stats"])
+    .current_dir(&temp_dir)
+    .assert()
+    .success()
+    .stdout(contains("live keys: 2").and(contains("garbage: 1")));
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["stats", "--json"])
+    .current_dir(&temp_dir)
+    .assert()
+    .success()
+    .stdout(contains("\"live_keys\":2"));
+
+  Ok(())
+}
+
+// `kvs compact` should shrink a log with garbage, and do nothing when there's none.
+#[test]
+fn cli_compact() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  for iter in 0..10 {
+    store.set("key1".to_owned(), format!("value{}", iter))?;
+  }
+  drop(store);
+
+  let log_size = || {
+    std::fs::metadata(temp_dir.path().join("kvs.log"))
+      .expect("unable to read log metadata")
+      .len()
+  };
+  let before = log_size();
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["compact"])
+    .current_dir(&temp_dir)
+    .assert()
+    .success()
+    .stdout(contains("reclaimed"));
+  assert!(log_size() < before);
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["compact"])
+    .current_dir(&temp_dir)
+    .assert()
+    .success()
+    .stdout(eq("nothing to reclaim").trim());
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["get", "key1"])
+    .current_dir(&temp_dir)
+    .assert()
+    .success()
+    .stdout(eq("value9").trim());
+
+  Ok(())
+}
+
+// `kvs scan` should print key-value pairs, optionally filtered by prefix, with newlines escaped.
+#[test]
+fn cli_scan() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("user:1".to_owned(), "alice".to_owned())?;
+  store.set("user:2".to_owned(), "bob\nsmith".to_owned())?;
+  store.set("item:1".to_owned(), "apple".to_owned())?;
+  drop(store);
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["scan"])
+    .current_dir(&temp_dir)
+    .assert()
+    .success()
+    .stdout(eq("item:1\tapple\nuser:1\talice\nuser:2\tbob\\nsmith\n"));
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["scan", "--prefix", "user:"])
+    .current_dir(&temp_dir)
+    .assert()
+    .success()
+    .stdout(contains("user:1\talice").and(contains("item:1").not()));
+
+  Ok(())
+}
+
+// `kvs set <KEY> --stdin` should store standard input as the value.
+#[test]
+fn cli_set_stdin() {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  assert_cmd::Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["set", "key1", "--stdin"])
+    .current_dir(&temp_dir)
+    .write_stdin("line1\nline2\n\nline4")
+    .assert()
+    .success()
+    .stdout(is_empty());
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["get", "key1"])
+    .current_dir(&temp_dir)
+    .assert()
+    .success()
+    .stdout(eq("line1\nline2\n\nline4\n"));
+
+  // A value can't be given both ways.
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["set", "key1", "value1", "--stdin"])
+    .current_dir(&temp_dir)
+    .assert()
+    .failure();
+}
+
+// `kvs rm --prefix P` should remove matching keys, and only remove everything with --all.
+#[test]
+fn cli_rm_prefix() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("user:1".to_owned(), "alice".to_owned())?;
+  store.set("user:2".to_owned(), "bob".to_owned())?;
+  store.set("item:1".to_owned(), "apple".to_owned())?;
+  drop(store);
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["rm", "--prefix", "user:"])
+    .current_dir(&temp_dir)
+    .assert()
+    .success()
+    .stdout(eq("removed 2 keys").trim());
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["rm", "--prefix", ""])
+    .current_dir(&temp_dir)
+    .assert()
+    .failure();
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["get", "item:1"])
+    .current_dir(&temp_dir)
+    .assert()
+    .success()
+    .stdout(eq("apple").trim());
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["rm", "--all"])
+    .current_dir(&temp_dir)
+    .assert()
+    .success()
+    .stdout(eq("removed 1 keys").trim());
+
+  Ok(())
+}
+
 #[test]
 fn cli_invalid_get() {
   Command::cargo_bin("kvs").unwrap().args(&["get"]).assert().failure();
@@ -284,3 +513,4622 @@ fn compaction() -> Result<()> {
 
   panic!("No compaction detected");
 }
+
+// The compaction benchmark setup should produce the requested garbage ratio.
+#[test]
+fn bench_setup_garbage_ratio() -> Result<()> {
+  for &ratio in &[0.25, 0.5, 0.75] {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = common::build_store(temp_dir.path(), 1000, ratio)?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 1000);
+    let actual = stats.garbage as f64 / (stats.live_keys as f64 + stats.garbage as f64);
+    assert!(
+      (actual - ratio).abs() < 0.01,
+      "expected ratio {}, got {}",
+      ratio,
+      actual
+    );
+  }
+
+  Ok(())
+}
+
+// Forced compaction should reclaim garbage and keep the data.
+#[test]
+fn forced_compaction() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = common::build_store(temp_dir.path(), 100, 0.5)?;
+
+  let before = store.stats()?;
+  let reclaimed = store.compact()?;
+  let after = store.stats()?;
+  assert!(reclaimed > 0);
+  assert_eq!(after.garbage, 0);
+  assert_eq!(after.log_bytes, before.log_bytes - reclaimed);
+
+  for key_id in 0..100 {
+    assert!(store.get(format!("key{}", key_id))?.is_some());
+  }
+
+  Ok(())
+}
+
+// In-memory store should support the full API without touching disk.
+#[test]
+fn in_memory_set_get_remove() -> Result<()> {
+  let mut store = KvStore::open_in_memory()?;
+
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.set("key2".to_owned(), "value2".to_owned())?;
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+  assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+  store.set("key1".to_owned(), "value3".to_owned())?;
+  assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+  store.remove("key2".to_owned())?;
+  assert_eq!(store.get("key2".to_owned())?, None);
+  assert!(store.remove("key2".to_owned()).is_err());
+
+  Ok(())
+}
+
+// In-memory store should compact like a file-backed one.
+#[test]
+fn in_memory_compaction() -> Result<()> {
+  let mut store = KvStore::open_in_memory()?;
+
+  let mut max_size = 0;
+  for iter in 0..100 {
+    for key_id in 0..100 {
+      store.set(format!("key{}", key_id), format!("{}", iter))?;
+    }
+
+    let size = store.stats()?.log_bytes;
+    if size > max_size {
+      max_size = size;
+      continue;
+    }
+    // Compaction triggered.
+
+    for key_id in 0..100 {
+      assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("{}", iter)));
+    }
+    return Ok(());
+  }
+
+  panic!("No compaction detected");
+}
+
+// A trivial Vec<u8> backed storage, shared through Rc so a store can be reopened on the same buffer.
+#[derive(Default, Clone)]
+struct VecStorage(Rc<RefCell<Vec<u8>>>);
+
+impl LogStorage for VecStorage {
+  fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    let log = self.0.borrow();
+    let start = (offset as usize).min(log.len());
+    let n = buf.len().min(log.len() - start);
+    buf[..n].copy_from_slice(&log[start..start + n]);
+    Ok(n)
+  }
+
+  fn append(&mut self, bytes: &[u8]) -> io::Result<u64> {
+    let mut log = self.0.borrow_mut();
+    let pos = log.len() as u64;
+    log.extend_from_slice(bytes);
+    Ok(pos)
+  }
+
+  fn truncate(&mut self, len: u64) -> io::Result<()> {
+    self.0.borrow_mut().resize(len as usize, 0);
+    Ok(())
+  }
+
+  fn sync(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+
+  fn len(&self) -> io::Result<u64> {
+    Ok(self.0.borrow().len() as u64)
+  }
+
+  fn open_scratch(&mut self) -> io::Result<Self> {
+    Ok(Self::default())
+  }
+
+  fn replace(&mut self, scratch: Self) -> io::Result<()> {
+    let new_log = scratch.0.borrow().clone();
+    *self.0.borrow_mut() = new_log;
+    Ok(())
+  }
+}
+
+// Custom storage should support the full API including replay and compaction.
+#[test]
+fn custom_storage() -> Result<()> {
+  let storage = VecStorage::default();
+  let mut store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::default())?;
+
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.set("key2".to_owned(), "value2".to_owned())?;
+  store.set("key1".to_owned(), "value3".to_owned())?;
+  store.remove("key2".to_owned())?;
+  assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+  assert_eq!(store.get("key2".to_owned())?, None);
+
+  // Open from the same buffer again and check replayed data.
+  drop(store);
+  let mut store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::default())?;
+  assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+  assert_eq!(store.get("key2".to_owned())?, None);
+
+  let before = storage.len()?;
+  assert!(store.compact()? > 0);
+  assert!(storage.len()? < before);
+  assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+  Ok(())
+}
+
+// Memory-mapped reads should see new writes and survive compaction remapping the log.
+#[test]
+fn mmap_reads() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let options = KvStoreOptions {
+    mmap_reads: true,
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+  for iter in 0..10 {
+    for key_id in 0..100 {
+      let key = format!("key{}", key_id);
+      store.set(key.clone(), format!("{}", iter))?;
+      assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+    }
+  }
+
+  store.compact()?;
+  for key_id in 0..100 {
+    assert_eq!(store.get(format!("key{}", key_id))?, Some("9".to_owned()));
+  }
+  store.set("key0".to_owned(), "new".to_owned())?;
+  assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+
+  // Open from disk again and check persistent data.
+  drop(store);
+  let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+  assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+  assert_eq!(store.get("key99".to_owned())?, Some("9".to_owned()));
+
+  Ok(())
+}
+
+// Opening after a flush should load the index hint instead of replaying the log.
+#[test]
+fn open_with_hint_skips_replay() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  for key_id in 0..50 {
+    store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+  }
+  store.flush()?;
+  drop(store);
+
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.stats()?.replayed_records, 0);
+  assert_eq!(store.get("key10".to_owned())?, Some("value10".to_owned()));
+
+  // Writes after the hint must still be found on the next open.
+  store.set("key50".to_owned(), "value50".to_owned())?;
+  drop(store);
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.stats()?.live_keys, 51);
+  assert_eq!(store.get("key50".to_owned())?, Some("value50".to_owned()));
+  store.flush()?;
+  drop(store);
+
+  // A broken hint falls back to replaying the log.
+  std::fs::write(temp_dir.path().join("kvs.log.hint"), b"not a hint")?;
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.stats()?.replayed_records, 51);
+  assert_eq!(store.get("key50".to_owned())?, Some("value50".to_owned()));
+
+  Ok(())
+}
+
+// Dropping a store should flush it, so the next open finds everything without replaying.
+#[test]
+fn drop_flushes() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.set("key2".to_owned(), "value2".to_owned())?;
+  drop(store);
+
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.stats()?.replayed_records, 0);
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+  assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+  store.set("key3".to_owned(), "value3".to_owned())?;
+  store.close()?;
+
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.stats()?.replayed_records, 0);
+  assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+  Ok(())
+}
+
+fn namespaced(namespace: &str) -> KvStoreOptions {
+  KvStoreOptions {
+    namespace: Some(namespace.to_owned()),
+    ..KvStoreOptions::default()
+  }
+}
+
+// Stores in different namespaces of one directory should not see each other.
+#[test]
+fn namespaces_are_isolated() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store_a = KvStore::open_with_options(temp_dir.path(), namespaced("a"))?;
+  let mut store_b = KvStore::open_with_options(temp_dir.path(), namespaced("b"))?;
+
+  store_a.set("key1".to_owned(), "value-a".to_owned())?;
+  store_b.set("key1".to_owned(), "value-b".to_owned())?;
+  store_b.set("key2".to_owned(), "value-b".to_owned())?;
+  assert_eq!(store_a.get("key1".to_owned())?, Some("value-a".to_owned()));
+  assert_eq!(store_a.get("key2".to_owned())?, None);
+
+  store_b.compact()?;
+  assert_eq!(store_b.get("key1".to_owned())?, Some("value-b".to_owned()));
+  assert_eq!(store_a.get("key1".to_owned())?, Some("value-a".to_owned()));
+
+  // Open from disk again and check persistent data.
+  drop(store_a);
+  drop(store_b);
+  let mut store_a = KvStore::open_with_options(temp_dir.path(), namespaced("a"))?;
+  assert_eq!(store_a.get("key1".to_owned())?, Some("value-a".to_owned()));
+  assert!(temp_dir.path().join("b").join("kvs.log").exists());
+
+  // The default store is separate from all namespaces.
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("key1".to_owned())?, None);
+
+  Ok(())
+}
+
+#[test]
+fn invalid_namespace() {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  for namespace in &["", "..", "a/b"] {
+    assert!(KvStore::open_with_options(temp_dir.path(), namespaced(namespace)).is_err());
+  }
+}
+
+// Bulk loading should end up in the same state as setting each entry in turn.
+#[test]
+fn bulk_load_matches_set() -> Result<()> {
+  let entries = || (0..1000).map(|i| (format!("key{}", i % 700), format!("value{}", i)));
+  let options = KvStoreOptions {
+    compaction_threshold: u64::MAX,
+    ..KvStoreOptions::default()
+  };
+
+  let set_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut set_store = KvStore::open_with_options(set_dir.path(), options.clone())?;
+  for (key, value) in entries() {
+    set_store.set(key, value)?;
+  }
+
+  let bulk_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut bulk_store = KvStore::open_with_options(bulk_dir.path(), options.clone())?;
+  bulk_store.bulk_load(entries())?;
+
+  assert_eq!(bulk_store.stats()?, set_store.stats()?);
+  for key_id in 0..700 {
+    let key = format!("key{}", key_id);
+    assert_eq!(bulk_store.get(key.clone())?, set_store.get(key)?);
+  }
+
+  // Open from disk again and check persistent data.
+  drop(bulk_store);
+  let mut bulk_store = KvStore::open_with_options(bulk_dir.path(), options)?;
+  for key_id in 0..700 {
+    let key = format!("key{}", key_id);
+    assert_eq!(bulk_store.get(key.clone())?, set_store.get(key)?);
+  }
+
+  Ok(())
+}
+
+// Prefix scan should return matching live pairs sorted by key.
+#[test]
+fn scan_prefix() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("user:2".to_owned(), "bob".to_owned())?;
+  store.set("user:1".to_owned(), "alice".to_owned())?;
+  store.set("user:3".to_owned(), "carol".to_owned())?;
+  store.set("item:1".to_owned(), "apple".to_owned())?;
+  store.remove("user:3".to_owned())?;
+
+  assert_eq!(
+    store.scan_prefix("user:")?,
+    vec![
+      ("user:1".to_owned(), "alice".to_owned()),
+      ("user:2".to_owned(), "bob".to_owned()),
+    ]
+  );
+  assert_eq!(store.scan_prefix("")?.len(), 3);
+  assert!(store.scan_prefix("none")?.is_empty());
+
+  Ok(())
+}
+
+// Removing by prefix should only remove matching keys, an empty prefix removes all.
+#[test]
+fn remove_prefix() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("user:1".to_owned(), "alice".to_owned())?;
+  store.set("user:2".to_owned(), "bob".to_owned())?;
+  store.set("item:1".to_owned(), "apple".to_owned())?;
+  store.set("item:2".to_owned(), "banana".to_owned())?;
+
+  assert_eq!(store.remove_prefix("user:")?, 2);
+  assert_eq!(store.get("user:1".to_owned())?, None);
+  assert_eq!(store.get("item:1".to_owned())?, Some("apple".to_owned()));
+  assert_eq!(store.remove_prefix("user:")?, 0);
+
+  // Open from disk again and check persistent data.
+  drop(store);
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("user:2".to_owned())?, None);
+  assert_eq!(store.get("item:2".to_owned())?, Some("banana".to_owned()));
+
+  assert_eq!(store.remove_prefix("")?, 2);
+  assert_eq!(store.stats()?.live_keys, 0);
+
+  Ok(())
+}
+
+// Expired keys should read as missing and be swept, without touching keys set again after expiring.
+#[test]
+fn purge_expired() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set_with_ttl("short1".to_owned(), "value".to_owned(), Duration::from_millis(50))?;
+  store.set_with_ttl("short2".to_owned(), "value".to_owned(), Duration::from_millis(50))?;
+  store.set_with_ttl("long".to_owned(), "value".to_owned(), Duration::from_secs(3600))?;
+  store.set("plain".to_owned(), "value".to_owned())?;
+
+  thread::sleep(Duration::from_millis(100));
+  assert_eq!(store.get("short1".to_owned())?, None);
+  assert_eq!(store.stats()?.live_keys, 2);
+
+  let garbage = store.stats()?.garbage;
+  assert_eq!(store.purge_expired()?, 2);
+  assert_eq!(store.stats()?.garbage, garbage + 2);
+  assert_eq!(store.purge_expired()?, 0);
+  assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+
+  // set again after expiring, the sweep must keep it
+  store.set_with_ttl("short2".to_owned(), "value".to_owned(), Duration::from_millis(50))?;
+  thread::sleep(Duration::from_millis(100));
+  store.set("short2".to_owned(), "again".to_owned())?;
+  assert_eq!(store.purge_expired()?, 0);
+  assert_eq!(store.get("short2".to_owned())?, Some("again".to_owned()));
+
+  // Compact, then open from disk again and check persistent data.
+  store.compact()?;
+  drop(store);
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("short1".to_owned())?, None);
+  assert_eq!(store.get("short2".to_owned())?, Some("again".to_owned()));
+  assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+  assert_eq!(store.get("plain".to_owned())?, Some("value".to_owned()));
+  assert_eq!(store.stats()?.live_keys, 3);
+
+  Ok(())
+}
+
+// Values over `max_value_bytes` should be rejected before reaching the log.
+#[test]
+fn max_value_bytes() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let options = KvStoreOptions {
+    max_value_bytes: Some(16),
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+  store.set("key1".to_owned(), "x".repeat(16))?;
+  assert_eq!(store.get("key1".to_owned())?, Some("x".repeat(16)));
+
+  let log_bytes = store.stats()?.log_bytes;
+  assert!(matches!(
+    store.set("key2".to_owned(), "x".repeat(17)),
+    Err(KvStoreError::ValueTooLarge(17))
+  ));
+  assert!(store
+    .set_with_ttl("key2".to_owned(), "x".repeat(17), Duration::from_secs(60))
+    .is_err());
+  assert_eq!(store.get("key2".to_owned())?, None);
+  assert_eq!(store.stats()?.log_bytes, log_bytes);
+
+  Ok(())
+}
+
+// Empty keys should always be rejected, long keys only once `max_key_bytes` is set.
+#[test]
+fn invalid_key() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert!(matches!(
+    store.set("".to_owned(), "value".to_owned()),
+    Err(KvStoreError::InvalidKey(_))
+  ));
+  assert!(store
+    .bulk_load(vec![("".to_owned(), "value".to_owned())].into_iter())
+    .is_err());
+  store.set("k".repeat(1024), "value".to_owned())?;
+  drop(store);
+
+  let options = KvStoreOptions {
+    max_key_bytes: Some(8),
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+  store.set("k".repeat(8), "value".to_owned())?;
+  assert!(matches!(
+    store.set("k".repeat(9), "value".to_owned()),
+    Err(KvStoreError::InvalidKey(_))
+  ));
+  assert_eq!(store.get("k".repeat(8))?, Some("value".to_owned()));
+  assert_eq!(store.get("k".repeat(9))?, None);
+
+  Ok(())
+}
+
+// Every error kind should render as a one line RESP error carrying its description.
+#[test]
+fn resp_errors() {
+  let errors = vec![
+    KvStoreError::IoError(io::Error::other("disk on fire")),
+    KvStoreError::ReplayError("bad record\r\nat offset 3".to_owned()),
+    KvStoreError::EncodeError(rmp_serde::encode::Error::Syntax("boom".to_owned())),
+    KvStoreError::DecodeError(rmp_serde::decode::Error::Syntax("boom".to_owned())),
+    KvStoreError::RmKeyNotFoundError,
+    KvStoreError::GetError,
+    KvStoreError::CompactionError,
+    KvStoreError::InvalidNamespaceError("a/b".to_owned()),
+    KvStoreError::InvalidLogFileNameError("../kvs.log".to_owned()),
+    KvStoreError::ValueTooLarge(17),
+    KvStoreError::InvalidKey("key is empty".to_owned()),
+    KvStoreError::DumpError("unsupported value".to_owned()),
+    KvStoreError::KeyNotFoundError,
+    KvStoreError::TypedValueError(serde_json::from_str::<i32>("nope").unwrap_err()),
+    KvStoreError::CompressionError("bad\nframe".to_owned()),
+    KvStoreError::PreconditionFailedError("key \"key1\" exists".to_owned()),
+    KvStoreError::TaskError("task panicked".to_owned()),
+    KvStoreError::ServerError("ERR unknown command 'foo'".to_owned()),
+    KvStoreError::ProtocolError("unexpected reply Integer(3)".to_owned()),
+    KvStoreError::MergeConflictError("key1".to_owned()),
+    KvStoreError::InvalidPatternError("invalid range pattern".to_owned()),
+    KvStoreError::ImmutableOptionError("log_file_name".to_owned()),
+    KvStoreError::ShardCountError("opened with 2 shards, the directory has 4".to_owned()),
+    KvStoreError::InvalidAddressError("'4000': expected host:port, e.g. 127.0.0.1:4000".to_owned()),
+    KvStoreError::LogFull(4096),
+    KvStoreError::UnsupportedFormatError("version 3".to_owned()),
+  ];
+
+  for e in errors {
+    let reply = e.to_resp_error();
+    assert!(reply.starts_with("-ERR "), "{:?}", reply);
+    assert!(reply.ends_with("\r\n"), "{:?}", reply);
+    assert!(!reply[..reply.len() - 2].contains(['\r', '\n']), "{:?}", reply);
+    assert!(reply.len() > "-ERR \r\n".len(), "{:?}", reply);
+  }
+
+  assert_eq!(
+    KvStoreError::RmKeyNotFoundError.to_resp_error(),
+    "-ERR Key not found when attempting remove\r\n"
+  );
+  assert_eq!(
+    KvStoreError::ReplayError("bad\nrecord".to_owned()).to_resp_error(),
+    "-ERR Replay existing log file failed: bad record\r\n"
+  );
+
+  let e: io::Error = KvStoreError::IoError(io::Error::new(io::ErrorKind::NotFound, "gone")).into();
+  assert_eq!(e.kind(), io::ErrorKind::NotFound);
+  let e: io::Error = KvStoreError::GetError.into();
+  assert_eq!(e.kind(), io::ErrorKind::Other);
+}
+
+// Repairing a log corrupted mid-record should keep every record before the corruption.
+#[test]
+fn repair_corrupted_log() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.set("key2".to_owned(), "value2".to_owned())?;
+  let valid_len = store.stats()?.log_bytes;
+  store.set("key3".to_owned(), "value3".to_owned())?;
+  let total_len = store.stats()?.log_bytes;
+  drop(store);
+
+  // 0xc1 is never used as a MessagePack marker
+  let log_path = temp_dir.path().join("kvs.log");
+  let mut bytes = std::fs::read(&log_path)?;
+  bytes[valid_len as usize + 1] = 0xc1;
+  std::fs::write(&log_path, bytes)?;
+
+  let report = KvStore::repair(temp_dir.path())?;
+  assert_eq!(report.salvaged_records, 2);
+  assert_eq!(report.discarded_bytes, total_len - valid_len);
+
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+  assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+  assert_eq!(store.get("key3".to_owned())?, None);
+  store.set("key4".to_owned(), "value4".to_owned())?;
+  drop(store);
+
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+  // a healthy log is left alone
+  drop(store);
+  let report = KvStore::repair(temp_dir.path())?;
+  assert_eq!(report.salvaged_records, 3);
+  assert_eq!(report.discarded_bytes, 0);
+
+  Ok(())
+}
+
+// With `compact_on_open` off, opening a store over the threshold should leave its log alone.
+#[test]
+fn skip_compact_on_open() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let options = KvStoreOptions {
+    compaction_threshold: u64::MAX,
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+  for iter in 0..10 {
+    store.set("key".to_owned(), format!("{}", iter))?;
+  }
+  let stats = store.stats()?;
+  drop(store);
+
+  let options = KvStoreOptions {
+    compaction_threshold: 5,
+    compact_on_open: false,
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+  assert_eq!(store.stats()?.garbage, stats.garbage);
+  assert_eq!(store.stats()?.log_bytes, stats.log_bytes);
+  assert_eq!(store.get("key".to_owned())?, Some("9".to_owned()));
+  assert!(!temp_dir.path().join("kvs.log.comp").exists());
+  drop(store);
+
+  let options = KvStoreOptions {
+    compaction_threshold: 5,
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+  assert_eq!(store.stats()?.garbage, 0);
+  assert!(store.stats()?.log_bytes < stats.log_bytes);
+
+  Ok(())
+}
+
+// A VecStorage that counts how many times the log is synced.
+#[derive(Default, Clone)]
+struct SyncCountingStorage {
+  log: VecStorage,
+  syncs: Rc<Cell<usize>>,
+}
+
+impl LogStorage for SyncCountingStorage {
+  fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    self.log.read_at(offset, buf)
+  }
+
+  fn append(&mut self, bytes: &[u8]) -> io::Result<u64> {
+    self.log.append(bytes)
+  }
+
+  fn truncate(&mut self, len: u64) -> io::Result<()> {
+    self.log.truncate(len)
+  }
+
+  fn sync(&mut self) -> io::Result<()> {
+    self.syncs.set(self.syncs.get() + 1);
+    Ok(())
+  }
+
+  fn len(&self) -> io::Result<u64> {
+    self.log.len()
+  }
+
+  fn open_scratch(&mut self) -> io::Result<Self> {
+    Ok(Self {
+      log: self.log.open_scratch()?,
+      syncs: self.syncs.clone(),
+    })
+  }
+
+  fn replace(&mut self, scratch: Self) -> io::Result<()> {
+    self.log.replace(scratch.log)
+  }
+}
+
+// Group commit and batches should take far fewer syncs than syncing every write, and keep every write.
+#[test]
+fn group_commit() -> Result<()> {
+  let count_syncs = |sync_policy, batched: bool| -> Result<usize> {
+    let storage = SyncCountingStorage::default();
+    let options = KvStoreOptions {
+      sync_policy,
+      ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_storage(storage.clone(), options)?;
+
+    if batched {
+      let mut batch = WriteBatch::new();
+      for key_id in 0..100 {
+        batch.set(format!("key{}", key_id), format!("value{}", key_id));
+      }
+      store.write(batch)?;
+    } else {
+      for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+      }
+    }
+    let syncs = storage.syncs.get();
+
+    // Open from the same buffer again and check every write made it in order.
+    drop(store);
+    let mut store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::default())?;
+    for key_id in 0..100 {
+      assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+
+    Ok(syncs)
+  };
+
+  assert_eq!(count_syncs(SyncPolicy::OnFlush, false)?, 0);
+  assert_eq!(count_syncs(SyncPolicy::EveryWrite, false)?, 100);
+  assert_eq!(count_syncs(SyncPolicy::Group(10), false)?, 10);
+  assert_eq!(count_syncs(SyncPolicy::EveryWrite, true)?, 1);
+
+  Ok(())
+}
+
+// A batch should apply all of its operations in order, or none of them at all.
+#[test]
+fn write_batch() -> Result<()> {
+  let mut store = KvStore::open_in_memory()?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+
+  let mut batch = WriteBatch::new();
+  batch
+    .set("key2".to_owned(), "value2".to_owned())
+    .remove("key1".to_owned())
+    .set("key3".to_owned(), "value3".to_owned())
+    .remove("key3".to_owned());
+  assert_eq!(batch.len(), 4);
+  store.write(batch)?;
+  assert_eq!(store.get("key1".to_owned())?, None);
+  assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+  assert_eq!(store.get("key3".to_owned())?, None);
+
+  let log_bytes = store.stats()?.log_bytes;
+  let mut batch = WriteBatch::new();
+  batch
+    .set("key4".to_owned(), "value4".to_owned())
+    .remove("key1".to_owned());
+  assert!(matches!(store.write(batch), Err(KvStoreError::RmKeyNotFoundError)));
+  assert_eq!(store.get("key4".to_owned())?, None);
+  assert_eq!(store.stats()?.log_bytes, log_bytes);
+
+  Ok(())
+}
+
+// A batch should only apply if all of its preconditions hold against the state before it.
+#[test]
+fn conditional_write_batch() -> Result<()> {
+  let mut store = KvStore::open_in_memory()?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+
+  let mut batch = WriteBatch::new();
+  batch
+    .compare_and_swap("key1".to_owned(), Some("value1".to_owned()), "value2".to_owned())
+    .compare_and_swap("key2".to_owned(), None, "value3".to_owned())
+    .require_exists("key1".to_owned())
+    .remove("key1".to_owned());
+  assert_eq!(batch.len(), 3);
+  store.write(batch)?;
+  assert_eq!(store.get("key1".to_owned())?, None);
+  assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+
+  // one failed precondition aborts the whole batch, before anything is written
+  let log_bytes = store.stats()?.log_bytes;
+  let mut batches = vec![WriteBatch::new(), WriteBatch::new(), WriteBatch::new()];
+  batches[0].set("key3".to_owned(), "value4".to_owned()).compare_and_swap(
+    "key2".to_owned(),
+    Some("stale".to_owned()),
+    "value5".to_owned(),
+  );
+  batches[1]
+    .set("key3".to_owned(), "value4".to_owned())
+    .require_absent("key2".to_owned());
+  batches[2]
+    .require_exists("key1".to_owned())
+    .set("key3".to_owned(), "value4".to_owned());
+  for batch in batches {
+    assert!(matches!(
+      store.write(batch),
+      Err(KvStoreError::PreconditionFailedError(_))
+    ));
+  }
+  assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+  assert_eq!(store.get("key3".to_owned())?, None);
+  assert_eq!(store.stats()?.log_bytes, log_bytes);
+
+  Ok(())
+}
+
+// `get_or` should fall back without inserting, `get_or_insert_with` should persist the computed value.
+#[test]
+fn get_or_default() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+
+  assert_eq!(store.get_or("key1".to_owned(), "default")?, "value1");
+  assert_eq!(store.get_or("key2".to_owned(), "default")?, "default");
+  assert_eq!(store.get("key2".to_owned())?, None);
+
+  let value = store.get_or_insert_with("key1".to_owned(), || panic!("key1 exists"))?;
+  assert_eq!(value, "value1");
+  let value = store.get_or_insert_with("key2".to_owned(), || "computed".to_owned())?;
+  assert_eq!(value, "computed");
+
+  // Open from disk again and check persistent data.
+  drop(store);
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("key2".to_owned())?, Some("computed".to_owned()));
+
+  Ok(())
+}
+
+// Dumping to JSON should give back exactly the live key-value pairs.
+#[test]
+fn dump_json() -> Result<()> {
+  let mut store = KvStore::open_in_memory()?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.set("key2".to_owned(), "value2".to_owned())?;
+  store.set("key3".to_owned(), "value3".to_owned())?;
+  store.remove("key2".to_owned())?;
+
+  let dump = store.dump(DumpFormat::Json)?;
+  let entries: std::collections::HashMap<String, String> = serde_json::from_str(&dump).expect("dump is not valid JSON");
+  assert_eq!(entries.len(), 2);
+  assert_eq!(entries["key1"], "value1");
+  assert_eq!(entries["key3"], "value3");
+
+  let dump = store.dump(DumpFormat::Ron)?;
+  assert!(dump.contains("\"key1\": \"value1\""), "{}", dump);
+
+  Ok(())
+}
+
+// Renaming should move the value, overwrite the destination and fail on a missing source.
+#[test]
+fn rename_key() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.set("key2".to_owned(), "value2".to_owned())?;
+
+  store.rename("key1".to_owned(), "key3".to_owned())?;
+  assert_eq!(store.get("key1".to_owned())?, None);
+  assert_eq!(store.get("key3".to_owned())?, Some("value1".to_owned()));
+
+  store.rename("key3".to_owned(), "key2".to_owned())?;
+  assert_eq!(store.get("key3".to_owned())?, None);
+  assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+
+  assert!(matches!(
+    store.rename("key1".to_owned(), "key4".to_owned()),
+    Err(KvStoreError::RmKeyNotFoundError)
+  ));
+  assert_eq!(store.get("key4".to_owned())?, None);
+
+  store.rename("key2".to_owned(), "key2".to_owned())?;
+  assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+
+  // Open from disk again and check persistent data.
+  drop(store);
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+  assert_eq!(store.stats()?.live_keys, 1);
+
+  Ok(())
+}
+
+// Copying should keep the source, honor `overwrite` and fail on a missing source.
+#[test]
+fn copy_key() -> Result<()> {
+  let mut store = KvStore::open_in_memory()?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.set("key2".to_owned(), "value2".to_owned())?;
+
+  assert!(store.copy("key1".to_owned(), "key3".to_owned(), false)?);
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+  assert_eq!(store.get("key3".to_owned())?, Some("value1".to_owned()));
+
+  assert!(!store.copy("key1".to_owned(), "key2".to_owned(), false)?);
+  assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+  assert!(store.copy("key1".to_owned(), "key2".to_owned(), true)?);
+  assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+
+  assert!(matches!(
+    store.copy("key4".to_owned(), "key5".to_owned(), true),
+    Err(KvStoreError::KeyNotFoundError)
+  ));
+  assert_eq!(store.get("key5".to_owned())?, None);
+
+  Ok(())
+}
+
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+struct Point {
+  x: i32,
+  y: i32,
+  label: String,
+}
+
+// Typed values should come back as they were stored, and fail to read as the wrong type.
+#[test]
+fn typed_values() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  let point = Point {
+    x: 1,
+    y: -2,
+    label: "origin-ish".to_owned(),
+  };
+  store.set_typed("point".to_owned(), &point)?;
+  store.set_typed("numbers".to_owned(), &vec![1, 2, 3])?;
+
+  // Open from disk again and check persistent data.
+  drop(store);
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get_typed::<Point>("point".to_owned())?, Some(point));
+  assert_eq!(store.get_typed::<Vec<i32>>("numbers".to_owned())?, Some(vec![1, 2, 3]));
+  assert_eq!(store.get_typed::<Vec<i32>>("missing".to_owned())?, None);
+  assert!(matches!(
+    store.get_typed::<Vec<i32>>("point".to_owned()),
+    Err(KvStoreError::TypedValueError(_))
+  ));
+
+  Ok(())
+}
+
+// Subscribers should receive events for matching keys, in the order changes were applied.
+#[test]
+fn subscribe_events() -> Result<()> {
+  let mut store = KvStore::open_in_memory()?;
+  let events = store.subscribe("user:".to_owned());
+  let everything = store.subscribe("".to_owned());
+
+  store.set("user:1".to_owned(), "alice".to_owned())?;
+  store.set("item:1".to_owned(), "apple".to_owned())?;
+  store.set("user:1".to_owned(), "bob".to_owned())?;
+  store.remove("user:1".to_owned())?;
+  store.rename("item:1".to_owned(), "user:2".to_owned())?;
+
+  let received: Vec<KeyEvent> = events.try_iter().collect();
+  assert_eq!(
+    received,
+    vec![
+      KeyEvent::Set {
+        key: "user:1".to_owned()
+      },
+      KeyEvent::Set {
+        key: "user:1".to_owned()
+      },
+      KeyEvent::Removed {
+        key: "user:1".to_owned()
+      },
+      KeyEvent::Set {
+        key: "user:2".to_owned()
+      },
+    ]
+  );
+  assert_eq!(everything.try_iter().count(), 6);
+
+  // a dropped receiver just stops getting events
+  drop(events);
+  store.set("user:3".to_owned(), "carol".to_owned())?;
+  assert_eq!(
+    everything.try_iter().collect::<Vec<_>>(),
+    vec![KeyEvent::Set {
+      key: "user:3".to_owned()
+    }]
+  );
+
+  Ok(())
+}
+
+// Compacting into another directory should swap the log in all the same.
+#[test]
+fn compaction_dir() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let scratch_dir = TempDir::new().expect("unable to create temporary scratch directory");
+  check_compaction_dir(&temp_dir, &scratch_dir)
+}
+
+// A compaction directory on another filesystem should have the compacted log copied over, where the host
+// has one to offer.
+#[cfg(unix)]
+#[test]
+fn compaction_dir_cross_device() -> Result<()> {
+  use std::os::unix::fs::MetadataExt;
+
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let scratch_dir = match TempDir::new_in("/dev/shm") {
+    Ok(scratch_dir) => scratch_dir,
+    // nothing to cross to on this host
+    Err(_) => return Ok(()),
+  };
+  if std::fs::metadata(temp_dir.path())?.dev() == std::fs::metadata(scratch_dir.path())?.dev() {
+    return Ok(());
+  }
+  check_compaction_dir(&temp_dir, &scratch_dir)
+}
+
+// Compact a store into `scratch_dir` and check the compacted log replaced the store's.
+fn check_compaction_dir(temp_dir: &TempDir, scratch_dir: &TempDir) -> Result<()> {
+  let options = KvStoreOptions {
+    compaction_threshold: u64::MAX,
+    compaction_dir: Some(scratch_dir.path().to_owned()),
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+  for iter in 0..10 {
+    for key_id in 0..10 {
+      store.set(format!("key{}", key_id), format!("{}", iter))?;
+    }
+  }
+
+  assert!(store.compact()? > 0);
+  assert!(!scratch_dir.path().join("kvs.log.comp").exists());
+  assert!(!temp_dir.path().join("kvs.log.comp").exists());
+  store.set("key0".to_owned(), "after".to_owned())?;
+
+  // Open from disk again and check persistent data.
+  drop(store);
+  let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+  assert_eq!(store.stats()?.garbage, 1);
+  assert_eq!(store.get("key0".to_owned())?, Some("after".to_owned()));
+  assert_eq!(store.get("key9".to_owned())?, Some("9".to_owned()));
+
+  Ok(())
+}
+
+// The log paths should point into the directory the store was opened with.
+#[test]
+fn log_paths() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.log_dir(), temp_dir.path());
+  assert_eq!(store.log_path(), temp_dir.path().join("kvs.log"));
+  assert!(store.log_path().exists());
+  drop(store);
+
+  let store = KvStore::open_with_options(temp_dir.path(), namespaced("users"))?;
+  assert_eq!(store.log_dir(), temp_dir.path().join("users"));
+  assert_eq!(store.log_path(), temp_dir.path().join("users").join("kvs.log"));
+
+  Ok(())
+}
+
+// Verifying should report a healthy log as such, flag a corrupted one and never create or change files.
+#[test]
+fn verify_log() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  assert!(KvStore::verify(temp_dir.path()).is_err());
+  assert!(!temp_dir.path().join("kvs.log").exists());
+
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.set("key2".to_owned(), "value2".to_owned())?;
+  store.set("key1".to_owned(), "value3".to_owned())?;
+  store.remove("key2".to_owned())?;
+  let valid_len = store.stats()?.log_bytes;
+  store.set("key3".to_owned(), "value3".to_owned())?;
+  drop(store);
+
+  let report = KvStore::verify(temp_dir.path())?;
+  assert!(report.is_ok());
+  assert_eq!(report.records, 5);
+  assert_eq!(report.live_keys, 2);
+  assert_eq!(report.garbage, 2);
+
+  // 0xc1 is never used as a MessagePack marker
+  let log_path = temp_dir.path().join("kvs.log");
+  let mut bytes = std::fs::read(&log_path)?;
+  bytes[valid_len as usize + 1] = 0xc1;
+  std::fs::write(&log_path, &bytes)?;
+  let files_before: Vec<_> = WalkDir::new(temp_dir.path())
+    .into_iter()
+    .filter_map(|e| e.ok())
+    .map(|e| e.into_path())
+    .collect();
+
+  let report = KvStore::verify(temp_dir.path())?;
+  assert!(!report.is_ok());
+  assert_eq!(report.records, 4);
+  assert_eq!(report.corrupted_at, Some(valid_len));
+
+  let files_after: Vec<_> = WalkDir::new(temp_dir.path())
+    .into_iter()
+    .filter_map(|e| e.ok())
+    .map(|e| e.into_path())
+    .collect();
+  assert_eq!(files_before, files_after);
+  assert_eq!(std::fs::read(&log_path)?, bytes);
+
+  Ok(())
+}
+
+// Start a server over a fresh in-memory store, returns the address it listens on.
+fn start_server() -> SocketAddr {
+  start_server_with_options(ServerOptions::default())
+}
+
+fn start_server_with_options(options: ServerOptions) -> SocketAddr {
+  let listener = TcpListener::bind("127.0.0.1:0").expect("unable to bind");
+  let addr = listener.local_addr().unwrap();
+  let server = KvsServer::with_options(KvStore::open_in_memory().unwrap(), options);
+  thread::spawn(move || server.serve(listener));
+  addr
+}
+
+// Send a single command to the server, returns the raw reply.
+fn request(addr: SocketAddr, args: Vec<&str>) -> String {
+  let mut stream = TcpStream::connect(addr).expect("unable to connect");
+  resp::write_command(&RespCommand::new(args), &mut stream).unwrap();
+  stream.shutdown(Shutdown::Write).unwrap();
+
+  let mut reply = String::new();
+  stream.read_to_string(&mut reply).unwrap();
+  reply
+}
+
+// Command names should be case-insensitive, arguments should not.
+#[test]
+fn server_case_insensitive_commands() {
+  assert_eq!(RespCommand::new(vec!["gEt", "Key"]).name(), "GET");
+  assert_eq!(RespCommand::new(vec!["gEt", "Key"]).args(), ["Key"]);
+
+  let addr = start_server();
+  assert_eq!(request(addr, vec!["set", "Key", "value1"]), "+OK\r\n");
+  assert_eq!(request(addr, vec!["GET", "Key"]), "$6\r\nvalue1\r\n");
+  assert_eq!(request(addr, vec!["Get", "Key"]), "$6\r\nvalue1\r\n");
+  assert_eq!(request(addr, vec!["get", "key"]), "$-1\r\n");
+  assert_eq!(request(addr, vec!["SeT", "Key", "value2"]), "+OK\r\n");
+  assert_eq!(request(addr, vec!["gEt", "Key"]), "$6\r\nvalue2\r\n");
+  assert_eq!(request(addr, vec!["del", "Key", "missing"]), ":1\r\n");
+  assert_eq!(request(addr, vec!["GET", "Key"]), "$-1\r\n");
+
+  assert!(request(addr, vec!["get"]).starts_with("-ERR wrong number of arguments"));
+  assert!(request(addr, vec!["nope"]).starts_with("-ERR unknown command 'nope'"));
+}
+
+// EXISTS should count the given keys that are present.
+#[test]
+fn server_exists() {
+  let addr = start_server();
+  assert_eq!(request(addr, vec!["SET", "key1", "value1"]), "+OK\r\n");
+  assert_eq!(request(addr, vec!["SET", "key2", "value2"]), "+OK\r\n");
+
+  assert_eq!(request(addr, vec!["EXISTS", "key1"]), ":1\r\n");
+  assert_eq!(request(addr, vec!["EXISTS", "missing"]), ":0\r\n");
+  assert_eq!(request(addr, vec!["EXISTS", "key1", "missing", "key2"]), ":2\r\n");
+  assert_eq!(request(addr, vec!["exists", "key1", "key1"]), ":2\r\n");
+  assert!(request(addr, vec!["EXISTS"]).starts_with("-ERR wrong number of arguments"));
+}
+
+// DBSIZE should reply with the number of live keys.
+#[test]
+fn server_dbsize() {
+  let addr = start_server();
+  assert_eq!(request(addr, vec!["DBSIZE"]), ":0\r\n");
+
+  for key_id in 0..10 {
+    let key = format!("key{}", key_id);
+    assert_eq!(request(addr, vec!["SET", &key, "value"]), "+OK\r\n");
+  }
+  assert_eq!(request(addr, vec!["SET", "key0", "again"]), "+OK\r\n");
+  assert_eq!(request(addr, vec!["DBSIZE"]), ":10\r\n");
+
+  assert_eq!(request(addr, vec!["DEL", "key0"]), ":1\r\n");
+  assert_eq!(request(addr, vec!["dbsize"]), ":9\r\n");
+  assert!(request(addr, vec!["DBSIZE", "key0"]).starts_with("-ERR wrong number of arguments"));
+}
+
+// FLUSHALL should empty the store, but only when the server allows it.
+#[test]
+fn server_flushall() {
+  let addr = start_server();
+  assert_eq!(request(addr, vec!["SET", "key1", "value1"]), "+OK\r\n");
+  assert!(request(addr, vec!["FLUSHALL"]).starts_with("-ERR FLUSHALL is disabled"));
+  assert_eq!(request(addr, vec!["DBSIZE"]), ":1\r\n");
+
+  let addr = start_server_with_options(ServerOptions {
+    allow_flush: true,
+    ..ServerOptions::default()
+  });
+  assert_eq!(request(addr, vec!["SET", "key1", "value1"]), "+OK\r\n");
+  assert_eq!(request(addr, vec!["SET", "key2", "value2"]), "+OK\r\n");
+  assert_eq!(request(addr, vec!["FLUSHALL"]), "+OK\r\n");
+  assert_eq!(request(addr, vec!["DBSIZE"]), ":0\r\n");
+  assert_eq!(request(addr, vec!["GET", "key1"]), "$-1\r\n");
+  assert_eq!(request(addr, vec!["SET", "key1", "value3"]), "+OK\r\n");
+  assert_eq!(request(addr, vec!["GET", "key1"]), "$6\r\nvalue3\r\n");
+}
+
+// Clearing should remove every key and persist as an empty log.
+#[test]
+fn clear_store() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.set("key2".to_owned(), "value2".to_owned())?;
+  store.clear()?;
+  assert!(store.is_empty()?);
+  assert_eq!(store.stats()?.log_bytes, kvs::format::HEADER_LEN as u64);
+
+  // Open from disk again and check persistent data.
+  drop(store);
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("key1".to_owned())?, None);
+  assert!(store.is_empty()?);
+
+  Ok(())
+}
+
+// A connection should stay open for many commands, and closing it shouldn't stop the server.
+#[test]
+fn server_keep_alive() {
+  let addr = start_server();
+  let mut stream = TcpStream::connect(addr).expect("unable to connect");
+  let mut reader = BufReader::new(stream.try_clone().unwrap());
+  let mut send = |args: Vec<&str>, reply_lines: usize| {
+    resp::write_command(&RespCommand::new(args), &mut stream).unwrap();
+    let mut reply = String::new();
+    for _ in 0..reply_lines {
+      reader.read_line(&mut reply).unwrap();
+    }
+    reply
+  };
+
+  assert_eq!(send(vec!["SET", "key1", "value1"], 1), "+OK\r\n");
+  assert_eq!(send(vec!["GET", "key1"], 2), "$6\r\nvalue1\r\n");
+  assert_eq!(send(vec!["DBSIZE"], 1), ":1\r\n");
+
+  drop(stream);
+  drop(reader);
+  assert_eq!(request(addr, vec!["GET", "key1"]), "$6\r\nvalue1\r\n");
+}
+
+// A client stalling in the middle of a command should be dropped once the read timeout passes.
+#[test]
+fn server_read_timeout() {
+  let addr = start_server_with_options(ServerOptions {
+    read_timeout: Some(Duration::from_millis(200)),
+    ..ServerOptions::default()
+  });
+
+  let mut stream = TcpStream::connect(addr).expect("unable to connect");
+  stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+  io::Write::write_all(&mut stream, b"*2\r\n$3\r\nGE").unwrap();
+
+  // the server closes the connection without replying
+  let mut reply = Vec::new();
+  stream
+    .read_to_end(&mut reply)
+    .expect("server did not drop the stalled connection");
+  assert!(reply.is_empty());
+
+  assert_eq!(request(addr, vec!["DBSIZE"]), ":0\r\n");
+}
+
+// Connections over the limit should be rejected with an error, and served again once a slot frees up.
+#[test]
+fn server_max_connections() {
+  let addr = start_server_with_options(ServerOptions {
+    max_connections: 2,
+    ..ServerOptions::default()
+  });
+
+  // make sure each connection is being served before opening the next
+  let connect = || {
+    let mut stream = TcpStream::connect(addr).expect("unable to connect");
+    resp::write_command(&RespCommand::new(vec!["DBSIZE"]), &mut stream).unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut reply = String::new();
+    reader.read_line(&mut reply).unwrap();
+    (reader, reply)
+  };
+  let (first, reply) = connect();
+  assert_eq!(reply, ":0\r\n");
+  let (_second, reply) = connect();
+  assert_eq!(reply, ":0\r\n");
+
+  let mut rejected = TcpStream::connect(addr).expect("unable to connect");
+  let mut reply = String::new();
+  rejected.read_to_string(&mut reply).unwrap();
+  assert_eq!(reply, "-ERR max number of clients reached\r\n");
+
+  // the freed slot is given back once the server notices the disconnect
+  drop(first);
+  let mut reply = String::new();
+  for _ in 0..50 {
+    reply = request(addr, vec!["DBSIZE"]);
+    if reply == ":0\r\n" {
+      break;
+    }
+    thread::sleep(Duration::from_millis(20));
+  }
+  assert_eq!(reply, ":0\r\n");
+}
+
+// Compressible values should take less space in the log, and logs mixing compressed
+// and plain values should read back whatever the store is opened with.
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_values() -> Result<()> {
+  use kvs::Compression;
+
+  let lz4 = || KvStoreOptions {
+    compression: Compression::Lz4,
+    ..KvStoreOptions::default()
+  };
+  let compressible = "all work and no play ".repeat(200);
+  // printable but random looking, so LZ4 finds nothing to match
+  let mut seed = 12345u32;
+  let incompressible: String = (0..4096)
+    .map(|_| {
+      seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+      (b'!' + (seed >> 16) as u8 % 94) as char
+    })
+    .collect();
+
+  let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+  let lz4_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut plain = KvStore::open(plain_dir.path())?;
+  let mut compressed = KvStore::open_with_options(lz4_dir.path(), lz4())?;
+
+  plain.set("key1".to_owned(), compressible.clone())?;
+  compressed.set("key1".to_owned(), compressible.clone())?;
+  assert_eq!(compressed.get("key1".to_owned())?, Some(compressible.clone()));
+  let plain_len = plain.stats()?.log_bytes;
+  let compressed_len = compressed.stats()?.log_bytes;
+  assert!(compressed_len < plain_len / 4, "{} vs {}", compressed_len, plain_len);
+
+  // values that don't shrink are stored as they are
+  plain.set("key2".to_owned(), incompressible.clone())?;
+  compressed.set("key2".to_owned(), incompressible.clone())?;
+  assert_eq!(compressed.get("key2".to_owned())?, Some(incompressible.clone()));
+  assert_eq!(
+    compressed.stats()?.log_bytes - compressed_len,
+    plain.stats()?.log_bytes - plain_len
+  );
+  drop(plain);
+  drop(compressed);
+
+  // reopened without compression, compressed values still read back
+  let mut store = KvStore::open(lz4_dir.path())?;
+  store.set("key3".to_owned(), compressible.clone())?;
+  assert_eq!(store.get("key1".to_owned())?, Some(compressible.clone()));
+  drop(store);
+
+  // and the other way around, plain values read back with compression on
+  let mut store = KvStore::open_with_options(plain_dir.path(), lz4())?;
+  assert_eq!(store.get("key1".to_owned())?, Some(compressible.clone()));
+  assert_eq!(store.get("key2".to_owned())?, Some(incompressible));
+  drop(store);
+
+  // compaction keeps both kinds of records readable
+  let mut store = KvStore::open_with_options(lz4_dir.path(), lz4())?;
+  store.compact()?;
+  assert_eq!(store.get("key1".to_owned())?, Some(compressible.clone()));
+  assert_eq!(store.get("key3".to_owned())?, Some(compressible));
+
+  Ok(())
+}
+
+// Cached values should be served without reading the log, dropped once their key is written,
+// and evicted least recently used first.
+#[test]
+fn value_cache() -> Result<()> {
+  let storage = VecStorage::default();
+  let options = KvStoreOptions {
+    // room for two of the entries below
+    value_cache_bytes: Some(24),
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_storage(storage.clone(), options)?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.set("key2".to_owned(), "value2".to_owned())?;
+  store.set("key3".to_owned(), "value3".to_owned())?;
+
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+  assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+  // key1 is now more recently used than key2, so caching key3 evicts key2
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+  assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+  // wipe the log behind the store's back, only cached values can still be read
+  storage.0.borrow_mut().iter_mut().for_each(|byte| *byte = 0);
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+  assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+  assert!(store.get("key2".to_owned()).is_err());
+
+  // writes drop the cached value, so the new one comes from the log
+  store.set("key1".to_owned(), "value4".to_owned())?;
+  assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+  store.remove("key3".to_owned())?;
+  assert_eq!(store.get("key3".to_owned())?, None);
+
+  Ok(())
+}
+
+// The async engine should serve concurrent tasks on a Tokio runtime from one shared store.
+#[cfg(feature = "async")]
+#[test]
+fn async_engine() -> Result<()> {
+  use kvs::async_engine::{AsyncKvStore, AsyncKvsEngine};
+
+  let runtime = tokio::runtime::Runtime::new()?;
+  let engine = AsyncKvStore::new(KvStore::open_in_memory()?);
+
+  runtime.block_on(async {
+    let tasks: Vec<_> = (0..20)
+      .map(|key_id| {
+        let engine = engine.clone();
+        tokio::spawn(async move { engine.set(format!("key{}", key_id), format!("value{}", key_id)).await })
+      })
+      .collect();
+    for task in tasks {
+      task.await.expect("task panicked")?;
+    }
+
+    for key_id in 0..20 {
+      assert_eq!(
+        engine.get(format!("key{}", key_id)).await?,
+        Some(format!("value{}", key_id))
+      );
+    }
+    engine.remove("key0".to_owned()).await?;
+    assert_eq!(engine.get("key0".to_owned()).await?, None);
+    assert!(matches!(
+      engine.remove("key0".to_owned()).await,
+      Err(KvStoreError::RmKeyNotFoundError)
+    ));
+
+    Ok(())
+  })
+}
+
+// Many threads should share a small pool of connections, and connections
+// the server closed while idle should be replaced without failing requests.
+#[test]
+fn client_pool() -> Result<()> {
+  let addr = start_server_with_options(ServerOptions {
+    read_timeout: Some(Duration::from_millis(200)),
+    ..ServerOptions::default()
+  });
+  let pool = ClientPool::new(addr, 3)?;
+
+  thread::scope(|scope| {
+    let threads: Vec<_> = (0..8)
+      .map(|thread_id| {
+        let pool = &pool;
+        scope.spawn(move || -> Result<()> {
+          for key_id in 0..25 {
+            let key = format!("key{}-{}", thread_id, key_id);
+            let mut client = pool.get()?;
+            client.set(key.clone(), format!("value{}", key_id))?;
+            assert_eq!(client.get(key)?, Some(format!("value{}", key_id)));
+          }
+          Ok(())
+        })
+      })
+      .collect();
+    threads
+      .into_iter()
+      .try_for_each(|thread| thread.join().expect("client thread panicked"))
+  })?;
+
+  let mut client = pool.get()?;
+  assert_eq!(client.get("key7-24".to_owned())?, Some("value24".to_owned()));
+  client.remove("key7-24".to_owned())?;
+  assert!(matches!(
+    client.remove("key7-24".to_owned()),
+    Err(KvStoreError::RmKeyNotFoundError)
+  ));
+  drop(client);
+
+  // the server drops idle connections after the read timeout
+  thread::sleep(Duration::from_millis(500));
+  let mut client = pool.get()?;
+  assert_eq!(client.get("key0-0".to_owned())?, Some("value0".to_owned()));
+
+  Ok(())
+}
+
+// A server that closes the first `dropped` connections it accepts, then serves the rest.
+fn start_flaky_server(dropped: usize) -> SocketAddr {
+  let listener = TcpListener::bind("127.0.0.1:0").expect("unable to bind");
+  let addr = listener.local_addr().unwrap();
+  thread::spawn(move || {
+    for _ in 0..dropped {
+      drop(listener.accept());
+    }
+    KvsServer::new(KvStore::open_in_memory().unwrap()).serve(listener)
+  });
+  addr
+}
+
+// Gets and sets should be retried on a new connection, removes only if asked to.
+#[test]
+fn client_retry() -> Result<()> {
+  let options = ClientOptions {
+    initial_backoff: Duration::from_millis(10),
+    ..ClientOptions::default()
+  };
+
+  let mut client = KvsClient::connect_with_options(start_flaky_server(1), options.clone())?;
+  client.set("key1".to_owned(), "value1".to_owned())?;
+  assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+  let mut client = KvsClient::connect_with_options(start_flaky_server(1), options.clone())?;
+  assert!(matches!(
+    client.remove("key1".to_owned()),
+    Err(KvStoreError::IoError(_))
+  ));
+  // the failed request dropped the connection, the next one reaches the server
+  assert!(matches!(
+    client.remove("key1".to_owned()),
+    Err(KvStoreError::RmKeyNotFoundError)
+  ));
+
+  let retry_remove = ClientOptions {
+    retry_remove: true,
+    ..options.clone()
+  };
+  let mut client = KvsClient::connect_with_options(start_flaky_server(1), retry_remove)?;
+  assert!(matches!(
+    client.remove("key1".to_owned()),
+    Err(KvStoreError::RmKeyNotFoundError)
+  ));
+
+  // once retries run out, the last error is returned
+  let mut client = KvsClient::connect_with_options(start_flaky_server(usize::MAX), options)?;
+  assert!(matches!(client.get("key1".to_owned()), Err(KvStoreError::IoError(_))));
+
+  Ok(())
+}
+
+// The server should speak the same protocol over a Unix domain socket.
+#[cfg(unix)]
+#[test]
+fn server_unix_socket() -> Result<()> {
+  use std::os::unix::net::{UnixListener, UnixStream};
+
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let path = temp_dir.path().join("kvs.sock");
+  let listener = UnixListener::bind(&path)?;
+  let server = KvsServer::new(KvStore::open_in_memory()?);
+  thread::spawn(move || server.serve_unix(listener));
+
+  let mut stream = UnixStream::connect(&path)?;
+  resp::write_command(&RespCommand::new(vec!["SET", "key1", "value1"]), &mut stream)?;
+  resp::write_command(&RespCommand::new(vec!["GET", "key1"]), &mut stream)?;
+  stream.shutdown(Shutdown::Write)?;
+  let mut reply = String::new();
+  stream.read_to_string(&mut reply)?;
+  assert_eq!(reply, "+OK\r\n$6\r\nvalue1\r\n");
+
+  Ok(())
+}
+
+// Reloading the index should pick up records appended or cut off behind the store's back.
+#[test]
+fn reload_index() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let options = KvStoreOptions {
+    compact_on_open: false,
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+  let log_len = store.stats()?.log_bytes;
+
+  let mut other = KvStore::open_with_options(temp_dir.path(), options)?;
+  other.set("key2".to_owned(), "value2".to_owned())?;
+  other.remove("key1".to_owned())?;
+  drop(other);
+
+  assert_eq!(store.get("key2".to_owned())?, None);
+  store.reload_index()?;
+  assert_eq!(store.get("key1".to_owned())?, None);
+  assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+  assert_eq!(store.stats()?.garbage, 1);
+
+  std::fs::OpenOptions::new()
+    .write(true)
+    .open(store.log_path())?
+    .set_len(log_len)?;
+  store.reload_index()?;
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+  assert_eq!(store.get("key2".to_owned())?, None);
+  assert_eq!(store.stats()?.garbage, 0);
+
+  Ok(())
+}
+
+// Metadata should say when a key was last set and how big its value is, surviving reopens and compaction.
+#[test]
+fn key_metadata() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get_meta("key1".to_owned())?, None);
+
+  let before = SystemTime::now() - Duration::from_millis(1);
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  let meta = store.get_meta("key1".to_owned())?.expect("key1 has metadata");
+  assert_eq!(meta.value_bytes, 6);
+  let first = meta.modified.expect("key1 has a timestamp");
+  assert!(first >= before && first <= SystemTime::now(), "{:?}", first);
+
+  thread::sleep(Duration::from_millis(10));
+  store.set("key1".to_owned(), "longer value".to_owned())?;
+  let meta = store.get_meta("key1".to_owned())?.expect("key1 has metadata");
+  assert_eq!(meta.value_bytes, 12);
+  let second = meta.modified.expect("key1 has a timestamp");
+  assert!(second > first, "{:?} <= {:?}", second, first);
+
+  drop(store);
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.compact()?;
+  assert_eq!(
+    store.get_meta("key1".to_owned())?.and_then(|meta| meta.modified),
+    Some(second)
+  );
+  assert_eq!(store.get("key1".to_owned())?, Some("longer value".to_owned()));
+
+  store.remove("key1".to_owned())?;
+  assert_eq!(store.get_meta("key1".to_owned())?, None);
+
+  Ok(())
+}
+
+// With compaction off the log should keep growing, and its history should include superseded commands.
+#[test]
+fn keep_full_history() -> Result<()> {
+  let options = KvStoreOptions {
+    compaction: false,
+    compaction_threshold: 10,
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_storage(VecStorage::default(), options)?;
+  for iter in 0..50 {
+    store.set("key1".to_owned(), format!("value{}", iter))?;
+  }
+  store.remove("key1".to_owned())?;
+  store.set("key2".to_owned(), "value2".to_owned())?;
+  assert_eq!(store.stats()?.garbage, 50);
+
+  let history = store.replay_history().collect::<Result<Vec<_>>>()?;
+  assert_eq!(history.len(), 52);
+  for (iter, cmd) in history.iter().take(50).enumerate() {
+    assert_eq!(*cmd, KvCommand::Set("key1".to_owned(), format!("value{}", iter)));
+  }
+  assert_eq!(history[50], KvCommand::Rm("key1".to_owned()));
+  assert_eq!(history[51], KvCommand::Set("key2".to_owned(), "value2".to_owned()));
+
+  // walking the history leaves the store as it was
+  assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+  assert_eq!(store.get("key1".to_owned())?, None);
+
+  Ok(())
+}
+
+// Dumping the log should list every record as written, at increasing offsets.
+#[test]
+fn dump_log_records() -> Result<()> {
+  let storage = VecStorage::default();
+  let mut store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::default())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.set("key1".to_owned(), "value2".to_owned())?;
+  store.remove("key1".to_owned())?;
+  store.set_with_ttl("key2".to_owned(), "value3".to_owned(), Duration::from_secs(60))?;
+
+  let records = store.dump_log()?;
+  let offsets: Vec<u64> = records.iter().map(|(offset, _)| *offset).collect();
+  assert_eq!(offsets[0], kvs::format::HEADER_LEN as u64);
+  assert!(offsets.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", offsets);
+  assert!(*offsets.last().unwrap() < store.stats()?.log_bytes);
+
+  // sets are stamped with when they were written, removes aren't
+  let commands: Vec<KvCommand> = records
+    .into_iter()
+    .map(|(_, record)| match record {
+      KvCommand::Timestamped(_, cmd) => *cmd,
+      cmd => cmd,
+    })
+    .collect();
+  assert_eq!(commands.len(), 4);
+  assert_eq!(commands[0], KvCommand::Set("key1".to_owned(), "value1".to_owned()));
+  assert_eq!(commands[1], KvCommand::Set("key1".to_owned(), "value2".to_owned()));
+  assert_eq!(commands[2], KvCommand::Rm("key1".to_owned()));
+  assert!(matches!(&commands[3], KvCommand::SetEx(key, value, _) if key == "key2" && value == "value3"));
+
+  // a log cut off mid-record doesn't dump
+  storage.0.borrow_mut().pop();
+  assert!(matches!(store.dump_log(), Err(KvStoreError::ReplayError(_))));
+
+  Ok(())
+}
+
+// Skipping bad records should recover the records after a corrupted one, which stopping at it loses.
+#[test]
+fn skip_bad_records() -> Result<()> {
+  let storage = VecStorage::default();
+  let mut store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::default())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.set("key2".to_owned(), "value2".to_owned())?;
+  store.set("key3".to_owned(), "value3".to_owned())?;
+  let offsets: Vec<u64> = store.dump_log()?.into_iter().map(|(offset, _)| offset).collect();
+  drop(store);
+
+  // 0xc1 is never used by MessagePack
+  storage.0.borrow_mut()[offsets[1] as usize..offsets[2] as usize]
+    .iter_mut()
+    .for_each(|byte| *byte = 0xc1);
+
+  let mut store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::default())?;
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+  assert_eq!(store.get("key3".to_owned())?, None);
+  drop(store);
+
+  let options = KvStoreOptions {
+    recovery_mode: RecoveryMode::SkipBadRecords,
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_storage(storage, options)?;
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+  assert_eq!(store.get("key2".to_owned())?, None);
+  assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+  assert_eq!(store.len()?, 2);
+
+  Ok(())
+}
+
+// A background compactor should reclaim garbage that writes left behind, and stop cleanly.
+#[test]
+fn background_compaction() -> Result<()> {
+  let options = KvStoreOptions {
+    compaction: false,
+    compaction_threshold: 10,
+    ..KvStoreOptions::default()
+  };
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let store = Arc::new(Mutex::new(KvStore::open_with_options(temp_dir.path(), options)?));
+  let compactor = BackgroundCompactor::start(store.clone(), Duration::from_millis(20));
+
+  for iter in 0..50 {
+    store.lock().unwrap().set("key1".to_owned(), format!("value{}", iter))?;
+  }
+
+  let mut garbage = 0;
+  for _ in 0..100 {
+    garbage = store.lock().unwrap().stats()?.garbage;
+    if garbage == 0 {
+      break;
+    }
+    thread::sleep(Duration::from_millis(20));
+  }
+  assert_eq!(garbage, 0);
+  compactor.shutdown();
+
+  let mut store = store.lock().unwrap();
+  assert_eq!(store.get("key1".to_owned())?, Some("value49".to_owned()));
+
+  Ok(())
+}
+
+// Stores with different log file names should share a directory without seeing each other's keys.
+#[test]
+fn log_file_names() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let named = |name: &str| KvStoreOptions {
+    log_file_name: name.to_owned(),
+    compaction_threshold: 10,
+    ..KvStoreOptions::default()
+  };
+
+  let mut first = KvStore::open_with_options(temp_dir.path(), named("first.log"))?;
+  let mut second = KvStore::open_with_options(temp_dir.path(), named("second.log"))?;
+  assert_eq!(first.log_path(), temp_dir.path().join("first.log"));
+  // overwrite enough to compact both
+  for iter in 0..20 {
+    first.set("key1".to_owned(), format!("first{}", iter))?;
+    second.set("key1".to_owned(), format!("second{}", iter))?;
+  }
+  second.set("key2".to_owned(), "value2".to_owned())?;
+  drop(first);
+  drop(second);
+
+  let mut first = KvStore::open_with_options(temp_dir.path(), named("first.log"))?;
+  let mut second = KvStore::open_with_options(temp_dir.path(), named("second.log"))?;
+  assert_eq!(first.get("key1".to_owned())?, Some("first19".to_owned()));
+  assert_eq!(first.get("key2".to_owned())?, None);
+  assert_eq!(second.get("key1".to_owned())?, Some("second19".to_owned()));
+  assert_eq!(second.get("key2".to_owned())?, Some("value2".to_owned()));
+  assert!(!temp_dir.path().join("kvs.log").exists());
+
+  for name in &["", "../kvs.log", "logs/kvs.log"] {
+    assert!(matches!(
+      KvStore::open_with_options(temp_dir.path(), named(name)),
+      Err(KvStoreError::InvalidLogFileNameError(_))
+    ));
+  }
+
+  // names differing only in their extension don't share hints either
+  let mut log = KvStore::open_with_options(temp_dir.path(), named("data.log"))?;
+  let mut db = KvStore::open_with_options(temp_dir.path(), named("data.db"))?;
+  log.set("key1".to_owned(), "log".to_owned())?;
+  db.set("key1".to_owned(), "db".to_owned())?;
+  db.compact()?;
+  drop(log);
+  drop(db);
+  assert!(temp_dir.path().join("data.log.hint").exists());
+  assert!(temp_dir.path().join("data.db.hint").exists());
+  let mut log = KvStore::open_with_options(temp_dir.path(), named("data.log"))?;
+  assert_eq!(log.get("key1".to_owned())?, Some("log".to_owned()));
+  drop(log);
+
+  // verifying, repairing, merging and backing up find the log by its name
+  let report = KvStore::verify_with_options(temp_dir.path(), &named("data.db"))?;
+  assert_eq!((report.records, report.live_keys), (1, 1));
+  let report = KvStore::repair_with_options(temp_dir.path(), &named("data.log"))?;
+  assert_eq!((report.salvaged_records, report.discarded_bytes), (1, 0));
+  let other_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut other = KvStore::open_with_options(other_dir.path(), named("data.db"))?;
+  assert_eq!(other.merge_from(temp_dir.path(), ConflictPolicy::Overwrite)?, 1);
+  assert_eq!(other.get("key1".to_owned())?, Some("db".to_owned()));
+  let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+  other.backup(backup_dir.path())?;
+  let mut backup = KvStore::open_with_options(backup_dir.path(), named("data.db"))?;
+  assert_eq!(backup.get("key1".to_owned())?, Some("db".to_owned()));
+  assert!(!backup_dir.path().join("kvs.log").exists());
+
+  Ok(())
+}
+
+// The same seed should give the same workload, and a zipfian one should favor the hottest keys.
+#[cfg(feature = "bench-utils")]
+#[test]
+fn workload_generation() {
+  use kvs::workload::{KeyDistribution, Workload, WorkloadOptions};
+
+  let zipfian = || WorkloadOptions {
+    keys: 100,
+    distribution: KeyDistribution::Zipfian(1.0),
+    value_size: 20,
+  };
+  let sample = |mut workload: Workload| -> Vec<(String, String)> {
+    (0..100).map(|_| (workload.next_key(), workload.next_value())).collect()
+  };
+  assert_eq!(sample(Workload::new(7)), sample(Workload::new(7)));
+  assert_ne!(sample(Workload::new(7)), sample(Workload::new(8)));
+  assert_eq!(
+    sample(Workload::with_options(7, zipfian())),
+    sample(Workload::with_options(7, zipfian()))
+  );
+  assert!(sample(Workload::with_options(7, zipfian()))
+    .iter()
+    .all(|(_, value)| value.len() == 20));
+
+  let mut hits = vec![0; 100];
+  let mut workload = Workload::with_options(7, zipfian());
+  for _ in 0..10_000 {
+    hits[workload.next_key_index() as usize] += 1;
+  }
+  // with an exponent of 1 the top key gets about a fifth of the picks, the top ten about half
+  assert!(hits[0] > hits[1] && hits[1] > hits[10]);
+  assert!(hits[..10].iter().sum::<u32>() > 4_000);
+  assert!(hits[0] > 10 * hits[99]);
+
+  let mut hits = vec![0; 100];
+  let mut workload = Workload::with_options(
+    7,
+    WorkloadOptions {
+      keys: 100,
+      ..WorkloadOptions::default()
+    },
+  );
+  for _ in 0..10_000 {
+    hits[workload.next_key_index() as usize] += 1;
+  }
+  assert!(hits.iter().all(|&count| count > 50 && count < 200));
+}
+
+// Tracking latency should record every get and set, and nothing when turned off.
+#[test]
+fn latency_tracking() -> Result<()> {
+  let mut store = KvStore::open_with_storage(
+    VecStorage::default(),
+    KvStoreOptions {
+      track_latency: true,
+      ..KvStoreOptions::default()
+    },
+  )?;
+  for key_id in 0..50 {
+    store.set(format!("key{}", key_id), "value".to_owned())?;
+  }
+  for key_id in 0..100 {
+    store.get(format!("key{}", key_id))?;
+  }
+
+  let report = store.latency_snapshot();
+  assert_eq!(report.set.count, 50);
+  assert_eq!(report.get.count, 100);
+  assert!(report.set.p99 > Duration::from_nanos(0));
+  assert!(report.get.p50 <= report.get.p99);
+
+  let mut store = KvStore::open_with_storage(VecStorage::default(), KvStoreOptions::default())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.get("key1".to_owned())?;
+  let report = store.latency_snapshot();
+  assert_eq!((report.get.count, report.set.count), (0, 0));
+
+  Ok(())
+}
+
+// Large values should read back the same whatever the size of the read buffer.
+#[test]
+fn read_buffer_size() -> Result<()> {
+  let value = |key_id: usize| format!("{}", key_id).repeat(50_000);
+  for &read_buffer_bytes in &[16, 1 << 20] {
+    let storage = VecStorage::default();
+    let options = KvStoreOptions {
+      read_buffer_bytes,
+      ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_storage(storage.clone(), options.clone())?;
+    for key_id in 0..10 {
+      store.set(format!("key{}", key_id), value(key_id))?;
+    }
+    for key_id in 0..10 {
+      assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id)));
+    }
+    drop(store);
+
+    // replayed through the buffer as well
+    let mut store = KvStore::open_with_storage(storage, options)?;
+    for key_id in (0..10).rev() {
+      assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id)));
+    }
+  }
+
+  Ok(())
+}
+
+// with_value should lend the value, cached or not, and None for missing or expired keys, without caching it.
+#[test]
+fn with_value() -> Result<()> {
+  let storage = VecStorage::default();
+  let mut store = KvStore::open_with_storage(
+    storage.clone(),
+    KvStoreOptions {
+      value_cache_bytes: Some(1024),
+      ..KvStoreOptions::default()
+    },
+  )?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.set_with_ttl("key2".to_owned(), "value2".to_owned(), Duration::from_millis(1))?;
+  thread::sleep(Duration::from_millis(5));
+
+  assert_eq!(store.with_value("key1", |value| value.map(str::len))?, Some(6));
+  assert!(store.with_value("key1", |value| value == Some("value1"))?);
+  assert_eq!(store.with_value("key2", |value| value.map(str::len))?, None);
+  assert_eq!(store.with_value("key3", |value| value.map(str::len))?, None);
+
+  // the buffer values are read into holds no more than the value at hand
+  store.set("key1".to_owned(), "longer value1".to_owned())?;
+  store.set("key3".to_owned(), "v3".to_owned())?;
+  assert!(store.with_value("key1", |value| value == Some("longer value1"))?);
+  assert!(store.with_value("key3", |value| value == Some("v3"))?);
+
+  // values got are cached, values lent from the log aren't
+  assert_eq!(store.get("key1".to_owned())?, Some("longer value1".to_owned()));
+  storage.0.borrow_mut().iter_mut().for_each(|byte| *byte = 0);
+  assert!(store.with_value("key1", |value| value == Some("longer value1"))?);
+  assert!(store.with_value("key3", |value| value.is_some()).is_err());
+
+  Ok(())
+}
+
+// Records encoded one after another, through compactions, should each read back as written.
+#[test]
+fn sequential_writes_and_gets() -> Result<()> {
+  let storage = VecStorage::default();
+  let options = KvStoreOptions {
+    compaction_threshold: 50,
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_storage(storage.clone(), options.clone())?;
+  // values shrinking and growing, including one past the size the encode buffer is kept at
+  let value = |iter: usize| format!("{}", iter).repeat(iter % 7 * 10 + 1);
+  for iter in 0..500 {
+    store.set(format!("key{}", iter % 20), value(iter))?;
+  }
+  store.set("big".to_owned(), "x".repeat(100_000))?;
+  store.set("small".to_owned(), "y".to_owned())?;
+  for iter in 480..500 {
+    assert_eq!(store.get(format!("key{}", iter % 20))?, Some(value(iter)));
+  }
+  drop(store);
+
+  let mut store = KvStore::open_with_storage(storage, options)?;
+  for iter in 480..500 {
+    assert_eq!(store.get(format!("key{}", iter % 20))?, Some(value(iter)));
+  }
+  assert_eq!(store.get("big".to_owned())?, Some("x".repeat(100_000)));
+  assert_eq!(store.get("small".to_owned())?, Some("y".to_owned()));
+
+  Ok(())
+}
+
+// A backup should open as a store with the same live keys, values and expiries, and replace an older backup.
+#[test]
+fn backup() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  for key_id in 0..100 {
+    store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+  }
+  store.backup(backup_dir.path())?;
+
+  for key_id in 0..50 {
+    store.set(format!("key{}", key_id), format!("new value{}", key_id))?;
+  }
+  for key_id in 90..100 {
+    store.remove(format!("key{}", key_id))?;
+  }
+  store.set_with_ttl("short".to_owned(), "gone".to_owned(), Duration::from_millis(1))?;
+  store.set_with_ttl("long".to_owned(), "kept".to_owned(), Duration::from_secs(3600))?;
+  thread::sleep(Duration::from_millis(5));
+  // replaces the first backup
+  store.backup(backup_dir.path())?;
+
+  let mut restored = KvStore::open(backup_dir.path())?;
+  assert_eq!(restored.scan_prefix("")?, store.scan_prefix("")?);
+  assert_eq!(restored.len()?, 91);
+  assert_eq!(restored.get("key1".to_owned())?, Some("new value1".to_owned()));
+  assert_eq!(restored.get("short".to_owned())?, None);
+  assert_eq!(
+    restored.get_meta("key1".to_owned())?,
+    store.get_meta("key1".to_owned())?
+  );
+  // only live records were copied
+  assert_eq!(restored.stats()?.garbage, 0);
+  assert!(!backup_dir.path().join("kvs.log.backup").exists());
+
+  Ok(())
+}
+
+// Backing up a store into its own directory should fail and leave the store be, however the path is spelled.
+#[test]
+fn backup_into_own_dir() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+
+  assert!(store.backup(temp_dir.path()).is_err());
+  assert!(store.backup(temp_dir.path().join(".")).is_err());
+  let own_dir = temp_dir.path().join("nested").join("..");
+  std::fs::create_dir(temp_dir.path().join("nested"))?;
+  assert!(store.backup(own_dir).is_err());
+
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+  drop(store);
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+  Ok(())
+}
+
+// Merging should copy disjoint keys as is and resolve overlapping ones by the conflict policy.
+#[test]
+fn merge_stores() -> Result<()> {
+  let other_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut other = KvStore::open(other_dir.path())?;
+  other.set("shared".to_owned(), "other".to_owned())?;
+  other.set("other only".to_owned(), "other".to_owned())?;
+  other.set_with_ttl("expiring".to_owned(), "other".to_owned(), Duration::from_secs(3600))?;
+  other.set("removed".to_owned(), "other".to_owned())?;
+  other.remove("removed".to_owned())?;
+  drop(other);
+
+  let open_self = || -> Result<KvStore<VecStorage>> {
+    let mut store = KvStore::open_with_storage(VecStorage::default(), KvStoreOptions::default())?;
+    store.set("shared".to_owned(), "self".to_owned())?;
+    store.set("self only".to_owned(), "self".to_owned())?;
+    Ok(store)
+  };
+
+  let mut store = open_self()?;
+  assert_eq!(store.merge_from(other_dir.path(), ConflictPolicy::KeepSelf)?, 2);
+  assert_eq!(store.get("shared".to_owned())?, Some("self".to_owned()));
+  assert_eq!(store.get("other only".to_owned())?, Some("other".to_owned()));
+  assert_eq!(store.get("self only".to_owned())?, Some("self".to_owned()));
+  assert_eq!(store.get("removed".to_owned())?, None);
+  assert_eq!(store.len()?, 4);
+  store.purge_expired()?;
+  assert_eq!(store.get("expiring".to_owned())?, Some("other".to_owned()));
+
+  let mut store = open_self()?;
+  assert_eq!(store.merge_from(other_dir.path(), ConflictPolicy::Overwrite)?, 3);
+  assert_eq!(store.get("shared".to_owned())?, Some("other".to_owned()));
+  assert_eq!(store.len()?, 4);
+
+  let mut store = open_self()?;
+  assert!(matches!(
+    store.merge_from(other_dir.path(), ConflictPolicy::Error),
+    Err(KvStoreError::MergeConflictError(ref key)) if key == "shared"
+  ));
+  assert_eq!(store.len()?, 2);
+  assert_eq!(store.get("other only".to_owned())?, None);
+
+  // without overlap every policy merges everything
+  let mut store = KvStore::open_with_storage(VecStorage::default(), KvStoreOptions::default())?;
+  store.set("self only".to_owned(), "self".to_owned())?;
+  assert_eq!(store.merge_from(other_dir.path(), ConflictPolicy::Error)?, 3);
+  assert_eq!(store.len()?, 4);
+
+  let missing = TempDir::new().expect("unable to create temporary working directory");
+  assert!(store.merge_from(missing.path(), ConflictPolicy::Error).is_err());
+  assert!(!missing.path().join("kvs.log").exists());
+
+  Ok(())
+}
+
+// Compaction should report progress every so many keys rather than every key, ending with every key processed.
+#[test]
+fn compaction_progress() -> Result<()> {
+  let mut store = KvStore::open_with_storage(VecStorage::default(), KvStoreOptions::default())?;
+  store.bulk_load((0..5000).map(|key_id| (format!("key{}", key_id), format!("value{}", key_id))))?;
+
+  let mut calls = Vec::new();
+  store.compact_with_progress(|processed, total| calls.push((processed, total)))?;
+  assert_eq!(calls.last(), Some(&(5000, 5000)));
+  assert!(calls.len() > 1 && calls.len() < 10, "{:?}", calls);
+  assert!(calls.windows(2).all(|pair| pair[0].0 < pair[1].0));
+  assert_eq!(store.len()?, 5000);
+  assert_eq!(store.get("key4999".to_owned())?, Some("value4999".to_owned()));
+
+  let mut store = KvStore::open_with_storage(VecStorage::default(), KvStoreOptions::default())?;
+  let mut calls = Vec::new();
+  store.compact_with_progress(|processed, total| calls.push((processed, total)))?;
+  assert_eq!(calls, vec![(0, 0)]);
+
+  Ok(())
+}
+
+// Glob scans should support wildcards, character classes and literal patterns, matching whole keys.
+#[test]
+fn scan_glob() -> Result<()> {
+  let mut store = KvStore::open_with_storage(VecStorage::default(), KvStoreOptions::default())?;
+  for key in &[
+    "hello", "hallo", "hxllo", "hllo", "heeello", "user:1", "user:22", "user/3",
+  ] {
+    store.set((*key).to_owned(), "value".to_owned())?;
+  }
+  store.set_with_ttl("user:4".to_owned(), "value".to_owned(), Duration::from_millis(1))?;
+  thread::sleep(Duration::from_millis(5));
+
+  assert_eq!(
+    store.scan_glob("h*llo")?,
+    vec!["hallo", "heeello", "hello", "hllo", "hxllo"]
+  );
+  assert_eq!(store.scan_glob("h?llo")?, vec!["hallo", "hello", "hxllo"]);
+  assert_eq!(store.scan_glob("h[ae]llo")?, vec!["hallo", "hello"]);
+  assert_eq!(store.scan_glob("h[!e]llo")?, vec!["hallo", "hxllo"]);
+  assert_eq!(store.scan_glob("h[a-f]llo")?, vec!["hallo", "hello"]);
+  assert_eq!(store.scan_glob("user*")?, vec!["user/3", "user:1", "user:22"]);
+  assert_eq!(store.scan_glob("user:?")?, vec!["user:1"]);
+  assert_eq!(store.scan_glob("hello")?, vec!["hello"]);
+  assert_eq!(store.scan_glob("hell")?, Vec::<String>::new());
+  assert_eq!(store.scan_glob("*")?.len(), 8);
+  assert!(matches!(
+    store.scan_glob("h[llo"),
+    Err(KvStoreError::InvalidPatternError(_))
+  ));
+
+  Ok(())
+}
+
+// KEYS should reply with the matching keys as an array of bulk strings.
+#[test]
+fn server_keys() {
+  let addr = start_server();
+  assert_eq!(request(addr, vec!["KEYS", "*"]), "*0\r\n");
+  for key in &["key1", "key2", "other"] {
+    assert_eq!(request(addr, vec!["SET", key, "value"]), "+OK\r\n");
+  }
+
+  assert_eq!(
+    request(addr, vec!["KEYS", "key?"]),
+    "*2\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n"
+  );
+  assert_eq!(request(addr, vec!["keys", "o*"]), "*1\r\n$5\r\nother\r\n");
+  assert!(request(addr, vec!["KEYS", "[x"]).starts_with("-ERR Invalid glob pattern"));
+  assert!(request(addr, vec!["KEYS"]).starts_with("-ERR wrong number of arguments"));
+}
+
+// Records every command it observes, and keys going away without one.
+#[derive(Default)]
+struct RecordingSink(Mutex<Vec<(KvCommand, u64)>>, Mutex<Vec<String>>);
+
+impl AuditSink for RecordingSink {
+  fn on_command(&self, cmd: &KvCommand, offset: u64) {
+    self.0.lock().unwrap().push((cmd.clone(), offset));
+  }
+
+  fn on_clear(&self) {
+    self.1.lock().unwrap().push("clear".to_owned());
+  }
+
+  fn on_expire(&self, key: &str) {
+    self.1.lock().unwrap().push(format!("expire {}", key));
+  }
+}
+
+// The audit sink should observe every set and remove once, with the offset of its record.
+#[test]
+fn audit_sink() -> Result<()> {
+  let sink = Arc::new(RecordingSink::default());
+  let mut store = KvStore::open_with_storage(
+    VecStorage::default(),
+    KvStoreOptions {
+      audit_sink: sink.clone(),
+      ..KvStoreOptions::default()
+    },
+  )?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.set("key2".to_owned(), "value2".to_owned())?;
+  store.remove("key1".to_owned())?;
+  let mut batch = WriteBatch::new();
+  batch
+    .set("key3".to_owned(), "value3".to_owned())
+    .remove("key2".to_owned());
+  store.write(batch)?;
+  store.bulk_load(vec![("key4".to_owned(), "value4".to_owned())].into_iter())?;
+  // failed writes aren't observed
+  assert!(store.remove("missing".to_owned()).is_err());
+
+  let observed = sink.0.lock().unwrap().clone();
+  let cmds: Vec<KvCommand> = observed.iter().map(|(cmd, _)| cmd.clone()).collect();
+  assert_eq!(
+    cmds,
+    vec![
+      KvCommand::Set("key1".to_owned(), "value1".to_owned()),
+      KvCommand::Set("key2".to_owned(), "value2".to_owned()),
+      KvCommand::Rm("key1".to_owned()),
+      KvCommand::Set("key3".to_owned(), "value3".to_owned()),
+      KvCommand::Rm("key2".to_owned()),
+      KvCommand::Set("key4".to_owned(), "value4".to_owned()),
+    ]
+  );
+  let dumped: Vec<u64> = store.dump_log()?.into_iter().map(|(offset, _)| offset).collect();
+  let offsets: Vec<u64> = observed.iter().map(|(_, offset)| *offset).collect();
+  assert_eq!(offsets, dumped);
+
+  Ok(())
+}
+
+// Reads should always see the store's own writes, and only see the log changing in place right away
+// with read_your_writes on.
+#[test]
+fn read_your_writes() -> Result<()> {
+  for &read_your_writes in &[true, false] {
+    let storage = VecStorage::default();
+    let mut store = KvStore::open_with_storage(
+      storage.clone(),
+      KvStoreOptions {
+        read_your_writes,
+        ..KvStoreOptions::default()
+      },
+    )?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // rewrite key2's value in place, behind the store's back
+    {
+      let mut bytes = storage.0.borrow_mut();
+      let at = bytes.windows(6).position(|window| window == b"value2").unwrap();
+      bytes[at..at + 6].copy_from_slice(b"VALUE2");
+    }
+    let expected = if read_your_writes { "VALUE2" } else { "value2" };
+    assert_eq!(store.get("key2".to_owned())?, Some(expected.to_owned()));
+  }
+
+  Ok(())
+}
+
+// Mirrors the layout of the index hint, to plant a garbage count no workload in a test could reach.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct HintLayout {
+  log_len: u64,
+  garbage: u64,
+  index: std::collections::HashMap<String, u64>,
+  expiry: std::collections::HashMap<String, u64>,
+}
+
+// The garbage count should saturate instead of overflowing.
+#[test]
+fn garbage_count_saturates() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let options = KvStoreOptions {
+    compaction: false,
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.set("key2".to_owned(), "value2".to_owned())?;
+  store.flush()?;
+  drop(store);
+
+  let hint_path = temp_dir.path().join("kvs.log.hint");
+  let mut hint: HintLayout = rmp_serde::from_read_ref(&std::fs::read(&hint_path)?)?;
+  hint.garbage = u64::MAX - 1;
+  std::fs::write(&hint_path, rmp_serde::to_vec(&hint)?)?;
+
+  let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+  assert_eq!(store.stats()?.garbage, u64::MAX - 1);
+  store.set("key1".to_owned(), "value3".to_owned())?;
+  store.set("key1".to_owned(), "value4".to_owned())?;
+  store.remove("key2".to_owned())?;
+  assert_eq!(store.stats()?.garbage, u64::MAX);
+
+  // compacting still resets it
+  store.compact()?;
+  assert_eq!(store.stats()?.garbage, 0);
+  assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+
+  Ok(())
+}
+
+// A get landing on a record cut short by a truncated log should say so, instead of failing generically.
+#[test]
+fn get_truncated_record() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  let len = store.stats()?.log_bytes;
+  store.set("key2".to_owned(), "x".repeat(1000))?;
+  store.set("key3".to_owned(), "value3".to_owned())?;
+
+  let log = std::fs::OpenOptions::new()
+    .write(true)
+    .open(temp_dir.path().join("kvs.log"))?;
+  // into the middle of key2's value, leaving nothing of key3
+  log.set_len(len + 500)?;
+  match store.get("key2".to_owned()) {
+    Err(KvStoreError::ReplayError(message)) => {
+      assert!(message.contains("\"key2\""), "{}", message);
+      assert!(message.contains(&format!("offset {}", len)), "{}", message);
+    }
+    result => panic!("unexpected result {:?}", result),
+  }
+  assert!(matches!(
+    store.get("key3".to_owned()),
+    Err(KvStoreError::ReplayError(_))
+  ));
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+  Ok(())
+}
+
+fn assert_send<T: Send>() {}
+
+fn assert_send_sync<T: Send + Sync>() {}
+
+// Stores should move between threads and the handles meant for sharing should be shareable,
+// this fails to compile if a change loses either.
+#[test]
+fn thread_safety() {
+  assert_send::<KvStore>();
+  assert_send::<KvStore<MemoryStorage>>();
+  assert_send::<FileStorage>();
+  assert_send::<KvsClient>();
+  assert_send_sync::<Arc<Mutex<KvStore>>>();
+  assert_send_sync::<KvsServer>();
+  assert_send_sync::<KvsServer<MemoryStorage>>();
+  assert_send_sync::<ClientPool>();
+  assert_send_sync::<BackgroundCompactor>();
+  #[cfg(feature = "async")]
+  assert_send_sync::<kvs::async_engine::AsyncKvStore>();
+}
+
+// The first byte should pick the protocol: RESP as is, the native protocol after its byte, nothing else.
+#[test]
+fn server_protocol_handshake() -> Result<()> {
+  let addr = start_server();
+  assert_eq!(request(addr, vec!["SET", "key1", "value1"]), "+OK\r\n");
+
+  let mut stream = TcpStream::connect(addr)?;
+  stream.write_all(&[server::SERDE_PROTOCOL])?;
+  let mut reader = BufReader::new(stream.try_clone()?);
+  let mut send = |request: server::Request| -> Result<server::Response> {
+    let mut frame = Vec::new();
+    server::encode_request(&request, &mut frame)?;
+    stream.write_all(&frame)?;
+    Ok(rmp_serde::from_read(&mut reader)?)
+  };
+  assert_eq!(
+    send(server::Request::Get("key1".to_owned()))?,
+    server::Response::Value(Some("value1".to_owned()))
+  );
+  assert_eq!(
+    send(server::Request::Set("key2".to_owned(), "value2".to_owned()))?,
+    server::Response::Ok
+  );
+  assert_eq!(send(server::Request::Remove("key1".to_owned()))?, server::Response::Ok);
+  assert!(matches!(
+    send(server::Request::Remove("key1".to_owned()))?,
+    server::Response::Err(_)
+  ));
+  assert_eq!(
+    send(server::Request::Get("key1".to_owned()))?,
+    server::Response::Value(None)
+  );
+  assert_eq!(request(addr, vec!["GET", "key2"]), "$6\r\nvalue2\r\n");
+
+  let mut stream = TcpStream::connect(addr)?;
+  stream.write_all(b"?")?;
+  let mut reply = String::new();
+  stream.read_to_string(&mut reply)?;
+  assert_eq!(reply, "-ERR unknown protocol 0x3f\r\n");
+
+  Ok(())
+}
+
+// Send several commands over one connection, returns all the raw replies.
+fn session(addr: SocketAddr, cmds: Vec<Vec<&str>>) -> String {
+  let mut stream = TcpStream::connect(addr).expect("unable to connect");
+  for args in cmds {
+    resp::write_command(&RespCommand::new(args), &mut stream).unwrap();
+  }
+  stream.shutdown(Shutdown::Write).unwrap();
+
+  let mut replies = String::new();
+  stream.read_to_string(&mut replies).unwrap();
+  replies
+}
+
+// EXEC should apply the queued commands at once, and nothing if a guard fails or queueing did.
+#[test]
+fn server_transactions() {
+  let addr = start_server();
+  assert_eq!(request(addr, vec!["SET", "guard", "1"]), "+OK\r\n");
+
+  let replies = session(
+    addr,
+    vec![
+      vec!["MULTI"],
+      vec!["SET", "key1", "value1"],
+      vec!["EXISTS", "guard"],
+      vec!["SET", "key2", "value2"],
+      vec!["GET", "key1"],
+    ],
+  );
+  assert_eq!(
+    replies,
+    "+OK\r\n+QUEUED\r\n+QUEUED\r\n+QUEUED\r\n-ERR 'get' can't be used in a transaction\r\n"
+  );
+  // the connection closed without EXEC, nothing was applied
+  assert_eq!(request(addr, vec!["EXISTS", "key1", "key2"]), ":0\r\n");
+
+  let replies = session(
+    addr,
+    vec![
+      vec!["MULTI"],
+      vec!["SET", "key1", "value1"],
+      vec!["EXISTS", "guard"],
+      vec!["SET", "key2", "value2"],
+      vec!["EXEC"],
+    ],
+  );
+  assert_eq!(
+    replies,
+    "+OK\r\n+QUEUED\r\n+QUEUED\r\n+QUEUED\r\n*3\r\n+OK\r\n:1\r\n+OK\r\n"
+  );
+  assert_eq!(request(addr, vec!["EXISTS", "key1", "key2"]), ":2\r\n");
+
+  // a guard failing at EXEC applies nothing
+  let replies = session(
+    addr,
+    vec![
+      vec!["MULTI"],
+      vec!["SET", "key3", "value3"],
+      vec!["EXISTS", "missing"],
+      vec!["EXEC"],
+    ],
+  );
+  assert!(
+    replies.starts_with("+OK\r\n+QUEUED\r\n+QUEUED\r\n-ERR Batch precondition failed"),
+    "{}",
+    replies
+  );
+  assert_eq!(request(addr, vec!["EXISTS", "key3"]), ":0\r\n");
+
+  // so does a command that couldn't be queued
+  let replies = session(
+    addr,
+    vec![
+      vec!["MULTI"],
+      vec!["SET", "key3"],
+      vec!["SET", "key4", "value4"],
+      vec!["EXEC"],
+    ],
+  );
+  assert_eq!(
+    replies,
+    "+OK\r\n-ERR wrong number of arguments for 'set' command\r\n+QUEUED\r\n\
+     -EXECABORT Transaction discarded because of previous errors\r\n"
+  );
+  assert_eq!(request(addr, vec!["EXISTS", "key3", "key4"]), ":0\r\n");
+
+  let replies = session(
+    addr,
+    vec![
+      vec!["MULTI"],
+      vec!["DEL", "key1"],
+      vec!["MULTI"],
+      vec!["DISCARD"],
+      vec!["EXEC"],
+      vec!["DISCARD"],
+    ],
+  );
+  assert_eq!(
+    replies,
+    "+OK\r\n+QUEUED\r\n-ERR MULTI calls can not be nested\r\n+OK\r\n-ERR EXEC without MULTI\r\n\
+     -ERR DISCARD without MULTI\r\n"
+  );
+  assert_eq!(request(addr, vec!["GET", "key1"]), "$6\r\nvalue1\r\n");
+}
+
+// Removing a range should drop exactly the keys within its bounds, in one write.
+#[test]
+fn remove_range() -> Result<()> {
+  let storage = VecStorage::default();
+  let mut store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::default())?;
+  for key in &["a", "b", "ba", "c", "d", "e"] {
+    store.set((*key).to_owned(), "value".to_owned())?;
+  }
+
+  assert_eq!(store.remove_range("b".to_owned().."c".to_owned())?, 2);
+  assert_eq!(store.scan_glob("*")?, vec!["a", "c", "d", "e"]);
+  assert_eq!(store.remove_range("c".to_owned()..="d".to_owned())?, 2);
+  assert_eq!(store.scan_glob("*")?, vec!["a", "e"]);
+  assert_eq!(store.remove_range("x".to_owned()..)?, 0);
+  assert_eq!(store.remove_range(.."b".to_owned())?, 1);
+  assert_eq!(store.scan_glob("*")?, vec!["e"]);
+  drop(store);
+
+  let mut store = KvStore::open_with_storage(storage, KvStoreOptions::default())?;
+  assert_eq!(store.scan_glob("*")?, vec!["e"]);
+  assert_eq!(store.remove_range::<std::ops::RangeFull>(..)?, 1);
+  assert!(store.is_empty()?);
+
+  Ok(())
+}
+
+// `kvs import --format kv` should load `key<sep>value` lines, unescaping the separator inside keys and values.
+#[test]
+fn cli_import_kv() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  std::fs::write(
+    temp_dir.path().join("pairs.tsv"),
+    "user:1\talice\nuser:2\tbob\\tsmith\\nline two\n\nitem\\t1\tapple\tpie\n",
+  )?;
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["import", "pairs.tsv", "--format", "kv"])
+    .current_dir(&temp_dir)
+    .assert()
+    .success()
+    .stdout(eq("imported 3 keys\n"));
+
+  std::fs::write(temp_dir.path().join("pairs.csv"), "key,a\\,b\n")?;
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["import", "pairs.csv", "--format", "kv", "--sep", ","])
+    .current_dir(&temp_dir)
+    .assert()
+    .success();
+
+  std::fs::write(temp_dir.path().join("bad.tsv"), "user:3\tcarol\nno separator\n")?;
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["import", "bad.tsv", "--format", "kv"])
+    .current_dir(&temp_dir)
+    .assert()
+    .failure()
+    .stderr(contains("line 2"));
+
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("user:1".to_owned())?, Some("alice".to_owned()));
+  assert_eq!(store.get("user:2".to_owned())?, Some("bob\tsmith\nline two".to_owned()));
+  assert_eq!(store.get("item\t1".to_owned())?, Some("apple\tpie".to_owned()));
+  assert_eq!(store.get("key".to_owned())?, Some("a,b".to_owned()));
+  assert_eq!(store.get("user:3".to_owned())?, None);
+
+  Ok(())
+}
+
+// `kvs import` should load a JSON object by default.
+#[test]
+fn cli_import_json() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  std::fs::write(
+    temp_dir.path().join("pairs.json"),
+    r#"{"key1": "value1", "key2": "value2"}"#,
+  )?;
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["import", "pairs.json"])
+    .current_dir(&temp_dir)
+    .assert()
+    .success()
+    .stdout(eq("imported 2 keys\n"));
+
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+  assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+  Ok(())
+}
+
+// Should stream a value into a writer, both from the log and from the cache.
+#[test]
+fn read_value_to() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  let value: String = (0..4 * 1024 * 1024).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+  store.set("large".to_owned(), value.clone())?;
+  drop(store);
+
+  let mut store = KvStore::open(temp_dir.path())?;
+  let mut streamed = Vec::new();
+  assert!(store.read_value_to("large", &mut streamed)?);
+  assert_eq!(streamed, value.as_bytes());
+
+  // copied over in pieces, never held whole
+  struct Pieces(Vec<u8>, usize);
+  impl Write for Pieces {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.1 = self.1.max(buf.len());
+      self.0.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+  let mut pieces = Pieces(Vec::new(), 0);
+  assert!(store.read_value_to("large", &mut pieces)?);
+  assert_eq!(pieces.0, value.as_bytes());
+  assert!(pieces.1 < value.len(), "{}", pieces.1);
+
+  store.set("small".to_owned(), "value".to_owned())?;
+  assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+  let mut streamed = Vec::new();
+  assert!(store.read_value_to("small", &mut streamed)?);
+  assert_eq!(streamed, b"value");
+
+  let mut streamed = Vec::new();
+  assert!(!store.read_value_to("missing", &mut streamed)?);
+  assert!(streamed.is_empty());
+
+  // a value the log ends within fails saying so
+  let storage = VecStorage::default();
+  let mut store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::default())?;
+  store.set("large".to_owned(), value.clone())?;
+  let len = storage.0.borrow().len();
+  storage.0.borrow_mut().truncate(len - 1);
+  let mut streamed = Vec::new();
+  assert!(matches!(
+    store.read_value_to("large", &mut streamed),
+    Err(KvStoreError::ReplayError(_))
+  ));
+
+  Ok(())
+}
+
+// Compaction with `sorted_compaction` should write the live records in key order.
+#[test]
+fn sorted_compaction() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let options = KvStoreOptions {
+    sorted_compaction: true,
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+  for i in (0..200).rev() {
+    store.set(format!("key{:03}", i), "old".to_owned())?;
+  }
+  for i in (0..200).step_by(2) {
+    store.set(format!("key{:03}", i), "new".to_owned())?;
+  }
+  store.compact()?;
+
+  let keys: Vec<String> = store
+    .dump_log()?
+    .into_iter()
+    .map(|(_, record)| match kvs::format::into_command(record) {
+      Ok((KvCommand::Set(key, _), _)) => key,
+      other => panic!("unexpected record {:?}", other),
+    })
+    .collect();
+  let expected: Vec<String> = (0..200).map(|i| format!("key{:03}", i)).collect();
+  assert_eq!(keys, expected);
+  assert_eq!(store.get("key002".to_owned())?, Some("new".to_owned()));
+  assert_eq!(store.get("key003".to_owned())?, Some("old".to_owned()));
+
+  Ok(())
+}
+
+// `kvs --format json` should print results and errors as JSON objects, keeping the exit codes of the text format.
+#[test]
+fn cli_json_format() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  drop(store);
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["--format", "json", "get", "key1"])
+    .current_dir(&temp_dir)
+    .assert()
+    .success()
+    .stdout(eq(r#"{"result":"value1"}"#).trim());
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["--format", "json", "get", "key2"])
+    .current_dir(&temp_dir)
+    .assert()
+    .success()
+    .stdout(eq(r#"{"result":null}"#).trim());
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["--format", "json", "rm", "key2"])
+    .current_dir(&temp_dir)
+    .assert()
+    .failure()
+    .stdout(eq(r#"{"code":"KEY_NOT_FOUND","error":"Key not found"}"#).trim());
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["--format", "json", "rm", "--prefix", ""])
+    .current_dir(&temp_dir)
+    .assert()
+    .failure()
+    .stdout(contains(r#"{"code":null,"error":"refusing to remove every key"#));
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["get", "key2"])
+    .current_dir(&temp_dir)
+    .assert()
+    .success()
+    .stdout(eq("Key not found").trim());
+
+  Ok(())
+}
+
+// `kvs version` should print the crate version, the engine and the format version of the store in the directory.
+#[test]
+fn cli_version_subcommand() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["version"])
+    .current_dir(&temp_dir)
+    .assert()
+    .success()
+    .stdout(eq(format!(
+      "kvs {}\nengine: kvs\nformat version: no store in this directory\n",
+      env!("CARGO_PKG_VERSION")
+    )));
+  assert!(!temp_dir.path().join("kvs.log").exists());
+
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  drop(store);
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["version"])
+    .current_dir(&temp_dir)
+    .assert()
+    .success()
+    .stdout(eq(format!(
+      "kvs {}\nengine: kvs\nformat version: {}\n",
+      env!("CARGO_PKG_VERSION"),
+      kvs::format::FORMAT_VERSION
+    )));
+  assert_eq!(kvs::format::FORMAT_VERSION, 1);
+
+  Command::cargo_bin("kvs")
+    .unwrap()
+    .args(&["--format", "json", "version"])
+    .current_dir(&temp_dir)
+    .assert()
+    .success()
+    .stdout(contains(r#""format_version":1"#).and(contains(r#""engine":"kvs""#)));
+
+  Ok(())
+}
+
+// `set_options` should apply a new compaction threshold and cache size to the open store, but refuse changing
+// options fixed on open.
+#[test]
+fn set_options() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let options = KvStoreOptions {
+    compaction_threshold: 1000,
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+  for i in 0..50 {
+    store.set("key1".to_owned(), format!("value{}", i))?;
+  }
+  assert_eq!(store.stats()?.garbage, 49);
+
+  let options = KvStoreOptions {
+    compaction_threshold: 10,
+    value_cache_bytes: Some(1024),
+    ..store.options().clone()
+  };
+  store.set_options(options)?;
+  assert_eq!(store.options().compaction_threshold, 10);
+  store.set("key1".to_owned(), "value50".to_owned())?;
+  assert_eq!(store.stats()?.garbage, 0);
+  assert_eq!(store.get("key1".to_owned())?, Some("value50".to_owned()));
+
+  let options = KvStoreOptions {
+    log_file_name: "other.log".to_owned(),
+    ..store.options().clone()
+  };
+  assert!(matches!(
+    store.set_options(options),
+    Err(KvStoreError::ImmutableOptionError(name)) if name == "log_file_name"
+  ));
+  assert_eq!(store.options().log_file_name, "kvs.log");
+
+  Ok(())
+}
+
+// `resp::read_value` should decode replies of any shape, including nested arrays mixing types.
+#[test]
+fn resp_read_value() -> Result<()> {
+  let mut reader = io::Cursor::new(b"*3\r\n:42\r\n*2\r\n$5\r\nhello\r\n$-1\r\n+OK\r\n-ERR boom\r\n".to_vec());
+  assert_eq!(
+    resp::read_value(&mut reader)?,
+    RespValue::Array(vec![
+      RespValue::Integer(42),
+      RespValue::Array(vec![RespValue::Bulk(Some(b"hello".to_vec())), RespValue::Bulk(None)]),
+      RespValue::SimpleString("OK".to_owned()),
+    ])
+  );
+  assert_eq!(resp::read_value(&mut reader)?, RespValue::Error("ERR boom".to_owned()));
+  assert_eq!(
+    resp::read_value(&mut reader).unwrap_err().kind(),
+    io::ErrorKind::UnexpectedEof
+  );
+
+  for malformed in [
+    &b":forty-two\r\n"[..],
+    b"$5\r\nhi\r\n",
+    b"*2\r\n:1\r\n",
+    b"?\r\n",
+    b"+OK\n",
+  ] {
+    assert!(
+      resp::read_value(&mut io::Cursor::new(malformed)).is_err(),
+      "{:?}",
+      malformed
+    );
+  }
+
+  Ok(())
+}
+
+// `resp::write_value` should frame values exactly as Redis does, and read back the same with `resp::read_value`.
+#[test]
+fn resp_write_value() -> Result<()> {
+  let value = RespValue::Array(vec![
+    RespValue::SimpleString("OK".to_owned()),
+    RespValue::Error("ERR boom".to_owned()),
+    RespValue::Integer(-7),
+    RespValue::Bulk(Some(b"a\r\nb".to_vec())),
+    RespValue::Bulk(None),
+    RespValue::Array(vec![RespValue::Array(Vec::new()), RespValue::Bulk(Some(Vec::new()))]),
+  ]);
+  let mut buf = Vec::new();
+  resp::write_value(&value, &mut buf)?;
+  assert_eq!(
+    String::from_utf8(buf.clone()).unwrap(),
+    "*6\r\n+OK\r\n-ERR boom\r\n:-7\r\n$4\r\na\r\nb\r\n$-1\r\n*2\r\n*0\r\n$0\r\n\r\n"
+  );
+  assert_eq!(resp::read_value(&mut io::Cursor::new(buf))?, value);
+
+  let mut buf = Vec::new();
+  resp::write_value(&RespValue::SimpleString("two\nlines".to_owned()), &mut buf)?;
+  assert_eq!(
+    resp::read_value(&mut io::Cursor::new(buf))?,
+    RespValue::SimpleString("two lines".to_owned())
+  );
+
+  Ok(())
+}
+
+// The RESP readers should only fail with errors on the fuzzing seed corpus and every truncation of it, never panic,
+// and reject lengths and nesting they can't back.
+#[test]
+fn resp_seed_corpus() -> Result<()> {
+  let corpus = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus/resp");
+  for entry in std::fs::read_dir(corpus)? {
+    let frames = std::fs::read(entry?.path())?;
+    for len in 0..=frames.len() {
+      let mut reader = io::Cursor::new(&frames[..len]);
+      while let Ok(Some(_)) = resp::read_command(&mut reader) {}
+      let mut reader = io::Cursor::new(&frames[..len]);
+      while resp::read_value(&mut reader).is_ok() {}
+    }
+  }
+
+  let deep = b"*1\r\n".repeat(1000);
+  assert_eq!(
+    resp::read_value(&mut io::Cursor::new(deep)).unwrap_err().kind(),
+    io::ErrorKind::InvalidData
+  );
+  let nested = [b"*1\r\n".repeat(63), b":1\r\n".to_vec()].concat();
+  assert!(resp::read_value(&mut io::Cursor::new(nested)).is_ok());
+  assert_eq!(
+    resp::read_value(&mut io::Cursor::new(b"*536870912\r\n"))
+      .unwrap_err()
+      .kind(),
+    io::ErrorKind::UnexpectedEof
+  );
+  assert_eq!(
+    resp::read_value(&mut io::Cursor::new(b"$536870912\r\nabc\r\n"))
+      .unwrap_err()
+      .kind(),
+    io::ErrorKind::UnexpectedEof
+  );
+
+  Ok(())
+}
+
+// A VecStorage that crashes once `budget` bytes were appended, keeping the part of the append that fit.
+#[derive(Clone)]
+struct FaultyStorage {
+  log: VecStorage,
+  budget: Rc<Cell<u64>>,
+}
+
+impl FaultyStorage {
+  fn new(budget: u64) -> Self {
+    Self {
+      log: VecStorage::default(),
+      budget: Rc::new(Cell::new(budget)),
+    }
+  }
+}
+
+impl LogStorage for FaultyStorage {
+  fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    self.log.read_at(offset, buf)
+  }
+
+  fn append(&mut self, bytes: &[u8]) -> io::Result<u64> {
+    let budget = self.budget.get();
+    let written = budget.min(bytes.len() as u64);
+    self.budget.set(budget - written);
+    let pos = self.log.append(&bytes[..written as usize])?;
+    if written < bytes.len() as u64 {
+      return Err(io::Error::other("injected fault"));
+    }
+    Ok(pos)
+  }
+
+  fn truncate(&mut self, len: u64) -> io::Result<()> {
+    self.log.truncate(len)
+  }
+
+  fn sync(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+
+  fn len(&self) -> io::Result<u64> {
+    self.log.len()
+  }
+
+  fn open_scratch(&mut self) -> io::Result<Self> {
+    Ok(Self {
+      log: self.log.open_scratch()?,
+      budget: self.budget.clone(),
+    })
+  }
+
+  fn replace(&mut self, scratch: Self) -> io::Result<()> {
+    self.log.replace(scratch.log)
+  }
+}
+
+// After a crash at any byte of the writes, within a record or between two, reopening should recover every write
+// that completed, and later writes should survive the next reopen.
+#[test]
+fn crash_consistency() -> Result<()> {
+  let writes = |store: &mut KvStore<FaultyStorage>, model: &mut Vec<(String, Option<String>)>| -> Result<()> {
+    for i in 0..4 {
+      store.set(format!("key{}", i), format!("value{}", i))?;
+      model.push((format!("key{}", i), Some(format!("value{}", i))));
+    }
+    store.remove("key1".to_owned())?;
+    model.push(("key1".to_owned(), None));
+    store.set("key2".to_owned(), "x".repeat(100))?;
+    model.push(("key2".to_owned(), Some("x".repeat(100))));
+    Ok(())
+  };
+
+  let mut model = Vec::new();
+  let storage = FaultyStorage::new(u64::MAX);
+  writes(
+    &mut KvStore::open_with_storage(storage.clone(), KvStoreOptions::default())?,
+    &mut model,
+  )?;
+  let total_len = storage.log.len()?;
+
+  for fault_at in 0..=total_len {
+    let storage = FaultyStorage::new(fault_at);
+    let mut completed = Vec::new();
+    let mut store = KvStore::open_with_storage(storage.clone(), KvStoreOptions::default())?;
+    let crashed = writes(&mut store, &mut completed).is_err();
+    assert_eq!(crashed, fault_at < total_len);
+    drop(store);
+
+    let mut expected = std::collections::BTreeMap::new();
+    for (key, value) in completed {
+      match value {
+        Some(value) => expected.insert(key, value),
+        None => expected.remove(&key),
+      };
+    }
+
+    let mut store = KvStore::open_with_storage(storage.log.clone(), KvStoreOptions::default())?;
+    let recovered: std::collections::BTreeMap<String, String> = store.scan_prefix("")?.into_iter().collect();
+    assert_eq!(recovered, expected, "crashed at byte {}", fault_at);
+    store.set("after".to_owned(), "crash".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open_with_storage(storage.log.clone(), KvStoreOptions::default())?;
+    assert_eq!(
+      store.get("after".to_owned())?,
+      Some("crash".to_owned()),
+      "crashed at byte {}",
+      fault_at
+    );
+    assert_eq!(store.len()?, expected.len() + 1);
+  }
+
+  Ok(())
+}
+
+// Opening should cut off a record the log file ends within, but leave corruption in the middle of the log alone.
+#[test]
+fn torn_tail_on_open() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  let valid_len = store.stats()?.log_bytes;
+  store.set("key2".to_owned(), "value2".to_owned())?;
+  let total_len = store.stats()?.log_bytes;
+  drop(store);
+
+  let log_path = temp_dir.path().join("kvs.log");
+  let log = std::fs::OpenOptions::new().write(true).open(&log_path)?;
+  log.set_len(total_len - 3)?;
+
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.stats()?.log_bytes, valid_len);
+  assert_eq!(store.get("key2".to_owned())?, None);
+  store.set("key3".to_owned(), "value3".to_owned())?;
+  drop(store);
+
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+  assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+  let len = store.stats()?.log_bytes;
+  drop(store);
+
+  // 0xc1 is never used as a MessagePack marker
+  let mut bytes = std::fs::read(&log_path)?;
+  bytes[kvs::format::HEADER_LEN + 1] = 0xc1;
+  std::fs::write(&log_path, bytes)?;
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.stats()?.log_bytes, len);
+
+  Ok(())
+}
+
+// Removing a new key whose set is the last record should cut the set off the log instead of appending a tombstone,
+// while keys with older records, or a log keeping its history, still get one.
+#[test]
+fn remove_fresh_tail() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  let len = store.stats()?.log_bytes;
+
+  for _ in 0..3 {
+    store.set("churn".to_owned(), "value".to_owned())?;
+    assert!(store.stats()?.log_bytes > len);
+    store.remove("churn".to_owned())?;
+    assert_eq!(store.stats()?.log_bytes, len);
+    assert_eq!(store.get("churn".to_owned())?, None);
+  }
+  assert_eq!(store.stats()?.garbage, 0);
+
+  // the key's older value would come back without a tombstone
+  store.set("key1".to_owned(), "value2".to_owned())?;
+  store.remove("key1".to_owned())?;
+  assert_eq!(store.stats()?.garbage, 2);
+
+  // not the last record anymore
+  store.set("key2".to_owned(), "value2".to_owned())?;
+  store.set("key3".to_owned(), "value3".to_owned())?;
+  let len = store.stats()?.log_bytes;
+  store.remove("key2".to_owned())?;
+  assert!(store.stats()?.log_bytes > len);
+  drop(store);
+
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("key1".to_owned())?, None);
+  assert_eq!(store.get("key2".to_owned())?, None);
+  assert_eq!(store.get("churn".to_owned())?, None);
+  assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+  let options = KvStoreOptions {
+    compaction: false,
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_storage(VecStorage::default(), options)?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  let len = store.stats()?.log_bytes;
+  store.remove("key1".to_owned())?;
+  assert!(store.stats()?.log_bytes > len);
+
+  Ok(())
+}
+
+// A sharded store should route keys to shards that can be written concurrently, and keep every key across a reopen.
+#[test]
+fn sharded_store() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let store = Arc::new(ShardedKvStore::open(temp_dir.path(), 4)?);
+  assert_eq!(store.shard_count(), 4);
+
+  let handles: Vec<_> = (0..4)
+    .map(|thread_id| {
+      let store = store.clone();
+      thread::spawn(move || -> Result<()> {
+        for key_id in 0..50 {
+          store.set(format!("key{}-{}", thread_id, key_id), format!("value{}", key_id))?;
+        }
+        Ok(())
+      })
+    })
+    .collect();
+  for handle in handles {
+    handle.join().expect("writer panicked")?;
+  }
+  let mut used = std::collections::HashSet::new();
+  for thread_id in 0..4 {
+    for key_id in 0..50 {
+      let key = format!("key{}-{}", thread_id, key_id);
+      used.insert(store.shard_of(&key));
+      assert_eq!(store.get(key)?, Some(format!("value{}", key_id)));
+    }
+  }
+  assert_eq!(used.len(), 4);
+
+  // a writer to another shard goes ahead while one shard is held
+  let held = store.shard_of("key0-0");
+  let other = (0..)
+    .map(|key_id| format!("other{}", key_id))
+    .find(|key| store.shard_of(key) != held)
+    .unwrap();
+  let (done, finished) = std::sync::mpsc::channel();
+  store.with_shard(held, |_| -> Result<()> {
+    let store = store.clone();
+    let other = other.clone();
+    thread::spawn(move || done.send(store.set(other, "value".to_owned())).unwrap());
+    finished
+      .recv_timeout(Duration::from_secs(5))
+      .expect("writer was blocked")?;
+    Ok(())
+  })??;
+
+  store.remove("key0-0".to_owned())?;
+  drop(store);
+  assert!(matches!(
+    ShardedKvStore::open(temp_dir.path(), 2),
+    Err(KvStoreError::ShardCountError(_))
+  ));
+  let store = ShardedKvStore::open(temp_dir.path(), 4)?;
+  assert_eq!(store.get("key0-0".to_owned())?, None);
+  assert_eq!(store.get("key3-49".to_owned())?, Some("value49".to_owned()));
+  assert_eq!(store.get(other)?, Some("value".to_owned()));
+
+  // namespaces are checked like a single store's, so shards can't land outside the directory
+  for namespace in &["../elsewhere", "/tmp/x"] {
+    let options = KvStoreOptions {
+      namespace: Some((*namespace).to_owned()),
+      ..KvStoreOptions::default()
+    };
+    assert!(matches!(
+      ShardedKvStore::open_with_options(temp_dir.path(), 4, options),
+      Err(KvStoreError::InvalidNamespaceError(_))
+    ));
+  }
+  assert!(!temp_dir.path().join("../elsewhere").exists());
+
+  assert!(matches!(
+    store.with_shard(4, |_| ()),
+    Err(KvStoreError::ShardCountError(_))
+  ));
+
+  Ok(())
+}
+
+// Messages published to a channel should reach its subscribers, and only them.
+#[test]
+fn server_pubsub() {
+  let addr = start_server();
+  let mut subscriber = TcpStream::connect(addr).expect("unable to connect");
+  subscriber.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+  resp::write_command(&RespCommand::new(vec!["SUBSCRIBE", "news"]), &mut subscriber).unwrap();
+  let mut reader = BufReader::new(subscriber.try_clone().unwrap());
+  let confirmation = RespValue::Array(vec![
+    RespValue::Bulk(Some(b"subscribe".to_vec())),
+    RespValue::Bulk(Some(b"news".to_vec())),
+    RespValue::Integer(1),
+  ]);
+  assert_eq!(resp::read_value(&mut reader).unwrap(), confirmation);
+
+  assert_eq!(request(addr, vec!["PUBLISH", "news", "hello"]), ":1\r\n");
+  assert_eq!(request(addr, vec!["PUBLISH", "sports", "goal"]), ":0\r\n");
+  let message = RespValue::Array(vec![
+    RespValue::Bulk(Some(b"message".to_vec())),
+    RespValue::Bulk(Some(b"news".to_vec())),
+    RespValue::Bulk(Some(b"hello".to_vec())),
+  ]);
+  assert_eq!(resp::read_value(&mut reader).unwrap(), message);
+
+  // a subscriber can't run other commands
+  resp::write_command(&RespCommand::new(vec!["GET", "news"]), &mut subscriber).unwrap();
+  assert!(matches!(resp::read_value(&mut reader).unwrap(), RespValue::Error(_)));
+
+  // nor keeps getting messages once gone
+  drop(reader);
+  drop(subscriber);
+  let mut delivered = request(addr, vec!["PUBLISH", "news", "bye"]);
+  for _ in 0..50 {
+    if delivered == ":0\r\n" {
+      break;
+    }
+    thread::sleep(Duration::from_millis(20));
+    delivered = request(addr, vec!["PUBLISH", "news", "bye"]);
+  }
+  assert_eq!(delivered, ":0\r\n");
+}
+
+// Shutting the server down should close idle connections, stop serving and leave the store flushed.
+#[test]
+fn server_shutdown() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let listener = TcpListener::bind("127.0.0.1:0")?;
+  let addr = listener.local_addr()?;
+  let server = KvsServer::new(KvStore::open(temp_dir.path())?);
+  let shutdown = server.shutdown_handle();
+  let serving = thread::spawn(move || server.serve(listener));
+
+  assert_eq!(request(addr, vec!["SET", "key1", "value1"]), "+OK\r\n");
+  let mut idle = TcpStream::connect(addr)?;
+  resp::write_command(&RespCommand::new(vec!["GET", "key1"]), &mut idle)?;
+  let mut reader = BufReader::new(idle.try_clone()?);
+  assert_eq!(
+    resp::read_value(&mut reader)?,
+    RespValue::Bulk(Some(b"value1".to_vec()))
+  );
+
+  shutdown.shutdown();
+  serving.join().expect("server panicked")?;
+  // the idle connection got closed rather than holding the server up
+  let mut rest = String::new();
+  reader.read_to_string(&mut rest)?;
+  assert_eq!(rest, "");
+  assert!(TcpStream::connect(addr).is_err());
+
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+  Ok(())
+}
+
+// kvs-server should shut down cleanly on SIGTERM, with what it stored durable.
+#[cfg(unix)]
+#[test]
+fn cli_server_sigterm() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+  let mut server = Command::cargo_bin("kvs-server")
+    .unwrap()
+    .args(&["--addr", &addr.to_string()])
+    .current_dir(&temp_dir)
+    .spawn()?;
+
+  let mut connected = None;
+  for _ in 0..100 {
+    match TcpStream::connect(addr) {
+      Ok(stream) => {
+        connected = Some(stream);
+        break;
+      }
+      Err(_) => thread::sleep(Duration::from_millis(50)),
+    }
+  }
+  let mut stream = connected.expect("kvs-server didn't start listening");
+  resp::write_command(&RespCommand::new(vec!["SET", "key1", "value1"]), &mut stream)?;
+  stream.shutdown(Shutdown::Write)?;
+  let mut reply = String::new();
+  stream.read_to_string(&mut reply)?;
+  assert_eq!(reply, "+OK\r\n");
+
+  let killed = Command::new("kill")
+    .args(&["-TERM", &server.id().to_string()])
+    .status()?;
+  assert!(killed.success());
+  assert!(server.wait()?.success());
+
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+  Ok(())
+}
+
+// Addresses should parse from host:port, and malformed ones fail saying what's wrong.
+#[test]
+fn parse_addr() -> Result<()> {
+  assert_eq!(
+    server::parse_addr("127.0.0.1:4000")?,
+    "127.0.0.1:4000".parse::<SocketAddr>().unwrap()
+  );
+  assert_eq!(
+    server::parse_addr("0.0.0.0:6379")?,
+    "0.0.0.0:6379".parse::<SocketAddr>().unwrap()
+  );
+  assert_eq!(
+    server::parse_addr("[::1]:4000")?,
+    "[::1]:4000".parse::<SocketAddr>().unwrap()
+  );
+  let localhost = server::parse_addr("localhost:4000")?;
+  assert!(localhost.ip().is_loopback());
+  assert_eq!(localhost.port(), 4000);
+
+  for (addr, reason) in &[
+    ("", "expected host:port"),
+    ("127.0.0.1", "expected host:port"),
+    ("127.0.0.1:", "the port must be a number"),
+    ("127.0.0.1:70000", "the port must be a number"),
+    ("127.0.0.1:port", "the port must be a number"),
+    (":4000", "missing host"),
+    ("::1:4000", "IPv6 addresses go in brackets"),
+    ("[::1:4000", "IPv6 addresses go in brackets"),
+  ] {
+    match server::parse_addr(addr) {
+      Err(KvStoreError::InvalidAddressError(message)) => assert!(message.contains(reason), "{}", message),
+      other => panic!("{:?} parsed to {:?}", addr, other),
+    }
+  }
+
+  // clients connect to parsed addresses
+  let addr = start_server();
+  let mut client = KvsClient::connect(server::parse_addr(&addr.to_string())?)?;
+  client.set("key1".to_owned(), "value1".to_owned())?;
+  let pool = ClientPool::new(server::parse_addr(&format!("127.0.0.1:{}", addr.port()))?, 1)?;
+  assert_eq!(pool.get()?.get("key1".to_owned())?, Some("value1".to_owned()));
+
+  Ok(())
+}
+
+// kvs-server should refuse a malformed --addr with an error rather than a panic.
+#[test]
+fn cli_server_invalid_addr() {
+  Command::cargo_bin("kvs-server")
+    .unwrap()
+    .args(&["--addr", "127.0.0.1"])
+    .assert()
+    .failure()
+    .code(2)
+    .stderr(contains("Invalid address"));
+}
+
+// PING should answer PONG over either protocol, leaving the store alone.
+#[test]
+fn server_ping() -> Result<()> {
+  let addr = start_server();
+  assert_eq!(request(addr, vec!["SET", "key1", "value1"]), "+OK\r\n");
+  assert_eq!(
+    session(addr, vec![vec!["PING"], vec!["ping", "hello"], vec!["PING", "a", "b"]]),
+    "+PONG\r\n$5\r\nhello\r\n-ERR wrong number of arguments for 'ping' command\r\n"
+  );
+
+  let mut stream = TcpStream::connect(addr)?;
+  stream.write_all(&[server::SERDE_PROTOCOL])?;
+  let mut frame = Vec::new();
+  server::encode_request(&server::Request::Ping, &mut frame)?;
+  stream.write_all(&frame)?;
+  let response: server::Response = rmp_serde::from_read(&mut stream)?;
+  assert_eq!(response, server::Response::Pong);
+
+  assert_eq!(request(addr, vec!["DBSIZE"]), ":1\r\n");
+  assert_eq!(request(addr, vec!["GET", "key1"]), "$6\r\nvalue1\r\n");
+
+  Ok(())
+}
+
+// Every kind of error should have its stable code.
+#[test]
+fn error_codes() {
+  let codes = vec![
+    (KvStoreError::IoError(io::Error::other("disk on fire")), "IO"),
+    (KvStoreError::ReplayError("unexpected record".to_owned()), "CORRUPT_LOG"),
+    (
+      KvStoreError::EncodeError(rmp_serde::encode::Error::UnknownLength),
+      "ENCODE",
+    ),
+    (
+      KvStoreError::DecodeError(rmp_serde::decode::Error::Syntax("bad record".to_owned())),
+      "DECODE",
+    ),
+    (KvStoreError::RmKeyNotFoundError, "KEY_NOT_FOUND"),
+    (KvStoreError::KeyNotFoundError, "KEY_NOT_FOUND"),
+    (KvStoreError::GetError, "GET"),
+    (KvStoreError::CompactionError, "COMPACTION"),
+    (
+      KvStoreError::InvalidNamespaceError("..".to_owned()),
+      "INVALID_NAMESPACE",
+    ),
+    (
+      KvStoreError::InvalidLogFileNameError("../kvs.log".to_owned()),
+      "INVALID_LOG_FILE_NAME",
+    ),
+    (KvStoreError::ValueTooLarge(17), "VALUE_TOO_LARGE"),
+    (KvStoreError::InvalidKey("key is empty".to_owned()), "INVALID_KEY"),
+    (KvStoreError::DumpError("unsupported value".to_owned()), "DUMP"),
+    (
+      KvStoreError::TypedValueError(serde_json::from_str::<i32>("nope").unwrap_err()),
+      "TYPED_VALUE",
+    ),
+    (KvStoreError::CompressionError("bad frame".to_owned()), "COMPRESSION"),
+    (
+      KvStoreError::PreconditionFailedError("key1".to_owned()),
+      "PRECONDITION_FAILED",
+    ),
+    (KvStoreError::TaskError("task panicked".to_owned()), "TASK"),
+    (KvStoreError::ServerError("ERR".to_owned()), "SERVER"),
+    (KvStoreError::ProtocolError("unexpected reply".to_owned()), "PROTOCOL"),
+    (KvStoreError::MergeConflictError("key1".to_owned()), "MERGE_CONFLICT"),
+    (KvStoreError::InvalidPatternError("[".to_owned()), "INVALID_PATTERN"),
+    (
+      KvStoreError::ImmutableOptionError("namespace".to_owned()),
+      "IMMUTABLE_OPTION",
+    ),
+    (KvStoreError::ShardCountError("0".to_owned()), "SHARD_COUNT"),
+    (KvStoreError::InvalidAddressError("4000".to_owned()), "INVALID_ADDRESS"),
+    (KvStoreError::LogFull(4096), "LOG_FULL"),
+    (
+      KvStoreError::UnsupportedFormatError("version 3".to_owned()),
+      "UNSUPPORTED_FORMAT",
+    ),
+  ];
+
+  for (e, code) in codes {
+    assert_eq!(e.code(), code, "{:?}", e);
+  }
+}
+
+// Replaying a log should report its progress as it goes, ending with all of the log.
+#[test]
+fn replay_progress() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  for i in 0..3000 {
+    store.set(format!("key{}", i), "v".repeat(1024))?;
+  }
+  drop(store);
+  // without a hint, opening replays the log
+  std::fs::remove_file(temp_dir.path().join("kvs.log.hint"))?;
+  let log_len = std::fs::metadata(temp_dir.path().join("kvs.log"))?.len();
+
+  let reports = Arc::new(Mutex::new(Vec::new()));
+  let recorded = reports.clone();
+  let options = KvStoreOptions {
+    replay_progress: Some(Arc::new(move |replayed, total| {
+      recorded.lock().unwrap().push((replayed, total));
+    })),
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+  assert_eq!(store.get("key2999".to_owned())?, Some("v".repeat(1024)));
+
+  let reports = reports.lock().unwrap();
+  // about once a MiB, so a handful for a log of 3 MiB rather than one per record
+  assert!(reports.len() >= 3 && reports.len() <= 6, "{:?}", reports);
+  assert!(reports.windows(2).all(|pair| pair[0].0 <= pair[1].0), "{:?}", reports);
+  assert!(reports
+    .iter()
+    .all(|(replayed, total)| *total == log_len && *replayed <= log_len));
+  assert_eq!(reports.last(), Some(&(log_len, log_len)));
+
+  Ok(())
+}
+
+// Compacting a shared store should let reads and writes go on meanwhile, with none of them failing or lost,
+// not even when the writes compact the store themselves.
+#[test]
+fn compact_shared() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let store = Arc::new(Mutex::new(KvStore::open(temp_dir.path())?));
+  {
+    let mut store = store.lock().unwrap();
+    for round in 0..5 {
+      for i in 0..2000 {
+        store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+      }
+    }
+  }
+
+  let compactor = {
+    let store = store.clone();
+    thread::spawn(move || KvStore::compact_shared(&store))
+  };
+  let mut i = 0;
+  while !compactor.is_finished() || i < 1000 {
+    let mut store = store.lock().unwrap();
+    let key = i % 2000;
+    assert_eq!(store.get(format!("key{}", key))?, Some(format!("value{}-4", key)));
+    // writes meanwhile go to the old log, and have to make it into the new one
+    store.set(format!("new{}", i), format!("value{}", i))?;
+    if i % 3 == 1 {
+      store.remove(format!("new{}", i - 1))?;
+    }
+    i += 1;
+  }
+  // compactions on the way got to the garbage first, so there may be nothing left to reclaim
+  compactor.join().expect("compaction panicked")?;
+
+  let check = |store: &mut KvStore| -> Result<()> {
+    for key in 0..2000 {
+      assert_eq!(store.get(format!("key{}", key))?, Some(format!("value{}-4", key)));
+    }
+    for n in 0..i {
+      let expected = Some(format!("value{}", n)).filter(|_| n % 3 != 0 || n + 1 >= i);
+      assert_eq!(store.get(format!("new{}", n))?, expected, "new{}", n);
+    }
+    Ok(())
+  };
+  check(&mut store.lock().unwrap())?;
+
+  drop(store);
+  check(&mut KvStore::open(temp_dir.path())?)?;
+
+  Ok(())
+}
+
+// Writes that would grow the log past its maximum should fail, until compaction makes room again.
+#[test]
+fn max_log_bytes() -> Result<()> {
+  let options = KvStoreOptions {
+    compaction: false,
+    max_log_bytes: Some(4096),
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_storage(MemoryStorage::default(), options)?;
+  let mut written = 0;
+  let e = loop {
+    match store.set("key1".to_owned(), format!("value{}", written)) {
+      Ok(()) => written += 1,
+      Err(e) => break e,
+    }
+  };
+  assert!(matches!(e, KvStoreError::LogFull(4096)), "{:?}", e);
+  assert!(written > 10);
+  assert!(store.stats()?.log_bytes <= 4096);
+  assert_eq!(store.get("key1".to_owned())?, Some(format!("value{}", written - 1)));
+  // removes go through regardless, it's their tombstones that let compaction make room
+  store.remove("key1".to_owned())?;
+  assert!(matches!(
+    store.set("key2".to_owned(), "value2".to_owned()),
+    Err(KvStoreError::LogFull(_))
+  ));
+
+  store.compact()?;
+  store.set("key2".to_owned(), "value2".to_owned())?;
+  assert_eq!(store.get("key1".to_owned())?, None);
+  assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+  // with compaction on, a write that doesn't fit compacts first
+  let options = KvStoreOptions {
+    compaction_threshold: u64::MAX,
+    max_log_bytes: Some(4096),
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_storage(MemoryStorage::default(), options)?;
+  for i in 0..1000 {
+    store.set("key1".to_owned(), format!("value{}", i))?;
+  }
+  assert_eq!(store.get("key1".to_owned())?, Some("value999".to_owned()));
+  // unless there's nothing to reclaim
+  let e = (0..1000)
+    .map(|i| store.set(format!("key{}", i), "value".to_owned()))
+    .find_map(|result| result.err());
+  assert!(matches!(e, Some(KvStoreError::LogFull(4096))), "{:?}", e);
+
+  // or too little of it to make room, which leaves the garbage for later
+  store.remove("key1".to_owned())?;
+  let garbage = store.stats()?.garbage;
+  assert!(matches!(
+    store.set("large".to_owned(), "v".repeat(2048)),
+    Err(KvStoreError::LogFull(4096))
+  ));
+  assert_eq!(store.stats()?.garbage, garbage);
+  store.set("key1".to_owned(), "value".to_owned())?;
+
+  Ok(())
+}
+
+// Exporting as RESP should write a SET command for every live key, with the TTL keys have.
+#[test]
+fn export_resp() -> Result<()> {
+  let mut store = KvStore::open_in_memory()?;
+  store.set("key2".to_owned(), "value\r\nwith a line break".to_owned())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.set("removed".to_owned(), "value".to_owned())?;
+  store.remove("removed".to_owned())?;
+  store.set_with_ttl("key3".to_owned(), "value3".to_owned(), Duration::from_secs(3600))?;
+  store.set_with_ttl("expired".to_owned(), "value".to_owned(), Duration::from_millis(1))?;
+  thread::sleep(Duration::from_millis(10));
+
+  let mut exported = Vec::new();
+  store.export_resp(&mut exported)?;
+
+  let mut reader = &exported[..];
+  let mut argvs = Vec::new();
+  while let Some(cmd) = resp::read_command(&mut reader)? {
+    let mut argv = vec![cmd.name()];
+    argv.extend(cmd.args().iter().cloned());
+    argvs.push(argv);
+  }
+  assert_eq!(argvs.len(), 3);
+  assert_eq!(argvs[0], vec!["SET", "key1", "value1"]);
+  assert_eq!(argvs[1], vec!["SET", "key2", "value\r\nwith a line break"]);
+  assert_eq!(argvs[2][..4], ["SET", "key3", "value3", "PXAT"]);
+  let expires_at: u128 = argvs[2][4].parse().unwrap();
+  let now = SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_millis();
+  assert!(expires_at > now && expires_at <= now + 3_600_000);
+
+  Ok(())
+}
+
+// Compaction should sync the log's directory once it swapped the new log in unless turned off, which can change
+// while the store is open.
+#[cfg(feature = "test-utils")]
+#[test]
+fn compaction_sync_dir() -> Result<()> {
+  for sync_dir in [true, false] {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+      sync_dir,
+      ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..10 {
+      store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    assert!(store.compact()? > 0);
+    assert_eq!(store.dir_syncs(), u64::from(sync_dir));
+
+    store.set_options(KvStoreOptions {
+      sync_dir: !sync_dir,
+      ..options.clone()
+    })?;
+    for i in 10..20 {
+      store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    assert!(store.compact()? > 0);
+    // synced by one of the two compactions
+    assert_eq!(store.dir_syncs(), 1);
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value19".to_owned()));
+  }
+
+  Ok(())
+}
+
+// Logs should be told apart by their header, or lack of one, and newer versions rejected.
+#[test]
+fn detect_format_version() -> Result<()> {
+  let mut header = Vec::new();
+  kvs::format::encode_header(1, &mut header);
+  assert!(header.is_empty());
+  kvs::format::encode_header(2, &mut header);
+  assert_eq!(header, b"KVS\0\0\0\0\x02");
+
+  assert_eq!(kvs::format::detect_version(b"")?, (1, 0));
+  assert_eq!(kvs::format::detect_version(b"\x92\x00\x92")?, (1, 0));
+  assert_eq!(kvs::format::detect_version(&header)?, (2, 8));
+  assert!(matches!(
+    kvs::format::detect_version(b"KVS\0\0\0\0\x03"),
+    Err(KvStoreError::UnsupportedFormatError(_))
+  ));
+  assert!(matches!(
+    kvs::format::detect_version(b"KVS\0\0"),
+    Err(KvStoreError::UnsupportedFormatError(_))
+  ));
+
+  Ok(())
+}
+
+// Logs of version 1 and 2, as checked in under tests/fixtures, should both open, replay and compact the same.
+#[test]
+fn open_format_versions() -> Result<()> {
+  let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+  // when the sets of tests/fixtures/v2.log are stamped as made
+  let modified = SystemTime::UNIX_EPOCH + Duration::from_millis(0x0000_0102_0304_0506);
+
+  for version in [1, 2] {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fixture = fixtures.join(format!("v{}.log", version));
+    std::fs::copy(&fixture, temp_dir.path().join("kvs.log"))?;
+    assert_eq!(kvs::format::detect_version(&std::fs::read(&fixture)?)?.0, version);
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert_eq!((report.records, report.live_keys, report.corrupted_at), (4, 1, None));
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.replay_history().count(), 4);
+    let meta = store.get_meta("key1".to_owned())?.unwrap();
+    assert_eq!(meta.modified, Some(modified).filter(|_| version == 2));
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    // appending leaves the log in its version, only logs of version 2 get stamps
+    assert_eq!(
+      store.get_meta("key3".to_owned())?.unwrap().modified.is_some(),
+      version == 2
+    );
+    drop(store);
+    let log = std::fs::read(temp_dir.path().join("kvs.log"))?;
+    assert_eq!(kvs::format::detect_version(&log)?.0, version);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    // the compacted log is written in the current version, keeping the stamps it had
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    let meta = store.get_meta("key1".to_owned())?.unwrap();
+    assert_eq!(meta.modified, Some(modified).filter(|_| version == 2));
+    drop(store);
+    let log = std::fs::read(temp_dir.path().join("kvs.log"))?;
+    assert_eq!(kvs::format::detect_version(&log)?, (2, 8));
+  }
+
+  // the records of the log of version 2 without its header make a log of version 1 that's broken from its first
+  // stamp on
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let log = std::fs::read(fixtures.join("v2.log"))?;
+  std::fs::write(temp_dir.path().join("kvs.log"), &log[kvs::format::HEADER_LEN..])?;
+  let report = KvStore::verify(temp_dir.path())?;
+  assert_eq!((report.records, report.corrupted_at), (0, Some(0)));
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("key1".to_owned())?, None);
+  assert!(matches!(
+    store.replay_history().next(),
+    Some(Err(KvStoreError::ReplayError(_)))
+  ));
+  drop(store);
+
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  std::fs::write(temp_dir.path().join("kvs.log"), b"KVS\0\0\0\0\x03")?;
+  assert!(matches!(
+    KvStore::open(temp_dir.path()),
+    Err(KvStoreError::UnsupportedFormatError(_))
+  ));
+
+  Ok(())
+}
+
+// A server configured through ServerConfig should run on an ephemeral port and stop when shut down.
+#[test]
+fn run_server_config() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let listener = TcpListener::bind("127.0.0.1:0")?;
+  let addr = listener.local_addr()?;
+  let config = ServerConfig::from_listener(listener)
+    .with_dir(temp_dir.path())
+    .with_allow_flush(true)
+    .with_read_timeout(Some(Duration::from_secs(5)))
+    .with_max_connections(4);
+  let shutdown = config.shutdown_handle();
+  let serving = thread::spawn(move || server::run_server(config));
+
+  assert_eq!(request(addr, vec!["SET", "key1", "value1"]), "+OK\r\n");
+  assert_eq!(request(addr, vec!["GET", "key1"]), "$6\r\nvalue1\r\n");
+  assert_eq!(request(addr, vec!["FLUSHALL"]), "+OK\r\n");
+  assert_eq!(request(addr, vec!["SET", "key2", "value2"]), "+OK\r\n");
+
+  shutdown.shutdown();
+  serving.join().expect("server panicked")?;
+  assert!(TcpStream::connect(addr).is_err());
+
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("key1".to_owned())?, None);
+  assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+  Ok(())
+}
+
+// Without an index, gets and removes should find the latest record of a key by scanning the log.
+#[test]
+fn index_mode_none() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.set("key2".to_owned(), "value2".to_owned())?;
+  store.set("key1".to_owned(), "value3".to_owned())?;
+  store.remove("key2".to_owned())?;
+  store.set_with_ttl("key3".to_owned(), "value4".to_owned(), Duration::from_millis(0))?;
+  drop(store);
+
+  let scanning = || KvStoreOptions {
+    index: IndexMode::None,
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_options(temp_dir.path(), scanning())?;
+  assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+  assert_eq!(store.get("key2".to_owned())?, None);
+  assert_eq!(store.get("key3".to_owned())?, None);
+  assert!(matches!(
+    store.remove("key2".to_owned()),
+    Err(KvStoreError::RmKeyNotFoundError)
+  ));
+
+  store.remove("key1".to_owned())?;
+  assert_eq!(store.get("key1".to_owned())?, None);
+  // a key that's new to the index but not to the log is removed with a tombstone
+  store.set("key2".to_owned(), "value5".to_owned())?;
+  store.remove("key2".to_owned())?;
+  assert_eq!(store.get("key2".to_owned())?, None);
+  store.set("key4".to_owned(), "value6".to_owned())?;
+  assert_eq!(store.get("key4".to_owned())?, Some("value6".to_owned()));
+  drop(store);
+
+  // reopened either way, the store holds what was written without an index
+  let mut store = KvStore::open_with_options(temp_dir.path(), scanning())?;
+  assert_eq!(store.get("key1".to_owned())?, None);
+  assert_eq!(store.get("key4".to_owned())?, Some("value6".to_owned()));
+  store.compact()?;
+  assert_eq!(store.get("key4".to_owned())?, Some("value6".to_owned()));
+  drop(store);
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("key1".to_owned())?, None);
+  assert_eq!(store.get("key2".to_owned())?, None);
+  assert_eq!(store.get("key4".to_owned())?, Some("value6".to_owned()));
+  assert_eq!(store.len()?, 1);
+
+  Ok(())
+}
+
+// A snapshot should keep the view it was taken with while another thread overwrites, removes, compacts
+// and clears.
+#[test]
+fn snapshot_consistent_view() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  for i in 0..100 {
+    store.set(format!("key{:03}", i), format!("value{}", i))?;
+  }
+  let store = Arc::new(Mutex::new(store));
+  let mut snapshot = store.lock().unwrap().snapshot()?;
+
+  let writer = {
+    let store = store.clone();
+    thread::spawn(move || -> Result<()> {
+      for round in 0..5 {
+        for i in 0..100 {
+          let mut store = store.lock().unwrap();
+          store.set(format!("key{:03}", i), format!("round{}", round))?;
+          store.set(format!("new{:03}", i), "new".to_owned())?;
+        }
+      }
+      let mut store = store.lock().unwrap();
+      store.remove("key000".to_owned())?;
+      store.compact()?;
+      store.clear()
+    })
+  };
+
+  for _ in 0..5 {
+    let entries = snapshot.iter().collect::<Result<Vec<_>>>()?;
+    assert_eq!(entries.len(), 100);
+    for (i, (key, value)) in entries.into_iter().enumerate() {
+      assert_eq!(key, format!("key{:03}", i));
+      assert_eq!(value, format!("value{}", i));
+    }
+  }
+  writer.join().expect("writer panicked")?;
+
+  // the store moved on, the snapshot didn't
+  assert!(store.lock().unwrap().is_empty()?);
+  assert_eq!(snapshot.len(), 100);
+  assert_eq!(snapshot.get("key000")?, Some("value0".to_owned()));
+  assert_eq!(snapshot.get("new000")?, None);
+  assert_eq!(snapshot.scan_prefix("key09")?.len(), 10);
+
+  Ok(())
+}
+
+// Opening a directory that doesn't exist should create it along with its parents, unless told not to.
+#[test]
+fn open_creates_dir() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let dir = temp_dir.path().join("a").join("b").join("c");
+
+  let no_create = KvStoreOptions {
+    create_dir: false,
+    ..KvStoreOptions::default()
+  };
+  assert!(matches!(
+    KvStore::open_with_options(&dir, no_create.clone()),
+    Err(KvStoreError::IoError(ref e)) if e.kind() == io::ErrorKind::NotFound
+  ));
+  assert!(!temp_dir.path().join("a").exists());
+
+  let mut store = KvStore::open(&dir)?;
+  assert!(dir.is_dir());
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  drop(store);
+
+  // the directory exists now, so it opens without creating it
+  let mut store = KvStore::open_with_options(&dir, no_create)?;
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+  Ok(())
+}
+
+// EXPIRE, PERSIST and TTL should manage TTLs with Redis' replies, including for missing keys and keys
+// without a TTL.
+#[test]
+fn server_expire_ttl_persist() {
+  let addr = start_server();
+  assert_eq!(request(addr, vec!["SET", "key1", "value1"]), "+OK\r\n");
+
+  assert_eq!(request(addr, vec!["TTL", "missing"]), ":-2\r\n");
+  assert_eq!(request(addr, vec!["EXPIRE", "missing", "100"]), ":0\r\n");
+  assert_eq!(request(addr, vec!["PERSIST", "missing"]), ":0\r\n");
+
+  assert_eq!(request(addr, vec!["TTL", "key1"]), ":-1\r\n");
+  assert_eq!(request(addr, vec!["PERSIST", "key1"]), ":0\r\n");
+  assert_eq!(request(addr, vec!["EXPIRE", "key1", "100"]), ":1\r\n");
+  assert_eq!(request(addr, vec!["TTL", "key1"]), ":100\r\n");
+  assert_eq!(request(addr, vec!["GET", "key1"]), "$6\r\nvalue1\r\n");
+  assert_eq!(request(addr, vec!["PERSIST", "key1"]), ":1\r\n");
+  assert_eq!(request(addr, vec!["TTL", "key1"]), ":-1\r\n");
+  assert_eq!(request(addr, vec!["GET", "key1"]), "$6\r\nvalue1\r\n");
+
+  assert!(request(addr, vec!["EXPIRE", "key1", "soon"]).starts_with("-ERR value is not an integer"));
+  assert!(request(addr, vec!["TTL"]).starts_with("-ERR wrong number of arguments for 'ttl' command"));
+  // a TTL that's already over removes the key
+  assert_eq!(request(addr, vec!["EXPIRE", "key1", "0"]), ":1\r\n");
+  assert_eq!(request(addr, vec!["TTL", "key1"]), ":-2\r\n");
+  assert_eq!(request(addr, vec!["GET", "key1"]), "$-1\r\n");
+}
+
+// Setting and clearing a key's expiry should keep its value, and survive reopening the store.
+#[test]
+fn expire_existing_key() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.set("key2".to_owned(), "value2".to_owned())?;
+
+  assert!(!store.expire("missing", Some(Duration::from_secs(100)))?);
+  assert!(!store.expire("missing", None)?);
+
+  assert!(store.expire("key1", Some(Duration::from_secs(100)))?);
+  let expires = store.get_meta("key1".to_owned())?.unwrap().expires.unwrap();
+  assert!(expires > SystemTime::now() + Duration::from_secs(90));
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+  // a TTL that's over hides the key right away
+  assert!(store.expire("key2", Some(Duration::from_millis(0)))?);
+  assert_eq!(store.get("key2".to_owned())?, None);
+  assert!(!store.expire("key2", None)?);
+  drop(store);
+
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get_meta("key1".to_owned())?.unwrap().expires, Some(expires));
+  assert_eq!(store.get("key2".to_owned())?, None);
+  assert!(store.expire("key1", None)?);
+  assert_eq!(store.get_meta("key1".to_owned())?.unwrap().expires, None);
+  drop(store);
+
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get_meta("key1".to_owned())?.unwrap().expires, None);
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+  Ok(())
+}
+
+// A log written on a machine of any endianness, with its integers big-endian in MessagePack form.
+#[rustfmt::skip]
+const PORTABLE_LOG: &[u8] = &[
+  // Timestamped(0x0000010203040506, Set("key1", "value1"))
+  0x92, 0x04, 0x92, 0xcf, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06,
+  0x92, 0x00, 0x92, 0xa4, b'k', b'e', b'y', b'1', 0xa6, b'v', b'a', b'l', b'u', b'e', b'1',
+  // SetEx("key2", "value2", 0x00007fff00000000)
+  0x92, 0x02, 0x93, 0xa4, b'k', b'e', b'y', b'2', 0xa6, b'v', b'a', b'l', b'u', b'e', b'2',
+  0xcf, 0x00, 0x00, 0x7f, 0xff, 0x00, 0x00, 0x00, 0x00,
+  // Set("key3", "value3")
+  0x92, 0x00, 0x92, 0xa4, b'k', b'e', b'y', b'3', 0xa6, b'v', b'a', b'l', b'u', b'e', b'3',
+];
+
+// Logs should decode, encode and open the same byte for byte on every platform.
+#[test]
+fn portable_log_fixture() -> Result<()> {
+  let records = vec![
+    KvCommand::Timestamped(
+      0x0000_0102_0304_0506,
+      Box::new(KvCommand::Set("key1".to_owned(), "value1".to_owned())),
+    ),
+    KvCommand::SetEx("key2".to_owned(), "value2".to_owned(), 0x0000_7fff_0000_0000),
+    KvCommand::Set("key3".to_owned(), "value3".to_owned()),
+  ];
+
+  let mut encoded = Vec::new();
+  let mut offset = 0;
+  for record in &records {
+    kvs::format::encode_record(record, &mut encoded)?;
+    let (decoded, len) = kvs::format::decode_record(&PORTABLE_LOG[offset..])?;
+    assert_eq!(&decoded, record);
+    offset += len;
+  }
+  assert_eq!(offset, PORTABLE_LOG.len());
+  assert_eq!(encoded, PORTABLE_LOG);
+
+  // behind the header of version 2, whose version is big-endian as well, the only version stamps can be in
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut log = Vec::new();
+  kvs::format::encode_header(2, &mut log);
+  log.extend_from_slice(PORTABLE_LOG);
+  std::fs::write(temp_dir.path().join("kvs.log"), &log)?;
+
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+  let meta = store.get_meta("key1".to_owned())?.unwrap();
+  assert_eq!(
+    meta.modified,
+    Some(SystemTime::UNIX_EPOCH + Duration::from_millis(0x0000_0102_0304_0506))
+  );
+  let meta = store.get_meta("key2".to_owned())?.unwrap();
+  assert_eq!(
+    meta.expires,
+    Some(SystemTime::UNIX_EPOCH + Duration::from_millis(0x0000_7fff_0000_0000))
+  );
+  assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+  Ok(())
+}
+
+// A line that never ends should be rejected once it's too long, rather than buffered until memory runs out.
+#[test]
+fn resp_line_too_long() {
+  let endless = io::repeat(b'1').take(1 << 20);
+  let mut reader = BufReader::new(io::Cursor::new(b"*".to_vec()).chain(endless));
+  let err = resp::read_command(&mut reader).unwrap_err();
+  assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+  let mut reader = BufReader::new(io::Cursor::new(b"+".to_vec()).chain(io::repeat(b'a').take(1 << 20)));
+  assert_eq!(
+    resp::read_value(&mut reader).unwrap_err().kind(),
+    io::ErrorKind::InvalidData
+  );
+}
+
+// A native request claiming to be huge should be rejected before anything is allocated for it.
+#[test]
+fn server_native_request_too_long() -> Result<()> {
+  let addr = start_server();
+  let mut stream = TcpStream::connect(addr)?;
+  stream.write_all(&[server::SERDE_PROTOCOL])?;
+  stream.write_all(&u32::MAX.to_be_bytes())?;
+  // the server hangs up rather than waiting for 4 GiB
+  let mut rest = Vec::new();
+  stream.read_to_end(&mut rest)?;
+  assert!(rest.is_empty());
+
+  // a frame whose request claims a string longer than the frame doesn't decode
+  let mut stream = TcpStream::connect(addr)?;
+  stream.write_all(&[server::SERDE_PROTOCOL])?;
+  let claim = [0x92, 0x00, 0x91, 0xdb, 0xff, 0xff, 0xff, 0xff];
+  stream.write_all(&(claim.len() as u32).to_be_bytes())?;
+  stream.write_all(&claim)?;
+  let mut rest = Vec::new();
+  stream.read_to_end(&mut rest)?;
+  assert!(rest.is_empty());
+
+  assert_eq!(request(addr, vec!["PING"]), "+PONG\r\n");
+
+  Ok(())
+}
+
+// A subscriber should answer PING, and go back to serving commands once unsubscribed from every channel.
+#[test]
+fn server_pubsub_unsubscribe() {
+  let addr = start_server();
+  let mut subscriber = TcpStream::connect(addr).expect("unable to connect");
+  subscriber.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+  let mut reader = BufReader::new(subscriber.try_clone().unwrap());
+  let reply = |kind: &str, channel: &str, count: i64| {
+    RespValue::Array(vec![
+      RespValue::Bulk(Some(kind.as_bytes().to_vec())),
+      RespValue::Bulk(Some(channel.as_bytes().to_vec())),
+      RespValue::Integer(count),
+    ])
+  };
+
+  resp::write_command(&RespCommand::new(vec!["SUBSCRIBE", "news", "sports"]), &mut subscriber).unwrap();
+  assert_eq!(resp::read_value(&mut reader).unwrap(), reply("subscribe", "news", 1));
+  assert_eq!(resp::read_value(&mut reader).unwrap(), reply("subscribe", "sports", 2));
+
+  resp::write_command(&RespCommand::new(vec!["PING"]), &mut subscriber).unwrap();
+  let pong = RespValue::Array(vec![
+    RespValue::Bulk(Some(b"pong".to_vec())),
+    RespValue::Bulk(Some(Vec::new())),
+  ]);
+  assert_eq!(resp::read_value(&mut reader).unwrap(), pong);
+
+  resp::write_command(&RespCommand::new(vec!["UNSUBSCRIBE", "news"]), &mut subscriber).unwrap();
+  assert_eq!(resp::read_value(&mut reader).unwrap(), reply("unsubscribe", "news", 1));
+  assert_eq!(request(addr, vec!["PUBLISH", "news", "hello"]), ":0\r\n");
+  assert_eq!(request(addr, vec!["PUBLISH", "sports", "goal"]), ":1\r\n");
+  let message = RespValue::Array(vec![
+    RespValue::Bulk(Some(b"message".to_vec())),
+    RespValue::Bulk(Some(b"sports".to_vec())),
+    RespValue::Bulk(Some(b"goal".to_vec())),
+  ]);
+  assert_eq!(resp::read_value(&mut reader).unwrap(), message);
+
+  // unsubscribing from all that's left ends subscribed mode
+  resp::write_command(&RespCommand::new(vec!["UNSUBSCRIBE"]), &mut subscriber).unwrap();
+  assert_eq!(
+    resp::read_value(&mut reader).unwrap(),
+    reply("unsubscribe", "sports", 0)
+  );
+  assert_eq!(request(addr, vec!["PUBLISH", "sports", "goal"]), ":0\r\n");
+  resp::write_command(&RespCommand::new(vec!["PING"]), &mut subscriber).unwrap();
+  assert_eq!(
+    resp::read_value(&mut reader).unwrap(),
+    RespValue::SimpleString("PONG".to_owned())
+  );
+}
+
+// A subscriber that doesn't read its messages should be disconnected rather than pile them up.
+#[test]
+fn server_pubsub_slow_subscriber() {
+  let addr = start_server();
+  let mut subscriber = TcpStream::connect(addr).expect("unable to connect");
+  resp::write_command(&RespCommand::new(vec!["SUBSCRIBE", "news"]), &mut subscriber).unwrap();
+  let mut reader = BufReader::new(subscriber.try_clone().unwrap());
+  assert!(matches!(resp::read_value(&mut reader).unwrap(), RespValue::Array(_)));
+
+  // once the socket buffers fill up, the subscriber's own buffer does, and it's dropped
+  let message = "m".repeat(16 * 1024);
+  let mut publisher = TcpStream::connect(addr).expect("unable to connect");
+  let mut replies = BufReader::new(publisher.try_clone().unwrap());
+  let mut delivered = RespValue::Integer(1);
+  for _ in 0..100_000 {
+    resp::write_command(&RespCommand::new(vec!["PUBLISH", "news", &message]), &mut publisher).unwrap();
+    delivered = resp::read_value(&mut replies).unwrap();
+    if delivered == RespValue::Integer(0) {
+      break;
+    }
+  }
+  assert_eq!(delivered, RespValue::Integer(0));
+}
+
+// Without an index, operations going through every key should still see the keys written before open, and the
+// first write should drop a record cut short at the end of the log rather than land behind it.
+#[test]
+fn index_mode_none_whole_store() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  store.set_with_ttl("key2".to_owned(), "value2".to_owned(), Duration::from_secs(3600))?;
+  store.set("key3".to_owned(), "value3".to_owned())?;
+  let total_len = store.stats()?.log_bytes;
+  drop(store);
+  let log = std::fs::OpenOptions::new()
+    .write(true)
+    .open(temp_dir.path().join("kvs.log"))?;
+  log.set_len(total_len - 3)?;
+
+  let scanning = || KvStoreOptions {
+    index: IndexMode::None,
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_options(temp_dir.path(), scanning())?;
+  assert_eq!(store.len()?, 2);
+  assert!(store.contains_key("key1")?);
+  assert!(!store.contains_key("key3")?);
+  assert_eq!(store.scan_glob("key*")?, vec!["key1", "key2"]);
+  store.set("key4".to_owned(), "value4".to_owned())?;
+  assert_eq!(
+    store.scan_prefix("key")?,
+    vec![
+      ("key1".to_owned(), "value1".to_owned()),
+      ("key2".to_owned(), "value2".to_owned()),
+      ("key4".to_owned(), "value4".to_owned()),
+    ]
+  );
+  store.rename("key2".to_owned(), "key5".to_owned())?;
+  assert!(store
+    .get_meta("key5".to_owned())?
+    .and_then(|meta| meta.expires)
+    .is_some());
+
+  let mut exported = Vec::new();
+  store.export_resp(&mut exported)?;
+  assert_eq!(String::from_utf8_lossy(&exported).matches("SET").count(), 3);
+  let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+  store.backup(backup_dir.path())?;
+  drop(store);
+
+  for dir in [temp_dir.path(), backup_dir.path()] {
+    let mut store = KvStore::open(dir)?;
+    assert_eq!(store.scan_glob("*")?, vec!["key1", "key4", "key5"]);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+  }
+
+  Ok(())
+}
+
+// A server with a socket configured should replace a stale socket file on startup, and unlink its own on shutdown.
+#[cfg(unix)]
+#[test]
+fn run_server_socket_file() -> Result<()> {
+  use std::os::unix::net::{UnixListener, UnixStream};
+
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let path = temp_dir.path().join("kvs.sock");
+  // a listener that's gone leaves its socket file behind
+  drop(UnixListener::bind(&path)?);
+  assert!(path.exists());
+
+  let config = ServerConfig::from_listener(TcpListener::bind("127.0.0.1:0")?)
+    .with_dir(temp_dir.path())
+    .with_socket(&path);
+  let shutdown = config.shutdown_handle();
+  let serving = thread::spawn(move || server::run_server(config));
+
+  let mut stream = loop {
+    match UnixStream::connect(&path) {
+      Ok(stream) => break stream,
+      Err(_) => thread::sleep(Duration::from_millis(10)),
+    }
+  };
+  resp::write_command(&RespCommand::new(vec!["PING"]), &mut stream)?;
+  stream.shutdown(Shutdown::Write)?;
+  let mut reply = String::new();
+  stream.read_to_string(&mut reply)?;
+  assert_eq!(reply, "+PONG\r\n");
+
+  shutdown.shutdown();
+  serving.join().expect("server panicked")?;
+  assert!(!path.exists());
+
+  Ok(())
+}
+
+// The audit sink should observe clearing the store and keys expiring, which write no commands.
+#[test]
+fn audit_sink_clear_and_expire() -> Result<()> {
+  let sink = Arc::new(RecordingSink::default());
+  let mut store = KvStore::open_with_storage(
+    VecStorage::default(),
+    KvStoreOptions {
+      audit_sink: sink.clone(),
+      ..KvStoreOptions::default()
+    },
+  )?;
+  store.set_with_ttl("key1".to_owned(), "value1".to_owned(), Duration::from_millis(1))?;
+  store.set("key2".to_owned(), "value2".to_owned())?;
+  thread::sleep(Duration::from_millis(10));
+  assert_eq!(store.purge_expired()?, 1);
+  store.clear()?;
+
+  assert_eq!(
+    *sink.1.lock().unwrap(),
+    vec!["expire key1".to_owned(), "clear".to_owned()]
+  );
+  assert_eq!(sink.0.lock().unwrap().len(), 2);
+
+  Ok(())
+}
+
+// Without an index, removing a prefix or a range and clearing should reach keys only in the log, not just the ones
+// written since open.
+#[test]
+fn index_mode_none_bulk_removes() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  for key in ["a1", "a2", "b1", "b2", "c1"] {
+    store.set(key.to_owned(), "value".to_owned())?;
+  }
+  drop(store);
+
+  let scanning = KvStoreOptions {
+    index: IndexMode::None,
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_options(temp_dir.path(), scanning.clone())?;
+  store.set("a3".to_owned(), "value".to_owned())?;
+  assert_eq!(store.remove_prefix("a")?, 3);
+  assert_eq!(store.remove_range("b1".to_owned().."b9".to_owned())?, 2);
+  assert_eq!(store.len()?, 1);
+  drop(store);
+
+  let mut store = KvStore::open_with_options(temp_dir.path(), scanning)?;
+  for key in ["a1", "a2", "a3", "b1", "b2"] {
+    assert_eq!(store.get(key.to_owned())?, None);
+  }
+  assert_eq!(store.get("c1".to_owned())?, Some("value".to_owned()));
+
+  // clearing tells subscribers about the keys only in the log too
+  let events = store.subscribe(String::new());
+  store.clear()?;
+  assert_eq!(
+    events.try_iter().collect::<Vec<_>>(),
+    vec![KeyEvent::Removed { key: "c1".to_owned() }]
+  );
+
+  Ok(())
+}
+
+// Without an index, renaming and copying should forget the replayed index however they return, so keys not written
+// since open are still looked up in the log, where changes made behind the store's back show.
+#[test]
+fn index_mode_none_forgets_index() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  drop(store);
+
+  let options = KvStoreOptions {
+    index: IndexMode::None,
+    ..KvStoreOptions::default()
+  };
+  let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+  assert!(matches!(
+    store.rename("missing".to_owned(), "key2".to_owned()),
+    Err(KvStoreError::RmKeyNotFoundError)
+  ));
+  store.rename("key1".to_owned(), "key1".to_owned())?;
+  assert!(matches!(
+    store.copy("missing".to_owned(), "key2".to_owned(), true),
+    Err(KvStoreError::KeyNotFoundError)
+  ));
+  assert!(!store.copy("key1".to_owned(), "key1".to_owned(), true)?);
+  store.set("key2".to_owned(), "value2".to_owned())?;
+  assert!(!store.copy("key1".to_owned(), "key2".to_owned(), false)?);
+
+  let options = KvStoreOptions {
+    compact_on_open: false,
+    ..KvStoreOptions::default()
+  };
+  let mut other = KvStore::open_with_options(temp_dir.path(), options)?;
+  other.remove("key1".to_owned())?;
+  drop(other);
+  assert_eq!(store.get("key1".to_owned())?, None);
+
+  Ok(())
+}
+
+// Writers to different shards should take about as long as one of them alone, where a single lock runs them one
+// after another, and gets should keep reading the right values once a shard's log is rewritten.
+#[test]
+fn sharded_store_contention() -> Result<()> {
+  // holds the lock of the store being written for a while on every set
+  struct SlowSink;
+
+  impl AuditSink for SlowSink {
+    fn on_command(&self, _: &KvCommand, _: u64) {
+      thread::sleep(Duration::from_millis(20));
+    }
+  }
+
+  let options = KvStoreOptions {
+    audit_sink: Arc::new(SlowSink),
+    ..KvStoreOptions::default()
+  };
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let sharded = Arc::new(ShardedKvStore::open_with_options(temp_dir.path(), 4, options.clone())?);
+  // five keys for each shard
+  let keys: Vec<Vec<String>> = (0..4)
+    .map(|shard| {
+      (0..)
+        .map(|key_id| format!("key{}", key_id))
+        .filter(|key| sharded.shard_of(key) == shard)
+        .take(5)
+        .collect()
+    })
+    .collect();
+
+  let started = std::time::Instant::now();
+  let handles: Vec<_> = keys
+    .iter()
+    .cloned()
+    .map(|keys| {
+      let sharded = sharded.clone();
+      thread::spawn(move || -> Result<()> {
+        for key in keys {
+          sharded.set(key.clone(), key)?;
+        }
+        Ok(())
+      })
+    })
+    .collect();
+  for handle in handles {
+    handle.join().expect("writer panicked")?;
+  }
+  let sharded_elapsed = started.elapsed();
+
+  let single_dir = TempDir::new().expect("unable to create temporary working directory");
+  let single = Arc::new(Mutex::new(KvStore::open_with_options(single_dir.path(), options)?));
+  let started = std::time::Instant::now();
+  let handles: Vec<_> = keys
+    .iter()
+    .cloned()
+    .map(|keys| {
+      let single = single.clone();
+      thread::spawn(move || -> Result<()> {
+        for key in keys {
+          single.lock().unwrap().set(key.clone(), key)?;
+        }
+        Ok(())
+      })
+    })
+    .collect();
+  for handle in handles {
+    handle.join().expect("writer panicked")?;
+  }
+  let single_elapsed = started.elapsed();
+
+  // 100ms of sets on each shard at once, 400ms one after another
+  assert!(
+    sharded_elapsed * 2 < single_elapsed,
+    "sharded writers took {:?}, a single lock {:?}",
+    sharded_elapsed,
+    single_elapsed
+  );
+
+  let key = keys[0][0].clone();
+  assert_eq!(sharded.get(key.clone())?, Some(key.clone()));
+  sharded.set(key.clone(), "new".to_owned())?;
+  sharded.with_shard(0, |store| store.compact())??;
+  assert_eq!(sharded.get(key)?, Some("new".to_owned()));
+  for key in keys.iter().flatten().skip(1) {
+    assert_eq!(sharded.get(key.clone())?, Some(key.clone()));
+  }
+  assert_eq!(sharded.get("missing".to_owned())?, None);
+
+  Ok(())
+}
+
+// Merging should only read the other store's directory, leaving every file in it as it was, torn tail and all.
+#[test]
+fn merge_leaves_source_untouched() -> Result<()> {
+  let other_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut other = KvStore::open(other_dir.path())?;
+  other.set("key1".to_owned(), "value1".to_owned())?;
+  other.set("key2".to_owned(), "value2".to_owned())?;
+  other.remove("key2".to_owned())?;
+  drop(other);
+  // a set cut short, as a crash while appending leaves it
+  let log_path = other_dir.path().join("kvs.log");
+  let mut bytes = std::fs::read(&log_path)?;
+  bytes.extend_from_slice(&[0x92, 0xa3]);
+  std::fs::write(&log_path, bytes)?;
+
+  let files = |dir: &std::path::Path| -> Vec<(std::path::PathBuf, Vec<u8>)> {
+    let mut files: Vec<_> = WalkDir::new(dir)
+      .into_iter()
+      .map(|entry| entry.unwrap().into_path())
+      .filter(|path| path.is_file())
+      .map(|path| {
+        let bytes = std::fs::read(&path).unwrap();
+        (path, bytes)
+      })
+      .collect();
+    files.sort();
+    files
+  };
+  let before = files(other_dir.path());
+
+  let mut store = KvStore::open_with_storage(VecStorage::default(), KvStoreOptions::default())?;
+  assert_eq!(store.merge_from(other_dir.path(), ConflictPolicy::Error)?, 1);
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+  assert_eq!(store.get("key2".to_owned())?, None);
+  assert_eq!(files(other_dir.path()), before);
+
+  Ok(())
+}
+
+// Changing a key's expiry should keep when it was last set, pass whatever size limits now apply, and write nothing
+// when the expiry stays the same.
+#[test]
+fn expire_keeps_record() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let mut store = KvStore::open(temp_dir.path())?;
+  store.set("key1".to_owned(), "value1".to_owned())?;
+  let modified = store.get_meta("key1".to_owned())?.unwrap().modified;
+  assert!(modified.is_some());
+  thread::sleep(Duration::from_millis(20));
+
+  let options = KvStoreOptions {
+    max_value_bytes: Some(2),
+    ..store.options().clone()
+  };
+  store.set_options(options)?;
+  assert!(store.expire("key1", Some(Duration::from_secs(100)))?);
+  let meta = store.get_meta("key1".to_owned())?.unwrap();
+  assert_eq!(meta.modified, modified);
+  assert!(meta.expires.is_some());
+  assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+  assert!(store.expire("key1", None)?);
+  let log_bytes = store.stats()?.log_bytes;
+  assert!(store.expire("key1", None)?);
+  assert_eq!(store.stats()?.log_bytes, log_bytes);
+  drop(store);
+
+  let mut store = KvStore::open(temp_dir.path())?;
+  let meta = store.get_meta("key1".to_owned())?.unwrap();
+  assert_eq!(meta.modified, modified);
+  assert_eq!(meta.expires, None);
+
+  Ok(())
+}
+
+// A server compacting in the background should still have removes undo the sets right before them, and leave the
+// garbage of removes to the compactor.
+#[test]
+fn run_server_compaction_interval() -> Result<()> {
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  let listener = TcpListener::bind("127.0.0.1:0")?;
+  let addr = listener.local_addr()?;
+  let config = ServerConfig::from_listener(listener)
+    .with_dir(temp_dir.path())
+    .with_compaction_interval(Some(Duration::from_secs(3600)))
+    .with_store_options(KvStoreOptions {
+      compaction_threshold: 1,
+      ..KvStoreOptions::default()
+    });
+  let shutdown = config.shutdown_handle();
+  let serving = thread::spawn(move || server::run_server(config));
+
+  assert_eq!(request(addr, vec!["SET", "key1", "value1"]), "+OK\r\n");
+  assert_eq!(request(addr, vec!["DEL", "key1"]), ":1\r\n");
+  // the log is back to just its header
+  assert_eq!(std::fs::metadata(temp_dir.path().join("kvs.log"))?.len(), 8);
+
+  // a tombstone is garbage, which writes don't compact away with the compactor around
+  assert_eq!(request(addr, vec!["SET", "key2", "value2"]), "+OK\r\n");
+  assert_eq!(request(addr, vec!["SET", "key3", "value3"]), "+OK\r\n");
+  let len = std::fs::metadata(temp_dir.path().join("kvs.log"))?.len();
+  assert_eq!(request(addr, vec!["DEL", "key2"]), ":1\r\n");
+  assert!(std::fs::metadata(temp_dir.path().join("kvs.log"))?.len() > len);
+
+  shutdown.shutdown();
+  serving.join().expect("server panicked")?;
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("key1".to_owned())?, None);
+  assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+  Ok(())
+}
+
+// Compacting a shared store whose log is of version 1 should bring every record to the current version, sets
+// appended since it was opened included.
+#[test]
+fn compact_shared_format_version_1() -> Result<()> {
+  let fixture = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/v1.log");
+  let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+  std::fs::copy(&fixture, temp_dir.path().join("kvs.log"))?;
+  let store = Mutex::new(KvStore::open(temp_dir.path())?);
+  store.lock().unwrap().set("key3".to_owned(), "value4".to_owned())?;
+
+  KvStore::compact_shared(&store)?;
+  let mut store = store.into_inner().unwrap();
+  store.set("key4".to_owned(), "value5".to_owned())?;
+  assert!(store.get_meta("key4".to_owned())?.unwrap().modified.is_some());
+  drop(store);
+
+  let log = std::fs::read(temp_dir.path().join("kvs.log"))?;
+  assert_eq!(kvs::format::detect_version(&log)?.0, kvs::format::FORMAT_VERSION);
+  let report = KvStore::verify(temp_dir.path())?;
+  assert_eq!((report.live_keys, report.corrupted_at), (3, None));
+  let mut store = KvStore::open(temp_dir.path())?;
+  assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+  assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+  assert_eq!(store.get("key4".to_owned())?, Some("value5".to_owned()));
+
+  Ok(())
+}